@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use serde::{Deserialize, Serialize};
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/// How long a password reset token stays valid.
+pub const RESET_TOKEN_TTL_SECS: i64 = 15 * 60;
+/// Reset tokens an account may have outstanding; a new request past this
+/// replaces the oldest.
+pub const MAX_RESET_TOKENS_PER_ACCOUNT: usize = 3;
+
+/// A registered account. Its `id` is the user ID used everywhere else and
+/// stays the same across logins; the matching `User` only tracks presence.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub id: String,
+    pub username: String,
+    /// Argon2id hash in PHC string format.
+    pub password_hash: String,
+    pub created: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountError {
+    InvalidUsername,
+    WeakPassword,
+    UsernameTaken(String),
+    /// Unknown username or wrong password; the two are not told apart.
+    InvalidCredentials,
+    InvalidResetToken,
+    Hash(String),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::InvalidUsername => write!(
+                f,
+                "Username must be 1-{} letters, digits, '.', '-' or '_'",
+                MAX_USERNAME_LEN
+            ),
+            AccountError::WeakPassword => {
+                write!(f, "Password must be at least {} characters", MIN_PASSWORD_LEN)
+            }
+            AccountError::UsernameTaken(username) => write!(f, "Username {} is taken", username),
+            AccountError::InvalidCredentials => write!(f, "Invalid username or password"),
+            AccountError::InvalidResetToken => write!(f, "Invalid or expired reset token"),
+            AccountError::Hash(e) => write!(f, "Password hashing failed: {}", e),
+        }
+    }
+}
+
+impl AccountError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountError::InvalidUsername => "invalid_username",
+            AccountError::WeakPassword => "weak_password",
+            AccountError::UsernameTaken(_) => "username_taken",
+            AccountError::InvalidCredentials => "invalid_credentials",
+            AccountError::InvalidResetToken => "invalid_reset_token",
+            AccountError::Hash(_) => "internal",
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+/// Outstanding password reset tokens, mapped to the account and expiry
+/// time. Each token can be used once. Expired tokens are dropped whenever
+/// one is created or taken, so the table only holds live ones.
+#[derive(Default)]
+pub struct ResetTokens {
+    tokens: HashMap<String, (String, i64)>,
+}
+
+impl ResetTokens {
+    pub fn create(&mut self, account_id: &str) -> String {
+        let now = chrono::Local::now().timestamp();
+        self.tokens.retain(|_, (_, expires)| *expires > now);
+
+        let mut outstanding: Vec<(i64, String)> = self
+            .tokens
+            .iter()
+            .filter(|(_, (id, _))| id == account_id)
+            .map(|(token, (_, expires))| (*expires, token.clone()))
+            .collect();
+        outstanding.sort();
+        for (_, token) in outstanding.iter().rev().skip(MAX_RESET_TOKENS_PER_ACCOUNT - 1) {
+            self.tokens.remove(token);
+        }
+
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        self.tokens.insert(token.clone(), (account_id.to_string(), now + RESET_TOKEN_TTL_SECS));
+        token
+    }
+
+    /// Consumes `token`, returning the account it was issued for if it has
+    /// not expired.
+    pub fn take(&mut self, token: &str) -> Option<String> {
+        let now = chrono::Local::now().timestamp();
+        self.tokens.retain(|_, (_, expires)| *expires > now);
+        self.tokens.remove(token).map(|(account_id, _)| account_id)
+    }
+
+    /// Drops every token issued for the account, e.g. once its password is set.
+    pub fn revoke_all(&mut self, account_id: &str) {
+        self.tokens.retain(|_, (id, _)| id != account_id);
+    }
+}
+
+/// How password reset tokens reach the account holder.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResetDelivery {
+    /// Not sent anywhere; only the request is logged.
+    Disabled,
+    /// Written to the server log, for testing.
+    Log,
+    /// Runs a program, e.g. a script that mails the token, with
+    /// `VOIP_RESET_USERNAME`, `VOIP_RESET_TOKEN` and `VOIP_RESET_TTL_SECS` in
+    /// its environment. The token is kept off the command line, where other
+    /// local users could see it.
+    Command(PathBuf),
+}
+
+impl Default for ResetDelivery {
+    /// Debug builds log tokens; release builds need delivery configured.
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            ResetDelivery::Log
+        } else {
+            ResetDelivery::Disabled
+        }
+    }
+}
+
+impl ResetDelivery {
+    /// Reads `VOIP_RESET_DELIVERY`: `none`, `log` or `command:<program>`.
+    pub fn from_env() -> Result<Self, String> {
+        let Ok(value) = std::env::var("VOIP_RESET_DELIVERY") else {
+            return Ok(ResetDelivery::default());
+        };
+        match value.as_str() {
+            "none" => Ok(ResetDelivery::Disabled),
+            "log" => Ok(ResetDelivery::Log),
+            _ => match value.strip_prefix("command:") {
+                Some(program) if !program.is_empty() => Ok(ResetDelivery::Command(PathBuf::from(program))),
+                _ => Err(format!("invalid value '{}' for VOIP_RESET_DELIVERY", value)),
+            },
+        }
+    }
+
+    pub async fn deliver(&self, username: &str, token: &str) -> Result<(), String> {
+        match self {
+            ResetDelivery::Disabled => {
+                log::info!("🔑 Password reset token issued for {} (no delivery configured)", username);
+                Ok(())
+            }
+            ResetDelivery::Log => {
+                log::warn!("🔑 Password reset token for {}: {} (valid for {} minutes)",
+                    username, token, RESET_TOKEN_TTL_SECS / 60);
+                Ok(())
+            }
+            ResetDelivery::Command(program) => {
+                let status = tokio::process::Command::new(program)
+                    .env("VOIP_RESET_USERNAME", username)
+                    .env("VOIP_RESET_TOKEN", token)
+                    .env("VOIP_RESET_TTL_SECS", RESET_TOKEN_TTL_SECS.to_string())
+                    .status()
+                    .await
+                    .map_err(|e| format!("cannot run {}: {}", program.display(), e))?;
+                if !status.success() {
+                    return Err(format!("{} exited with {}", program.display(), status));
+                }
+                log::info!("🔑 Password reset token for {} sent", username);
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn validate_username(username: &str) -> Result<(), AccountError> {
+    let valid = !username.is_empty()
+        && username.len() <= MAX_USERNAME_LEN
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    if valid {
+        Ok(())
+    } else {
+        Err(AccountError::InvalidUsername)
+    }
+}
+
+/// Hashes `password` with Argon2id and a random salt. This is deliberately
+/// slow, so callers should run it off the async executor.
+pub fn hash_password(password: &str) -> Result<String, AccountError> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(AccountError::WeakPassword);
+    }
+    let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes())
+        .map_err(|e| AccountError::Hash(e.to_string()))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AccountError::Hash(e.to_string()))
+}
+
+/// A valid hash of no account's password, checked in place of a real one
+/// when the username is unknown so that such logins take as long as a
+/// wrong password. Computed on first use.
+pub fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password(&uuid::Uuid::new_v4().to_string()).unwrap_or_default())
+}
+
+pub fn verify_password(password_hash: &str, password: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::call_manager::CallManager;
+    use crate::cdr::CdrStore;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn dummy_hash_is_a_real_hash() {
+        assert!(PasswordHash::new(dummy_hash()).is_ok());
+        assert!(!verify_password(dummy_hash(), "password"));
+    }
+
+    #[test]
+    fn reset_tokens_expire() {
+        let mut resets = ResetTokens::default();
+        let token = resets.create("alice");
+        resets.tokens.get_mut(&token).unwrap().1 = chrono::Local::now().timestamp();
+        assert_eq!(resets.take(&token), None);
+        assert!(resets.tokens.is_empty(), "expired tokens are dropped");
+    }
+
+    #[test]
+    fn reset_tokens_are_capped_per_account() {
+        let mut resets = ResetTokens::default();
+        let expired = resets.create("bob");
+        resets.tokens.get_mut(&expired).unwrap().1 = chrono::Local::now().timestamp();
+
+        let mut issued: Vec<String> = (0..MAX_RESET_TOKENS_PER_ACCOUNT).map(|_| resets.create("alice")).collect();
+        assert!(!resets.tokens.contains_key(&expired), "expired tokens are pruned on create");
+        // Issued in the same second, so make the first clearly the oldest.
+        resets.tokens.get_mut(&issued[0]).unwrap().1 -= 60;
+        issued.push(resets.create("alice"));
+        let bob = resets.create("bob");
+
+        assert_eq!(resets.tokens.len(), MAX_RESET_TOKENS_PER_ACCOUNT + 1);
+        assert_eq!(resets.take(&issued[0]), None, "the oldest is replaced");
+        for token in &issued[1..] {
+            assert_eq!(resets.take(token).as_deref(), Some("alice"));
+        }
+        assert_eq!(resets.take(&bob).as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn reset_tokens_are_single_use() {
+        let mut resets = ResetTokens::default();
+        let token = resets.create("alice");
+        let other = resets.create("bob");
+        assert_eq!(resets.take(&token).as_deref(), Some("alice"));
+        assert_eq!(resets.take(&token), None);
+        assert_eq!(resets.take("unknown"), None);
+
+        let first = resets.create("bob");
+        resets.revoke_all("bob");
+        assert_eq!(resets.take(&first), None);
+        assert_eq!(resets.take(&other), None);
+    }
+
+    #[tokio::test]
+    async fn delivers_reset_tokens_through_a_command() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("voip-reset-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let script = dir.join("deliver.sh");
+        let out = dir.join("out");
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\necho \"$VOIP_RESET_USERNAME $VOIP_RESET_TOKEN $VOIP_RESET_TTL_SECS\" > {}\n", out.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        ResetDelivery::Command(script).deliver("alice", "t0k3n").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "alice t0k3n 900\n");
+
+        let missing = ResetDelivery::Command(dir.join("missing"));
+        assert!(missing.deliver("alice", "t0k3n").await.is_err());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn resetting_the_password_ends_sessions_and_other_tokens() {
+        let cdr_path = std::env::temp_dir().join(format!("voip-accounts-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut manager = CallManager::new(MemoryStorage::new(), CdrStore::open(cdr_path.clone()).unwrap());
+        let alice = manager.create_account("alice", "old".into()).unwrap();
+        let bob = manager.create_account("bob", "old".into()).unwrap();
+        let alice_session = manager.log_in(&alice);
+        let bob_session = manager.log_in(&bob);
+        let token = manager.create_reset_token(&alice.id);
+        let second = manager.create_reset_token(&alice.id);
+
+        let account_id = manager.take_reset_token(&token).unwrap();
+        assert!(manager.set_password_hash(&account_id, "new".into()));
+        assert_eq!(manager.account_by_name("alice").unwrap().password_hash, "new");
+        assert_eq!(manager.session_user(&alice_session), None);
+        assert_eq!(manager.session_user(&bob_session), Some(bob.id.as_str()));
+        assert_eq!(manager.take_reset_token(&second), None);
+        let _ = std::fs::remove_file(cdr_path);
+    }
+}
@@ -0,0 +1,657 @@
+use std::collections::VecDeque;
+use std::env;
+use std::sync::Mutex;
+
+use crate::config::env_bool;
+use crate::fft::{fft, spectrum, Complex};
+use crate::resample::Downsampler;
+
+/// Geigel double-talk threshold: near-end talk is assumed when the
+/// microphone peaks above this fraction of the recent far-end peak, which
+/// holds for echo paths that attenuate by at least 6 dB.
+const DOUBLE_TALK_THRESHOLD: f32 = 0.5;
+/// Blocks adaptation stays frozen after double talk was last detected.
+const HANGOVER_BLOCKS: usize = 8;
+/// Far-end peaks below this are treated as silence, with nothing to learn.
+const FAR_END_SILENCE: f32 = 64.0;
+
+/// Played audio kept for capture to look back on: 1 s.
+const FAR_END_HISTORY_MS: u32 = 1000;
+/// Capture calls the playout-to-capture lag is left to settle for after
+/// the streams start, before drift is corrected against it.
+const SETTLE_CALLS: usize = 100;
+/// Lag drift tolerated before the read position is nudged: 1 ms.
+const DRIFT_DEADBAND_MS: u32 = 1;
+/// Lag change that moves the read position at once rather than sample by
+/// sample: a stream that stalled or dropped audio.
+const RESYNC_MS: u32 = 20;
+/// Rate the delay estimator correlates at; speech energy is mostly below 2 kHz.
+const ESTIMATOR_RATE: u32 = 4000;
+/// Capture the delay estimator correlates against the far end at a time.
+const ESTIMATOR_WINDOW_MS: u32 = 1000;
+/// How often the delay is estimated.
+const ESTIMATOR_INTERVAL_MS: u32 = 500;
+/// Longest echo delay looked for.
+const MAX_DELAY_MS: u32 = 400;
+/// Normalised correlation below which a peak is not trusted as the echo.
+const MIN_CORRELATION: f32 = 0.3;
+/// Where realignment puts the echo: this far after the reference, so the
+/// start of the echo path stays inside the filter.
+const ALIGN_MARGIN_MS: u32 = 4;
+
+/// Whether captured audio goes through the echo canceller, and the longest
+/// echo path it models.
+#[derive(Debug, Clone)]
+pub struct EchoConfig {
+    pub enabled: bool,
+    pub tail_ms: u32,
+}
+
+impl Default for EchoConfig {
+    fn default() -> Self {
+        EchoConfig {
+            enabled: true,
+            tail_ms: 128,
+        }
+    }
+}
+
+impl EchoConfig {
+    /// Reads `VOIP_AEC_ENABLED` and `VOIP_AEC_TAIL_MS`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = EchoConfig::default();
+        if let Some(enabled) = env_bool("VOIP_AEC_ENABLED")? {
+            config.enabled = enabled;
+        }
+        if let Ok(value) = env::var("VOIP_AEC_TAIL_MS") {
+            config.tail_ms = match value.parse() {
+                Ok(ms) if (10..=1000).contains(&ms) => ms,
+                _ => return Err(format!("invalid value '{}' for VOIP_AEC_TAIL_MS", value)),
+            };
+        }
+        Ok(config)
+    }
+}
+
+/// Acoustic echo canceller: a partitioned-block frequency-domain NLMS
+/// filter (overlap-save) that models the echo path from the far-end
+/// signal being played to the microphone, and subtracts its estimate of
+/// the echo from the captured audio.
+///
+/// Two copies of the filter are kept. The background filter adapts; the
+/// foreground filter produces the output and only takes the background's
+/// weights once they have cancelled more echo for a while. Near-end speech
+/// the double-talk detector misses can then throw the background off, but
+/// not what is sent.
+pub struct EchoCanceller {
+    block: usize,
+    /// Far-end spectra of the last `partitions` blocks, newest first.
+    far_spectra: VecDeque<Vec<Complex>>,
+    background: Vec<Vec<Complex>>,
+    foreground: Vec<Vec<Complex>>,
+    /// Smoothed error energies of the background and foreground filters.
+    background_error: f32,
+    foreground_error: f32,
+    /// Smoothed far-end power per frequency bin, for step normalisation.
+    power: Vec<f32>,
+    last_far: Vec<f32>,
+    /// Far-end peaks of the blocks the filter spans, for the Geigel detector.
+    far_peaks: VecDeque<f32>,
+    hangover: usize,
+    step: f32,
+    near_pending: Vec<i16>,
+    far_pending: Vec<i16>,
+}
+
+impl EchoCanceller {
+    /// A canceller for audio at `sample_rate` covering echo paths up to
+    /// `tail_ms` long.
+    pub fn new(sample_rate: u32, tail_ms: u32) -> Self {
+        // Blocks of about 10 ms, rounded up to a power of two for the FFT.
+        let block = (sample_rate as usize / 100).next_power_of_two();
+        let tail = sample_rate as usize * tail_ms as usize / 1000;
+        let partitions = tail.div_ceil(block).max(1);
+        let bins = 2 * block;
+        Self {
+            block,
+            far_spectra: (0..partitions).map(|_| vec![Complex::default(); bins]).collect(),
+            background: vec![vec![Complex::default(); bins]; partitions],
+            foreground: vec![vec![Complex::default(); bins]; partitions],
+            background_error: 0.0,
+            foreground_error: 0.0,
+            power: vec![0.0; bins],
+            last_far: vec![0.0; block],
+            far_peaks: VecDeque::from(vec![0.0; partitions + 1]),
+            hangover: 0,
+            step: 1.0 / partitions as f32,
+            near_pending: Vec::new(),
+            far_pending: Vec::new(),
+        }
+    }
+
+    /// Removes the echo of `far` (the audio played, sample-aligned with the
+    /// capture) from `near`. Output comes in whole blocks, so it may lag the
+    /// input by up to one block.
+    pub fn process(&mut self, near: &[i16], far: &[i16]) -> Vec<i16> {
+        self.near_pending.extend_from_slice(near);
+        self.far_pending.extend_from_slice(far);
+        // A missing reference is silence.
+        self.far_pending.resize(self.near_pending.len(), 0);
+
+        let mut out = Vec::with_capacity(self.near_pending.len());
+        while self.near_pending.len() >= self.block {
+            let near: Vec<f32> = self.near_pending.drain(..self.block).map(f32::from).collect();
+            let far: Vec<f32> = self.far_pending.drain(..self.block).map(f32::from).collect();
+            out.extend(self.process_block(&near, &far));
+        }
+        out
+    }
+
+    fn process_block(&mut self, near: &[f32], far: &[f32]) -> Vec<i16> {
+        let far_spectrum = spectrum(self.last_far.iter().chain(far).copied(), 2 * self.block);
+        self.last_far.copy_from_slice(far);
+        self.far_spectra.pop_back();
+        self.far_spectra.push_front(far_spectrum);
+        for (power, x) in self.power.iter_mut().zip(&self.far_spectra[0]) {
+            *power = 0.9 * *power + 0.1 * x.norm_sqr();
+        }
+
+        let background_error = self.error(&self.background, near);
+        let foreground_error = self.error(&self.foreground, near);
+        let energy = |signal: &[f32]| signal.iter().map(|s| s * s).sum::<f32>();
+        self.background_error = 0.7 * self.background_error + 0.3 * energy(&background_error);
+        self.foreground_error = 0.7 * self.foreground_error + 0.3 * energy(&foreground_error);
+
+        // Promote the background filter once it is clearly ahead; pull it
+        // back once it has clearly diverged, rather than wait for it to
+        // find its way back.
+        if self.background_error < 0.5 * self.foreground_error {
+            self.foreground.clone_from(&self.background);
+            self.foreground_error = self.background_error;
+        } else if self.background_error > 4.0 * self.foreground_error {
+            self.background.clone_from(&self.foreground);
+            self.background_error = self.foreground_error;
+        }
+
+        let far_peak = far.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        self.far_peaks.pop_back();
+        self.far_peaks.push_front(far_peak);
+        let recent_far_peak = self.far_peaks.iter().copied().fold(0.0, f32::max);
+        let near_peak = near.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        if near_peak > DOUBLE_TALK_THRESHOLD * recent_far_peak {
+            self.hangover = HANGOVER_BLOCKS;
+        } else {
+            self.hangover = self.hangover.saturating_sub(1);
+        }
+        if self.hangover == 0 && recent_far_peak > FAR_END_SILENCE {
+            self.adapt(&background_error);
+        }
+
+        foreground_error.iter().map(|s| s.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16).collect()
+    }
+
+    /// The near-end block minus the echo `weights` estimate for it: the last
+    /// half of the filtered far-end blocks.
+    fn error(&self, weights: &[Vec<Complex>], near: &[f32]) -> Vec<f32> {
+        let n = self.block;
+        let mut echo = vec![Complex::default(); 2 * n];
+        for (weights, spectrum) in weights.iter().zip(&self.far_spectra) {
+            for ((e, w), x) in echo.iter_mut().zip(weights).zip(spectrum) {
+                let y = w.mul(*x);
+                e.re += y.re;
+                e.im += y.im;
+            }
+        }
+        fft(&mut echo, true);
+        near.iter().zip(&echo[n..]).map(|(d, y)| d - y.re).collect()
+    }
+
+    /// One NLMS step of the background filter. Each partition's gradient is
+    /// constrained back to a linear (not circular) convolution.
+    fn adapt(&mut self, error: &[f32]) {
+        let n = self.block;
+        let error_spectrum = spectrum(std::iter::repeat_n(0.0, n).chain(error.iter().copied()), 2 * n);
+        let mean_power = self.power.iter().sum::<f32>() / self.power.len() as f32;
+        let regularisation = 0.01 * mean_power + 1.0;
+
+        for (weights, spectrum) in self.background.iter_mut().zip(&self.far_spectra) {
+            let mut gradient: Vec<Complex> = spectrum
+                .iter()
+                .zip(&error_spectrum)
+                .zip(&self.power)
+                .map(|((x, e), power)| {
+                    let g = x.conj().mul(*e);
+                    let scale = self.step / (power + regularisation);
+                    Complex { re: g.re * scale, im: g.im * scale }
+                })
+                .collect();
+            fft(&mut gradient, true);
+            for g in &mut gradient[n..] {
+                *g = Complex::default();
+            }
+            fft(&mut gradient, false);
+            for (w, g) in weights.iter_mut().zip(&gradient) {
+                w.re += g.re;
+                w.im += g.im;
+            }
+        }
+    }
+}
+
+/// What playout has handed to the output device, numbered by position
+/// since the stream started, so capture can find the stretch an echo came
+/// from however the two device callbacks interleave.
+pub struct FarEnd {
+    samples: VecDeque<i16>,
+    capacity: usize,
+    /// Position just past the newest sample.
+    end: u64,
+}
+
+impl FarEnd {
+    pub fn new(sample_rate: u32) -> Self {
+        let capacity = (sample_rate * FAR_END_HISTORY_MS / 1000) as usize;
+        FarEnd {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            end: 0,
+        }
+    }
+
+    pub fn push(&mut self, samples: &[i16]) {
+        self.samples.extend(samples);
+        let excess = self.samples.len().saturating_sub(self.capacity);
+        self.samples.drain(..excess);
+        self.end += samples.len() as u64;
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// `len` samples from position `start`; silence for any not played yet
+    /// or no longer kept.
+    pub fn read(&self, start: i64, len: usize) -> Vec<i16> {
+        let first = self.end as i64 - self.samples.len() as i64;
+        (start..start + len as i64)
+            .map(|pos| {
+                if pos >= first && pos < self.end as i64 {
+                    self.samples[(pos - first) as usize]
+                } else {
+                    0
+                }
+            })
+            .collect()
+    }
+}
+
+/// Where in the far end capture reads its reference from. Capture advances
+/// the read position by what it captured, so with both devices on one
+/// clock the lag behind the newest far-end sample holds steady; when the
+/// clocks drift apart the read position is nudged a sample at a time to
+/// hold it, and moved at once if the lag jumps.
+struct Alignment {
+    read: Option<i64>,
+    /// Smoothed lag behind the far end's newest sample.
+    lag: f32,
+    target: f32,
+    calls: usize,
+    deadband: f32,
+    resync: f32,
+    jitter: i64,
+}
+
+impl Alignment {
+    fn new(sample_rate: u32) -> Self {
+        Alignment {
+            read: None,
+            lag: 0.0,
+            target: 0.0,
+            calls: 0,
+            deadband: (sample_rate * DRIFT_DEADBAND_MS / 1000) as f32,
+            resync: (sample_rate * RESYNC_MS / 1000) as f32,
+            // Callbacks arrive a block of about 10 ms apart; starting that
+            // far back keeps capture from running past what was played.
+            jitter: sample_rate as i64 / 100,
+        }
+    }
+
+    /// The far-end position to read `len` samples of reference from, given
+    /// that playout has reached `end`.
+    fn next(&mut self, end: u64, len: usize) -> i64 {
+        let end = end as i64;
+        let start = *self.read.get_or_insert(end - len as i64 - self.jitter);
+        let read = start + len as i64;
+        self.read = Some(read);
+
+        let lag = (end - read) as f32;
+        self.lag = if self.calls == 0 { lag } else { 0.95 * self.lag + 0.05 * lag };
+        self.calls += 1;
+        if self.calls == SETTLE_CALLS {
+            self.target = self.lag;
+        } else if self.calls > SETTLE_CALLS {
+            let error = self.lag - self.target;
+            let correction = if error.abs() > self.resync {
+                // Jump straight to where the lag is now, not where the
+                // smoothed lag has got to.
+                (lag - self.target).round() as i64
+            } else if error > self.deadband {
+                1
+            } else if error < -self.deadband {
+                -1
+            } else {
+                0
+            };
+            self.read = Some(read + correction);
+            self.lag -= correction as f32;
+        }
+        start
+    }
+
+    /// Reads `samples` further back from now on, and holds the lag there.
+    fn shift(&mut self, samples: i64) {
+        if let Some(read) = &mut self.read {
+            *read -= samples;
+        }
+        self.lag += samples as f32;
+        self.target += samples as f32;
+    }
+}
+
+/// Estimates how long after the reference its echo reaches the capture,
+/// by cross-correlating the two at a low rate.
+struct DelayEstimator {
+    factor: usize,
+    near_decimator: Downsampler,
+    far_decimator: Downsampler,
+    near: VecDeque<f32>,
+    far: VecDeque<f32>,
+    window: usize,
+    max_lag: usize,
+    interval: usize,
+    since_estimate: usize,
+    /// The last estimate, confirmed once the next one agrees.
+    candidate: Option<usize>,
+}
+
+impl DelayEstimator {
+    fn new(sample_rate: u32) -> Self {
+        let factor = (sample_rate / ESTIMATOR_RATE).max(1) as usize;
+        let rate = sample_rate as usize / factor;
+        DelayEstimator {
+            factor,
+            near_decimator: Downsampler::new(factor),
+            far_decimator: Downsampler::new(factor),
+            near: VecDeque::new(),
+            far: VecDeque::new(),
+            window: rate * ESTIMATOR_WINDOW_MS as usize / 1000,
+            max_lag: rate * MAX_DELAY_MS as usize / 1000,
+            interval: rate * ESTIMATOR_INTERVAL_MS as usize / 1000,
+            since_estimate: 0,
+            candidate: None,
+        }
+    }
+
+    /// Takes capture and the reference read for it, and returns the echo
+    /// delay in samples whenever two estimates in a row agree on it.
+    fn process(&mut self, near: &[i16], far: &[i16]) -> Option<usize> {
+        let near = self.near_decimator.process(near);
+        self.since_estimate += near.len();
+        self.near.extend(near.iter().map(|&s| f32::from(s)));
+        self.far.extend(self.far_decimator.process(far).iter().map(|&s| f32::from(s)));
+        let excess = self.near.len().saturating_sub(self.window);
+        self.near.drain(..excess);
+        let excess = self.far.len().saturating_sub(self.window + self.max_lag);
+        self.far.drain(..excess);
+
+        if self.since_estimate < self.interval || self.far.len() < self.window + self.max_lag {
+            return None;
+        }
+        self.since_estimate = 0;
+        let estimate = self.correlate();
+        let confirmed = match (estimate, self.candidate) {
+            (Some(lag), Some(previous)) if lag.abs_diff(previous) <= 1 => Some(lag * self.factor),
+            _ => None,
+        };
+        self.candidate = estimate;
+        confirmed
+    }
+
+    /// The lag, in decimated samples, at which the far end best explains the
+    /// capture window, if it does so clearly.
+    fn correlate(&self) -> Option<usize> {
+        // far[max_lag + t - lag] is what was played `lag` before near[t].
+        let len = (2 * self.window + self.max_lag).next_power_of_two();
+        let near = spectrum(self.near.iter().copied(), len);
+        let mut products: Vec<Complex> = spectrum(self.far.iter().copied(), len)
+            .iter()
+            .zip(&near)
+            .map(|(f, n)| f.mul(n.conj()))
+            .collect();
+        fft(&mut products, true);
+
+        let near_energy: f32 = self.near.iter().map(|s| s * s).sum();
+        let mut far_energy = vec![0.0f32; self.far.len() + 1];
+        for (i, s) in self.far.iter().enumerate() {
+            far_energy[i + 1] = far_energy[i] + s * s;
+        }
+        let (mut best, mut best_score) = (0, 0.0);
+        for lag in 0..=self.max_lag {
+            let offset = self.max_lag - lag;
+            let energy = far_energy[offset + self.window] - far_energy[offset];
+            if energy <= 0.0 {
+                continue;
+            }
+            let score = products[offset].re.abs() / (near_energy * energy).sqrt();
+            if score > best_score {
+                (best, best_score) = (lag, score);
+            }
+        }
+        (best_score >= MIN_CORRELATION).then_some(best)
+    }
+}
+
+/// An `EchoCanceller` fed from a shared `FarEnd`: it reads the reference
+/// by position, follows drift between the playout and capture clocks,
+/// and moves the reference when the echo arrives later than the filter
+/// reaches, as it does behind deep device buffers.
+pub struct AlignedCanceller {
+    canceller: EchoCanceller,
+    alignment: Alignment,
+    estimator: DelayEstimator,
+    sample_rate: u32,
+    tail_ms: u32,
+}
+
+impl AlignedCanceller {
+    pub fn new(sample_rate: u32, tail_ms: u32) -> Self {
+        AlignedCanceller {
+            canceller: EchoCanceller::new(sample_rate, tail_ms),
+            alignment: Alignment::new(sample_rate),
+            estimator: DelayEstimator::new(sample_rate),
+            sample_rate,
+            tail_ms,
+        }
+    }
+
+    /// Removes the echo of the far end from `near`, captured just now.
+    pub fn process(&mut self, near: &[i16], far_end: &Mutex<FarEnd>) -> Vec<i16> {
+        let far = {
+            let far_end = far_end.lock().unwrap();
+            let start = self.alignment.next(far_end.end(), near.len());
+            far_end.read(start, near.len())
+        };
+        if let Some(delay) = self.estimator.process(near, &far) {
+            let tail = (self.sample_rate * self.tail_ms / 1000) as usize;
+            let margin = (self.sample_rate * ALIGN_MARGIN_MS / 1000) as usize;
+            if delay > tail / 4 {
+                log::info!("🔁 Echo delay {} ms, realigning the reference", delay * 1000 / self.sample_rate as usize);
+                self.alignment.shift((delay - margin) as i64);
+                self.canceller = EchoCanceller::new(self.sample_rate, self.tail_ms);
+                self.estimator = DelayEstimator::new(self.sample_rate);
+            }
+        }
+        self.canceller.process(near, &far)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resample::Resampler;
+    use crate::test_support::{db, energy, mix, read_fixture, seconds};
+
+    struct Scene {
+        rate: u32,
+        far: Vec<i16>,
+        near: Vec<i16>,
+        echo: Vec<i16>,
+    }
+
+    /// The far-end fixture as played, its echo as the microphone picked it
+    /// up, and the near-end talker who starts at 3.5 s.
+    fn scene() -> Scene {
+        let (rate, far) = read_fixture("aec/far_end.wav");
+        let (_, echo) = read_fixture("aec/echo.wav");
+        let (_, near) = read_fixture("aec/near_end.wav");
+        Scene { rate, far, near, echo }
+    }
+
+    /// Runs the canceller, configured as for calls, over the capture in
+    /// 10 ms chunks, as audio callbacks deliver it.
+    fn cancel(rate: u32, near: &[i16], far: &[i16]) -> Vec<i16> {
+        let mut aec = EchoCanceller::new(rate, EchoConfig::default().tail_ms);
+        let chunk = rate as usize / 100;
+        near.chunks(chunk).zip(far.chunks(chunk)).flat_map(|(n, f)| aec.process(n, f)).collect()
+    }
+
+    #[test]
+    fn cancels_echo_of_far_end_speech() {
+        let scene = scene();
+        assert_eq!(scene.rate, 48000);
+        let out = cancel(scene.rate, &scene.echo, &scene.far);
+
+        // Echo return loss enhancement once converged, before the near end talks.
+        let window = seconds(scene.rate, 2.5, 3.5);
+        let erle = db(energy(&scene.echo[window.clone()]) / energy(&out[window]));
+        assert!(erle > 20.0, "ERLE {:.1} dB", erle);
+    }
+
+    #[test]
+    fn keeps_near_end_speech_during_double_talk() {
+        let scene = scene();
+        let mic = mix(&scene.echo, &scene.near);
+        let out = cancel(scene.rate, &mic, &scene.far);
+
+        // While both talk, what is left of the echo is well below the near end.
+        let residual: Vec<i16> = out.iter().zip(&scene.near).map(|(o, n)| o.saturating_sub(*n)).collect();
+        let talk = seconds(scene.rate, 3.5, 5.0);
+        let before = db(energy(&scene.near[talk.clone()]) / energy(&scene.echo[talk.clone()]));
+        let after = db(energy(&scene.near[talk.clone()]) / energy(&residual[talk]));
+        assert!(after > before + 15.0, "near-end to echo {:.1} dB, {:.1} dB before", after, before);
+
+        // And the filter has not been pulled off the echo path by it.
+        let window = seconds(scene.rate, 5.0, 5.9);
+        let erle = db(energy(&scene.echo[window.clone()]) / energy(&residual[window]));
+        assert!(erle > 20.0, "ERLE after double talk {:.1} dB", erle);
+    }
+
+    #[test]
+    fn passes_near_end_through_without_far_end() {
+        let scene = scene();
+        let silence = vec![0; scene.near.len()];
+        let out = cancel(scene.rate, &scene.near, &silence);
+        assert_eq!(out, scene.near[..out.len()]);
+    }
+
+    #[test]
+    fn reads_the_far_end_by_position() {
+        let mut far_end = FarEnd::new(1000);
+        far_end.push(&(1..=600).collect::<Vec<i16>>());
+        far_end.push(&(601..=1200).collect::<Vec<i16>>());
+        assert_eq!(far_end.end(), 1200);
+
+        // A second is kept; older samples and ones not played yet are silence.
+        assert_eq!(far_end.read(198, 4), vec![0, 0, 201, 202]);
+        assert_eq!(far_end.read(1198, 4), vec![1199, 1200, 0, 0]);
+        assert_eq!(far_end.read(-2, 2), vec![0, 0]);
+    }
+
+    #[test]
+    fn follows_clock_drift_between_playout_and_capture() {
+        // Playout runs 1000 ppm faster than capture for a minute of 10 ms
+        // callbacks.
+        let mut alignment = Alignment::new(48000);
+        let mut played = 0.0f64;
+        let mut lags = Vec::new();
+        for _ in 0..6000 {
+            played += 480.0 * 1.001;
+            let start = alignment.next(played as u64, 480);
+            lags.push(played as i64 - (start + 480));
+        }
+
+        // Without correction the lag would have grown by 2880 samples; it is
+        // held to within about the deadband of where it settled.
+        let settled = lags[SETTLE_CALLS];
+        for lag in &lags[SETTLE_CALLS..] {
+            assert!((lag - settled).abs() <= 96, "lag {} settled at {}", lag, settled);
+        }
+    }
+
+    #[test]
+    fn resyncs_when_a_stream_drops_audio() {
+        let mut alignment = Alignment::new(48000);
+        let mut played = 0;
+        let mut lag = 0;
+        for call in 0..400 {
+            // Capture loses 100 ms of audio halfway through.
+            played += if call == 200 { 480 + 4800 } else { 480 };
+            let start = alignment.next(played, 480);
+            lag = played as i64 - (start + 480);
+        }
+        assert!((lag - 480).abs() <= 48, "lag {}", lag);
+    }
+
+    #[test]
+    fn estimates_echo_delay() {
+        let scene = scene();
+        // The echo reaches the capture 150 ms after the reference.
+        let delay = 7200;
+        let near: Vec<i16> = std::iter::repeat_n(0, delay).chain(scene.echo.iter().copied()).collect();
+        let mut estimator = DelayEstimator::new(scene.rate);
+        let estimate = near
+            .chunks(480)
+            .zip(scene.far.chunks(480))
+            .find_map(|(n, f)| estimator.process(n, f))
+            .expect("no delay estimate");
+        // The echo path's direct sound comes 2.5 ms after it is played.
+        assert!(estimate.abs_diff(delay + 120) <= 2 * 12, "estimated {} samples", estimate);
+    }
+
+    #[test]
+    fn cancels_echo_behind_device_latency_and_clock_drift() {
+        let scene = scene();
+        // Capture hears the echo 150 ms after playout hands the reference
+        // over, on a clock 1000 ppm fast; both run in 10 ms callbacks. The
+        // far end plays twice, for 12 s.
+        let far = [&scene.far[..], &scene.far[..]].concat();
+        let latency = vec![0; 7200];
+        let captured = Resampler::new(48000, 48048).process(&[&latency[..], &scene.echo[..], &scene.echo[..]].concat());
+        let far_end = Mutex::new(FarEnd::new(scene.rate));
+        let mut aec = AlignedCanceller::new(scene.rate, EchoConfig::default().tail_ms);
+        let mut out = Vec::new();
+        let mut taken = 0;
+        for (call, played) in far.chunks(480).enumerate() {
+            far_end.lock().unwrap().push(played);
+            let upto = ((call + 1) * 480480 / 1000).min(captured.len());
+            out.extend(aec.process(&captured[taken..upto], &far_end));
+            taken = upto;
+        }
+
+        // Once the delay is found and the filter has converged on it, the
+        // echo is cancelled as well as with a reference aligned to start with.
+        let window = seconds(scene.rate, 8.0, 12.0);
+        let erle = db(energy(&captured[window.clone()]) / energy(&out[window]));
+        assert!(erle > 20.0, "ERLE {:.1} dB", erle);
+    }
+}
@@ -1,16 +1,22 @@
 use std::{
+    collections::HashMap,
     error::Error,
+    io,
     net::{IpAddr, SocketAddr},
-    sync::{Arc, Mutex as StdMutex},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, Instant},
 };
 
 use tokio::{
     net::UdpSocket,
     sync::{
-        broadcast::{self, Receiver as BroadcastReceiver},
-        mpsc,
-        mpsc::Receiver as SingleReceiver,
-        Mutex,
+        broadcast::{self, error::RecvError, Receiver as BroadcastReceiver, Sender as BroadcastSender},
+        mpsc::{self, UnboundedReceiver},
+        oneshot, watch, Mutex,
     },
     task::JoinHandle,
 };
@@ -18,168 +24,643 @@ use tokio::{
 use tokio_util::sync::CancellationToken;
 
 use crate::{
+    aec::EchoConfig,
     call_manager::CallManager,
-    io::AudioState,
-    jitter::JitterBuffer,
-    packet::AudioPacket,
+    cdr::MediaStats,
+    codec::{self, Codec, CodecConfig},
+    conference::Conference,
+    io::{AudioState, DeviceSelection},
+    jitter::{JitterBuffer, JitterConfig},
+    packet::{AudioPacket, ControlMessage, RtpStream, MAX_PACKET_LEN},
+    processing::{ProcessingConfig, ProcessingStages, StageToggles},
+    recorder::{Recorder, RecordingConfig},
+    relay::{self, RelayLegs},
+    sequence::{self, SeqStatus, SequenceTracker},
 };
 
-struct CallHandler {
+const MEDIA_PORT: u16 = 40000;
+/// How long an address latched from a ping waits for its call's media.
+const PING_LATCH_LIFETIME: Duration = Duration::from_secs(60);
+/// Most calls that may have an address latched from a ping at a time.
+const MAX_PINGED: usize = 1024;
+
+/// Media state for one call. All sessions share the UDP socket; incoming
+/// packets are demultiplexed on the remote address the session is latched to.
+struct MediaSession {
+    remote: SocketAddr,
+    /// Whether `remote` has been confirmed by a ping or by media from it.
+    /// Pings are unauthenticated, so they may only settle an unconfirmed
+    /// address, never move a confirmed one.
+    latched: bool,
+    /// Whether local audio is the caller's, which decides the channel each
+    /// side is recorded to.
+    local_is_caller: bool,
+    remote_tx: watch::Sender<SocketAddr>,
+    jitter: Arc<StdMutex<JitterBuffer>>,
+    /// Capture processing stages, shared with the audio thread.
+    processing: Arc<StdMutex<ProcessingStages>>,
+    source: Option<(u32, SequenceTracker)>,
+    packets_sent: Arc<AtomicU64>,
+    packets_received: u64,
+    last_log: std::time::Instant,
+    audio_tx: BroadcastSender<Vec<i16>>,
+    recording: Option<(CancellationToken, JoinHandle<()>)>,
     cancel_token: CancellationToken,
     send_handle: JoinHandle<()>,
 }
 
-#[derive(Debug, Clone)]
-pub struct UdpCommand {
-    #[allow(dead_code)]
-    pub user_id: String,
-    pub command: String,
-    pub target_ip: Option<IpAddr>,
+/// Instruction for the UDP audio task.
+#[derive(Debug)]
+pub enum UdpCommand {
+    /// Starts media for a call on `devices`, sent to wherever the peer's
+    /// pings came from or else to `target_ip`. `local_is_caller` says which
+    /// party the local audio belongs to.
+    StartCall { call_id: String, target_ip: IpAddr, devices: DeviceSelection, local_is_caller: bool },
+    /// Forwards media between the two relay sockets bound for a call.
+    StartRelay { call_id: String, legs: RelayLegs },
+    /// Mixes `user_id` into the room's conference.
+    JoinRoom { room_id: String, user_id: String, target_ip: IpAddr },
+    LeaveRoom { room_id: String, user_id: String },
+    /// Starts or stops recording a call. `applied` is told whether the call
+    /// had media running to record.
+    StartRecording { call_id: String, applied: oneshot::Sender<bool> },
+    StopRecording { call_id: String, applied: oneshot::Sender<bool> },
+    /// Switches capture processing stages for the rest of a call. `applied`
+    /// is told whether the call had media running to apply them to.
+    SetProcessing { call_id: String, toggles: StageToggles, applied: oneshot::Sender<bool> },
+    /// Stops the call's media and relay.
+    EndCall { call_id: String },
+}
+
+/// Addresses latched from pings for calls whose media has not started yet.
+/// The first ping for a call wins; entries expire, and pings for new calls
+/// are dropped while the table is full.
+#[derive(Default)]
+struct Pinged {
+    addrs: HashMap<String, (SocketAddr, Instant)>,
+}
+
+impl Pinged {
+    fn insert(&mut self, call_id: &str, addr: SocketAddr, now: Instant) {
+        self.addrs.retain(|_, (_, expires)| *expires > now);
+        if self.addrs.contains_key(call_id) {
+            return;
+        }
+        if self.addrs.len() >= MAX_PINGED {
+            log::warn!("❌ Dropped ping for call {} from {}: too many pending pings", call_id, addr);
+            return;
+        }
+        self.addrs.insert(call_id.to_string(), (addr, now + PING_LATCH_LIFETIME));
+    }
+
+    fn take(&mut self, call_id: &str, now: Instant) -> Option<SocketAddr> {
+        self.addrs
+            .remove(call_id)
+            .filter(|(_, expires)| *expires > now)
+            .map(|(addr, _)| addr)
+    }
 }
 
 pub async fn udp_audio_task(
-    _call_manager: Arc<Mutex<CallManager>>,
-    mut control_channel: SingleReceiver<UdpCommand>,
+    call_manager: Arc<Mutex<CallManager>>,
+    mut control_channel: UnboundedReceiver<UdpCommand>,
+    codec_config: CodecConfig,
+    jitter_config: JitterConfig,
+    recording_config: RecordingConfig,
+    echo_config: EchoConfig,
+    processing_config: ProcessingConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let (tx_audio, _rx_audio) = broadcast::channel::<Vec<u8>>(128);
-    let socket = Arc::new(UdpSocket::bind("0.0.0.0:40000").await?);
+    let socket = Arc::new(UdpSocket::bind(("0.0.0.0", MEDIA_PORT)).await?);
     let local_ip = socket.local_addr()?.ip();
 
-    log::info!("UDP audio server listening on {}:40000", local_ip);
-
-    let mut call_handler: Option<CallHandler> = None;
-    let mut caller_ip: Option<IpAddr> = None;
-    let jitter = Arc::new(StdMutex::new(JitterBuffer::new()));
-
-    let (tx_caller, mut rx_caller) = mpsc::channel::<IpAddr>(1);
-
-   
-    {
-        let socket_recv = socket.clone();
-        let jitter_recv = jitter.clone();
-        let tx_caller_recv = tx_caller.clone();
-
-        tokio::spawn(async move {
-            let _ = receive_task(
-                socket_recv,
-                jitter_recv,
-                CancellationToken::new(),
-                tx_caller_recv,
-                local_ip,
-            )
-            .await;
-        });
-    }
+    log::info!("UDP audio server listening on {}:{}", local_ip, MEDIA_PORT);
+
+    let mut sessions: HashMap<String, MediaSession> = HashMap::new();
+    let mut pinged = Pinged::default();
+    let mut relays: HashMap<String, (CancellationToken, JoinHandle<()>)> = HashMap::new();
+    let mut conferences: HashMap<String, Conference> = HashMap::new();
+    let mut mix_timer = tokio::time::interval(std::time::Duration::from_micros(
+        codec_config.frame_size as u64 * 1_000_000 / codec::SAMPLE_RATE as u64,
+    ));
+    let mut buf = [0u8; MAX_PACKET_LEN];
 
     loop {
         tokio::select! {
-            Some(ip) = rx_caller.recv() => {
-                log::info!("Detected caller IP: {}", ip);
-                caller_ip = Some(ip);
+            recv = socket.recv_from(&mut buf) => {
+                if let Ok((size, addr)) = recv {
+
+                    if addr.ip() == local_ip {
+                        continue;
+                    }
+
+                    if let Some(ControlMessage::Ping { call_id }) = ControlMessage::deserialize(&buf[..size]) {
+                        log::info!("📡 Received ping for call {} from {}", call_id, addr);
+                        if let Some(session) = sessions.get_mut(&call_id) {
+                            session.latch(&call_id, addr);
+                        } else if let Some(conference) = conferences.get_mut(&call_id) {
+                            conference.latch(&call_id, addr);
+                        } else {
+                            pinged.insert(&call_id, addr, Instant::now());
+                        }
+                    } else if let Ok(packet) = AudioPacket::deserialize(&buf[..size]) {
+                        match route(&mut sessions, &mut conferences, addr) {
+                            Some(Route::Call(call_id, session)) => session.receive(&call_id, packet, addr),
+                            Some(Route::Room(room_id, conference)) => conference.receive(&room_id, packet, addr),
+                            None => log::warn!("❌ Dropped RTP packet from {} with no matching call", addr),
+                        }
+                    } else {
+                        log::warn!("❌ Dropped malformed RTP packet ({} bytes) from {}", size, addr);
+                    }
+                }
+            }
+
+            _ = mix_timer.tick(), if !conferences.is_empty() => {
+                for conference in conferences.values_mut() {
+                    conference.mix(&socket).await;
+                }
             }
 
             msg = control_channel.recv() => {
-                if let Some(cmd) = msg {
-                    log::info!("Received UDP command: {:?}", cmd);
-
-                    match cmd.command.as_str() {
-                        "ping" => {
-                            if let Some(ip) = cmd.target_ip {
-                                let addr = SocketAddr::new(ip, 40000);
-                                let packet = AudioPacket { seq: 0, samples: vec![] };
-                                let _ = socket.send_to(&packet.serialize(), addr).await;
-                                log::info!("Sent ping to {}", addr);
-                            }
+                let Some(cmd) = msg else { break };
+                log::info!("Received UDP command: {:?}", cmd);
+
+                match cmd {
+                    UdpCommand::StartCall { call_id, target_ip, devices, local_is_caller } => {
+                        if sessions.contains_key(&call_id) {
+                            log::warn!("Media for call {} is already running", call_id);
+                            continue;
+                        }
+
+                        let latched = pinged.take(&call_id, Instant::now());
+                        let remote = latched.unwrap_or(SocketAddr::new(target_ip, MEDIA_PORT));
+                        if latched.is_none() {
+                            // Lets the peer latch onto us in turn.
+                            let ping = ControlMessage::Ping { call_id: call_id.clone() };
+                            let _ = socket.send_to(&ping.serialize(), remote).await;
+                            log::info!("Sent ping for call {} to {}", call_id, remote);
                         }
-                        "start_call" => {
-                            if let Some(target_ip) = caller_ip.or(cmd.target_ip) {
-                                let target_addr = SocketAddr::new(target_ip, 40000);
-                                log::info!("Starting call with target {}", target_addr);
-
-                                let cancel_token = CancellationToken::new();
-                                let audio_rx = tx_audio.subscribe();
-
-                                let send_handle = {
-                                    let token = cancel_token.clone();
-                                    let socket_send = socket.clone();
-                                    let target = target_addr;
-                                    tokio::spawn(async move {
-                                        let _ = send_task(socket_send, audio_rx, token, target).await;
-                                    })
-                                };
-
-                                call_handler = Some(CallHandler {
-                                    cancel_token,
-                                    send_handle,
-                                });
-
-                               
-                                let tx_audio_clone = tx_audio.clone();
-                                let jitter_clone = jitter.clone();
-                                std::thread::spawn(move || {
-                                    let host = cpal::default_host();
-                                    let mut audio_state = AudioState::new(host);
-                                    audio_state.start(tx_audio_clone, jitter_clone);
-                                   
-                                    loop {
-                                        std::thread::sleep(std::time::Duration::from_secs(1));
-                                    }
-                                });
-
-                                log::info!("Audio streams started");
-                            } else {
-                                log::warn!("Cannot start call: no target IP available");
+                        log::info!("Starting media for call {} with {}", call_id, remote);
+                        let mut session = MediaSession::start(
+                            socket.clone(),
+                            remote,
+                            &codec_config,
+                            &jitter_config,
+                            &echo_config,
+                            &processing_config,
+                            devices,
+                        );
+                        session.latched = latched.is_some();
+                        session.local_is_caller = local_is_caller;
+                        sessions.insert(call_id, session);
+                    }
+                    UdpCommand::StartRelay { call_id, legs } => {
+                        let token = CancellationToken::new();
+                        let handle = {
+                            let token = token.clone();
+                            let call_id = call_id.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = relay::relay_task(call_id, legs, token).await {
+                                    log::error!("Relay task failed: {}", e);
+                                }
+                            })
+                        };
+                        if let Some((old_token, _)) = relays.insert(call_id, (token, handle)) {
+                            old_token.cancel();
+                        }
+                    }
+                    UdpCommand::JoinRoom { room_id, user_id, target_ip } => {
+                        let remote = pinged
+                            .take(&room_id, Instant::now())
+                            .unwrap_or(SocketAddr::new(target_ip, MEDIA_PORT));
+                        let conference = conferences
+                            .entry(room_id.clone())
+                            .or_insert_with(|| Conference::new(codec_config.clone(), jitter_config.clone()));
+                        match conference.join(&user_id, remote) {
+                            Ok(()) => log::info!("Mixing {} into room {} at {}", user_id, room_id, remote),
+                            Err(e) => log::error!("Failed to add {} to room {}: {}", user_id, room_id, e),
+                        }
+                    }
+                    UdpCommand::LeaveRoom { room_id, user_id } => {
+                        if let Some(conference) = conferences.get_mut(&room_id) {
+                            conference.leave(&room_id, &user_id);
+                            if conference.is_empty() {
+                                conferences.remove(&room_id);
+                                log::info!("Room {} mixer stopped", room_id);
                             }
                         }
-                        "end_call" => {
-                            caller_ip = None;
+                    }
+                    UdpCommand::StartRecording { call_id, applied } => {
+                        let session = sessions.get_mut(&call_id);
+                        let running = session.is_some();
+                        match session {
+                            Some(session) => session.start_recording(&call_id, &recording_config, &call_manager),
+                            None => log::warn!("Cannot record call {}: no media running", call_id),
+                        }
+                        let _ = applied.send(running);
+                    }
+                    UdpCommand::StopRecording { call_id, applied } => {
+                        let session = sessions.get_mut(&call_id);
+                        let running = session.is_some();
+                        if let Some(session) = session {
+                            session.stop_recording().await;
+                        }
+                        let _ = applied.send(running);
+                    }
+                    UdpCommand::SetProcessing { call_id, toggles, applied } => {
+                        let session = sessions.get(&call_id);
+                        match session {
+                            Some(session) => session.set_processing(&call_id, &toggles),
+                            None => log::warn!("Cannot change processing for call {}: no media running", call_id),
+                        }
+                        let _ = applied.send(session.is_some());
+                    }
+                    UdpCommand::EndCall { call_id } => {
+                        pinged.addrs.remove(&call_id);
 
-                            if let Some(call) = call_handler.take() {
-                                call.cancel_token.cancel();
-                                let _ = call.send_handle.await;
-                                log::info!("Call ended");
-                            }
+                        if let Some((token, handle)) = relays.remove(&call_id) {
+                            token.cancel();
+                            let _ = handle.await;
                         }
-                        _ => {
-                            log::warn!("Unknown UDP command: {}", cmd.command);
+
+                        if let Some(session) = sessions.remove(&call_id) {
+                            let stats = session.stop(&call_id).await;
+                            let call_manager = call_manager.clone();
+                            tokio::spawn(async move {
+                                call_manager.lock().await.record_media_stats(&call_id, stats);
+                            });
                         }
                     }
                 }
             }
         }
     }
+
+    for (call_id, session) in sessions {
+        session.stop(&call_id).await;
+    }
+    for (token, handle) in relays.into_values() {
+        token.cancel();
+        let _ = handle.await;
+    }
+    Ok(())
+}
+
+/// Where a media packet goes: a call's session or a room's mixer.
+enum Route<'a> {
+    Call(String, &'a mut MediaSession),
+    Room(String, &'a mut Conference),
+}
+
+/// Finds the call or room latched to `addr`, which media from it confirms.
+/// Exact addresses are matched across calls and rooms before anything else.
+/// A packet from a known host on a new port (e.g. after a NAT rebinding)
+/// then re-latches the call or room participant if that host matches
+/// exactly one of them.
+fn route<'a>(
+    sessions: &'a mut HashMap<String, MediaSession>,
+    conferences: &'a mut HashMap<String, Conference>,
+    addr: SocketAddr,
+) -> Option<Route<'a>> {
+    let exact_call = sessions.iter().find(|(_, s)| s.remote == addr).map(|(id, _)| id.clone());
+    let exact_room = conferences.iter().find(|(_, c)| c.has_address(addr)).map(|(id, _)| id.clone());
+    let (call_id, room_id) = match (exact_call, exact_room) {
+        (Some(call_id), _) => (Some(call_id), None),
+        (None, Some(room_id)) => (None, Some(room_id)),
+        (None, None) => {
+            let calls: Vec<&String> = sessions.iter().filter(|(_, s)| s.remote.ip() == addr.ip()).map(|(id, _)| id).collect();
+            let rooms: Vec<(&String, usize)> = conferences
+                .iter()
+                .map(|(id, c)| (id, c.participants_on(addr.ip())))
+                .filter(|(_, n)| *n > 0)
+                .collect();
+            match (calls.as_slice(), rooms.as_slice()) {
+                ([call_id], []) => (Some((*call_id).clone()), None),
+                ([], [(room_id, 1)]) => (None, Some((*room_id).clone())),
+                _ => return None,
+            }
+        }
+    };
+
+    if let Some(room_id) = room_id {
+        let conference = conferences.get_mut(&room_id)?;
+        return Some(Route::Room(room_id, conference));
+    }
+    let call_id = call_id?;
+    let session = sessions.get_mut(&call_id)?;
+    session.set_remote(&call_id, addr);
+    session.latched = true;
+    Some(Route::Call(call_id, session))
+}
+
+impl MediaSession {
+    fn start(
+        socket: Arc<UdpSocket>,
+        remote: SocketAddr,
+        codec_config: &CodecConfig,
+        jitter_config: &JitterConfig,
+        echo_config: &EchoConfig,
+        processing_config: &ProcessingConfig,
+        devices: DeviceSelection,
+    ) -> Self {
+        let (tx_audio, audio_rx) = broadcast::channel::<Vec<i16>>(128);
+        let audio_tx = tx_audio.clone();
+        let jitter = Arc::new(StdMutex::new(JitterBuffer::new(jitter_config.clone())));
+        let processing = Arc::new(StdMutex::new(processing_config.stages));
+        let cancel_token = CancellationToken::new();
+        let packets_sent = Arc::new(AtomicU64::new(0));
+        let (remote_tx, remote_rx) = watch::channel(remote);
+
+        let send_handle = {
+            let token = cancel_token.clone();
+            let config = codec_config.clone();
+            let sent = packets_sent.clone();
+            tokio::spawn(async move {
+                if let Err(e) = send_task(socket, audio_rx, token, remote_rx, config, sent).await {
+                    log::error!("Send task failed: {}", e);
+                }
+            })
+        };
+
+        {
+            let token = cancel_token.clone();
+            let jitter = jitter.clone();
+            let echo_config = echo_config.clone();
+            let processing_config = processing_config.clone();
+            let processing = processing.clone();
+            std::thread::spawn(move || {
+                let host = cpal::default_host();
+                let mut audio_state = AudioState::new(host);
+                audio_state.start(tx_audio, jitter, &devices, &echo_config, &processing_config, processing);
+
+                while !token.is_cancelled() {
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                }
+                audio_state.clear();
+            });
+        }
+
+        log::info!("Audio streams started");
+
+        MediaSession {
+            remote,
+            latched: false,
+            local_is_caller: false,
+            remote_tx,
+            jitter,
+            processing,
+            source: None,
+            packets_sent,
+            packets_received: 0,
+            last_log: std::time::Instant::now(),
+            audio_tx,
+            recording: None,
+            cancel_token,
+            send_handle,
+        }
+    }
+
+    /// Latches the session onto `addr`; outgoing media follows it.
+    fn set_remote(&mut self, call_id: &str, addr: SocketAddr) {
+        if self.remote != addr {
+            log::info!("Call {} latched from {} to {}", call_id, self.remote, addr);
+            self.remote = addr;
+            self.remote_tx.send_replace(addr);
+        }
+    }
+
+    /// Latches the session onto the address a ping came from, unless it is
+    /// already latched.
+    fn latch(&mut self, call_id: &str, addr: SocketAddr) {
+        if self.latched {
+            if self.remote != addr {
+                log::warn!("❌ Ignored ping for call {} from {}: media is latched to {}", call_id, addr, self.remote);
+            }
+            return;
+        }
+        self.set_remote(call_id, addr);
+        self.latched = true;
+    }
+
+    /// Records both directions of the call: the remote stream as it is
+    /// played out and local capture, each on its party's channel. The
+    /// file's path is stored on the call once it has been created.
+    fn start_recording(&mut self, call_id: &str, config: &RecordingConfig, call_manager: &Arc<Mutex<CallManager>>) {
+        if self.recording.is_some() {
+            log::warn!("Call {} is already being recorded", call_id);
+            return;
+        }
+
+        let recorder = match Recorder::create(config, call_id, self.local_is_caller) {
+            Ok(recorder) => recorder,
+            Err(e) => {
+                log::error!("Failed to create recording for call {}: {}", call_id, e);
+                return;
+            }
+        };
+        let path = recorder.path().display().to_string();
+        log::info!("🔴 Recording call {} to {}", call_id, path);
+        {
+            let call_manager = call_manager.clone();
+            let call_id = call_id.to_string();
+            tokio::spawn(async move {
+                call_manager.lock().await.set_recording(&call_id, path);
+            });
+        }
+
+        let (tap_tx, tap_rx) = mpsc::unbounded_channel();
+        self.jitter.lock().unwrap().set_tap(Some(tap_tx));
+        let outgoing = self.audio_tx.subscribe();
+
+        let token = CancellationToken::new();
+        let handle = {
+            let token = token.clone();
+            let call_id = call_id.to_string();
+            tokio::spawn(async move {
+                match record_task(recorder, outgoing, tap_rx, token).await {
+                    Ok(path) => log::info!("Recording of call {} saved to {}", call_id, path.display()),
+                    Err(e) => log::error!("Recording of call {} failed: {}", call_id, e),
+                }
+            })
+        };
+        self.recording = Some((token, handle));
+    }
+
+    fn set_processing(&self, call_id: &str, toggles: &StageToggles) {
+        let mut stages = self.processing.lock().unwrap();
+        stages.apply(toggles);
+        log::info!("Capture processing for call {}: {:?}", call_id, *stages);
+    }
+
+    async fn stop_recording(&mut self) {
+        if let Some((token, handle)) = self.recording.take() {
+            self.jitter.lock().unwrap().set_tap(None);
+            token.cancel();
+            let _ = handle.await;
+        }
+    }
+
+    async fn stop(mut self, call_id: &str) -> MediaStats {
+        self.stop_recording().await;
+        self.cancel_token.cancel();
+        let _ = self.send_handle.await;
+
+        let stats = self.jitter.lock().unwrap().stats();
+        log::info!("Call {} media ended | Sent: {} | Received: {} | Lost: {} | Late: {} | Concealed: {}",
+            call_id,
+            self.packets_sent.load(Ordering::Relaxed),
+            stats.received,
+            stats.lost,
+            stats.late,
+            stats.concealed
+        );
+
+        MediaStats {
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: stats.received,
+            lost: stats.lost,
+            late: stats.late,
+            concealed: stats.concealed,
+            jitter_ms: stats.jitter_ms,
+        }
+    }
+
+    fn receive(&mut self, call_id: &str, packet: AudioPacket, addr: SocketAddr) {
+        if Codec::from_payload_type(packet.payload_type).is_none() {
+            log::warn!("❌ Dropped packet with unsupported payload type {} from {}",
+                packet.payload_type, addr);
+            return;
+        }
+
+        let (status, new_source) = sequence::track_source(&mut self.source, packet.ssrc, packet.seq);
+        if new_source {
+            log::info!("New RTP source {:08x} for call {} starting at seq {}", packet.ssrc, call_id, packet.seq);
+            self.jitter.lock().unwrap().reset();
+        }
+        let ext_seq = match status {
+            SeqStatus::Valid(ext_seq) => ext_seq,
+            SeqStatus::Restarted(ext_seq) => {
+                log::warn!("⚠️  RTP source {:08x} restarted at seq {}", packet.ssrc, packet.seq);
+                self.jitter.lock().unwrap().reset();
+                ext_seq
+            }
+            SeqStatus::Probation | SeqStatus::Invalid => return,
+        };
+
+        let seq = packet.seq;
+        let payload_len = packet.payload.len();
+        self.packets_received += 1;
+
+        let (buffer_size, stats) = {
+            let mut jb = self.jitter.lock().unwrap();
+            jb.push_packet(packet, ext_seq, std::time::Instant::now());
+            (jb.buffered_samples(), jb.stats())
+        };
+
+        if self.last_log.elapsed().as_secs() >= 1 {
+            let expected = stats.received + stats.lost;
+            let loss_rate = if expected > 0 {
+                (stats.lost as f32 / expected as f32) * 100.0
+            } else {
+                0.0
+            };
+
+            log::info!("📥 Call {} | Received {} packets from {} | Seq: {} | Bytes: {} | Buffer: {} | Jitter: {:.1} ms | Delay: {} ms | Late: {} | Concealed: {} | Loss: {:.1}%",
+                call_id,
+                self.packets_received,
+                addr,
+                seq,
+                payload_len,
+                buffer_size,
+                stats.jitter_ms,
+                stats.target_delay_ms,
+                stats.late,
+                stats.concealed,
+                loss_rate
+            );
+            self.packets_received = 0;
+            self.last_log = std::time::Instant::now();
+        }
+    }
+}
+
+async fn record_task(
+    mut recorder: Recorder,
+    mut outgoing: BroadcastReceiver<Vec<i16>>,
+    mut incoming: UnboundedReceiver<Vec<i16>>,
+    cancel_token: CancellationToken,
+) -> io::Result<PathBuf> {
+    loop {
+        tokio::select! {
+            frame = outgoing.recv() => match frame {
+                Ok(samples) => recorder.push_local(&samples)?,
+                Err(RecvError::Lagged(skipped)) => log::warn!("Recorder skipped {} captured frames", skipped),
+                Err(RecvError::Closed) => break,
+            },
+            frame = incoming.recv() => match frame {
+                Some(samples) => recorder.push_remote(&samples)?,
+                None => break,
+            },
+            _ = cancel_token.cancelled() => break,
+        }
+    }
+    recorder.finish()
 }
 
 async fn send_task(
     socket: Arc<UdpSocket>,
-    mut audio_channel: BroadcastReceiver<Vec<u8>>,
+    mut audio_channel: BroadcastReceiver<Vec<i16>>,
     cancel_token: CancellationToken,
-    target_addr: SocketAddr,
+    target: watch::Receiver<SocketAddr>,
+    config: CodecConfig,
+    packets_sent: Arc<AtomicU64>,
 ) -> Result<(), Box<dyn Error>> {
+    let mut encoder = codec::new_encoder(&config)?;
+    let payload_type = encoder.codec().payload_type();
+    let clock_rate = encoder.codec().clock_rate();
+    let mut rtp = RtpStream::new();
+    let mut pending: Vec<i16> = Vec::new();
     let mut packet_count = 0u64;
     let mut last_log = std::time::Instant::now();
 
+    log::info!("Encoding outgoing audio as {:?} ({} samples/frame)", encoder.codec(), config.frame_size);
+
     loop {
         if cancel_token.is_cancelled() {
             break;
         }
 
         match audio_channel.try_recv() {
-            Ok(data) => {
-                if let Some(packet) = AudioPacket::deserialize(&data) {
+            Ok(samples) => {
+                pending.extend_from_slice(&samples);
+
+                while pending.len() >= config.frame_size {
+                    let frame: Vec<i16> = pending.drain(..config.frame_size).collect();
+                    let payload = match encoder.encode(&frame) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            log::warn!("Failed to encode frame: {}", e);
+                            continue;
+                        }
+                    };
+                    let ticks = frame.len() * clock_rate as usize / codec::SAMPLE_RATE as usize;
+                    let packet = rtp.next_packet(payload_type, payload, ticks);
+                    let target_addr = *target.borrow();
+                    let _ = socket.send_to(&packet.serialize(), target_addr).await;
+                    packets_sent.fetch_add(1, Ordering::Relaxed);
+
                    
-                    let max_sample = packet.samples.iter().map(|s| s.abs()).max().unwrap_or(0);
+                    let max_sample = frame.iter().map(|s| s.abs()).max().unwrap_or(0);
                     let is_silence = max_sample < 100;
 
                     packet_count += 1;
 
                    
                     if last_log.elapsed().as_secs() >= 1 {
-                        log::info!("📤 Sent {} packets to {} | Seq: {} | Samples: {} | Max: {} | {}",
+                        log::info!("📤 Sent {} packets to {} | Seq: {} | Bytes: {} | Max: {} | {}",
                             packet_count,
                             target_addr,
                             packet.seq,
-                            packet.samples.len(),
+                            packet.payload.len(),
                             max_sample,
                             if is_silence { "🔇 SILENCE" } else { "🔊 AUDIO" }
                         );
@@ -187,7 +668,6 @@ async fn send_task(
                         last_log = std::time::Instant::now();
                     }
                 }
-                let _ = socket.send_to(&data, target_addr).await;
             }
             Err(_) => {
                
@@ -198,88 +678,152 @@ async fn send_task(
     Ok(())
 }
 
-async fn receive_task(
-    socket: Arc<UdpSocket>,
-    jitter: Arc<StdMutex<JitterBuffer>>,
-    cancel_token: CancellationToken,
-    tx_caller: mpsc::Sender<IpAddr>,
-    local_ip: IpAddr,
-) -> Result<(), Box<dyn Error>> {
-    let mut buf = [0u8; 4096];
-    let mut last_seq: u16 = 0;
-    let mut packet_count = 0u64;
-    let mut lost_packet_count = 0u64;
-    let mut last_log = std::time::Instant::now();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session with no audio or send task behind it.
+    fn session(remote: &str) -> MediaSession {
+        let remote = remote.parse().unwrap();
+        MediaSession {
+            remote,
+            latched: false,
+            local_is_caller: false,
+            remote_tx: watch::channel(remote).0,
+            jitter: Arc::new(StdMutex::new(JitterBuffer::new(JitterConfig::default()))),
+            processing: Arc::new(StdMutex::new(ProcessingConfig::default().stages)),
+            source: None,
+            packets_sent: Arc::new(AtomicU64::new(0)),
+            packets_received: 0,
+            last_log: Instant::now(),
+            audio_tx: broadcast::channel(1).0,
+            recording: None,
+            cancel_token: CancellationToken::new(),
+            send_handle: tokio::spawn(async {}),
+        }
+    }
 
-    loop {
-        tokio::select! {
-            recv = socket.recv_from(&mut buf) => {
-                if let Ok((size, addr)) = recv {
-                   
-                    if addr.ip() == local_ip {
-                        continue;
-                    }
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
 
-                    if let Some(packet) = AudioPacket::deserialize(&buf[..size]) {
-                        if packet.seq == 0 {
-                           
-                            log::info!("📡 Received ping from {}", addr);
-                            let _ = tx_caller.send(addr.ip()).await;
-                        } else {
-                           
-                            let max_sample = packet.samples.iter().map(|s| s.abs()).max().unwrap_or(0);
-                            let is_silence = max_sample < 100;
-
-                            packet_count += 1;
-
-                           
-                            if last_seq != 0 && packet.seq > last_seq + 1 {
-                                let missing = (packet.seq - last_seq - 1) as usize;
-                                lost_packet_count += missing as u64;
-                                let silence = vec![0i16; missing * 960];
-                                jitter.lock().unwrap().push_packet(&silence);
-                                log::warn!("⚠️  Packet loss detected: {} packets missing (seq {} -> {})",
-                                    missing, last_seq, packet.seq);
-                            }
+    /// The call `route` picks for `addr`, with `rooms` also in play.
+    fn route_call<'a>(
+        sessions: &'a mut HashMap<String, MediaSession>,
+        rooms: &'a mut HashMap<String, Conference>,
+        addr: SocketAddr,
+    ) -> Option<(String, &'a mut MediaSession)> {
+        match route(sessions, rooms, addr)? {
+            Route::Call(call_id, session) => Some((call_id, session)),
+            Route::Room(room_id, _) => panic!("{} routed to room {}", addr, room_id),
+        }
+    }
 
-                            let buffer_size = {
-                                let mut jb = jitter.lock().unwrap();
-                                jb.push_packet(&packet.samples);
-                                jb.buffer.len()
-                            };
-
-                            last_seq = packet.seq;
-
-                           
-                            if last_log.elapsed().as_secs() >= 1 {
-                                let loss_rate = if packet_count > 0 {
-                                    (lost_packet_count as f32 / (packet_count + lost_packet_count) as f32) * 100.0
-                                } else {
-                                    0.0
-                                };
-
-                                log::info!("📥 Received {} packets from {} | Seq: {} | Samples: {} | Max: {} | Buffer: {} | Loss: {:.1}% | {}",
-                                    packet_count,
-                                    addr,
-                                    packet.seq,
-                                    packet.samples.len(),
-                                    max_sample,
-                                    buffer_size,
-                                    loss_rate,
-                                    if is_silence { "🔇 SILENCE" } else { "🔊 AUDIO" }
-                                );
-                                packet_count = 0;
-                                lost_packet_count = 0;
-                                last_log = std::time::Instant::now();
-                            }
-                        }
-                    } else {
-                        log::warn!("❌ Failed to deserialize packet from {}", addr);
-                    }
-                }
-            }
-            _ = cancel_token.cancelled() => break,
+    fn room(participants: &[(&str, &str)]) -> Conference {
+        let mut conference = Conference::new(CodecConfig::default(), JitterConfig::default());
+        for (user_id, remote) in participants {
+            conference.join(user_id, addr(remote)).unwrap();
         }
+        conference
+    }
+
+    #[tokio::test]
+    async fn routes_by_address_and_follows_rebinding() {
+        let mut sessions = HashMap::from([
+            ("a".to_string(), session("198.51.100.1:40000")),
+            ("b".to_string(), session("198.51.100.2:40000")),
+        ]);
+        let mut rooms = HashMap::new();
+
+        let (call_id, session) = route_call(&mut sessions, &mut rooms, addr("198.51.100.1:40000")).unwrap();
+        assert_eq!(call_id, "a");
+        assert!(session.latched);
+
+        let (call_id, session) = route_call(&mut sessions, &mut rooms, addr("198.51.100.2:51234")).unwrap();
+        assert_eq!(call_id, "b");
+        assert_eq!(session.remote, addr("198.51.100.2:51234"));
+        assert_eq!(*session.remote_tx.borrow(), addr("198.51.100.2:51234"));
+
+        assert!(route(&mut sessions, &mut rooms, addr("203.0.113.9:40000")).is_none());
+    }
+
+    #[tokio::test]
+    async fn does_not_guess_between_calls_on_one_host() {
+        let mut sessions = HashMap::from([
+            ("a".to_string(), session("198.51.100.1:40000")),
+            ("b".to_string(), session("198.51.100.1:40002")),
+        ]);
+        let mut rooms = HashMap::new();
+
+        assert!(route(&mut sessions, &mut rooms, addr("198.51.100.1:40004")).is_none());
+        assert_eq!(route_call(&mut sessions, &mut rooms, addr("198.51.100.1:40002")).unwrap().0, "b");
+        assert_eq!(sessions["a"].remote, addr("198.51.100.1:40000"));
+    }
+
+    #[tokio::test]
+    async fn routes_a_call_and_a_room_on_one_host_by_port() {
+        let mut sessions = HashMap::from([("a".to_string(), session("198.51.100.1:40000"))]);
+        let mut rooms = HashMap::from([(
+            "r".to_string(),
+            room(&[("carol", "198.51.100.1:40002"), ("dave", "198.51.100.3:40000")]),
+        )]);
+
+        assert!(matches!(
+            route(&mut sessions, &mut rooms, addr("198.51.100.1:40002")),
+            Some(Route::Room(room_id, _)) if room_id == "r"
+        ));
+        assert_eq!(route_call(&mut sessions, &mut rooms, addr("198.51.100.1:40000")).unwrap().0, "a");
+        // A new port on the shared host could be either.
+        assert!(route(&mut sessions, &mut rooms, addr("198.51.100.1:40004")).is_none());
+        assert_eq!(sessions["a"].remote, addr("198.51.100.1:40000"));
+        // One on a participant's own host follows the rebinding.
+        assert!(matches!(
+            route(&mut sessions, &mut rooms, addr("198.51.100.3:51234")),
+            Some(Route::Room(room_id, _)) if room_id == "r"
+        ));
+    }
+
+    #[tokio::test]
+    async fn pings_latch_only_an_unconfirmed_session() {
+        let mut session = session("198.51.100.1:40000");
+        session.latch("a", addr("198.51.100.1:51234"));
+        assert_eq!(session.remote, addr("198.51.100.1:51234"));
+
+        session.latch("a", addr("203.0.113.9:40000"));
+        assert_eq!(session.remote, addr("198.51.100.1:51234"));
+        assert_eq!(*session.remote_tx.borrow(), addr("198.51.100.1:51234"));
+
+        let mut sessions = HashMap::from([("b".to_string(), self::session("198.51.100.2:40000"))]);
+        route(&mut sessions, &mut HashMap::new(), addr("198.51.100.2:40000"));
+        let session = sessions.get_mut("b").unwrap();
+        session.latch("b", addr("203.0.113.9:40000"));
+        assert_eq!(session.remote, addr("198.51.100.2:40000"));
+    }
+
+    #[test]
+    fn keeps_the_first_ping_until_it_expires() {
+        let now = Instant::now();
+        let mut pinged = Pinged::default();
+        pinged.insert("a", addr("198.51.100.1:40000"), now);
+        pinged.insert("a", addr("203.0.113.9:40000"), now);
+        assert_eq!(pinged.take("a", now), Some(addr("198.51.100.1:40000")));
+        assert_eq!(pinged.take("a", now), None);
+
+        pinged.insert("b", addr("198.51.100.2:40000"), now);
+        assert_eq!(pinged.take("b", now + PING_LATCH_LIFETIME), None);
+    }
+
+    #[test]
+    fn caps_pending_pings() {
+        let now = Instant::now();
+        let mut pinged = Pinged::default();
+        for i in 0..MAX_PINGED + 10 {
+            pinged.insert(&i.to_string(), addr("198.51.100.1:40000"), now);
+        }
+        assert_eq!(pinged.addrs.len(), MAX_PINGED);
+        assert_eq!(pinged.take(&MAX_PINGED.to_string(), now), None);
+
+        pinged.insert("late", addr("198.51.100.1:40000"), now + PING_LATCH_LIFETIME);
+        assert_eq!(pinged.addrs.len(), 1);
     }
-    Ok(())
 }
@@ -0,0 +1,288 @@
+use std::{collections::HashMap, future::{ready, Ready}, sync::Arc};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::InternalError,
+    middleware::Next,
+    web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use blake2::{Blake2s256, Digest};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::call_manager::CallManager;
+
+/// How long a session token is valid after login.
+pub const SESSION_TTL_SECS: i64 = 24 * 60 * 60;
+
+/// The user a request was authenticated as by `require_session`.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: String,
+    pub token: String,
+}
+
+impl AuthUser {
+    /// Checks a `user_id` the client also sent in the request, which older
+    /// clients still do, against the session. Omitting it is fine.
+    pub fn check_claimed(&self, claimed: Option<&str>) -> Result<(), Error> {
+        match claimed {
+            Some(user_id) if !user_id.is_empty() && user_id != self.user_id => {
+                let message = "user_id does not match the session";
+                let response = HttpResponse::Forbidden().json(serde_json::json!({
+                    "status": "error",
+                    "code": "not_permitted",
+                    "message": message
+                }));
+                Err(InternalError::from_response(message, response).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthUser>()
+                .cloned()
+                .ok_or_else(|| unauthorized("Authentication required")),
+        )
+    }
+}
+
+/// A session as kept in `Storage`. Only a digest of the token is stored,
+/// so a copy of the database does not hand out live sessions.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub key: String,
+    pub user_id: String,
+    pub expires: i64,
+}
+
+/// The digest a session token is stored and looked up under.
+pub fn session_key(token: &str) -> String {
+    Blake2s256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Session tokens issued at login, keyed by `session_key` and mapped to the
+/// user and expiry time. Changes are returned to the caller to persist.
+#[derive(Default)]
+pub struct Sessions {
+    tokens: HashMap<String, (String, i64)>,
+}
+
+impl Sessions {
+    pub fn restore(sessions: Vec<Session>) -> Self {
+        Sessions {
+            tokens: sessions.into_iter().map(|s| (s.key, (s.user_id, s.expires))).collect(),
+        }
+    }
+
+    /// Returns the new token and the session to store for it.
+    pub fn create(&mut self, user_id: &str) -> (String, Session) {
+        let token = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+        let session = Session {
+            key: session_key(&token),
+            user_id: user_id.to_string(),
+            expires: chrono::Local::now().timestamp() + SESSION_TTL_SECS,
+        };
+        self.tokens.insert(session.key.clone(), (session.user_id.clone(), session.expires));
+        (token, session)
+    }
+
+    pub fn user_of(&self, token: &str) -> Option<&str> {
+        let now = chrono::Local::now().timestamp();
+        self.tokens
+            .get(&session_key(token))
+            .filter(|(_, expires)| *expires > now)
+            .map(|(user_id, _)| user_id.as_str())
+    }
+
+    /// Returns the key of the session ended, if there was one.
+    pub fn end(&mut self, token: &str) -> Option<String> {
+        let key = session_key(token);
+        self.tokens.remove(&key).map(|_| key)
+    }
+
+    /// Logs the user out everywhere, e.g. after a password change, and
+    /// returns the keys of the sessions ended.
+    pub fn end_all(&mut self, user_id: &str) -> Vec<String> {
+        self.remove_where(|id, _| id == user_id)
+    }
+
+    /// Drops expired sessions and returns their keys.
+    pub fn prune(&mut self) -> Vec<String> {
+        let now = chrono::Local::now().timestamp();
+        self.remove_where(|_, expires| expires <= now)
+    }
+
+    fn remove_where(&mut self, remove: impl Fn(&str, i64) -> bool) -> Vec<String> {
+        let keys: Vec<String> = self
+            .tokens
+            .iter()
+            .filter(|(_, (user_id, expires))| remove(user_id, *expires))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            self.tokens.remove(key);
+        }
+        keys
+    }
+}
+
+fn unauthorized(message: &str) -> Error {
+    let response = HttpResponse::Unauthorized()
+        .insert_header(("WWW-Authenticate", "Bearer"))
+        .json(serde_json::json!({
+            "status": "error",
+            "code": "unauthorized",
+            "message": message
+        }));
+    InternalError::from_response(message.to_string(), response).into()
+}
+
+/// The token from `Authorization: Bearer <token>`, or from the
+/// `access_token` query parameter for WebSocket clients, which cannot set
+/// headers.
+fn request_token(req: &ServiceRequest) -> Option<String> {
+    if let Some(header) = req.headers().get("Authorization") {
+        return header
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")
+            .map(|t| t.trim().to_string());
+    }
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()?
+        .get("access_token")
+        .cloned()
+}
+
+/// Rejects requests without a valid session token with 401 and makes the
+/// authenticated user available to handlers as `AuthUser`.
+pub async fn require_session(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(token) = request_token(&req) else {
+        return Err(unauthorized("Missing bearer token"));
+    };
+    let Some(call_manager) = req.app_data::<web::Data<Arc<Mutex<CallManager>>>>().cloned() else {
+        return Err(unauthorized("Authentication unavailable"));
+    };
+    let user_id = call_manager.lock().await.session_user(&token).map(str::to_string);
+    let Some(user_id) = user_id else {
+        return Err(unauthorized("Invalid or expired session"));
+    };
+
+    req.extensions_mut().insert(AuthUser { user_id, token });
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, test::{self as actix_test, TestRequest}, App};
+    use crate::cdr::CdrStore;
+    use crate::storage::{MemoryStorage, Storage};
+
+    fn manager() -> (CallManager, std::path::PathBuf) {
+        let cdr_path = std::env::temp_dir().join(format!("voip-auth-{}.jsonl", uuid::Uuid::new_v4()));
+        let storage: Box<dyn Storage> = Box::new(MemoryStorage::new());
+        (CallManager::new(storage, CdrStore::open(cdr_path.clone()).unwrap()), cdr_path)
+    }
+
+    async fn whoami(auth: AuthUser) -> HttpResponse {
+        HttpResponse::Ok().body(auth.user_id)
+    }
+
+    /// Sends `req` to an app with one authenticated route, `/whoami`,
+    /// returning the status and body.
+    async fn call(manager: &Arc<Mutex<CallManager>>, req: TestRequest) -> (u16, String) {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::clone(manager)))
+                .service(web::scope("").wrap(from_fn(require_session)).route("/whoami", web::get().to(whoami))),
+        )
+        .await;
+        match actix_test::try_call_service(&app, req.to_request()).await {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                (status, String::from_utf8(actix_test::read_body(response).await.to_vec()).unwrap())
+            }
+            Err(e) => (e.as_response_error().status_code().as_u16(), String::new()),
+        }
+    }
+
+    fn bearer(token: &str) -> TestRequest {
+        TestRequest::get().uri("/whoami").insert_header(("Authorization", format!("Bearer {}", token)))
+    }
+
+    #[actix_web::test]
+    async fn requires_a_valid_session() {
+        let (mut manager, cdr_path) = manager();
+        let account = manager.create_account("alice", "hash".into()).unwrap();
+        let token = manager.log_in(&account);
+        let expired = manager.log_in(&account);
+        manager.sessions_mut().tokens.get_mut(&session_key(&expired)).unwrap().1 = chrono::Local::now().timestamp() - 1;
+        let manager = Arc::new(Mutex::new(manager));
+
+        assert_eq!(call(&manager, TestRequest::get().uri("/whoami")).await.0, 401);
+        assert_eq!(call(&manager, bearer("not-a-session")).await.0, 401);
+        assert_eq!(call(&manager, bearer(&expired)).await.0, 401);
+        assert_eq!(call(&manager, bearer(&token)).await, (200, account.id.clone()));
+        let query = TestRequest::get().uri(&format!("/whoami?access_token={}", token));
+        assert_eq!(call(&manager, query).await, (200, account.id.clone()));
+
+        manager.lock().await.end_session(&token);
+        assert_eq!(call(&manager, bearer(&token)).await.0, 401);
+        let _ = std::fs::remove_file(cdr_path);
+    }
+
+    #[test]
+    fn rejects_a_claimed_user_other_than_the_session() {
+        let auth = AuthUser { user_id: "alice".to_string(), token: "token".to_string() };
+        assert!(auth.check_claimed(None).is_ok());
+        assert!(auth.check_claimed(Some("")).is_ok());
+        assert!(auth.check_claimed(Some("alice")).is_ok());
+        let error = auth.check_claimed(Some("bob")).unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), actix_web::http::StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn sessions_expire_and_end() {
+        let mut sessions = Sessions::default();
+        let (first, stored) = sessions.create("alice");
+        let (second, _) = sessions.create("alice");
+        let (other, _) = sessions.create("bob");
+        assert_eq!(sessions.user_of(&first), Some("alice"));
+        assert_ne!(first, second);
+        assert_eq!(stored.key, session_key(&first));
+        assert_ne!(stored.key, first, "only a digest of the token is stored");
+
+        sessions.tokens.get_mut(&stored.key).unwrap().1 = chrono::Local::now().timestamp();
+        assert_eq!(sessions.user_of(&first), None);
+        assert_eq!(sessions.prune(), vec![stored.key.clone()]);
+
+        assert_eq!(sessions.end(&second), Some(session_key(&second)));
+        assert_eq!(sessions.end(&second), None);
+        assert_eq!(sessions.user_of(&second), None);
+        let (third, _) = sessions.create("alice");
+        assert_eq!(sessions.end_all("alice"), vec![session_key(&third)]);
+        assert_eq!(sessions.user_of(&third), None);
+        assert_eq!(sessions.user_of(&other), Some("bob"));
+
+        let restored = Sessions::restore(vec![Session {
+            key: session_key(&first),
+            user_id: "alice".to_string(),
+            expires: chrono::Local::now().timestamp() + 60,
+        }]);
+        assert_eq!(restored.user_of(&first), Some("alice"));
+    }
+}
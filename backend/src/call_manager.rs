@@ -1,37 +1,266 @@
-use crate::user::{User, CallStatus};
+use crate::account::{Account, AccountError, ResetTokens};
+use crate::audio_udp::UdpCommand;
+use crate::auth::Sessions;
+use crate::call_state::{CallError, CallState, CallTransition, EndReason};
+use crate::cdr::{CallRecord, CdrStore, HistoryQuery, MediaStats, MissedCall};
+use crate::events::{EventHub, SignalEvent};
+use crate::io::DeviceSelection;
+use crate::relay::{RelayLegs, RelayPorts};
+use crate::storage::{Storage, StorageWrite, StorageWriter};
+use crate::user::{User, CallStatus, Unavailable};
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::{self, UnboundedReceiver, UnboundedSender}, Mutex};
+
+/// How long a call may ring before it is ended as unanswered, and where
+/// call detail records are kept.
+#[derive(Debug, Clone)]
+pub struct CallConfig {
+    pub ring_timeout_secs: i64,
+    pub cdr_path: PathBuf,
+}
+
+impl Default for CallConfig {
+    fn default() -> Self {
+        CallConfig {
+            ring_timeout_secs: 30,
+            cdr_path: PathBuf::from("cdr.jsonl"),
+        }
+    }
+}
+
+impl CallConfig {
+    /// Reads `VOIP_RING_TIMEOUT_SECS` and `VOIP_CDR_PATH`, falling back to
+    /// the defaults for anything unset.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = CallConfig::default();
+        if let Ok(path) = std::env::var("VOIP_CDR_PATH") {
+            config.cdr_path = PathBuf::from(path);
+        }
+        if let Ok(value) = std::env::var("VOIP_RING_TIMEOUT_SECS") {
+            config.ring_timeout_secs = match value.parse() {
+                Ok(secs) if secs > 0 => secs,
+                _ => return Err(format!("invalid value '{}' for VOIP_RING_TIMEOUT_SECS", value)),
+            };
+        }
+        Ok(config)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Call {
     pub call_id: String,
     pub caller_id: String,
     pub callee_id: String,
-    pub status: CallStatus,
+    pub state: CallState,
+    pub transitions: Vec<CallTransition>,
     pub timestamp: i64,
     pub offer: Option<String>,
     pub answer: Option<String>,
+    /// The party whose latest offer has not been answered yet.
+    #[serde(default)]
+    pub pending_offer_from: Option<String>,
     pub caller_candidates: Vec<String>,
     pub callee_candidates: Vec<String>,
+    pub relay: Option<RelayPorts>,
+    pub recording: Option<String>,
+    pub end_reason: Option<EndReason>,
+    /// Rang while the callee was already on another call.
+    pub waiting: bool,
+}
+
+/// A conference room. Participants' media is mixed server-side by
+/// `conference::Conference`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Room {
+    pub room_id: String,
+    pub participants: Vec<String>,
+    pub timestamp: i64,
 }
 
-pub struct CallManager {
+/// Accounts, users, sessions and calls are queued for a `StorageWriter` as
+/// they change, so registrations and calls in progress survive a restart.
+pub struct CallManager<S: Storage = Box<dyn Storage>> {
+    accounts: HashMap<String, Account>,
+    password_resets: ResetTokens,
+    sessions: Sessions,
     users: HashMap<String, User>,
     calls: HashMap<String, Call>,
+    rooms: HashMap<String, Room>,
+    storage: UnboundedSender<StorageWrite>,
+    storage_writer: Option<StorageWriter<S>>,
+    cdr: CdrStore,
+    events: EventHub,
+    /// Commands to the UDP audio task, so that calls ending here also stop
+    /// their media and relay.
+    media: Option<UnboundedSender<UdpCommand>>,
 }
 
-impl CallManager {
-    pub fn new() -> Self {
-        CallManager {
-            users: HashMap::new(),
-            calls: HashMap::new(),
+impl<S: Storage> CallManager<S> {
+    /// Restores users and calls from `storage`. Heartbeats restart from now
+    /// so clients get the usual grace period to reconnect, and relay ports
+    /// are dropped since their sockets did not survive.
+    pub fn new(storage: S, cdr: CdrStore) -> Self {
+        let accounts = storage.load_accounts().unwrap_or_else(|e| {
+            log::error!("Failed to load accounts: {}", e);
+            Vec::new()
+        });
+        let users = storage.load_users().unwrap_or_else(|e| {
+            log::error!("Failed to load users: {}", e);
+            Vec::new()
+        });
+        let calls = storage.load_calls().unwrap_or_else(|e| {
+            log::error!("Failed to load calls: {}", e);
+            Vec::new()
+        });
+        let sessions = storage.load_sessions().unwrap_or_else(|e| {
+            log::error!("Failed to load sessions: {}", e);
+            Vec::new()
+        });
+        if !accounts.is_empty() || !calls.is_empty() {
+            log::info!("💾 Restored {} accounts and {} calls", accounts.len(), calls.len());
+        }
+
+        let (storage_tx, storage_rx) = mpsc::unbounded_channel();
+        let mut manager = CallManager {
+            accounts: accounts.into_iter().map(|a| (a.id.clone(), a)).collect(),
+            password_resets: ResetTokens::default(),
+            sessions: Sessions::restore(sessions),
+            users: users
+                .into_iter()
+                .map(|mut u| {
+                    u.update_heartbeat();
+                    (u.id.clone(), u)
+                })
+                .collect(),
+            calls: calls
+                .into_iter()
+                .map(|mut c| {
+                    c.relay = None;
+                    (c.call_id.clone(), c)
+                })
+                .collect(),
+            rooms: HashMap::new(),
+            storage: storage_tx,
+            storage_writer: Some(StorageWriter::new(storage, storage_rx)),
+            cdr,
+            events: EventHub::new(),
+            media: None,
+        };
+
+        let expired = manager.sessions.prune();
+        manager.forget_sessions(&expired);
+
+        let online: Vec<String> = manager.users
+            .values()
+            .filter(|u| u.status != CallStatus::Offline)
+            .map(|u| u.id.clone())
+            .collect();
+        for user_id in online {
+            manager.refresh_status(&user_id);
+        }
+        manager
+    }
+
+    /// Creates an account for `username`, which must not be taken by
+    /// another account (ignoring case).
+    pub fn create_account(&mut self, username: &str, password_hash: String) -> Result<Account, AccountError> {
+        if self.account_by_name(username).is_some() {
+            return Err(AccountError::UsernameTaken(username.to_string()));
+        }
+        let account = Account {
+            id: uuid::Uuid::new_v4().to_string(),
+            username: username.to_string(),
+            password_hash,
+            created: chrono::Local::now().timestamp(),
+        };
+        self.write(StorageWrite::SaveAccount(account.clone()));
+        self.accounts.insert(account.id.clone(), account.clone());
+        log::info!("👤 Created account {} ({})", account.username, account.id);
+        Ok(account)
+    }
+
+    pub fn account_by_name(&self, username: &str) -> Option<&Account> {
+        self.accounts.values().find(|a| a.username.eq_ignore_ascii_case(username))
+    }
+
+    /// Replaces the account's password hash, invalidating any outstanding
+    /// reset tokens and sessions for it.
+    pub fn set_password_hash(&mut self, account_id: &str, password_hash: String) -> bool {
+        let Some(account) = self.accounts.get_mut(account_id) else {
+            return false;
+        };
+        account.password_hash = password_hash;
+        let account = account.clone();
+        self.write(StorageWrite::SaveAccount(account));
+        self.password_resets.revoke_all(account_id);
+        let ended = self.sessions.end_all(account_id);
+        self.forget_sessions(&ended);
+        true
+    }
+
+    /// Issues a single-use token that allows setting a new password without
+    /// the old one. It is never returned to the requester.
+    pub fn create_reset_token(&mut self, account_id: &str) -> String {
+        self.password_resets.create(account_id)
+    }
+
+    /// Consumes a reset token, returning the account it was issued for if
+    /// it has not expired.
+    pub fn take_reset_token(&mut self, token: &str) -> Option<String> {
+        self.password_resets.take(token)
+    }
+
+    /// Brings the account's user online after a login and returns a new
+    /// session token. A user who logs in again keeps their ID, settings and
+    /// any calls still in progress.
+    pub fn log_in(&mut self, account: &Account) -> String {
+        match self.users.get_mut(&account.id) {
+            Some(user) => {
+                user.username = account.username.clone();
+                user.update_heartbeat();
+                if user.status == CallStatus::Offline {
+                    user.set_status(CallStatus::Idle);
+                }
+                self.refresh_status(&account.id);
+            }
+            None => {
+                let mut user = User::new(account.id.clone(), account.username.clone());
+                user.set_status(CallStatus::Idle);
+                self.users.insert(account.id.clone(), user);
+            }
+        }
+        self.save_user(&account.id);
+
+        let expired = self.sessions.prune();
+        self.forget_sessions(&expired);
+        let (token, session) = self.sessions.create(&account.id);
+        self.write(StorageWrite::SaveSession(session));
+        token
+    }
+
+    pub fn session_user(&self, token: &str) -> Option<&str> {
+        self.sessions.user_of(token)
+    }
+
+    #[cfg(test)]
+    pub fn sessions_mut(&mut self) -> &mut Sessions {
+        &mut self.sessions
+    }
+
+    pub fn end_session(&mut self, token: &str) {
+        if let Some(key) = self.sessions.end(token) {
+            self.forget_sessions(&[key]);
         }
     }
 
-    pub fn register_user(&mut self, user_id: String, username: String) {
-        let mut user = User::new(user_id, username);
-        user.set_status(CallStatus::Idle);
-        self.users.insert(user.id.clone(), user);
+    fn forget_sessions(&mut self, keys: &[String]) {
+        if !keys.is_empty() {
+            self.write(StorageWrite::DeleteSessions(keys.to_vec()));
+        }
     }
 
     pub fn list_users(&self) -> Vec<User> {
@@ -45,6 +274,7 @@ impl CallManager {
     pub fn disconnect_user(&mut self, user_id: &str) -> bool {
         if let Some(user) = self.users.get_mut(user_id) {
             user.set_status(CallStatus::Offline);
+            self.save_user(user_id);
             true
         } else {
             false
@@ -57,7 +287,11 @@ impl CallManager {
 
     pub fn update_user_status(&mut self, user_id: &str, status: CallStatus) -> bool {
         if let Some(user) = self.users.get_mut(user_id) {
+            if user.status == status {
+                return true;
+            }
             user.set_status(status);
+            self.save_user(user_id);
             true
         } else {
             false
@@ -67,73 +301,216 @@ impl CallManager {
     pub fn update_user_ip(&mut self, user_id: &str, ip: String) -> bool {
         if let Some(user) = self.users.get_mut(user_id) {
             user.set_ip_address(ip);
+            self.save_user(user_id);
             true
         } else {
             false
         }
     }
 
-    pub fn create_call(&mut self, caller_id: String, callee_id: String) -> Call {
+    pub fn create_call(&mut self, caller_id: String, callee_id: String) -> Result<Call, CallError> {
+        self.check_available(&caller_id, false)?;
+        let waiting = self.check_available(&callee_id, true)?;
+
         let call_id = uuid::Uuid::new_v4().to_string();
+        let timestamp = chrono::Local::now().timestamp();
         let call = Call {
             call_id: call_id.clone(),
             caller_id: caller_id.clone(),
             callee_id: callee_id.clone(),
-            status: CallStatus::Calling,
-            timestamp: chrono::Local::now().timestamp(),
+            state: CallState::Ringing,
+            transitions: vec![CallTransition { state: CallState::Ringing, timestamp }],
+            timestamp,
             offer: None,
             answer: None,
+            pending_offer_from: None,
             caller_candidates: Vec::new(),
             callee_candidates: Vec::new(),
+            relay: None,
+            recording: None,
+            end_reason: None,
+            waiting,
         };
         
-        self.calls.insert(call_id, call.clone());
-        self.update_user_status(&caller_id, CallStatus::Calling);
+        self.calls.insert(call_id.clone(), call.clone());
+        self.save_call(&call_id);
+        self.refresh_status(&caller_id);
+        self.refresh_status(&callee_id);
+        self.events.publish(&callee_id, SignalEvent::IncomingCall { call_id, caller_id, waiting });
         
-        call
+        Ok(call)
     }
 
-    pub fn accept_call(&mut self, call_id: &str) {
-        if let Some(call) = self.calls.get_mut(call_id) {
-            let caller_id = call.caller_id.clone();
-            let callee_id = call.callee_id.clone();
-            call.status = CallStatus::InCall;
-            let _ = call;
-            self.update_user_status(&caller_id, CallStatus::InCall);
-            self.update_user_status(&callee_id, CallStatus::InCall);
+    /// Marks the call active. Only the callee can accept, and only while the
+    /// call is ringing. A waiting call's callee has their current call put
+    /// on hold first. With `use_relay`, the server also binds a pair of
+    /// relay sockets for the call's media and returns them so the caller
+    /// can start forwarding; their ports are recorded on the call.
+    pub fn accept_call(&mut self, call_id: &str, user_id: &str, use_relay: bool) -> Result<Option<RelayLegs>, CallError> {
+        let call = self.party_call(call_id, user_id, "accept")?;
+        if call.callee_id != user_id {
+            return Err(CallError::NotPermitted { user_id: user_id.to_string(), action: "accept" });
+        }
+        if !call.state.can_transition(CallState::Active) {
+            return Err(CallError::InvalidTransition { from: call.state, to: CallState::Active });
         }
+
+        let current = self
+            .calls_of(user_id)
+            .find(|c| c.call_id != call_id && c.state == CallState::Active)
+            .map(|c| c.call_id.clone());
+        if let Some(current) = current {
+            self.hold_call(&current, user_id)?;
+        }
+        self.transition(call_id, CallState::Active)?;
+
+        let call = self.calls.get_mut(call_id).ok_or_else(|| CallError::CallNotFound(call_id.to_string()))?;
+        let mut legs = None;
+        if use_relay && call.relay.is_none() {
+            match RelayLegs::bind().and_then(|l| Ok((l.ports()?, l))) {
+                Ok((ports, l)) => {
+                    call.relay = Some(ports);
+                    legs = Some(l);
+                }
+                Err(e) => log::error!("Failed to bind relay sockets for call {}: {}", call_id, e),
+            }
+        }
+        let relay = call.relay;
+        let caller_id = call.caller_id.clone();
+        let callee_id = call.callee_id.clone();
+        if relay.is_some() {
+            self.save_call(call_id);
+        }
+
+        self.notify_parties(&caller_id, &callee_id, SignalEvent::Accepted { call_id: call_id.to_string(), relay });
+        Ok(legs)
     }
 
-    pub fn reject_call(&mut self, call_id: &str) {
-        if let Some(call) = self.calls.get_mut(call_id) {
-            let caller_id = call.caller_id.clone();
-            let callee_id = call.callee_id.clone();
-            call.status = CallStatus::Idle;
-            let _ = call;
-            self.update_user_status(&caller_id, CallStatus::Idle);
-            self.update_user_status(&callee_id, CallStatus::Idle);
-            self.calls.remove(call_id);
+    /// Declines a ringing call, either outright (`EndReason::Rejected`) or
+    /// as busy from a callee who is already on another call.
+    pub fn reject_call(&mut self, call_id: &str, user_id: &str, reason: EndReason) -> Result<(), CallError> {
+        let call = self.party_call(call_id, user_id, "reject")?;
+        if call.callee_id != user_id {
+            return Err(CallError::NotPermitted { user_id: user_id.to_string(), action: "reject" });
         }
+        if !call.state.is_ringing() {
+            return Err(CallError::InvalidTransition { from: call.state, to: CallState::Ended });
+        }
+        let (caller_id, callee_id) = (call.caller_id.clone(), call.callee_id.clone());
+
+        self.close_call(call_id, reason)?;
+        self.notify_parties(&caller_id, &callee_id, SignalEvent::Rejected { call_id: call_id.to_string(), reason });
+        Ok(())
     }
 
-    pub fn hold_call(&mut self, call_id: &str) {
-        if let Some(call) = self.calls.get_mut(call_id) {
-            let caller_id = call.caller_id.clone();
-            let callee_id = call.callee_id.clone();
-            call.status = CallStatus::OnHold;
-            let _ = call;
-            self.update_user_status(&caller_id, CallStatus::OnHold);
-            self.update_user_status(&callee_id, CallStatus::OnHold);
+    pub fn hold_call(&mut self, call_id: &str, user_id: &str) -> Result<(), CallError> {
+        let call = self.party_call(call_id, user_id, "hold")?;
+        let held = Self::held_by(call, user_id);
+        let (caller_id, callee_id) = (call.caller_id.clone(), call.callee_id.clone());
+
+        self.transition(call_id, held)?;
+        self.notify_parties(&caller_id, &callee_id, SignalEvent::Held { call_id: call_id.to_string() });
+        Ok(())
+    }
+
+    /// Takes a held call off hold. Only the party who held it may resume it.
+    pub fn resume_call(&mut self, call_id: &str, user_id: &str) -> Result<(), CallError> {
+        let call = self.party_call(call_id, user_id, "resume")?;
+        if !call.state.is_held() {
+            return Err(CallError::InvalidTransition { from: call.state, to: CallState::Active });
+        }
+        if call.state != Self::held_by(call, user_id) {
+            return Err(CallError::NotPermitted { user_id: user_id.to_string(), action: "resume" });
+        }
+        let (caller_id, callee_id) = (call.caller_id.clone(), call.callee_id.clone());
+
+        self.transition(call_id, CallState::Active)?;
+        self.notify_parties(&caller_id, &callee_id, SignalEvent::Resumed { call_id: call_id.to_string() });
+        Ok(())
+    }
+
+    /// Follows a re-offer from `user_id`: one that only sends or is
+    /// inactive puts an active call on hold, and a later one that receives
+    /// again resumes it if `user_id` was the one who held it. Offers before
+    /// the call is answered do not change anything.
+    pub fn apply_offer_direction(&mut self, call_id: &str, user_id: &str, hold: bool) -> Result<(), CallError> {
+        let call = self.party_call(call_id, user_id, "signal")?;
+        let held_by_user = Self::held_by(call, user_id);
+        match call.state {
+            CallState::Active if hold => self.hold_call(call_id, user_id),
+            state if state == held_by_user && !hold => self.resume_call(call_id, user_id),
+            _ => Ok(()),
+        }
+    }
+
+    /// Ends the call from any live state (hanging up, or cancelling while it
+    /// rings) and returns its final record.
+    pub fn end_call(&mut self, call_id: &str, user_id: &str) -> Result<Call, CallError> {
+        let call = self.party_call(call_id, user_id, "end")?;
+        let (caller_id, callee_id) = (call.caller_id.clone(), call.callee_id.clone());
+
+        let call = self.close_call(call_id, EndReason::Hangup)?;
+        self.notify_parties(&caller_id, &callee_id, SignalEvent::Ended {
+            call_id: call_id.to_string(),
+            reason: EndReason::Hangup,
+        });
+        Ok(call)
+    }
+
+    /// Ends every call that has been ringing for `timeout_secs` or longer
+    /// and records it as missed for the callee. Returns the ended call IDs.
+    pub fn expire_unanswered(&mut self, timeout_secs: i64) -> Vec<String> {
+        let now = chrono::Local::now().timestamp();
+        let expired: Vec<String> = self.calls
+            .values()
+            .filter(|c| c.state.is_ringing() && now - c.timestamp >= timeout_secs)
+            .map(|c| c.call_id.clone())
+            .collect();
+
+        for call_id in &expired {
+            let Ok(call) = self.close_call(call_id, EndReason::NoAnswer) else { continue };
+            log::info!("⏰ Call {} from {} to {} was not answered", call_id, call.caller_id, call.callee_id);
+
+            self.notify_parties(&call.caller_id, &call.callee_id, SignalEvent::Ended {
+                call_id: call_id.clone(),
+                reason: EndReason::NoAnswer,
+            });
+            self.events.publish(&call.callee_id, SignalEvent::MissedCall {
+                call_id: call_id.clone(),
+                caller_id: call.caller_id.clone(),
+            });
+        }
+        expired
+    }
+
+    /// Adds media statistics to the record of a call whose media has
+    /// stopped.
+    pub fn record_media_stats(&mut self, call_id: &str, stats: MediaStats) {
+        match self.cdr.set_media(call_id, stats) {
+            Ok(true) => {}
+            Ok(false) => log::warn!("No call record for {} to attach media statistics to", call_id),
+            Err(e) => log::error!("Failed to write media statistics for {}: {}", call_id, e),
         }
     }
 
-    pub fn end_call(&mut self, call_id: &str) {
+    pub fn call_history(&self, query: &HistoryQuery) -> (usize, Vec<CallRecord>) {
+        self.cdr.history(query)
+    }
+
+    pub fn get_missed_calls(&self, user_id: &str) -> Vec<MissedCall> {
+        self.cdr.missed_calls(user_id)
+    }
+
+    pub fn clear_missed_calls(&mut self, user_id: &str) {
+        if let Err(e) = self.cdr.clear_missed(user_id) {
+            log::error!("Failed to clear missed calls for {}: {}", user_id, e);
+        }
+    }
+
+    pub fn set_recording(&mut self, call_id: &str, path: String) {
         if let Some(call) = self.calls.get_mut(call_id) {
-            let caller_id = call.caller_id.clone();
-            let callee_id = call.callee_id.clone();
-            self.update_user_status(&caller_id, CallStatus::Idle);
-            self.update_user_status(&callee_id, CallStatus::Idle);
-            self.calls.remove(call_id);
+            call.recording = Some(path);
+            self.save_call(call_id);
         }
     }
 
@@ -141,36 +518,320 @@ impl CallManager {
         self.calls.get(call_id)
     }
 
-    pub fn set_offer(&mut self, call_id: &str, offer: String) {
+    /// Stores an offer from either party and sends it to the other one,
+    /// who is then expected to answer it.
+    pub fn set_offer(&mut self, call_id: &str, user_id: &str, offer: String) -> Result<(), CallError> {
+        let peer_id = self.peer_of(call_id, user_id, "signal")?;
         if let Some(call) = self.calls.get_mut(call_id) {
-            call.offer = Some(offer);
+            call.offer = Some(offer.clone());
+            call.answer = None;
+            call.pending_offer_from = Some(user_id.to_string());
         }
+        self.save_call(call_id);
+        self.events.publish(&peer_id, SignalEvent::Offer { call_id: call_id.to_string(), offer });
+        Ok(())
     }
 
-    pub fn set_answer(&mut self, call_id: &str, answer: String) {
-        if let Some(call) = self.calls.get_mut(call_id) {
-            call.answer = Some(answer);
+    /// Stores `user_id`'s answer to the other party's pending offer and
+    /// sends it to them. An answer while the call is still ringing moves it
+    /// to early media.
+    pub fn set_answer(&mut self, call_id: &str, user_id: &str, answer: String) -> Result<(), CallError> {
+        let peer_id = self.peer_of(call_id, user_id, "signal")?;
+        let Some(call) = self.calls.get_mut(call_id) else {
+            return Err(CallError::CallNotFound(call_id.to_string()));
+        };
+        if call.pending_offer_from.as_deref() != Some(peer_id.as_str()) {
+            return Err(CallError::NoPendingOffer(user_id.to_string()));
+        }
+        call.answer = Some(answer.clone());
+        call.pending_offer_from = None;
+        if call.state == CallState::Ringing {
+            self.transition(call_id, CallState::EarlyMedia)?;
+        } else {
+            self.save_call(call_id);
         }
+        self.events.publish(&peer_id, SignalEvent::Answer { call_id: call_id.to_string(), answer });
+        Ok(())
     }
 
     pub fn add_candidate(&mut self, call_id: &str, candidate: String, is_caller: bool) {
         if let Some(call) = self.calls.get_mut(call_id) {
-            if is_caller {
-                call.caller_candidates.push(candidate);
+            let peer_id = if is_caller {
+                call.caller_candidates.push(candidate.clone());
+                call.callee_id.clone()
             } else {
-                call.callee_candidates.push(candidate);
-            }
+                call.callee_candidates.push(candidate.clone());
+                call.caller_id.clone()
+            };
+            self.save_call(call_id);
+            self.events.publish(&peer_id, SignalEvent::Candidate { call_id: call_id.to_string(), candidate });
         }
     }
 
     pub fn get_incoming_calls(&self, user_id: &str) -> Vec<Call> {
         self.calls
             .values()
-            .filter(|call| call.callee_id == user_id && call.status == CallStatus::Calling)
+            .filter(|call| call.callee_id == user_id && call.state.is_ringing())
             .cloned()
             .collect()
     }
 
+    /// Adds `user_id` to `room_id`, creating the room if it does not exist
+    /// or a fresh one if no ID is given.
+    pub fn join_room(&mut self, room_id: Option<String>, user_id: &str) -> Room {
+        let room_id = room_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let room = self.rooms.entry(room_id.clone()).or_insert_with(|| Room {
+            room_id: room_id.clone(),
+            participants: Vec::new(),
+            timestamp: chrono::Local::now().timestamp(),
+        });
+        if !room.participants.iter().any(|p| p == user_id) {
+            room.participants.push(user_id.to_string());
+        }
+        let room = room.clone();
+
+        self.update_user_status(user_id, CallStatus::InCall);
+        for participant in room.participants.iter().filter(|p| *p != user_id) {
+            self.events.publish(participant, SignalEvent::RoomJoined {
+                room_id: room_id.clone(),
+                user_id: user_id.to_string(),
+            });
+        }
+        room
+    }
+
+    /// Removes `user_id` from the room and from its mixer, closing the room
+    /// once it is empty.
+    pub fn leave_room(&mut self, room_id: &str, user_id: &str) -> bool {
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return false;
+        };
+        let before = room.participants.len();
+        room.participants.retain(|p| p != user_id);
+        if room.participants.len() == before {
+            return false;
+        }
+        let remaining = room.participants.clone();
+        if remaining.is_empty() {
+            self.rooms.remove(room_id);
+        }
+        self.send_media(UdpCommand::LeaveRoom {
+            room_id: room_id.to_string(),
+            user_id: user_id.to_string(),
+        });
+
+        self.update_user_status(user_id, CallStatus::Idle);
+        for participant in &remaining {
+            self.events.publish(participant, SignalEvent::RoomLeft {
+                room_id: room_id.to_string(),
+                user_id: user_id.to_string(),
+            });
+        }
+        true
+    }
+
+    pub fn get_room(&self, room_id: &str) -> Option<&Room> {
+        self.rooms.get(room_id)
+    }
+
+    pub fn list_rooms(&self) -> Vec<Room> {
+        self.rooms.values().cloned().collect()
+    }
+
+    pub fn call_of(&self, user_id: &str) -> Option<&Call> {
+        self.calls.values().find(|c| c.caller_id == user_id || c.callee_id == user_id)
+    }
+
+    pub fn room_of(&self, user_id: &str) -> Option<&Room> {
+        self.rooms.values().find(|r| r.participants.iter().any(|p| p == user_id))
+    }
+
+    pub fn subscribe(&mut self, user_id: &str) -> UnboundedReceiver<SignalEvent> {
+        self.events.subscribe(user_id)
+    }
+
+    /// Checks that `user_id` can take part in a new call. A user already on
+    /// a call is busy, unless `allow_waiting` is set, they have call waiting
+    /// enabled and their one current call is answered; `Ok(true)` then means
+    /// the new call waits behind it.
+    fn check_available(&self, user_id: &str, allow_waiting: bool) -> Result<bool, Unavailable> {
+        let user = self.users.get(user_id).ok_or_else(|| Unavailable::Unknown(user_id.to_string()))?;
+        if user.status == CallStatus::Offline {
+            return Err(Unavailable::Offline(user_id.to_string()));
+        }
+        if self.room_of(user_id).is_some() {
+            return Err(Unavailable::Busy(user_id.to_string()));
+        }
+
+        let calls: Vec<&Call> = self.calls_of(user_id).collect();
+        match calls.as_slice() {
+            [] => Ok(false),
+            [current] if allow_waiting && user.call_waiting && !current.state.is_ringing() => Ok(true),
+            _ => Err(Unavailable::Busy(user_id.to_string())),
+        }
+    }
+
+    fn calls_of<'a>(&'a self, user_id: &'a str) -> impl Iterator<Item = &'a Call> + 'a {
+        self.calls.values().filter(move |c| c.caller_id == user_id || c.callee_id == user_id)
+    }
+
+    /// Derives a user's status from every call and room they are in, so a
+    /// user with a held call and a waiting one shows the most relevant.
+    fn refresh_status(&mut self, user_id: &str) {
+        let states: Vec<CallState> = self
+            .calls_of(user_id)
+            .map(|c| c.state)
+            .filter(|s| *s != CallState::Ended)
+            .collect();
+        let status = if self.room_of(user_id).is_some() || states.contains(&CallState::Active) {
+            CallStatus::InCall
+        } else if states.iter().any(|s| s.is_held()) {
+            CallStatus::OnHold
+        } else if !states.is_empty() {
+            CallStatus::Calling
+        } else {
+            CallStatus::Idle
+        };
+        self.update_user_status(user_id, status);
+    }
+
+    /// Looks up a call that `user_id` is a party to.
+    pub fn party_call(&self, call_id: &str, user_id: &str, action: &'static str) -> Result<&Call, CallError> {
+        let call = self.calls.get(call_id).ok_or_else(|| CallError::CallNotFound(call_id.to_string()))?;
+        if call.caller_id != user_id && call.callee_id != user_id {
+            return Err(CallError::NotPermitted { user_id: user_id.to_string(), action });
+        }
+        Ok(call)
+    }
+
+    /// The state of `call` once `user_id` has put it on hold.
+    fn held_by(call: &Call, user_id: &str) -> CallState {
+        if call.caller_id == user_id {
+            CallState::HeldByCaller
+        } else {
+            CallState::HeldByCallee
+        }
+    }
+
+    /// The other party to a call `user_id` is on.
+    fn peer_of(&self, call_id: &str, user_id: &str, action: &'static str) -> Result<String, CallError> {
+        let call = self.party_call(call_id, user_id, action)?;
+        Ok(if call.caller_id == user_id { call.callee_id.clone() } else { call.caller_id.clone() })
+    }
+
+    /// Moves a call to `to` if the state machine allows it, recording the
+    /// transition and updating both parties' status.
+    fn transition(&mut self, call_id: &str, to: CallState) -> Result<(), CallError> {
+        let call = self.calls.get_mut(call_id).ok_or_else(|| CallError::CallNotFound(call_id.to_string()))?;
+        if !call.state.can_transition(to) {
+            return Err(CallError::InvalidTransition { from: call.state, to });
+        }
+        call.state = to;
+        call.transitions.push(CallTransition {
+            state: to,
+            timestamp: chrono::Local::now().timestamp(),
+        });
+        log::info!("Call {} is now {:?}", call_id, to);
+
+        let (caller_id, callee_id) = (call.caller_id.clone(), call.callee_id.clone());
+        if to != CallState::Ended {
+            self.save_call(call_id);
+        }
+        self.refresh_status(&caller_id);
+        self.refresh_status(&callee_id);
+        Ok(())
+    }
+
+    /// Moves a call to `Ended` and removes it, stopping its media and
+    /// relay, and returns its final record. Every way a call ends goes
+    /// through here.
+    fn close_call(&mut self, call_id: &str, reason: EndReason) -> Result<Call, CallError> {
+        self.transition(call_id, CallState::Ended)?;
+        let mut call = self.calls.remove(call_id).ok_or_else(|| CallError::CallNotFound(call_id.to_string()))?;
+        call.end_reason = Some(reason);
+        self.send_media(UdpCommand::EndCall { call_id: call_id.to_string() });
+        self.write(StorageWrite::DeleteCall(call_id.to_string()));
+        if let Err(e) = self.cdr.record(CallRecord::from_call(&call, reason)) {
+            log::error!("Failed to write call record for {}: {}", call_id, e);
+        }
+        Ok(call)
+    }
+
+    fn save_user(&mut self, user_id: &str) {
+        if let Some(user) = self.users.get(user_id) {
+            self.write(StorageWrite::SaveUser(user.clone()));
+        }
+    }
+
+    fn save_call(&mut self, call_id: &str) {
+        if let Some(call) = self.calls.get(call_id) {
+            self.write(StorageWrite::SaveCall(call.clone()));
+        }
+    }
+
+    fn write(&self, write: StorageWrite) {
+        if self.storage.send(write).is_err() {
+            log::error!("Storage writer has stopped; change not persisted");
+        }
+    }
+
+    /// The writer that applies changes to storage, to be run as a task.
+    /// Until it is taken, changes are only queued.
+    pub fn take_storage_writer(&mut self) -> Option<StorageWriter<S>> {
+        self.storage_writer.take()
+    }
+
+    pub fn set_media_sender(&mut self, sender: UnboundedSender<UdpCommand>) {
+        self.media = Some(sender);
+    }
+
+    fn send_media(&self, command: UdpCommand) {
+        if let Some(media) = &self.media {
+            if let Err(e) = media.send(command) {
+                log::error!("Failed to send UDP command: {}", e);
+            }
+        }
+    }
+
+    fn notify_parties(&mut self, caller_id: &str, callee_id: &str, event: SignalEvent) {
+        self.events.publish(caller_id, event.clone());
+        self.events.publish(callee_id, event);
+    }
+
+    pub fn set_call_waiting(&mut self, user_id: &str, enabled: bool) -> bool {
+        if let Some(user) = self.users.get_mut(user_id) {
+            user.call_waiting = enabled;
+            self.save_user(user_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_audio_devices(&mut self, user_id: &str, selection: DeviceSelection) -> bool {
+        if let Some(user) = self.users.get_mut(user_id) {
+            user.audio_devices = selection;
+            self.save_user(user_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn audio_devices(&self, user_id: &str) -> DeviceSelection {
+        self.users.get(user_id).map(|u| u.audio_devices.clone()).unwrap_or_default()
+    }
+
+    /// The devices a call's media starts on. Media starts when the callee
+    /// accepts, so their selection comes first; devices they left at the
+    /// default follow the caller's saved selection.
+    pub fn call_devices(&self, call_id: &str) -> DeviceSelection {
+        let Some(call) = self.calls.get(call_id) else {
+            return DeviceSelection::default();
+        };
+        self.audio_devices(&call.callee_id).or(self.audio_devices(&call.caller_id))
+    }
+
     pub fn update_heartbeat(&mut self, user_id: &str) -> bool {
         if let Some(user) = self.users.get_mut(user_id) {
             user.update_heartbeat();
@@ -197,7 +858,11 @@ impl CallManager {
                 .collect();
 
             for call_id in active_calls {
-                self.end_call(&call_id);
+                let _ = self.end_call(&call_id, &user_id);
+            }
+
+            if let Some(room_id) = self.room_of(&user_id).map(|r| r.room_id.clone()) {
+                self.leave_room(&room_id, &user_id);
             }
 
            
@@ -208,3 +873,311 @@ impl CallManager {
         disconnected_users
     }
 }
+
+/// Periodically ends calls that have rung longer than the configured
+/// timeout.
+pub async fn ring_timeout_task(call_manager: Arc<Mutex<CallManager>>, config: CallConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        call_manager.lock().await.expire_unanswered(config.ring_timeout_secs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use tokio::sync::mpsc;
+
+    struct Setup {
+        manager: CallManager<MemoryStorage>,
+        media: UnboundedReceiver<UdpCommand>,
+        alice: String,
+        bob: String,
+        cdr_path: PathBuf,
+    }
+
+    impl Drop for Setup {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.cdr_path);
+        }
+    }
+
+    /// A manager with alice and bob logged in and media commands captured.
+    fn setup() -> Setup {
+        let cdr_path = std::env::temp_dir().join(format!("voip-calls-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut manager = CallManager::new(MemoryStorage::new(), CdrStore::open(cdr_path.clone()).unwrap());
+        let (tx, media) = mpsc::unbounded_channel();
+        manager.set_media_sender(tx);
+        let alice = manager.create_account("alice", "hash".into()).unwrap();
+        let bob = manager.create_account("bob", "hash".into()).unwrap();
+        manager.log_in(&alice);
+        manager.log_in(&bob);
+        Setup { manager, media, alice: alice.id, bob: bob.id, cdr_path }
+    }
+
+    /// Adds another logged-in user.
+    fn log_in(manager: &mut CallManager<MemoryStorage>, name: &str) -> String {
+        let account = manager.create_account(name, "hash".into()).unwrap();
+        manager.log_in(&account);
+        account.id
+    }
+
+    fn busy<T: std::fmt::Debug>(result: Result<T, CallError>) -> bool {
+        matches!(result, Err(CallError::Unavailable(Unavailable::Busy(_))))
+    }
+
+    fn ended_media(media: &mut UnboundedReceiver<UdpCommand>) -> Vec<String> {
+        std::iter::from_fn(|| media.try_recv().ok())
+            .filter_map(|command| match command {
+                UdpCommand::EndCall { call_id } => Some(call_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn timed_out_call_releases_its_relay() {
+        let mut s = setup();
+        let call = s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        let legs = s.manager.accept_call(&call.call_id, &s.bob, true).unwrap();
+        assert!(legs.is_some());
+        assert!(s.manager.get_call(&call.call_id).unwrap().relay.is_some());
+
+        let disconnected = s.manager.disconnect_inactive_users(-1);
+        assert_eq!(disconnected.len(), 2);
+        assert!(s.manager.get_call(&call.call_id).is_none());
+        assert_eq!(ended_media(&mut s.media), vec![call.call_id]);
+    }
+
+    #[test]
+    fn timed_out_user_leaves_the_room_mixer() {
+        let mut s = setup();
+        let room = s.manager.join_room(None, &s.alice);
+        s.manager.join_room(Some(room.room_id.clone()), &s.bob);
+        s.manager.update_heartbeat(&s.bob);
+        s.manager.users.get_mut(&s.alice).unwrap().last_heartbeat -= 60;
+
+        assert_eq!(s.manager.disconnect_inactive_users(10), vec![s.alice.clone()]);
+        assert_eq!(s.manager.get_room(&room.room_id).unwrap().participants, vec![s.bob.clone()]);
+        let left: Vec<(String, String)> = std::iter::from_fn(|| s.media.try_recv().ok())
+            .filter_map(|command| match command {
+                UdpCommand::LeaveRoom { room_id, user_id } => Some((room_id, user_id)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(left, vec![(room.room_id, s.alice.clone())]);
+    }
+
+    #[test]
+    fn unanswered_and_rejected_calls_stop_their_media() {
+        let mut s = setup();
+        let call = s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        assert_eq!(s.manager.expire_unanswered(0), vec![call.call_id.clone()]);
+        assert_eq!(ended_media(&mut s.media), vec![call.call_id]);
+
+        let call = s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        s.manager.reject_call(&call.call_id, &s.bob, EndReason::Rejected).unwrap();
+        assert_eq!(ended_media(&mut s.media), vec![call.call_id]);
+
+        let call = s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        s.manager.accept_call(&call.call_id, &s.bob, false).unwrap();
+        s.manager.end_call(&call.call_id, &s.alice).unwrap();
+        assert_eq!(ended_media(&mut s.media), vec![call.call_id]);
+    }
+
+    #[test]
+    fn unanswered_calls_are_missed_until_cleared() {
+        let mut s = setup();
+        let missed = s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        s.manager.expire_unanswered(0);
+        let rejected = s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        s.manager.reject_call(&rejected.call_id, &s.bob, EndReason::Rejected).unwrap();
+
+        let listed = s.manager.get_missed_calls(&s.bob);
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].call_id, missed.call_id);
+        assert_eq!(listed[0].caller_id, s.alice);
+        assert!(s.manager.get_missed_calls(&s.alice).is_empty());
+
+        s.manager.clear_missed_calls(&s.bob);
+        assert!(s.manager.get_missed_calls(&s.bob).is_empty());
+        let (total, _) = s.manager.call_history(&HistoryQuery { user_id: s.bob.clone(), limit: 10, ..Default::default() });
+        assert_eq!(total, 2, "cleared calls stay in the history");
+    }
+
+    #[test]
+    fn new_calls_need_both_parties_available() {
+        let mut s = setup();
+        let carol = log_in(&mut s.manager, "carol");
+        let dave = log_in(&mut s.manager, "dave");
+        assert!(matches!(
+            s.manager.create_call(s.alice.clone(), "nobody".into()),
+            Err(CallError::Unavailable(Unavailable::Unknown(_)))
+        ));
+        s.manager.disconnect_user(&dave);
+        assert!(matches!(
+            s.manager.create_call(s.alice.clone(), dave.clone()),
+            Err(CallError::Unavailable(Unavailable::Offline(_)))
+        ));
+
+        s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        assert!(busy(s.manager.create_call(carol.clone(), s.bob.clone())), "callee is ringing");
+        assert!(busy(s.manager.create_call(carol.clone(), s.alice.clone())), "caller is ringing out");
+        assert!(busy(s.manager.create_call(s.alice.clone(), carol.clone())), "a caller cannot place two calls");
+
+        s.manager.join_room(None, &carol);
+        let fresh = log_in(&mut s.manager, "erin");
+        assert!(busy(s.manager.create_call(fresh, carol)), "callee is in a room");
+    }
+
+    #[test]
+    fn waiting_call_holds_the_current_one_when_accepted() {
+        let mut s = setup();
+        let carol = log_in(&mut s.manager, "carol");
+        let dave = log_in(&mut s.manager, "dave");
+        let first = s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        s.manager.accept_call(&first.call_id, &s.bob, false).unwrap();
+        assert!(busy(s.manager.create_call(carol.clone(), s.bob.clone())), "call waiting is off");
+
+        s.manager.set_call_waiting(&s.bob, true);
+        let mut events = s.manager.subscribe(&s.bob);
+        let second = s.manager.create_call(carol.clone(), s.bob.clone()).unwrap();
+        assert!(second.waiting);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(SignalEvent::IncomingCall { call_id, waiting: true, .. }) if call_id == second.call_id
+        ));
+        assert!(busy(s.manager.create_call(dave, s.bob.clone())), "only one call can wait");
+        assert_eq!(s.manager.get_user(&s.bob).unwrap().status, CallStatus::InCall);
+
+        s.manager.accept_call(&second.call_id, &s.bob, false).unwrap();
+        assert_eq!(s.manager.get_call(&first.call_id).unwrap().state, CallState::HeldByCallee);
+        assert_eq!(s.manager.get_call(&second.call_id).unwrap().state, CallState::Active);
+        assert_eq!(s.manager.get_user(&s.alice).unwrap().status, CallStatus::OnHold);
+        assert_eq!(s.manager.get_user(&s.bob).unwrap().status, CallStatus::InCall);
+
+        s.manager.end_call(&second.call_id, &carol).unwrap();
+        assert_eq!(s.manager.get_user(&s.bob).unwrap().status, CallStatus::OnHold);
+        s.manager.resume_call(&first.call_id, &s.bob).unwrap();
+        assert_eq!(s.manager.get_call(&first.call_id).unwrap().state, CallState::Active);
+        assert_eq!(s.manager.get_user(&s.bob).unwrap().status, CallStatus::InCall);
+    }
+
+    #[test]
+    fn re_offers_hold_and_resume_by_their_direction() {
+        let mut s = setup();
+        let call = s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        let id = call.call_id.clone();
+        let offer = |manager: &mut CallManager<MemoryStorage>, user_id: &str, direction: &str| {
+            let sdp = crate::test_support::BROWSER_OFFER.replace("a=sendrecv", &format!("a={}", direction));
+            let hold = crate::sdp::parse(&sdp).unwrap().is_hold();
+            manager.apply_offer_direction(&id, user_id, hold).unwrap();
+            manager.get_call(&id).unwrap().state
+        };
+
+        assert_eq!(offer(&mut s.manager, &s.alice, "sendonly"), CallState::Ringing, "no hold before answer");
+        s.manager.accept_call(&id, &s.bob, false).unwrap();
+
+        for direction in ["sendonly", "inactive"] {
+            assert_eq!(offer(&mut s.manager, &s.alice, direction), CallState::HeldByCaller, "{}", direction);
+            assert_eq!(offer(&mut s.manager, &s.bob, "sendrecv"), CallState::HeldByCaller, "only the holder resumes");
+            assert_eq!(offer(&mut s.manager, &s.bob, direction), CallState::HeldByCaller, "already held");
+            assert_eq!(offer(&mut s.manager, &s.alice, "sendrecv"), CallState::Active);
+        }
+        // Receive-only is the answer to a hold, not one (RFC 3264 section 8.4).
+        assert_eq!(offer(&mut s.manager, &s.alice, "recvonly"), CallState::Active);
+
+        assert_eq!(offer(&mut s.manager, &s.bob, "sendonly"), CallState::HeldByCallee);
+        assert_eq!(offer(&mut s.manager, &s.alice, "sendrecv"), CallState::HeldByCallee);
+        assert_eq!(offer(&mut s.manager, &s.bob, "sendrecv"), CallState::Active);
+        assert!(matches!(
+            s.manager.apply_offer_direction(&id, "mallory", true),
+            Err(CallError::NotPermitted { .. })
+        ));
+    }
+
+    #[test]
+    fn offers_and_answers_go_to_the_other_party() {
+        let mut s = setup();
+        let mut alice_events = s.manager.subscribe(&s.alice);
+        let mut bob_events = s.manager.subscribe(&s.bob);
+        let sdp = |events: &mut UnboundedReceiver<SignalEvent>| -> Vec<String> {
+            std::iter::from_fn(|| events.try_recv().ok())
+                .filter_map(|event| match event {
+                    SignalEvent::Offer { offer, .. } => Some(format!("offer {}", offer)),
+                    SignalEvent::Answer { answer, .. } => Some(format!("answer {}", answer)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let call = s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        let id = call.call_id.clone();
+
+        assert!(matches!(s.manager.set_answer(&id, &s.bob, "a0".into()), Err(CallError::NoPendingOffer(_))));
+        s.manager.set_offer(&id, &s.alice, "o1".into()).unwrap();
+        assert!(matches!(s.manager.set_answer(&id, &s.alice, "a1".into()), Err(CallError::NoPendingOffer(_))));
+        s.manager.set_answer(&id, &s.bob, "a1".into()).unwrap();
+        assert_eq!(s.manager.get_call(&id).unwrap().state, CallState::EarlyMedia);
+        assert_eq!(sdp(&mut bob_events), ["offer o1"]);
+        assert_eq!(sdp(&mut alice_events), ["answer a1"]);
+        s.manager.accept_call(&id, &s.bob, false).unwrap();
+
+        // The callee holds and resumes by re-offering; the caller answers.
+        s.manager.set_offer(&id, &s.bob, "o2".into()).unwrap();
+        s.manager.apply_offer_direction(&id, &s.bob, true).unwrap();
+        assert_eq!(s.manager.get_call(&id).unwrap().state, CallState::HeldByCallee);
+        s.manager.set_answer(&id, &s.alice, "a2".into()).unwrap();
+        assert!(matches!(s.manager.set_answer(&id, &s.alice, "a2".into()), Err(CallError::NoPendingOffer(_))));
+        s.manager.set_offer(&id, &s.bob, "o3".into()).unwrap();
+        s.manager.apply_offer_direction(&id, &s.bob, false).unwrap();
+        assert_eq!(s.manager.get_call(&id).unwrap().state, CallState::Active);
+        s.manager.set_answer(&id, &s.alice, "a3".into()).unwrap();
+
+        assert_eq!(sdp(&mut alice_events), ["offer o2", "offer o3"]);
+        assert_eq!(sdp(&mut bob_events), ["answer a2", "answer a3"]);
+        assert!(matches!(s.manager.set_offer(&id, "mallory", "o4".into()), Err(CallError::NotPermitted { .. })));
+    }
+
+    #[test]
+    fn hold_resume_and_end_follow_the_state_machine() {
+        let mut s = setup();
+        let call = s.manager.create_call(s.alice.clone(), s.bob.clone()).unwrap();
+        let id = call.call_id.clone();
+        assert!(matches!(
+            s.manager.hold_call(&id, &s.alice),
+            Err(CallError::InvalidTransition { from: CallState::Ringing, to: CallState::HeldByCaller })
+        ));
+        s.manager.accept_call(&id, &s.bob, false).unwrap();
+
+        s.manager.hold_call(&id, &s.bob).unwrap();
+        assert_eq!(s.manager.get_call(&id).unwrap().state, CallState::HeldByCallee);
+        assert!(s.manager.hold_call(&id, &s.alice).is_err());
+        assert!(matches!(
+            s.manager.resume_call(&id, &s.alice),
+            Err(CallError::NotPermitted { action: "resume", .. })
+        ));
+        s.manager.resume_call(&id, &s.bob).unwrap();
+        assert!(matches!(
+            s.manager.resume_call(&id, &s.bob),
+            Err(CallError::InvalidTransition { from: CallState::Active, to: CallState::Active })
+        ));
+
+        s.manager.hold_call(&id, &s.alice).unwrap();
+        assert_eq!(s.manager.get_call(&id).unwrap().state, CallState::HeldByCaller);
+        let ended = s.manager.end_call(&id, &s.bob).unwrap();
+        let states: Vec<CallState> = ended.transitions.iter().map(|t| t.state).collect();
+        assert_eq!(
+            states,
+            [
+                CallState::Ringing,
+                CallState::Active,
+                CallState::HeldByCallee,
+                CallState::Active,
+                CallState::HeldByCaller,
+                CallState::Ended
+            ]
+        );
+    }
+}
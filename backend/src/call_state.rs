@@ -0,0 +1,188 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::user::{CallStatus, Unavailable};
+
+/// Lifecycle of a call. A hold records which party placed it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CallState {
+    Ringing,
+    /// The callee answered the SDP offer before accepting the call.
+    EarlyMedia,
+    Active,
+    #[serde(alias = "held_by_local")]
+    HeldByCaller,
+    #[serde(alias = "held_by_remote")]
+    HeldByCallee,
+    Ended,
+}
+
+impl CallState {
+    pub fn can_transition(self, to: CallState) -> bool {
+        use CallState::*;
+        matches!(
+            (self, to),
+            (Ringing, EarlyMedia)
+                | (Ringing | EarlyMedia, Active)
+                | (Active, HeldByCaller | HeldByCallee)
+                | (HeldByCaller | HeldByCallee, Active)
+                | (Ringing | EarlyMedia | Active | HeldByCaller | HeldByCallee, Ended)
+        )
+    }
+
+    pub fn is_ringing(self) -> bool {
+        matches!(self, CallState::Ringing | CallState::EarlyMedia)
+    }
+
+    pub fn is_held(self) -> bool {
+        matches!(self, CallState::HeldByCaller | CallState::HeldByCallee)
+    }
+
+    /// The coarser status reported for the call and its parties.
+    pub fn call_status(self) -> CallStatus {
+        match self {
+            CallState::Ringing | CallState::EarlyMedia => CallStatus::Calling,
+            CallState::Active => CallStatus::InCall,
+            CallState::HeldByCaller | CallState::HeldByCallee => CallStatus::OnHold,
+            CallState::Ended => CallStatus::Idle,
+        }
+    }
+}
+
+/// Why a call ended.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    Hangup,
+    Rejected,
+    Busy,
+    NoAnswer,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallTransition {
+    pub state: CallState,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallError {
+    CallNotFound(String),
+    /// The other party cannot take the call.
+    Unavailable(Unavailable),
+    /// The user is not the party allowed to perform the action.
+    NotPermitted { user_id: String, action: &'static str },
+    InvalidTransition { from: CallState, to: CallState },
+    /// An answer from a user with no offer from the other party to answer.
+    NoPendingOffer(String),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::CallNotFound(call_id) => write!(f, "Call {} not found", call_id),
+            CallError::Unavailable(unavailable) => unavailable.fmt(f),
+            CallError::NotPermitted { user_id, action } => {
+                write!(f, "User {} may not {} this call", user_id, action)
+            }
+            CallError::InvalidTransition { from, to } => {
+                write!(f, "Cannot move call from {:?} to {:?}", from, to)
+            }
+            CallError::NoPendingOffer(user_id) => write!(f, "No offer for user {} to answer", user_id),
+        }
+    }
+}
+
+impl CallError {
+    /// Stable identifier for clients, e.g. to tell a busy callee from an
+    /// offline one.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CallError::CallNotFound(_) => "call_not_found",
+            CallError::Unavailable(unavailable) => unavailable.code(),
+            CallError::NotPermitted { .. } => "not_permitted",
+            CallError::InvalidTransition { .. } => "invalid_transition",
+            CallError::NoPendingOffer(_) => "no_pending_offer",
+        }
+    }
+}
+
+impl From<Unavailable> for CallError {
+    fn from(unavailable: Unavailable) -> Self {
+        CallError::Unavailable(unavailable)
+    }
+}
+
+impl std::error::Error for CallError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CallState::*;
+
+    const STATES: [CallState; 6] = [Ringing, EarlyMedia, Active, HeldByCaller, HeldByCallee, Ended];
+
+    #[test]
+    fn allows_only_the_transitions_in_the_table() {
+        let allowed = [
+            (Ringing, EarlyMedia),
+            (Ringing, Active),
+            (EarlyMedia, Active),
+            (Active, HeldByCaller),
+            (Active, HeldByCallee),
+            (HeldByCaller, Active),
+            (HeldByCallee, Active),
+            (Ringing, Ended),
+            (EarlyMedia, Ended),
+            (Active, Ended),
+            (HeldByCaller, Ended),
+            (HeldByCallee, Ended),
+        ];
+        for from in STATES {
+            for to in STATES {
+                assert_eq!(
+                    from.can_transition(to),
+                    allowed.contains(&(from, to)),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn held_calls_cannot_be_held_again_or_swap_holder() {
+        assert!(!HeldByCaller.can_transition(HeldByCallee));
+        assert!(!HeldByCallee.can_transition(HeldByCaller));
+        assert!(!Ringing.can_transition(HeldByCaller));
+        assert!(!Ended.can_transition(Active));
+    }
+
+    #[test]
+    fn reports_coarser_status() {
+        let statuses: Vec<CallStatus> = STATES.iter().map(|s| s.call_status()).collect();
+        assert_eq!(
+            statuses,
+            [
+                CallStatus::Calling,
+                CallStatus::Calling,
+                CallStatus::InCall,
+                CallStatus::OnHold,
+                CallStatus::OnHold,
+                CallStatus::Idle
+            ]
+        );
+        assert!(HeldByCallee.is_held() && !Active.is_held());
+        assert!(EarlyMedia.is_ringing() && !Active.is_ringing());
+    }
+
+    #[test]
+    fn reads_states_stored_under_their_old_names() {
+        assert_eq!(serde_json::to_string(&HeldByCaller).unwrap(), "\"held_by_caller\"");
+        assert_eq!(serde_json::from_str::<CallState>("\"held_by_local\"").unwrap(), HeldByCaller);
+        assert_eq!(serde_json::from_str::<CallState>("\"held_by_remote\"").unwrap(), HeldByCallee);
+    }
+}
@@ -0,0 +1,471 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::call_manager::Call;
+use crate::call_state::{CallState, EndReason};
+
+/// Most missed calls listed per user. Older ones drop off the list but stay
+/// in the call history.
+pub const MAX_MISSED_CALLS: usize = 100;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MediaStats {
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub lost: u64,
+    pub late: u64,
+    pub concealed: u64,
+    pub jitter_ms: f32,
+}
+
+/// Call detail record, written when a call ends. Times are Unix seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallRecord {
+    pub call_id: String,
+    pub caller_id: String,
+    pub callee_id: String,
+    pub start: i64,
+    pub answer: Option<i64>,
+    pub end: i64,
+    pub end_reason: EndReason,
+    pub held_secs: i64,
+    pub media: Option<MediaStats>,
+    /// For an unanswered call, whether the callee has cleared it from their
+    /// missed calls.
+    #[serde(default)]
+    pub missed_cleared: bool,
+}
+
+/// A call that rang out without being answered, kept for the callee.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MissedCall {
+    pub call_id: String,
+    pub caller_id: String,
+    pub timestamp: i64,
+}
+
+impl CallRecord {
+    pub fn from_call(call: &Call, reason: EndReason) -> Self {
+        let end = call
+            .transitions
+            .last()
+            .map_or(call.timestamp, |t| t.timestamp);
+        let answer = call
+            .transitions
+            .iter()
+            .find(|t| t.state == CallState::Active)
+            .map(|t| t.timestamp);
+
+        let mut held_secs = 0;
+        for pair in call.transitions.windows(2) {
+            if pair[0].state.is_held() {
+                held_secs += pair[1].timestamp - pair[0].timestamp;
+            }
+        }
+
+        CallRecord {
+            call_id: call.call_id.clone(),
+            caller_id: call.caller_id.clone(),
+            callee_id: call.callee_id.clone(),
+            start: call.timestamp,
+            answer,
+            end,
+            end_reason: reason,
+            held_secs,
+            media: None,
+            missed_cleared: false,
+        }
+    }
+}
+
+/// Filters for `CdrStore::history`.
+#[derive(Debug, Default)]
+pub struct HistoryQuery {
+    pub user_id: String,
+    /// Only calls with this other party.
+    pub peer_id: Option<String>,
+    /// `Some(true)` for calls the user placed, `Some(false)` for received.
+    pub outgoing: Option<bool>,
+    pub end_reason: Option<EndReason>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Append-only JSON-lines log of call records. A record is appended again
+/// when it is updated (e.g. media statistics arriving after the call
+/// ended); on load, later lines replace earlier ones for the same call.
+///
+/// Records are kept in memory and queued for a `CdrWriter` to append, so
+/// recording one never blocks on the disk.
+pub struct CdrStore {
+    lines: UnboundedSender<Vec<u8>>,
+    writer: Option<CdrWriter>,
+    records: Vec<CallRecord>,
+    index: HashMap<String, usize>,
+    /// Each user's uncleared missed calls, oldest first, as indices into
+    /// `records`.
+    missed: HashMap<String, VecDeque<usize>>,
+}
+
+/// Appends queued record lines to the CDR file in order, syncing each
+/// batch on the blocking thread pool.
+pub struct CdrWriter {
+    file: File,
+    lines: UnboundedReceiver<Vec<u8>>,
+}
+
+impl CdrWriter {
+    pub async fn run(self) {
+        let CdrWriter { mut file, mut lines } = self;
+        while let Some(mut batch) = lines.recv().await {
+            while let Ok(line) = lines.try_recv() {
+                batch.extend(line);
+            }
+            let result;
+            (file, result) = match tokio::task::spawn_blocking(move || {
+                let result = file.write_all(&batch).and_then(|_| file.sync_data());
+                (file, result)
+            })
+            .await
+            {
+                Ok(written) => written,
+                Err(e) => {
+                    log::error!("Call record writer stopped: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = result {
+                log::error!("Failed to write call records: {}", e);
+            }
+        }
+    }
+}
+
+impl CdrStore {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut records: Vec<CallRecord> = Vec::new();
+        let mut index = HashMap::new();
+
+        if path.exists() {
+            for (n, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<CallRecord>(&line) {
+                    Ok(record) => match index.get(&record.call_id) {
+                        Some(&i) => records[i] = record,
+                        None => {
+                            index.insert(record.call_id.clone(), records.len());
+                            records.push(record);
+                        }
+                    },
+                    Err(e) => log::warn!("Skipping malformed CDR at {}:{}: {}", path.display(), n + 1, e),
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        log::info!("📒 Loaded {} call records from {}", records.len(), path.display());
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut store = CdrStore {
+            lines: tx,
+            writer: Some(CdrWriter { file, lines: rx }),
+            records,
+            index,
+            missed: HashMap::new(),
+        };
+        for i in 0..store.records.len() {
+            store.update_missed(i);
+        }
+        Ok(store)
+    }
+
+    /// The writer that appends records to the file, to be run as a task.
+    /// Until it is taken, records are only queued.
+    pub fn take_writer(&mut self) -> Option<CdrWriter> {
+        self.writer.take()
+    }
+
+    pub fn record(&mut self, record: CallRecord) -> io::Result<()> {
+        self.append(&record)?;
+        let i = match self.index.get(&record.call_id) {
+            Some(&i) => {
+                self.records[i] = record;
+                i
+            }
+            None => {
+                self.index.insert(record.call_id.clone(), self.records.len());
+                self.records.push(record);
+                self.records.len() - 1
+            }
+        };
+        self.update_missed(i);
+        Ok(())
+    }
+
+    /// The user's uncleared missed calls, oldest first.
+    pub fn missed_calls(&self, user_id: &str) -> Vec<MissedCall> {
+        let Some(missed) = self.missed.get(user_id) else {
+            return Vec::new();
+        };
+        missed
+            .iter()
+            .map(|&i| {
+                let r = &self.records[i];
+                MissedCall {
+                    call_id: r.call_id.clone(),
+                    caller_id: r.caller_id.clone(),
+                    timestamp: r.start,
+                }
+            })
+            .collect()
+    }
+
+    pub fn clear_missed(&mut self, user_id: &str) -> io::Result<()> {
+        let Some(missed) = self.missed.remove(user_id) else {
+            return Ok(());
+        };
+        for i in missed {
+            let mut record = self.records[i].clone();
+            record.missed_cleared = true;
+            self.record(record)?;
+        }
+        Ok(())
+    }
+
+    /// Attaches media statistics to an already recorded call.
+    pub fn set_media(&mut self, call_id: &str, media: MediaStats) -> io::Result<bool> {
+        let Some(&i) = self.index.get(call_id) else {
+            return Ok(false);
+        };
+        let mut record = self.records[i].clone();
+        record.media = Some(media);
+        self.record(record)?;
+        Ok(true)
+    }
+
+    /// The user's calls matching `query`, newest first, along with the
+    /// total number of matches before pagination.
+    pub fn history(&self, query: &HistoryQuery) -> (usize, Vec<CallRecord>) {
+        let matches: Vec<&CallRecord> = self
+            .records
+            .iter()
+            .rev()
+            .filter(|r| r.caller_id == query.user_id || r.callee_id == query.user_id)
+            .filter(|r| {
+                let outgoing = r.caller_id == query.user_id;
+                let peer = if outgoing { &r.callee_id } else { &r.caller_id };
+                query.peer_id.as_ref().is_none_or(|p| p == peer)
+                    && query.outgoing.is_none_or(|o| o == outgoing)
+                    && query.end_reason.is_none_or(|reason| reason == r.end_reason)
+                    && query.since.is_none_or(|since| r.start >= since)
+                    && query.until.is_none_or(|until| r.start < until)
+            })
+            .collect();
+
+        let page = matches
+            .iter()
+            .skip(query.offset)
+            .take(query.limit)
+            .map(|r| (*r).clone())
+            .collect();
+        (matches.len(), page)
+    }
+
+    /// Lists or unlists `records[i]` as a missed call of its callee.
+    fn update_missed(&mut self, i: usize) {
+        let record = &self.records[i];
+        if record.end_reason != EndReason::NoAnswer {
+            return;
+        }
+        let missed = self.missed.entry(record.callee_id.clone()).or_default();
+        if record.missed_cleared {
+            missed.retain(|&m| m != i);
+        } else if !missed.contains(&i) {
+            missed.push_back(i);
+            if missed.len() > MAX_MISSED_CALLS {
+                missed.pop_front();
+            }
+        }
+    }
+
+    fn append(&mut self, record: &CallRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record).map_err(io::Error::other)?;
+        line.push(b'\n');
+        self.lines
+            .send(line)
+            .map_err(|_| io::Error::other("call record writer stopped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempStore {
+        store: CdrStore,
+        path: PathBuf,
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn temp_store() -> TempStore {
+        let path = std::env::temp_dir().join(format!("voip-cdr-{}.jsonl", uuid::Uuid::new_v4()));
+        TempStore { store: CdrStore::open(path.clone()).unwrap(), path }
+    }
+
+    fn record(call_id: &str, caller: &str, callee: &str, start: i64, reason: EndReason) -> CallRecord {
+        CallRecord {
+            call_id: call_id.to_string(),
+            caller_id: caller.to_string(),
+            callee_id: callee.to_string(),
+            start,
+            answer: (reason == EndReason::Hangup).then_some(start + 5),
+            end: start + 60,
+            end_reason: reason,
+            held_secs: 0,
+            media: None,
+            missed_cleared: false,
+        }
+    }
+
+    /// Alice's calls: placed to Bob at 100 and 300, received from Carol at
+    /// 200 (missed) and from Bob at 400 (rejected). Carol also called Bob.
+    fn populated() -> TempStore {
+        let mut temp = temp_store();
+        for r in [
+            record("c1", "alice", "bob", 100, EndReason::Hangup),
+            record("c2", "carol", "alice", 200, EndReason::NoAnswer),
+            record("c3", "alice", "bob", 300, EndReason::Busy),
+            record("c4", "bob", "alice", 400, EndReason::Rejected),
+            record("c5", "carol", "bob", 500, EndReason::Hangup),
+        ] {
+            temp.store.record(r).unwrap();
+        }
+        temp
+    }
+
+    fn ids(store: &CdrStore, query: HistoryQuery) -> (usize, Vec<String>) {
+        let (total, page) = store.history(&HistoryQuery { limit: 50, ..query });
+        (total, page.into_iter().map(|r| r.call_id).collect())
+    }
+
+    fn alice() -> HistoryQuery {
+        HistoryQuery { user_id: "alice".to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn history_filters_the_users_calls() {
+        let temp = populated();
+        let store = &temp.store;
+
+        assert_eq!(ids(store, alice()), (4, vec!["c4".into(), "c3".into(), "c2".into(), "c1".into()]));
+        let peer = HistoryQuery { peer_id: Some("bob".into()), ..alice() };
+        assert_eq!(ids(store, peer), (3, vec!["c4".into(), "c3".into(), "c1".into()]));
+        let outgoing = HistoryQuery { outgoing: Some(true), ..alice() };
+        assert_eq!(ids(store, outgoing), (2, vec!["c3".into(), "c1".into()]));
+        let incoming = HistoryQuery { outgoing: Some(false), ..alice() };
+        assert_eq!(ids(store, incoming), (2, vec!["c4".into(), "c2".into()]));
+        let missed = HistoryQuery { end_reason: Some(EndReason::NoAnswer), ..alice() };
+        assert_eq!(ids(store, missed), (1, vec!["c2".into()]));
+        let window = HistoryQuery { since: Some(200), until: Some(400), ..alice() };
+        assert_eq!(ids(store, window), (2, vec!["c3".into(), "c2".into()]));
+        let combined = HistoryQuery { peer_id: Some("bob".into()), outgoing: Some(false), ..alice() };
+        assert_eq!(ids(store, combined), (1, vec!["c4".into()]));
+        let nobody = HistoryQuery { user_id: "dave".into(), ..Default::default() };
+        assert_eq!(ids(store, nobody), (0, vec![]));
+    }
+
+    #[test]
+    fn history_pages_after_filtering() {
+        let temp = populated();
+        let page = |offset, limit| {
+            let (total, page) = temp.store.history(&HistoryQuery { offset, limit, ..alice() });
+            (total, page.into_iter().map(|r| r.call_id).collect::<Vec<_>>())
+        };
+
+        assert_eq!(page(0, 2), (4, vec!["c4".to_string(), "c3".to_string()]));
+        assert_eq!(page(2, 2), (4, vec!["c2".to_string(), "c1".to_string()]));
+        assert_eq!(page(3, 2), (4, vec!["c1".to_string()]));
+        assert_eq!(page(4, 2), (4, vec![]));
+        assert_eq!(page(0, 0), (4, vec![]));
+    }
+
+    #[test]
+    fn updates_replace_the_record_in_place() {
+        let mut temp = populated();
+        let media = MediaStats { packets_received: 10, ..Default::default() };
+        assert!(temp.store.set_media("c1", media).unwrap());
+        assert!(!temp.store.set_media("missing", MediaStats::default()).unwrap());
+
+        let (total, page) = temp.store.history(&HistoryQuery { limit: 50, ..alice() });
+        assert_eq!(total, 4);
+        assert_eq!(page[3].call_id, "c1");
+        assert_eq!(page[3].media.as_ref().unwrap().packets_received, 10);
+    }
+
+    #[test]
+    fn missed_calls_are_capped_per_user() {
+        let mut temp = populated();
+        assert_eq!(temp.store.missed_calls("alice").len(), 1);
+        for n in 0..MAX_MISSED_CALLS + 5 {
+            let r = record(&format!("m{}", n), "carol", "bob", 1000 + n as i64, EndReason::NoAnswer);
+            temp.store.record(r).unwrap();
+        }
+
+        let missed = temp.store.missed_calls("bob");
+        assert_eq!(missed.len(), MAX_MISSED_CALLS);
+        assert_eq!(missed[0].call_id, "m5", "the oldest drop off");
+        assert_eq!(missed.last().unwrap().timestamp, 1000 + MAX_MISSED_CALLS as i64 + 4);
+        assert_eq!(temp.store.missed_calls("alice").len(), 1);
+
+        temp.store.clear_missed("bob").unwrap();
+        assert!(temp.store.missed_calls("bob").is_empty());
+        let (total, _) = temp.store.history(&HistoryQuery { user_id: "bob".into(), limit: 1, ..Default::default() });
+        assert_eq!(total, MAX_MISSED_CALLS + 5 + 4);
+    }
+
+    #[tokio::test]
+    async fn writer_persists_records_for_the_next_start() {
+        let path = std::env::temp_dir().join(format!("voip-cdr-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut store = CdrStore::open(path.clone()).unwrap();
+        let writer = tokio::spawn(store.take_writer().unwrap().run());
+        assert!(store.take_writer().is_none());
+        store.record(record("c1", "alice", "bob", 100, EndReason::Hangup)).unwrap();
+        store.record(record("c2", "carol", "alice", 200, EndReason::NoAnswer)).unwrap();
+        let media = MediaStats { lost: 3, ..Default::default() };
+        store.set_media("c1", media).unwrap();
+        store.record(record("c3", "alice", "bob", 300, EndReason::NoAnswer)).unwrap();
+        store.clear_missed("bob").unwrap();
+
+        // Dropping the store closes the queue, so the writer finishes once
+        // everything is on disk.
+        drop(store);
+        writer.await.unwrap();
+
+        let reloaded = TempStore { store: CdrStore::open(path.clone()).unwrap(), path };
+        let (total, page) = reloaded.store.history(&HistoryQuery { limit: 50, ..alice() });
+        assert_eq!(total, 3);
+        assert_eq!(page[1].call_id, "c2");
+        assert_eq!(page[2].call_id, "c1");
+        assert_eq!(page[2].media.as_ref().unwrap().lost, 3, "later lines replace earlier ones");
+        let missed = reloaded.store.missed_calls("alice");
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].call_id, "c2");
+        assert!(reloaded.store.missed_calls("bob").is_empty(), "clearing is persisted");
+    }
+}
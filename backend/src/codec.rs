@@ -0,0 +1,440 @@
+use std::{env, error::Error, fmt};
+
+use crate::g711;
+use crate::packet::{MAX_PACKET_LEN, PAYLOAD_TYPE_L16, RTP_HEADER_LEN};
+use crate::resample::{Downsampler, Upsampler};
+
+/// Dynamic payload type used for Opus (opus/48000/2 in SDP terms, sent mono).
+pub const PAYLOAD_TYPE_OPUS: u8 = 111;
+
+/// Static payload types for G.711 (RFC 3551).
+pub const PAYLOAD_TYPE_PCMU: u8 = 0;
+pub const PAYLOAD_TYPE_PCMA: u8 = 8;
+
+pub const SAMPLE_RATE: u32 = 48000;
+const G711_RATE: u32 = 8000;
+const G711_FACTOR: usize = (SAMPLE_RATE / G711_RATE) as usize;
+
+/// Largest frame Opus can produce: 120 ms at 48 kHz.
+const MAX_OPUS_FRAME: usize = 5760;
+/// Opus's own recommended maximum, which fits a `MAX_PACKET_LEN` datagram.
+const MAX_OPUS_PACKET: usize = 4000;
+/// Longest L16 frame whose packet fits a `MAX_PACKET_LEN` datagram.
+const MAX_PCM_FRAME: usize = (MAX_PACKET_LEN - RTP_HEADER_LEN) / 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Pcm,
+    Opus,
+    Pcmu,
+    Pcma,
+}
+
+impl Codec {
+    pub fn payload_type(self) -> u8 {
+        match self {
+            Codec::Pcm => PAYLOAD_TYPE_L16,
+            Codec::Opus => PAYLOAD_TYPE_OPUS,
+            Codec::Pcmu => PAYLOAD_TYPE_PCMU,
+            Codec::Pcma => PAYLOAD_TYPE_PCMA,
+        }
+    }
+
+    /// RTP timestamp clock rate for this payload type.
+    pub fn clock_rate(self) -> u32 {
+        match self {
+            Codec::Pcm | Codec::Opus => SAMPLE_RATE,
+            Codec::Pcmu | Codec::Pcma => G711_RATE,
+        }
+    }
+
+    pub fn from_payload_type(payload_type: u8) -> Option<Self> {
+        match payload_type {
+            PAYLOAD_TYPE_L16 => Some(Codec::Pcm),
+            PAYLOAD_TYPE_OPUS => Some(Codec::Opus),
+            PAYLOAD_TYPE_PCMU => Some(Codec::Pcmu),
+            PAYLOAD_TYPE_PCMA => Some(Codec::Pcma),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "pcm" | "l16" => Some(Codec::Pcm),
+            "opus" => Some(Codec::Opus),
+            "pcmu" | "ulaw" => Some(Codec::Pcmu),
+            "pcma" | "alaw" => Some(Codec::Pcma),
+            _ => None,
+        }
+    }
+}
+
+/// Encoder settings for the outgoing stream. Decoders are picked per packet
+/// from the RTP payload type, so only the sending side needs configuring.
+#[derive(Debug, Clone)]
+pub struct CodecConfig {
+    pub codec: Codec,
+    /// Target bitrate in bits per second (Opus only).
+    pub bitrate: i32,
+    /// Encoder complexity, 0 (fastest) to 10 (best quality) (Opus only).
+    pub complexity: i32,
+    /// Samples per packet at 48 kHz; 960 is 20 ms.
+    pub frame_size: usize,
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        CodecConfig {
+            codec: Codec::Opus,
+            bitrate: 32000,
+            complexity: 5,
+            frame_size: 960,
+        }
+    }
+}
+
+impl CodecConfig {
+    /// Reads `VOIP_CODEC`, `VOIP_OPUS_BITRATE`, `VOIP_OPUS_COMPLEXITY` and
+    /// `VOIP_FRAME_MS`, falling back to the defaults for anything unset.
+    pub fn from_env() -> Result<Self, CodecError> {
+        let mut config = CodecConfig::default();
+
+        if let Ok(name) = env::var("VOIP_CODEC") {
+            config.codec = Codec::from_name(&name).ok_or(CodecError::UnknownCodec(name))?;
+        }
+        if let Some(bitrate) = parse_env("VOIP_OPUS_BITRATE")? {
+            config.bitrate = bitrate;
+        }
+        if let Some(complexity) = parse_env("VOIP_OPUS_COMPLEXITY")? {
+            config.complexity = complexity;
+        }
+        if let Some(frame_ms) = parse_env::<usize>("VOIP_FRAME_MS")? {
+            config.frame_size = frame_ms * SAMPLE_RATE as usize / 1000;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), CodecError> {
+        match self.codec {
+            Codec::Pcm => {
+                if self.frame_size == 0 || self.frame_size > MAX_PCM_FRAME {
+                    return Err(CodecError::InvalidFrameSize(self.frame_size));
+                }
+            }
+            Codec::Opus => {
+                if ![120, 240, 480, 960, 1920, 2880].contains(&self.frame_size) {
+                    return Err(CodecError::InvalidFrameSize(self.frame_size));
+                }
+                if !(500..=512000).contains(&self.bitrate) {
+                    return Err(CodecError::InvalidSetting("bitrate", self.bitrate));
+                }
+                if !(0..=10).contains(&self.complexity) {
+                    return Err(CodecError::InvalidSetting("complexity", self.complexity));
+                }
+            }
+            Codec::Pcmu | Codec::Pcma => {
+                if self.frame_size == 0
+                    || self.frame_size > MAX_OPUS_FRAME
+                    || !self.frame_size.is_multiple_of(G711_FACTOR)
+                {
+                    return Err(CodecError::InvalidFrameSize(self.frame_size));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(key: &'static str) -> Result<Option<T>, CodecError> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| CodecError::InvalidEnv(key, value)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    UnknownCodec(String),
+    UnsupportedPayloadType(u8),
+    InvalidFrameSize(usize),
+    InvalidSetting(&'static str, i32),
+    InvalidEnv(&'static str, String),
+    MalformedPayload(usize),
+    Opus(opus::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::UnknownCodec(name) => write!(f, "unknown codec '{}'", name),
+            CodecError::UnsupportedPayloadType(pt) => write!(f, "unsupported payload type {}", pt),
+            CodecError::InvalidFrameSize(size) => write!(f, "invalid frame size {} samples", size),
+            CodecError::InvalidSetting(name, value) => write!(f, "invalid {} {}", name, value),
+            CodecError::InvalidEnv(key, value) => write!(f, "invalid value '{}' for {}", value, key),
+            CodecError::MalformedPayload(len) => write!(f, "malformed {}-byte payload", len),
+            CodecError::Opus(e) => write!(f, "opus: {}", e),
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+impl From<opus::Error> for CodecError {
+    fn from(e: opus::Error) -> Self {
+        CodecError::Opus(e)
+    }
+}
+
+pub trait Encoder: Send {
+    fn codec(&self) -> Codec;
+    fn encode(&mut self, pcm: &[i16]) -> Result<Vec<u8>, CodecError>;
+}
+
+pub trait Decoder: Send {
+    fn decode(&mut self, payload: &[u8]) -> Result<Vec<i16>, CodecError>;
+
+    /// Native loss concealment for one missing frame of `frame_samples`
+    /// samples. `None` means the codec has none and the caller should use
+    /// `plc::Concealer` instead.
+    fn conceal(&mut self, _frame_samples: usize) -> Option<Vec<i16>> {
+        None
+    }
+}
+
+pub fn new_encoder(config: &CodecConfig) -> Result<Box<dyn Encoder>, CodecError> {
+    config.validate()?;
+    match config.codec {
+        Codec::Pcm => Ok(Box::new(PcmCodec)),
+        Codec::Opus => {
+            let mut inner = opus::Encoder::new(SAMPLE_RATE, opus::Channels::Mono, opus::Application::Voip)?;
+            inner.set_bitrate(opus::Bitrate::Bits(config.bitrate))?;
+            inner.set_complexity(config.complexity)?;
+            Ok(Box::new(OpusEncoder { inner }))
+        }
+        Codec::Pcmu | Codec::Pcma => Ok(Box::new(G711Encoder {
+            codec: config.codec,
+            resampler: Downsampler::new(G711_FACTOR),
+        })),
+    }
+}
+
+pub fn new_decoder(payload_type: u8) -> Result<Box<dyn Decoder>, CodecError> {
+    match Codec::from_payload_type(payload_type) {
+        Some(Codec::Pcm) => Ok(Box::new(PcmCodec)),
+        Some(Codec::Opus) => Ok(Box::new(OpusDecoder {
+            inner: opus::Decoder::new(SAMPLE_RATE, opus::Channels::Mono)?,
+        })),
+        Some(codec @ (Codec::Pcmu | Codec::Pcma)) => Ok(Box::new(G711Decoder {
+            codec,
+            resampler: Upsampler::new(G711_FACTOR),
+        })),
+        None => Err(CodecError::UnsupportedPayloadType(payload_type)),
+    }
+}
+
+/// L16: samples in network byte order.
+struct PcmCodec;
+
+impl Encoder for PcmCodec {
+    fn codec(&self) -> Codec {
+        Codec::Pcm
+    }
+
+    fn encode(&mut self, pcm: &[i16]) -> Result<Vec<u8>, CodecError> {
+        Ok(pcm.iter().flat_map(|s| s.to_be_bytes()).collect())
+    }
+}
+
+impl Decoder for PcmCodec {
+    fn decode(&mut self, payload: &[u8]) -> Result<Vec<i16>, CodecError> {
+        if !payload.len().is_multiple_of(2) {
+            return Err(CodecError::MalformedPayload(payload.len()));
+        }
+        Ok(payload
+            .chunks_exact(2)
+            .map(|chunk| i16::from_be_bytes([chunk[0], chunk[1]]))
+            .collect())
+    }
+}
+
+struct OpusEncoder {
+    inner: opus::Encoder,
+}
+
+impl Encoder for OpusEncoder {
+    fn codec(&self) -> Codec {
+        Codec::Opus
+    }
+
+    fn encode(&mut self, pcm: &[i16]) -> Result<Vec<u8>, CodecError> {
+        let mut out = vec![0u8; MAX_OPUS_PACKET];
+        let len = self.inner.encode(pcm, &mut out)?;
+        out.truncate(len);
+        Ok(out)
+    }
+}
+
+struct OpusDecoder {
+    inner: opus::Decoder,
+}
+
+impl Decoder for OpusDecoder {
+    fn decode(&mut self, payload: &[u8]) -> Result<Vec<i16>, CodecError> {
+        if payload.is_empty() {
+            return Err(CodecError::MalformedPayload(0));
+        }
+        let mut out = vec![0i16; MAX_OPUS_FRAME];
+        let len = self.inner.decode(payload, &mut out, false)?;
+        out.truncate(len);
+        Ok(out)
+    }
+
+    fn conceal(&mut self, frame_samples: usize) -> Option<Vec<i16>> {
+        let mut out = vec![0i16; frame_samples.min(MAX_OPUS_FRAME)];
+        match self.inner.decode(&[], &mut out, false) {
+            Ok(len) => {
+                out.truncate(len);
+                Some(out)
+            }
+            Err(e) => {
+                log::warn!("Opus concealment failed: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// G.711 at 8 kHz, resampled from and to the 48 kHz frames used internally.
+struct G711Encoder {
+    codec: Codec,
+    resampler: Downsampler,
+}
+
+impl Encoder for G711Encoder {
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn encode(&mut self, pcm: &[i16]) -> Result<Vec<u8>, CodecError> {
+        let narrowband = self.resampler.process(pcm);
+        let compand = if self.codec == Codec::Pcmu { g711::linear_to_ulaw } else { g711::linear_to_alaw };
+        Ok(narrowband.into_iter().map(compand).collect())
+    }
+}
+
+struct G711Decoder {
+    codec: Codec,
+    resampler: Upsampler,
+}
+
+impl Decoder for G711Decoder {
+    fn decode(&mut self, payload: &[u8]) -> Result<Vec<i16>, CodecError> {
+        let expand = if self.codec == Codec::Pcmu { g711::ulaw_to_linear } else { g711::alaw_to_linear };
+        let narrowband: Vec<i16> = payload.iter().map(|&b| expand(b)).collect();
+        Ok(self.resampler.process(&narrowband))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODECS: [Codec; 4] = [Codec::Pcm, Codec::Opus, Codec::Pcmu, Codec::Pcma];
+
+    fn config(codec: Codec, frame_size: usize) -> CodecConfig {
+        CodecConfig { codec, frame_size, ..CodecConfig::default() }
+    }
+
+    fn tone(len: usize, level: f32) -> Vec<i16> {
+        (0..len)
+            .map(|i| (level * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / SAMPLE_RATE as f32).sin()) as i16)
+            .collect()
+    }
+
+    fn rms(samples: &[i16]) -> f32 {
+        (samples.iter().map(|&s| (s as f32).powi(2)).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn maps_payload_types_and_names() {
+        for codec in CODECS {
+            assert_eq!(Codec::from_payload_type(codec.payload_type()), Some(codec));
+        }
+        assert_eq!(Codec::from_name("ULAW"), Some(Codec::Pcmu));
+        assert_eq!(Codec::from_name("l16"), Some(Codec::Pcm));
+        assert_eq!(Codec::from_name("g729"), None);
+        assert_eq!(Codec::Pcma.clock_rate(), 8000);
+        assert!(matches!(new_decoder(18), Err(CodecError::UnsupportedPayloadType(18))));
+    }
+
+    #[test]
+    fn validates_frame_sizes() {
+        assert!(CodecConfig::default().validate().is_ok());
+        assert!(config(Codec::Opus, 2880).validate().is_ok());
+        assert!(config(Codec::Opus, 1000).validate().is_err());
+        assert!(config(Codec::Opus, 5760).validate().is_err());
+        assert!(config(Codec::Pcmu, 5760).validate().is_ok());
+        assert!(config(Codec::Pcma, 965).validate().is_err());
+        assert!(config(Codec::Pcm, 0).validate().is_err());
+        assert!(config(Codec::Pcm, 1920).validate().is_ok());
+        // 60 ms of L16 is 5760 bytes, more than the media sockets read.
+        assert!(matches!(config(Codec::Pcm, 2880).validate(), Err(CodecError::InvalidFrameSize(2880))));
+        let loud = CodecConfig { bitrate: 600000, ..CodecConfig::default() };
+        assert!(matches!(loud.validate(), Err(CodecError::InvalidSetting("bitrate", 600000))));
+    }
+
+    #[test]
+    fn largest_frames_fit_a_datagram() {
+        for (codec, frame_size) in [(Codec::Pcm, MAX_PCM_FRAME), (Codec::Opus, 2880), (Codec::Pcmu, MAX_OPUS_FRAME)] {
+            let config = CodecConfig { bitrate: 512000, complexity: 10, ..config(codec, frame_size) };
+            let mut encoder = new_encoder(&config).unwrap();
+            // Noise is the hardest thing to compress.
+            let mut state = 1u32;
+            let noise: Vec<i16> = (0..frame_size)
+                .map(|_| {
+                    state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                    (state >> 16) as i16
+                })
+                .collect();
+            let payload = encoder.encode(&noise).unwrap();
+            assert!(RTP_HEADER_LEN + payload.len() <= MAX_PACKET_LEN, "{:?}: {} bytes", codec, payload.len());
+        }
+    }
+
+    #[test]
+    fn round_trips_each_codec() {
+        let input = tone(960 * 10, 8000.0);
+        for codec in CODECS {
+            let mut encoder = new_encoder(&config(codec, 960)).unwrap();
+            let mut decoder = new_decoder(codec.payload_type()).unwrap();
+            let mut output = Vec::new();
+            for frame in input.chunks(960) {
+                let decoded = decoder.decode(&encoder.encode(frame).unwrap()).unwrap();
+                assert_eq!(decoded.len(), 960, "{:?}", codec);
+                output.extend(decoded);
+            }
+            if codec == Codec::Pcm {
+                assert_eq!(output, input);
+            }
+            // Past the filters' and codec's start-up, the level comes through.
+            let ratio = rms(&output[4800..]) / rms(&input[4800..]);
+            assert!((0.85..1.15).contains(&ratio), "{:?}: level ratio {:.2}", codec, ratio);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        let mut pcm = new_decoder(PAYLOAD_TYPE_L16).unwrap();
+        assert!(matches!(pcm.decode(&[1, 2, 3]), Err(CodecError::MalformedPayload(3))));
+        assert!(pcm.conceal(960).is_none());
+
+        let mut opus = new_decoder(PAYLOAD_TYPE_OPUS).unwrap();
+        assert!(matches!(opus.decode(&[]), Err(CodecError::MalformedPayload(0))));
+        assert_eq!(opus.conceal(960).map(|frame| frame.len()), Some(960));
+    }
+}
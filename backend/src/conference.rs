@@ -0,0 +1,306 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, time::Instant};
+
+use tokio::net::UdpSocket;
+
+use crate::{
+    codec::{self, Codec, CodecConfig, CodecError, Encoder},
+    jitter::{JitterBuffer, JitterConfig},
+    packet::{AudioPacket, RtpStream},
+    sequence::{self, SeqStatus, SequenceTracker},
+};
+
+struct Participant {
+    remote: SocketAddr,
+    /// Whether `remote` has been confirmed by a ping or by media from it.
+    latched: bool,
+    source: Option<(u32, SequenceTracker)>,
+    jitter: JitterBuffer,
+    encoder: Box<dyn Encoder>,
+    rtp: RtpStream,
+    frame: Vec<i16>,
+    packets_sent: u64,
+}
+
+/// Server-side mixer for one conference room. Every participant's stream
+/// is jitter-buffered and decoded independently; on each tick the mixer
+/// takes one frame from each and sends every participant the sum of all
+/// the others (N-1 mixing), encoded on that participant's own stream.
+pub struct Conference {
+    codec_config: CodecConfig,
+    jitter_config: JitterConfig,
+    participants: HashMap<String, Participant>,
+}
+
+impl Conference {
+    pub fn new(codec_config: CodecConfig, jitter_config: JitterConfig) -> Self {
+        Conference {
+            codec_config,
+            jitter_config,
+            participants: HashMap::new(),
+        }
+    }
+
+    pub fn join(&mut self, user_id: &str, remote: SocketAddr) -> Result<(), CodecError> {
+        if let Some(participant) = self.participants.get_mut(user_id) {
+            participant.remote = remote;
+            participant.latched = false;
+            return Ok(());
+        }
+
+        let participant = Participant {
+            remote,
+            latched: false,
+            source: None,
+            jitter: JitterBuffer::new(self.jitter_config.clone()),
+            encoder: codec::new_encoder(&self.codec_config)?,
+            rtp: RtpStream::new(),
+            frame: Vec::with_capacity(self.codec_config.frame_size),
+            packets_sent: 0,
+        };
+        self.participants.insert(user_id.to_string(), participant);
+        Ok(())
+    }
+
+    pub fn leave(&mut self, room_id: &str, user_id: &str) {
+        if let Some(participant) = self.participants.remove(user_id) {
+            let stats = participant.jitter.stats();
+            log::info!("Room {} participant {} left | Sent: {} | Received: {} | Lost: {} | Late: {} | Concealed: {}",
+                room_id,
+                user_id,
+                participant.packets_sent,
+                stats.received,
+                stats.lost,
+                stats.late,
+                stats.concealed
+            );
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.participants.is_empty()
+    }
+
+    /// Re-latches the participant on `addr`'s host, if exactly one matches,
+    /// onto that address. Used for pings, which identify only the room and
+    /// are unauthenticated, so they only settle an unconfirmed address.
+    pub fn latch(&mut self, room_id: &str, addr: SocketAddr) {
+        let confirmed = self.find(addr).and_then(|user_id| self.participants.get(user_id)).is_some_and(|p| p.latched);
+        if !confirmed {
+            self.route(room_id, addr);
+        }
+    }
+
+    /// Whether a participant is latched to exactly `addr`.
+    pub fn has_address(&self, addr: SocketAddr) -> bool {
+        self.participants.values().any(|p| p.remote == addr)
+    }
+
+    /// How many participants are on `ip`, on any port.
+    pub fn participants_on(&self, ip: IpAddr) -> usize {
+        self.participants.values().filter(|p| p.remote.ip() == ip).count()
+    }
+
+    pub fn receive(&mut self, room_id: &str, packet: AudioPacket, addr: SocketAddr) {
+        let Some((user_id, participant)) = self.route(room_id, addr) else {
+            return;
+        };
+
+        if Codec::from_payload_type(packet.payload_type).is_none() {
+            log::warn!("❌ Dropped packet with unsupported payload type {} from {}",
+                packet.payload_type, addr);
+            return;
+        }
+
+        let (status, new_source) = sequence::track_source(&mut participant.source, packet.ssrc, packet.seq);
+        if new_source {
+            log::info!("New RTP source {:08x} for {} in room {} starting at seq {}",
+                packet.ssrc, user_id, room_id, packet.seq);
+            participant.jitter.reset();
+        }
+        let ext_seq = match status {
+            SeqStatus::Valid(ext_seq) => ext_seq,
+            SeqStatus::Restarted(ext_seq) => {
+                log::warn!("⚠️  RTP source {:08x} restarted at seq {}", packet.ssrc, packet.seq);
+                participant.jitter.reset();
+                ext_seq
+            }
+            SeqStatus::Probation | SeqStatus::Invalid => return,
+        };
+
+        participant.jitter.push_packet(packet, ext_seq, Instant::now());
+    }
+
+    /// Plays out one frame from every participant and sends each of them
+    /// the mix of everyone else.
+    pub async fn mix(&mut self, socket: &UdpSocket) {
+        let frame_size = self.codec_config.frame_size;
+        let mut total = vec![0i32; frame_size];
+
+        for participant in self.participants.values_mut() {
+            let jitter = &mut participant.jitter;
+            participant.frame.clear();
+            participant.frame.extend((0..frame_size).map(|_| jitter.pop_sample()));
+            for (sum, &sample) in total.iter_mut().zip(&participant.frame) {
+                *sum += sample as i32;
+            }
+        }
+
+        for participant in self.participants.values_mut() {
+            let mix: Vec<i16> = total
+                .iter()
+                .zip(&participant.frame)
+                .map(|(&sum, &own)| (sum - own as i32).clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+                .collect();
+
+            let payload = match participant.encoder.encode(&mix) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    log::warn!("Failed to encode mix: {}", e);
+                    continue;
+                }
+            };
+            let format = participant.encoder.codec();
+            let ticks = frame_size * format.clock_rate() as usize / codec::SAMPLE_RATE as usize;
+            let packet = participant.rtp.next_packet(format.payload_type(), payload, ticks);
+            let _ = socket.send_to(&packet.serialize(), participant.remote).await;
+            participant.packets_sent += 1;
+        }
+    }
+
+    /// Finds the participant latched to `addr`. As with calls, a packet from
+    /// a participant's host on a new port matches it if that host matches
+    /// exactly one participant.
+    fn find(&self, addr: SocketAddr) -> Option<&String> {
+        if let Some((user_id, _)) = self.participants.iter().find(|(_, p)| p.remote == addr) {
+            return Some(user_id);
+        }
+        let mut by_host = self.participants.iter().filter(|(_, p)| p.remote.ip() == addr.ip());
+        let (user_id, _) = by_host.next()?;
+        if by_host.next().is_some() {
+            return None;
+        }
+        Some(user_id)
+    }
+
+    /// Finds the participant for `addr` and re-latches it onto that address.
+    fn route(&mut self, room_id: &str, addr: SocketAddr) -> Option<(String, &mut Participant)> {
+        let user_id = self.find(addr)?.clone();
+        let participant = self.participants.get_mut(&user_id)?;
+        if participant.remote != addr {
+            log::info!("Room {} participant {} latched from {} to {}", room_id, user_id, participant.remote, addr);
+            participant.remote = addr;
+        }
+        participant.latched = true;
+        Some((user_id, participant))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PAYLOAD_TYPE_L16;
+    use std::time::Duration;
+
+    const FRAME: usize = 960;
+
+    /// A room that mixes L16 and plays each frame as soon as it arrives.
+    fn conference() -> Conference {
+        let codec = CodecConfig { codec: Codec::Pcm, frame_size: FRAME, ..CodecConfig::default() };
+        let jitter = JitterConfig { min_delay_ms: 0, max_delay_ms: 400, initial_delay_ms: 0 };
+        Conference::new(codec, jitter)
+    }
+
+    /// A constant L16 frame, so mixes can be checked sample by sample.
+    fn packet(ssrc: u32, seq: u16, level: i16) -> AudioPacket {
+        AudioPacket {
+            marker: false,
+            payload_type: PAYLOAD_TYPE_L16,
+            seq,
+            timestamp: seq as u32 * FRAME as u32,
+            ssrc,
+            payload: std::iter::repeat_n(level, FRAME).flat_map(i16::to_be_bytes).collect(),
+        }
+    }
+
+    struct Member {
+        socket: UdpSocket,
+        ssrc: u32,
+    }
+
+    impl Member {
+        async fn join(conference: &mut Conference, user_id: &str, ssrc: u32) -> Member {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            conference.join(user_id, socket.local_addr().unwrap()).unwrap();
+            Member { socket, ssrc }
+        }
+
+        fn speak(&self, conference: &mut Conference, seq: u16, level: i16) {
+            let addr = self.socket.local_addr().unwrap();
+            conference.receive("room", packet(self.ssrc, seq, level), addr);
+        }
+
+        /// The level of the next mix sent to this member, if one arrives.
+        async fn hear(&self) -> Option<i16> {
+            let mut buf = [0u8; 4096];
+            let size = tokio::time::timeout(Duration::from_millis(200), self.socket.recv(&mut buf)).await.ok()?.unwrap();
+            let packet = AudioPacket::deserialize(&buf[..size]).unwrap();
+            let samples: Vec<i16> = packet.payload.chunks_exact(2).map(|b| i16::from_be_bytes([b[0], b[1]])).collect();
+            assert_eq!(samples.len(), FRAME);
+            assert!(samples.iter().all(|&s| s == samples[0]), "mix is not constant");
+            Some(samples[0])
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_everyone_the_others_mix_and_clamps_it() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conference = conference();
+        let alice = Member::join(&mut conference, "alice", 1).await;
+        let bob = Member::join(&mut conference, "bob", 2).await;
+        let carol = Member::join(&mut conference, "carol", 3).await;
+
+        // The first packet of each source is held on probation.
+        for seq in [1, 2] {
+            alice.speak(&mut conference, seq, 1000);
+            bob.speak(&mut conference, seq, 2000);
+            carol.speak(&mut conference, seq, 32000);
+        }
+        conference.mix(&socket).await;
+
+        assert_eq!(alice.hear().await, Some(i16::MAX), "2000 + 32000 clamps");
+        assert_eq!(bob.hear().await, Some(i16::MAX), "1000 + 32000 clamps");
+        assert_eq!(carol.hear().await, Some(3000));
+    }
+
+    #[tokio::test]
+    async fn stops_mixing_participants_who_leave() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conference = conference();
+        let alice = Member::join(&mut conference, "alice", 1).await;
+        let bob = Member::join(&mut conference, "bob", 2).await;
+        let carol = Member::join(&mut conference, "carol", 3).await;
+        for seq in [1, 2] {
+            alice.speak(&mut conference, seq, 100);
+            bob.speak(&mut conference, seq, 200);
+            carol.speak(&mut conference, seq, 400);
+        }
+        conference.mix(&socket).await;
+        assert_eq!(alice.hear().await, Some(600));
+        assert_eq!(bob.hear().await, Some(500));
+        assert_eq!(carol.hear().await, Some(300));
+
+        conference.leave("room", "carol");
+        alice.speak(&mut conference, 3, 100);
+        bob.speak(&mut conference, 3, 200);
+        carol.speak(&mut conference, 3, 400);
+        conference.mix(&socket).await;
+
+        assert_eq!(alice.hear().await, Some(200));
+        assert_eq!(bob.hear().await, Some(100));
+        assert_eq!(carol.hear().await, None);
+
+        conference.leave("room", "alice");
+        conference.leave("room", "bob");
+        assert!(conference.is_empty());
+    }
+}
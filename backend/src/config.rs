@@ -0,0 +1,23 @@
+use std::env;
+
+/// Reads a boolean environment variable: `1`, `true` or `yes` switch it on
+/// and `0`, `false` or `no` off, in any case. `None` if it is not set.
+pub fn env_bool(key: &str) -> Result<Option<bool>, String> {
+    let Ok(value) = env::var(key) else {
+        return Ok(None);
+    };
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" => Ok(Some(true)),
+        "0" | "false" | "no" => Ok(Some(false)),
+        _ => Err(format!("invalid value '{}' for {}", value, key)),
+    }
+}
+
+/// The configuration `from_env` returned, or the defaults if it was
+/// invalid, logging why. `name` describes it in the log, e.g. "codec".
+pub fn load_or_default<T: Default, E: std::fmt::Display>(name: &str, result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        log::error!("Invalid {} configuration ({}), using defaults", name, e);
+        T::default()
+    })
+}
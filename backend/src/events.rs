@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::call_state::EndReason;
+use crate::relay::RelayPorts;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SignalEvent {
+    IncomingCall { call_id: String, caller_id: String, waiting: bool },
+    Accepted { call_id: String, relay: Option<RelayPorts> },
+    Rejected { call_id: String, reason: EndReason },
+    Held { call_id: String },
+    Resumed { call_id: String },
+    Ended { call_id: String, reason: EndReason },
+    MissedCall { call_id: String, caller_id: String },
+    Offer { call_id: String, offer: String },
+    Answer { call_id: String, answer: String },
+    Candidate { call_id: String, candidate: String },
+    RoomJoined { room_id: String, user_id: String },
+    RoomLeft { room_id: String, user_id: String },
+}
+
+/// Per-user push channels for sockets connected to `/api/ws`.
+pub struct EventHub {
+    sessions: HashMap<String, UnboundedSender<SignalEvent>>,
+}
+
+impl EventHub {
+    pub fn new() -> Self {
+        EventHub {
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Registers a socket for `user_id`. A newer socket replaces the old one,
+    /// whose receiver then yields `None` and shuts it down.
+    pub fn subscribe(&mut self, user_id: &str) -> UnboundedReceiver<SignalEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.sessions.insert(user_id.to_string(), tx);
+        rx
+    }
+
+    pub fn publish(&mut self, user_id: &str, event: SignalEvent) {
+        if let Some(tx) = self.sessions.get(user_id) {
+            if tx.send(event).is_err() {
+                self.sessions.remove(user_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(call_id: &str) -> SignalEvent {
+        SignalEvent::Held { call_id: call_id.to_string() }
+    }
+
+    #[test]
+    fn events_serialize_with_a_type_tag() {
+        let event = SignalEvent::IncomingCall { call_id: "c1".into(), caller_id: "alice".into(), waiting: true };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({"type": "incoming_call", "call_id": "c1", "caller_id": "alice", "waiting": true})
+        );
+        let event = SignalEvent::Ended { call_id: "c1".into(), reason: EndReason::NoAnswer };
+        assert_eq!(serde_json::to_value(&event).unwrap()["type"], "ended");
+    }
+
+    #[test]
+    fn publishes_only_to_the_users_latest_socket() {
+        let mut hub = EventHub::new();
+        hub.publish("alice", held("before"));
+        let mut first = hub.subscribe("alice");
+        let mut bob = hub.subscribe("bob");
+        hub.publish("alice", held("c1"));
+        assert!(matches!(first.try_recv(), Ok(SignalEvent::Held { call_id }) if call_id == "c1"));
+        assert!(bob.try_recv().is_err(), "events go to their user only");
+
+        let mut second = hub.subscribe("alice");
+        assert!(matches!(first.try_recv(), Err(mpsc::error::TryRecvError::Disconnected)), "replaced sockets shut down");
+        hub.publish("alice", held("c2"));
+        assert!(matches!(second.try_recv(), Ok(SignalEvent::Held { call_id }) if call_id == "c2"));
+    }
+
+    #[test]
+    fn forgets_closed_sockets() {
+        let mut hub = EventHub::new();
+        drop(hub.subscribe("alice"));
+        hub.publish("alice", held("c1"));
+        assert!(!hub.sessions.contains_key("alice"));
+    }
+}
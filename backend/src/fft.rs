@@ -0,0 +1,89 @@
+use std::f32::consts::PI;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+
+    pub fn conj(self) -> Complex {
+        Complex { re: self.re, im: -self.im }
+    }
+
+    pub fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+}
+
+/// In-place radix-2 FFT of a power-of-two length. The inverse transform
+/// is scaled by 1/n.
+pub fn fft(buf: &mut [Complex], inverse: bool) {
+    let n = buf.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            buf.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let w = Complex { re: (angle * k as f32).cos(), im: (angle * k as f32).sin() };
+                let a = buf[start + k];
+                let b = buf[start + k + len / 2].mul(w);
+                buf[start + k] = Complex { re: a.re + b.re, im: a.im + b.im };
+                buf[start + k + len / 2] = Complex { re: a.re - b.re, im: a.im - b.im };
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        let scale = 1.0 / n as f32;
+        for c in buf.iter_mut() {
+            c.re *= scale;
+            c.im *= scale;
+        }
+    }
+}
+
+/// The FFT of `samples`, zero-padded to `len`.
+pub fn spectrum(samples: impl Iterator<Item = f32>, len: usize) -> Vec<Complex> {
+    let mut buf: Vec<Complex> = samples.map(|re| Complex { re, im: 0.0 }).collect();
+    buf.resize(len, Complex::default());
+    fft(&mut buf, false);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fft_round_trips() {
+        let signal: Vec<f32> = (0..64).map(|i| (i as f32 * 0.3).sin() * 100.0).collect();
+        let mut buf = spectrum(signal.iter().copied(), 64);
+        fft(&mut buf, true);
+        for (x, y) in signal.iter().zip(&buf) {
+            assert!((x - y.re).abs() < 1e-3 && y.im.abs() < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! G.711 companding (ITU-T G.711), after the Sun Microsystems reference
+//! implementation that the ITU G.191 tools are checked against.
+
+const SEG_ULAW_END: [i16; 8] = [0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF, 0x1FFF];
+const SEG_ALAW_END: [i16; 8] = [0x1F, 0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF];
+
+const ULAW_BIAS: i16 = 0x84;
+const ULAW_CLIP: i16 = 8159;
+
+fn segment(value: i16, table: &[i16; 8]) -> usize {
+    table.iter().position(|&end| value <= end).unwrap_or(8)
+}
+
+pub fn linear_to_ulaw(sample: i16) -> u8 {
+    let mut value = sample >> 2;
+    let mask = if value < 0 {
+        value = -value;
+        0x7F
+    } else {
+        0xFF
+    };
+    value = value.min(ULAW_CLIP) + (ULAW_BIAS >> 2);
+
+    let seg = segment(value, &SEG_ULAW_END);
+    if seg >= 8 {
+        return 0x7F ^ mask;
+    }
+    let code = ((seg as u8) << 4) | ((value >> (seg + 1)) & 0x0F) as u8;
+    code ^ mask
+}
+
+pub fn ulaw_to_linear(code: u8) -> i16 {
+    let code = !code;
+    let mut t = (((code & 0x0F) as i16) << 3) + ULAW_BIAS;
+    t <<= (code & 0x70) >> 4;
+    if code & 0x80 != 0 {
+        ULAW_BIAS - t
+    } else {
+        t - ULAW_BIAS
+    }
+}
+
+pub fn linear_to_alaw(sample: i16) -> u8 {
+    let mut value = sample >> 3;
+    let mask = if value >= 0 {
+        0xD5
+    } else {
+        value = -value - 1;
+        0x55
+    };
+
+    let seg = segment(value, &SEG_ALAW_END);
+    if seg >= 8 {
+        return 0x7F ^ mask;
+    }
+    let mut code = (seg as u8) << 4;
+    code |= if seg < 2 {
+        ((value >> 1) & 0x0F) as u8
+    } else {
+        ((value >> seg) & 0x0F) as u8
+    };
+    code ^ mask
+}
+
+pub fn alaw_to_linear(code: u8) -> i16 {
+    let code = code ^ 0x55;
+    let mut t = ((code & 0x0F) as i16) << 4;
+    let seg = (code & 0x70) >> 4;
+    match seg {
+        0 => t += 8,
+        1 => t += 0x108,
+        _ => {
+            t += 0x108;
+            t <<= seg - 1;
+        }
+    }
+    if code & 0x80 != 0 {
+        t
+    } else {
+        -t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{self, Codec, CodecConfig};
+
+    #[test]
+    fn ulaw_matches_reference_table() {
+        // Segment boundaries from G.711 Table 2a, scaled to 16-bit.
+        let table: [(u8, i16); 13] = [
+            (0xFF, 0),
+            (0x7F, 0),
+            (0xFE, 8),
+            (0xF0, 120),
+            (0xEF, 132),
+            (0xDF, 396),
+            (0xCF, 924),
+            (0xBF, 1980),
+            (0xAF, 4092),
+            (0x9F, 8316),
+            (0x8F, 16764),
+            (0x80, 32124),
+            (0x00, -32124),
+        ];
+        for (code, linear) in table {
+            assert_eq!(ulaw_to_linear(code), linear, "code {:#04x}", code);
+        }
+
+        assert_eq!(linear_to_ulaw(0), 0xFF);
+        assert_eq!(linear_to_ulaw(i16::MAX), 0x80);
+        assert_eq!(linear_to_ulaw(i16::MIN), 0x00);
+    }
+
+    #[test]
+    fn alaw_matches_reference_table() {
+        // Segment boundaries from G.711 Table 1a, scaled to 16-bit.
+        let table: [(u8, i16); 10] = [
+            (0xD5, 8),
+            (0x55, -8),
+            (0xDA, 248),
+            (0xC5, 264),
+            (0xF5, 528),
+            (0xE5, 1056),
+            (0x95, 2112),
+            (0x85, 4224),
+            (0xAA, 32256),
+            (0x2A, -32256),
+        ];
+        for (code, linear) in table {
+            assert_eq!(alaw_to_linear(code), linear, "code {:#04x}", code);
+        }
+
+        assert_eq!(linear_to_alaw(0), 0xD5);
+        assert_eq!(linear_to_alaw(i16::MAX), 0xAA);
+        assert_eq!(linear_to_alaw(i16::MIN), 0x2A);
+    }
+
+    #[test]
+    fn every_code_round_trips() {
+        for code in 0..=255u8 {
+            // 0x7F is mu-law negative zero, which re-encodes as 0xFF.
+            if code != 0x7F {
+                assert_eq!(linear_to_ulaw(ulaw_to_linear(code)), code, "ulaw {:#04x}", code);
+            }
+            assert_eq!(linear_to_alaw(alaw_to_linear(code)), code, "alaw {:#04x}", code);
+        }
+    }
+
+    #[test]
+    fn quantization_error_stays_within_segment_step() {
+        for sample in (i16::MIN..=i16::MAX).step_by(7) {
+            let step = (sample as i32).abs() / 16 + 16;
+
+            let ulaw = ulaw_to_linear(linear_to_ulaw(sample)) as i32;
+            assert!((ulaw - sample as i32).abs() <= step.max(132), "ulaw {}", sample);
+
+            let alaw = alaw_to_linear(linear_to_alaw(sample)) as i32;
+            assert!((alaw - sample as i32).abs() <= step, "alaw {}", sample);
+        }
+    }
+
+    #[test]
+    fn codec_round_trip_preserves_voice_band_tone() {
+        for codec in [Codec::Pcmu, Codec::Pcma] {
+            let config = CodecConfig {
+                codec,
+                ..CodecConfig::default()
+            };
+            let mut encoder = codec::new_encoder(&config).unwrap();
+            let mut decoder = codec::new_decoder(codec.payload_type()).unwrap();
+
+            let tone: Vec<i16> = (0..960 * 10)
+                .map(|n| (8000.0 * (2.0 * std::f32::consts::PI * 1000.0 * n as f32 / 48000.0).sin()) as i16)
+                .collect();
+
+            let mut decoded = Vec::new();
+            for frame in tone.chunks(960) {
+                let payload = encoder.encode(frame).unwrap();
+                assert_eq!(payload.len(), 160);
+                decoded.extend(decoder.decode(&payload).unwrap());
+            }
+            assert_eq!(decoded.len(), tone.len());
+
+            // Skip the filter warm-up, then compare RMS levels.
+            let rms = |s: &[i16]| (s.iter().map(|&x| (x as f64).powi(2)).sum::<f64>() / s.len() as f64).sqrt();
+            let ratio = rms(&decoded[1920..]) / rms(&tone[1920..]);
+            assert!((0.9..1.1).contains(&ratio), "{:?} level ratio {}", codec, ratio);
+        }
+    }
+}
@@ -1,12 +1,158 @@
-use std::sync::atomic::{AtomicU16, Ordering::Relaxed};
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
+use crate::aec::{AlignedCanceller, EchoConfig, FarEnd};
+use crate::codec;
 use crate::jitter::JitterBuffer;
-use crate::packet::AudioPacket;
+use crate::processing::{CaptureProcessor, ProcessingConfig, ProcessingStages};
+use crate::resample::{self, Resampler};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, Host, Stream, StreamError};
+use cpal::{Device, Host, Stream, StreamError, SupportedStreamConfig};
+use serde::{Deserialize, Serialize};
 use tokio::sync::broadcast::Sender;
 
+/// A device chosen by name, or by its index in `list_devices`. Names are
+/// stable across restarts and replugging; indices are not.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DeviceSelector {
+    Index(usize),
+    Name(String),
+}
+
+/// The devices a user makes calls on. `None` means the host default.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceSelection {
+    pub input: Option<DeviceSelector>,
+    pub output: Option<DeviceSelector>,
+}
+
+impl DeviceSelection {
+    /// This selection, with any device left at the default taken from
+    /// `fallback`.
+    pub fn or(self, fallback: DeviceSelection) -> DeviceSelection {
+        DeviceSelection {
+            input: self.input.or(fallback.input),
+            output: self.output.or(fallback.output),
+        }
+    }
+}
+
+/// A range of stream configurations a device supports.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigRange {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<ConfigRange>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioDevices {
+    pub inputs: Vec<DeviceInfo>,
+    pub outputs: Vec<DeviceInfo>,
+}
+
+impl AudioDevices {
+    /// Checks that the selected devices exist, naming the first that does not.
+    pub fn check(&self, selection: &DeviceSelection) -> Result<(), String> {
+        let found = |devices: &[DeviceInfo], selector: &DeviceSelector| {
+            devices.iter().any(|d| match selector {
+                DeviceSelector::Index(index) => d.index == *index,
+                DeviceSelector::Name(name) => d.name == *name,
+            })
+        };
+        if let Some(input) = selection.input.as_ref().filter(|s| !found(&self.inputs, s)) {
+            return Err(format!("No input device {}", input));
+        }
+        if let Some(output) = selection.output.as_ref().filter(|s| !found(&self.outputs, s)) {
+            return Err(format!("No output device {}", output));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSelector::Index(index) => write!(f, "#{}", index),
+            DeviceSelector::Name(name) => write!(f, "'{}'", name),
+        }
+    }
+}
+
+/// Lists the host's input and output devices with their supported configs.
+pub fn list_devices(host: &Host) -> AudioDevices {
+    let default_input = host.default_input_device().and_then(|d| d.name().ok());
+    let default_output = host.default_output_device().and_then(|d| d.name().ok());
+
+    let inputs = match host.input_devices() {
+        Ok(devices) => describe(devices, default_input, |d| {
+            d.supported_input_configs().map(|c| c.collect()).unwrap_or_default()
+        }),
+        Err(e) => {
+            log::warn!("Failed to list input devices: {}", e);
+            Vec::new()
+        }
+    };
+    let outputs = match host.output_devices() {
+        Ok(devices) => describe(devices, default_output, |d| {
+            d.supported_output_configs().map(|c| c.collect()).unwrap_or_default()
+        }),
+        Err(e) => {
+            log::warn!("Failed to list output devices: {}", e);
+            Vec::new()
+        }
+    };
+    AudioDevices { inputs, outputs }
+}
+
+fn describe(
+    devices: impl Iterator<Item = Device>,
+    default: Option<String>,
+    configs: impl Fn(&Device) -> Vec<cpal::SupportedStreamConfigRange>,
+) -> Vec<DeviceInfo> {
+    devices
+        .enumerate()
+        .map(|(index, device)| {
+            let name = device.name().unwrap_or_else(|_| format!("Device {}", index));
+            DeviceInfo {
+                index,
+                is_default: default.as_deref() == Some(name.as_str()),
+                configs: configs(&device)
+                    .into_iter()
+                    .map(|c| ConfigRange {
+                        channels: c.channels(),
+                        min_sample_rate: c.min_sample_rate().0,
+                        max_sample_rate: c.max_sample_rate().0,
+                        sample_format: c.sample_format().to_string(),
+                    })
+                    .collect(),
+                name,
+            }
+        })
+        .collect()
+}
+
+/// Finds the selected device among `devices`.
+fn find_device(mut devices: impl Iterator<Item = Device>, selector: &DeviceSelector) -> Option<Device> {
+    match selector {
+        DeviceSelector::Index(index) => devices.nth(*index),
+        DeviceSelector::Name(name) => devices.find(|d| d.name().is_ok_and(|n| n == *name)),
+    }
+}
+
 pub struct AudioState {
     host: Host,
     input: Option<Stream>,
@@ -22,42 +168,80 @@ impl AudioState {
         }
     }
 
+    /// Starts audio on the selected devices. A selected device that has
+    /// gone away is replaced by the default one.
     pub fn start(
         &mut self,
-        input_channel: Sender<Vec<u8>>,
+        input_channel: Sender<Vec<i16>>,
         output_jitter: Arc<Mutex<JitterBuffer>>,
+        selection: &DeviceSelection,
+        echo: &EchoConfig,
+        processing: &ProcessingConfig,
+        stages: Arc<Mutex<ProcessingStages>>,
     ) {
-        let output_device = match self.host.default_output_device() {
+        let output_device = match self.output_device(selection.output.as_ref()) {
             Some(dev) => dev,
             None => {
-                eprintln!("No output device available");
+                log::error!("No output device available");
                 return;
             }
         };
 
-        let input_device = self.host.default_input_device();
+        let input_device = self.input_device(selection.input.as_ref());
 
        
         if let Some(ref input_dev) = input_device {
             if let (Ok(input_name), Ok(output_name)) =
                 (input_dev.name(), output_device.name()) {
-                if input_name == output_name {
+                if input_name == output_name && !echo.enabled {
                     log::warn!("Input and output devices are the same. This may cause audio feedback.");
                 }
             }
         }
 
-        self.output = output_stream_fn(output_device, output_jitter.clone()).unwrap_or(None);
+        log::info!(
+            "🎧 Audio devices: input {}, output {}",
+            input_device.as_ref().and_then(|d| d.name().ok()).unwrap_or_else(|| "none".to_string()),
+            output_device.name().unwrap_or_default()
+        );
+
+        // What is played is the far-end reference for cancelling its echo
+        // from what is captured.
+        let reference = (echo.enabled && input_device.is_some())
+            .then(|| EchoReference::new(Mutex::new(FarEnd::new(codec::SAMPLE_RATE))));
+        self.output = output_stream_fn(output_device, output_jitter.clone(), reference.clone()).unwrap_or(None);
         if let Some(input_device) = input_device {
-            self.input = input_stream_fn(input_device, input_channel).unwrap_or(None);
+            let canceller = reference.map(|r| (AlignedCanceller::new(codec::SAMPLE_RATE, echo.tail_ms), r));
+            let processor = (CaptureProcessor::new(codec::SAMPLE_RATE, processing), stages);
+            self.input = input_stream_fn(input_device, input_channel, canceller, processor).unwrap_or(None);
         } else {
             self.input = None;
         }
 
         if self.output.is_none() {
             self.clear();
-            eprintln!("Failed to create output stream");
+            log::error!("Failed to create output stream");
+        }
+    }
+
+    fn input_device(&self, selector: Option<&DeviceSelector>) -> Option<Device> {
+        if let Some(selector) = selector {
+            match self.host.input_devices().ok().and_then(|d| find_device(d, selector)) {
+                Some(device) => return Some(device),
+                None => log::warn!("Input device {} not found, using the default", selector),
+            }
+        }
+        self.host.default_input_device()
+    }
+
+    fn output_device(&self, selector: Option<&DeviceSelector>) -> Option<Device> {
+        if let Some(selector) = selector {
+            match self.host.output_devices().ok().and_then(|d| find_device(d, selector)) {
+                Some(device) => return Some(device),
+                None => log::warn!("Output device {} not found, using the default", selector),
+            }
         }
+        self.host.default_output_device()
     }
 
     pub fn clear(&mut self) {
@@ -66,102 +250,174 @@ impl AudioState {
     }
 }
 
-pub fn input_stream_fn(
+/// Samples per frame handed to the encoder: 20 ms at 48 kHz.
+const FRAME_SIZE: usize = 960;
+/// Samples taken from the jitter buffer at a time during playout: 10 ms.
+const PLAYOUT_BLOCK: usize = 480;
+/// 48 kHz mono samples handed to playout, by position, for the echo canceller.
+type EchoReference = Arc<Mutex<FarEnd>>;
+
+/// Turns device capture (any rate, interleaved channels) into 48 kHz mono
+/// frames of `FRAME_SIZE` samples, with the echo of playout removed and
+/// the call's processing stages applied.
+struct Capture {
+    channels: usize,
+    resampler: Resampler,
+    echo: Option<(AlignedCanceller, EchoReference)>,
+    processor: CaptureProcessor,
+    stages: Arc<Mutex<ProcessingStages>>,
+    buffer: Vec<i16>,
+    channel: Sender<Vec<i16>>,
+    last_log: Instant,
+}
+
+impl Capture {
+    fn new(
+        config: &SupportedStreamConfig,
+        channel: Sender<Vec<i16>>,
+        echo: Option<(AlignedCanceller, EchoReference)>,
+        (processor, stages): (CaptureProcessor, Arc<Mutex<ProcessingStages>>),
+    ) -> Self {
+        Capture {
+            channels: config.channels() as usize,
+            resampler: Resampler::new(config.sample_rate().0, codec::SAMPLE_RATE),
+            echo,
+            processor,
+            stages,
+            buffer: Vec::new(),
+            channel,
+            last_log: Instant::now(),
+        }
+    }
+
+    fn push(&mut self, interleaved: &[i16]) {
+        let mono = resample::downmix(interleaved, self.channels);
+        let mut captured = self.resampler.process(&mono);
+        if let Some((canceller, reference)) = &mut self.echo {
+            captured = canceller.process(&captured, reference);
+        }
+        let stages = *self.stages.lock().unwrap();
+        self.buffer.extend(self.processor.process(&captured, stages));
+
+        while self.buffer.len() >= FRAME_SIZE {
+            let chunk: Vec<i16> = self.buffer.drain(..FRAME_SIZE).collect();
+            if self.last_log.elapsed().as_secs() >= 2 {
+                let max_sample = chunk.iter().map(|s| s.saturating_abs()).max().unwrap_or(0);
+                log::info!("🎤 Input: Max: {} | {}",
+                    max_sample,
+                    if max_sample < 100 { "🔇 SILENCE" } else { "🔊 AUDIO" }
+                );
+                self.last_log = Instant::now();
+            }
+            let _ = self.channel.send(chunk);
+        }
+    }
+}
+
+/// Feeds a device (any rate, interleaved channels) from the 48 kHz mono
+/// jitter buffer.
+struct Playout {
+    channels: usize,
+    resampler: Resampler,
+    pending: VecDeque<i16>,
+    buffer: Arc<Mutex<JitterBuffer>>,
+    reference: Option<EchoReference>,
+    last_log: Instant,
+}
+
+impl Playout {
+    fn new(
+        config: &SupportedStreamConfig,
+        buffer: Arc<Mutex<JitterBuffer>>,
+        reference: Option<EchoReference>,
+    ) -> Self {
+        Playout {
+            channels: config.channels() as usize,
+            resampler: Resampler::new(codec::SAMPLE_RATE, config.sample_rate().0),
+            pending: VecDeque::new(),
+            buffer,
+            reference,
+            last_log: Instant::now(),
+        }
+    }
+
+    /// The next `len` interleaved device samples.
+    fn pull(&mut self, len: usize) -> Vec<i16> {
+        let frames = len.div_ceil(self.channels);
+        {
+            let mut jb = self.buffer.lock().unwrap();
+            if self.last_log.elapsed().as_secs() >= 2 {
+                let max_sample = self.pending.iter().map(|s| s.saturating_abs()).max().unwrap_or(0);
+                log::info!("🔊 Output: Buffer {} samples | Max: {} | {}",
+                    jb.buffered_samples(),
+                    max_sample,
+                    if max_sample < 100 { "🔇 SILENCE" } else { "🎵 PLAYING" }
+                );
+                self.last_log = Instant::now();
+            }
+            while self.pending.len() < frames {
+                let block: Vec<i16> = (0..PLAYOUT_BLOCK).map(|_| jb.pop_sample()).collect();
+                if let Some(reference) = &self.reference {
+                    reference.lock().unwrap().push(&block);
+                }
+                self.pending.extend(self.resampler.process(&block));
+            }
+        }
+
+        let mono: Vec<i16> = self.pending.drain(..frames).collect();
+        let mut out = resample::upmix(&mono, self.channels);
+        out.truncate(len);
+        out
+    }
+}
+
+/// Captures from `input_device` in its default config, converted to
+/// 48 kHz mono and, given a canceller, with the echo of playout removed.
+/// `processing` runs the stages its shared settings switch on.
+fn input_stream_fn(
     input_device: Device,
-    channel: Sender<Vec<u8>>,
+    channel: Sender<Vec<i16>>,
+    echo: Option<(AlignedCanceller, EchoReference)>,
+    processing: (CaptureProcessor, Arc<Mutex<ProcessingStages>>),
 ) -> Result<Option<Stream>, ()> {
     let config = match input_device.default_input_config() {
         Ok(config) => config,
         Err(e) => {
-            eprintln!("Input config error: {}", e);
+            log::error!("Input config error: {}", e);
             return Err(());
         }
     };
 
     log::info!("Using input config: {:?}", config);
 
-    let seq = AtomicU16::new(0);
-    let sample_buffer = Arc::new(Mutex::new(Vec::new()));
-    let last_log = Arc::new(Mutex::new(std::time::Instant::now()));
-
+    let mut capture = Capture::new(&config, channel, echo, processing);
     let stream = match config.sample_format() {
-        cpal::SampleFormat::F32 => {
-            let last_log_clone = last_log.clone();
-            input_device.build_input_stream(
-                &config.into(),
-                move |data: &[f32], _| {
-                    let mut buffer = sample_buffer.lock().unwrap();
-                    for &s in data {
-                        buffer.push((s * i16::MAX as f32) as i16);
-                    }
-                    while buffer.len() >= 960 {
-                        let chunk: Vec<i16> = buffer.drain(0..960).collect();
-                        let max_sample = chunk.iter().map(|s| s.abs()).max().unwrap_or(0);
-                        let is_silence = max_sample < 100;
-
-                        let packet = AudioPacket {
-                            seq: seq.fetch_add(1, Relaxed),
-                            samples: chunk,
-                        };
-
-                       
-                        let mut last = last_log_clone.lock().unwrap();
-                        if last.elapsed().as_secs() >= 2 {
-                            log::info!("🎤 Input: Seq {} | Max: {} | {}",
-                                packet.seq,
-                                max_sample,
-                                if is_silence { "🔇 SILENCE" } else { "🔊 AUDIO" }
-                            );
-                            *last = std::time::Instant::now();
-                        }
-
-                        let _ = channel.send(packet.serialize());
-                    }
-                },
-                err_fn,
-                None,
-            )
-        },
-        cpal::SampleFormat::I16 => input_device.build_input_stream(
+        cpal::SampleFormat::F32 => input_device.build_input_stream(
             &config.into(),
-            move |data: &[i16], _| {
-                let mut buffer = sample_buffer.lock().unwrap();
-                for &s in data {
-                    buffer.push(s);
-                }
-                while buffer.len() >= 960 {
-                    let chunk: Vec<i16> = buffer.drain(0..960).collect();
-                    let packet = AudioPacket {
-                        seq: seq.fetch_add(1, Relaxed),
-                        samples: chunk,
-                    };
-                    let _ = channel.send(packet.serialize());
-                }
+            move |data: &[f32], _| {
+                let samples: Vec<i16> = data.iter().map(|&s| (s * i16::MAX as f32) as i16).collect();
+                capture.push(&samples);
             },
             err_fn,
             None,
         ),
+        cpal::SampleFormat::I16 => input_device.build_input_stream(
+            &config.into(),
+            move |data: &[i16], _| capture.push(data),
+            err_fn,
+            None,
+        ),
         cpal::SampleFormat::U16 => input_device.build_input_stream(
             &config.into(),
             move |data: &[u16], _| {
-                let mut buffer = sample_buffer.lock().unwrap();
-                for &s in data {
-                    buffer.push((s as i32 - 32768) as i16);
-                }
-                while buffer.len() >= 960 {
-                    let chunk: Vec<i16> = buffer.drain(0..960).collect();
-                    let packet = AudioPacket {
-                        seq: seq.fetch_add(1, Relaxed),
-                        samples: chunk,
-                    };
-                    let _ = channel.send(packet.serialize());
-                }
+                let samples: Vec<i16> = data.iter().map(|&s| (s as i32 - 32768) as i16).collect();
+                capture.push(&samples);
             },
             err_fn,
             None,
         ),
         _ => {
-            eprintln!("Unsupported sample format: {:?}", config.sample_format());
+            log::error!("Unsupported sample format: {:?}", config.sample_format());
             return Err(());
         }
     };
@@ -169,74 +425,54 @@ pub fn input_stream_fn(
     match stream {
         Ok(s) => {
             if let Err(e) = s.play() {
-                eprintln!("Failed to play input stream: {}", e);
+                log::error!("Failed to play input stream: {}", e);
                 return Err(());
             }
             log::info!("Sending audio...");
             Ok(Some(s))
         }
         Err(e) => {
-            eprintln!("Input stream error: {}", e);
+            log::error!("Input stream error: {}", e);
             Err(())
         }
     }
 }
 
-pub fn output_stream_fn(
+/// Plays the 48 kHz mono jitter buffer on `output_device` in its default
+/// config, on every channel, copying what it plays to `reference`.
+fn output_stream_fn(
     output_device: Device,
     buffer: Arc<Mutex<JitterBuffer>>,
+    reference: Option<EchoReference>,
 ) -> Result<Option<Stream>, ()> {
     let config = match output_device.default_output_config() {
         Ok(config) => config,
         Err(e) => {
-            eprintln!("Output config error: {}", e);
+            log::error!("Output config error: {}", e);
             return Err(());
         }
     };
 
     log::info!("Using output config: {:?}", config);
 
-    let last_log = Arc::new(Mutex::new(std::time::Instant::now()));
+    let mut playout = Playout::new(&config, buffer, reference);
     let stream = match config.sample_format() {
-        cpal::SampleFormat::F32 => {
-            let last_log_clone = last_log.clone();
-            output_device.build_output_stream(
-                &config.into(),
-                move |data: &mut [f32], _| {
-                    let mut jb = buffer.lock().unwrap();
-                    let buffer_size = jb.buffer.len();
-                    let mut max_sample = 0i16;
-
-                    for s in data {
-                        let sample = jb.pop_sample();
-                        max_sample = max_sample.max(sample.abs());
-                        *s = sample as f32 / i16::MAX as f32;
-                    }
-
-                    let is_silence = max_sample < 100;
-
-                   
-                    let mut last = last_log_clone.lock().unwrap();
-                    if last.elapsed().as_secs() >= 2 {
-                        log::info!("🔊 Output: Buffer {} samples | Max: {} | {}",
-                            buffer_size,
-                            max_sample,
-                            if is_silence { "🔇 SILENCE" } else { "🎵 PLAYING" }
-                        );
-                        *last = std::time::Instant::now();
-                    }
-                },
-                err_fn,
-                None,
-            )
-        },
+        cpal::SampleFormat::F32 => output_device.build_output_stream(
+            &config.into(),
+            move |data: &mut [f32], _| {
+                let samples = playout.pull(data.len());
+                for (s, sample) in data.iter_mut().zip(samples) {
+                    *s = sample as f32 / i16::MAX as f32;
+                }
+            },
+            err_fn,
+            None,
+        ),
         cpal::SampleFormat::I16 => output_device.build_output_stream(
             &config.into(),
             move |data: &mut [i16], _| {
-                let mut jb = buffer.lock().unwrap();
-                for s in data {
-                    *s = jb.pop_sample();
-                }
+                let samples = playout.pull(data.len());
+                data.copy_from_slice(&samples);
             },
             err_fn,
             None,
@@ -244,9 +480,8 @@ pub fn output_stream_fn(
         cpal::SampleFormat::U16 => output_device.build_output_stream(
             &config.into(),
             move |data: &mut [u16], _| {
-                let mut jb = buffer.lock().unwrap();
-                for s in data {
-                    let sample = jb.pop_sample();
+                let samples = playout.pull(data.len());
+                for (s, sample) in data.iter_mut().zip(samples) {
                     *s = (sample as i32 + 32768) as u16;
                 }
             },
@@ -254,7 +489,7 @@ pub fn output_stream_fn(
             None,
         ),
         _ => {
-            eprintln!("Unsupported sample format: {:?}", config.sample_format());
+            log::error!("Unsupported sample format: {:?}", config.sample_format());
             return Err(());
         }
     };
@@ -262,19 +497,146 @@ pub fn output_stream_fn(
     match stream {
         Ok(s) => {
             if let Err(e) = s.play() {
-                eprintln!("Failed to play output stream: {}", e);
+                log::error!("Failed to play output stream: {}", e);
                 return Err(());
             }
             log::info!("Receiving audio...");
             Ok(Some(s))
         }
         Err(e) => {
-            eprintln!("Output stream error: {}", e);
+            log::error!("Output stream error: {}", e);
             Err(())
         }
     }
 }
 
 fn err_fn(err: StreamError) {
-    eprintln!("Stream error: {}", err);
-}
\ No newline at end of file
+    log::error!("Stream error: {}", err);
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(index: usize, name: &str) -> DeviceInfo {
+        DeviceInfo {
+            index,
+            name: name.to_string(),
+            is_default: index == 0,
+            configs: Vec::new(),
+        }
+    }
+
+    fn devices() -> AudioDevices {
+        AudioDevices {
+            inputs: vec![device(0, "Built-in Microphone"), device(1, "USB Headset")],
+            outputs: vec![device(0, "Built-in Speakers")],
+        }
+    }
+
+    /// Zero crossings of `signal` per second at 48 kHz.
+    fn crossings_per_sec(signal: &[i16]) -> f32 {
+        let crossings = signal.windows(2).filter(|w| (w[0] < 0) != (w[1] < 0)).count();
+        crossings as f32 * codec::SAMPLE_RATE as f32 / signal.len() as f32
+    }
+
+    #[test]
+    fn captures_44_1_khz_stereo_as_48_khz_mono() {
+        let config = SupportedStreamConfig::new(
+            2,
+            cpal::SampleRate(44100),
+            cpal::SupportedBufferSize::Unknown,
+            cpal::SampleFormat::I16,
+        );
+        let (tx, mut rx) = tokio::sync::broadcast::channel(128);
+        let processing = (
+            CaptureProcessor::new(codec::SAMPLE_RATE, &ProcessingConfig::default()),
+            Arc::new(Mutex::new(ProcessingStages { high_pass: false, noise_suppression: false, agc: false })),
+        );
+        let mut capture = Capture::new(&config, tx, None, processing);
+
+        // A second of a 1 kHz tone on the left channel only, in 10 ms
+        // callbacks.
+        let tone: Vec<i16> = (0..44100)
+            .flat_map(|n| {
+                let left = (16000.0 * (2.0 * std::f32::consts::PI * 1000.0 * n as f32 / 44100.0).sin()) as i16;
+                [left, 0]
+            })
+            .collect();
+        for callback in tone.chunks(2 * 441) {
+            capture.push(callback);
+        }
+
+        let frames: Vec<Vec<i16>> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert!(frames.iter().all(|f| f.len() == FRAME_SIZE));
+        let out: Vec<i16> = frames.concat();
+        assert!(out.len() >= 46 * FRAME_SIZE && out.len() <= 48000, "{} samples", out.len());
+
+        let steady = &out[4800..out.len() - 4800];
+        let rate = crossings_per_sec(steady);
+        assert!((rate - 2000.0).abs() < 20.0, "{} crossings per second", rate);
+        let peak = steady.iter().map(|s| s.saturating_abs()).max().unwrap();
+        assert!((7600..=8400).contains(&peak), "peak {} after downmixing", peak);
+    }
+
+    #[test]
+    fn selectors_are_names_or_indices() {
+        let selection: DeviceSelection =
+            serde_json::from_value(serde_json::json!({"input": 1, "output": "Built-in Speakers"})).unwrap();
+        assert_eq!(selection.input, Some(DeviceSelector::Index(1)));
+        assert_eq!(selection.output, Some(DeviceSelector::Name("Built-in Speakers".to_string())));
+        assert_eq!(serde_json::to_value(&selection).unwrap(), serde_json::json!({"input": 1, "output": "Built-in Speakers"}));
+
+        let defaults: DeviceSelection = serde_json::from_value(serde_json::json!({"input": null})).unwrap();
+        assert_eq!(defaults, DeviceSelection::default());
+        assert!(serde_json::from_value::<DeviceSelection>(serde_json::json!({"input": -1})).is_err());
+
+        assert_eq!(DeviceSelector::Index(2).to_string(), "#2");
+        assert_eq!(DeviceSelector::Name("USB Headset".to_string()).to_string(), "'USB Headset'");
+    }
+
+    #[test]
+    fn checks_a_saved_selection_against_the_devices() {
+        let devices = devices();
+        assert_eq!(devices.check(&DeviceSelection::default()), Ok(()));
+        assert_eq!(
+            devices.check(&DeviceSelection {
+                input: Some(DeviceSelector::Name("USB Headset".to_string())),
+                output: Some(DeviceSelector::Index(0)),
+            }),
+            Ok(())
+        );
+        assert_eq!(
+            devices.check(&DeviceSelection {
+                input: Some(DeviceSelector::Index(2)),
+                output: None,
+            }),
+            Err("No input device #2".to_string())
+        );
+        assert_eq!(
+            devices.check(&DeviceSelection {
+                input: None,
+                output: Some(DeviceSelector::Name("USB Headset".to_string())),
+            }),
+            Err("No output device 'USB Headset'".to_string())
+        );
+    }
+
+    #[test]
+    fn fills_default_devices_from_a_fallback() {
+        let callee = DeviceSelection {
+            input: Some(DeviceSelector::Index(1)),
+            output: None,
+        };
+        let caller = DeviceSelection {
+            input: Some(DeviceSelector::Index(0)),
+            output: Some(DeviceSelector::Name("Built-in Speakers".to_string())),
+        };
+        assert_eq!(
+            callee.or(caller),
+            DeviceSelection {
+                input: Some(DeviceSelector::Index(1)),
+                output: Some(DeviceSelector::Name("Built-in Speakers".to_string())),
+            }
+        );
+    }
+}
@@ -1,48 +1,427 @@
-use std::collections::VecDeque;
+use std::collections::BTreeMap;
+use std::time::Instant;
 
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::codec::{self, Codec, Decoder};
+use crate::packet::AudioPacket;
+use crate::plc::Concealer;
+
+const SAMPLES_PER_MS: usize = 48;
+const DEFAULT_FRAME_SAMPLES: usize = 960;
+
+/// Playout delay bounds. The buffer starts at `initial_delay_ms` and then
+/// follows measured jitter within `min_delay_ms..=max_delay_ms`.
+#[derive(Debug, Clone)]
+pub struct JitterConfig {
+    pub min_delay_ms: u32,
+    pub max_delay_ms: u32,
+    pub initial_delay_ms: u32,
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        JitterConfig {
+            min_delay_ms: 20,
+            max_delay_ms: 400,
+            initial_delay_ms: 60,
+        }
+    }
+}
+
+impl JitterConfig {
+    /// Reads `VOIP_JITTER_MIN_MS`, `VOIP_JITTER_MAX_MS` and
+    /// `VOIP_JITTER_INITIAL_MS`, falling back to the defaults for anything unset.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = JitterConfig::default();
+        for (key, field) in [
+            ("VOIP_JITTER_MIN_MS", &mut config.min_delay_ms),
+            ("VOIP_JITTER_MAX_MS", &mut config.max_delay_ms),
+            ("VOIP_JITTER_INITIAL_MS", &mut config.initial_delay_ms),
+        ] {
+            if let Ok(value) = std::env::var(key) {
+                *field = value
+                    .parse()
+                    .map_err(|_| format!("invalid value '{}' for {}", value, key))?;
+            }
+        }
+        if config.min_delay_ms > config.max_delay_ms {
+            return Err(format!(
+                "minimum delay {} ms exceeds maximum {} ms",
+                config.min_delay_ms, config.max_delay_ms
+            ));
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JitterStats {
+    pub received: u64,
+    pub late: u64,
+    pub duplicate: u64,
+    pub lost: u64,
+    pub concealed: u64,
+    pub discarded: u64,
+    pub underruns: u64,
+    pub jitter_ms: f32,
+    pub target_delay_ms: u32,
+}
+
+/// Packet-level playout buffer. Packets are held in sequence order and
+/// decoded as they reach playout, so reordered arrivals play in order and
+/// anything arriving after its slot has played is dropped as late.
 pub struct JitterBuffer {
-    pub buffer: VecDeque<i16>,
-    min_delay: usize, 
+    config: JitterConfig,
+    packets: BTreeMap<u64, AudioPacket>,
+    highest_seq: Option<u64>,
+    next_seq: Option<u64>,
+    playing: bool,
+    decoder: Option<(u8, Box<dyn Decoder>)>,
+    concealer: Concealer,
+    frame: Vec<i16>,
+    frame_pos: usize,
+    frame_samples: usize,
+    last_arrival: Option<(Instant, u32)>,
+    jitter: f64,
+    target_delay: usize,
+    stats: JitterStats,
+    tap: Option<UnboundedSender<Vec<i16>>>,
 }
 
 impl JitterBuffer {
-    pub fn new() -> Self {
-        Self {
-            buffer: VecDeque::new(),
-            min_delay: 4800, 
-        }
+    pub fn new(config: JitterConfig) -> Self {
+        let initial_delay = config.initial_delay_ms as usize * SAMPLES_PER_MS;
+        let mut buffer = Self {
+            config,
+            packets: BTreeMap::new(),
+            highest_seq: None,
+            next_seq: None,
+            playing: false,
+            decoder: None,
+            concealer: Concealer::new(),
+            frame: Vec::new(),
+            frame_pos: 0,
+            frame_samples: DEFAULT_FRAME_SAMPLES,
+            last_arrival: None,
+            jitter: 0.0,
+            target_delay: 0,
+            stats: JitterStats::default(),
+            tap: None,
+        };
+        buffer.target_delay = buffer.clamp_delay(initial_delay);
+        buffer
     }
 
-    pub fn push_packet(&mut self, samples: &[i16]) {
-        for &s in samples {
-            self.buffer.push_back(s);
+    /// Queues a packet under its extended sequence number (see
+    /// `sequence::SequenceTracker`).
+    pub fn push_packet(&mut self, packet: AudioPacket, seq: u64, arrival: Instant) {
+        if self.next_seq.is_some_and(|next| seq < next) {
+            self.stats.late += 1;
+            return;
+        }
+        if self.packets.contains_key(&seq) {
+            self.stats.duplicate += 1;
+            return;
         }
-       
-        while self.buffer.len() > 19200 {
-            self.buffer.pop_front();
+
+        if self.highest_seq.is_none_or(|highest| seq > highest) {
+            self.update_jitter(&packet, arrival);
+            self.highest_seq = Some(seq);
+        }
+
+        self.packets.insert(seq, packet);
+        self.stats.received += 1;
+
+        let max_delay = self.config.max_delay_ms as usize * SAMPLES_PER_MS;
+        while self.buffered_samples() > max_delay + self.frame_samples {
+            let Some((oldest, _)) = self.packets.pop_first() else { break };
+            self.next_seq = Some(oldest + 1);
+            self.stats.discarded += 1;
         }
     }
 
+    /// Drops everything queued for the current stream, e.g. when the sender
+    /// restarts with a new SSRC or sequence space. Statistics are kept.
+    pub fn reset(&mut self) {
+        self.packets.clear();
+        self.highest_seq = None;
+        self.next_seq = None;
+        self.playing = false;
+        self.decoder = None;
+        self.concealer = Concealer::new();
+        self.frame.clear();
+        self.frame_pos = 0;
+        self.last_arrival = None;
+    }
+
+    /// Sends a copy of every frame as it is played out, decoded or
+    /// concealed, e.g. for recording.
+    pub fn set_tap(&mut self, tap: Option<UnboundedSender<Vec<i16>>>) {
+        self.tap = tap;
+    }
+
     pub fn pop_sample(&mut self) -> i16 {
-       
-        if self.buffer.len() < self.min_delay {
-            return 0; 
+        if self.frame_pos >= self.frame.len() {
+            self.next_frame();
+        }
+        match self.frame.get(self.frame_pos) {
+            Some(&sample) => {
+                self.frame_pos += 1;
+                sample
+            }
+            None => 0,
+        }
+    }
+
+    /// Audio queued ahead of the playout point, in 48 kHz samples. Sequence
+    /// gaps count too, since they will be played out as lost frames.
+    pub fn buffered_samples(&self) -> usize {
+        let pending = match (self.packets.first_key_value(), self.highest_seq) {
+            (Some((&first, _)), Some(highest)) => {
+                let start = self.next_seq.map_or(first, |next| next.min(first));
+                (highest + 1 - start) as usize * self.frame_samples
+            }
+            _ => 0,
+        };
+        pending + self.frame.len().saturating_sub(self.frame_pos)
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        let mut stats = self.stats.clone();
+        stats.jitter_ms = (self.jitter / SAMPLES_PER_MS as f64) as f32;
+        stats.target_delay_ms = (self.target_delay / SAMPLES_PER_MS) as u32;
+        stats
+    }
+
+    fn next_frame(&mut self) {
+        self.frame.clear();
+        self.frame_pos = 0;
+
+        if !self.playing {
+            if self.packets.is_empty() || self.buffered_samples() < self.target_delay {
+                return;
+            }
+            self.playing = true;
+            self.next_seq = self.packets.keys().next().copied();
+        }
+
+        if self.packets.is_empty() {
+            self.playing = false;
+            self.stats.underruns += 1;
+            return;
+        }
+
+        let Some(mut seq) = self.next_seq else { return };
+
+        // Well above target: skip a frame to bring latency back down.
+        if self.buffered_samples() > self.target_delay * 2 + self.frame_samples {
+            self.packets.remove(&seq);
+            self.stats.discarded += 1;
+            seq += 1;
+        }
+        self.next_seq = Some(seq + 1);
+
+        match self.packets.remove(&seq) {
+            Some(packet) => match self.decode(&packet) {
+                Some(mut samples) => {
+                    self.concealer.good_frame(&mut samples);
+                    self.frame_samples = samples.len().max(1);
+                    self.frame = samples;
+                }
+                None => self.frame = self.conceal(),
+            },
+            None => {
+                self.stats.lost += 1;
+                self.frame = self.conceal();
+            }
         }
-        self.buffer.pop_front().unwrap_or(0)
+
+        if let Some(tap) = &self.tap {
+            if tap.send(self.frame.clone()).is_err() {
+                self.tap = None;
+            }
+        }
+    }
+
+    /// Fills one missing frame, preferring the codec's own concealment.
+    fn conceal(&mut self) -> Vec<i16> {
+        self.stats.concealed += 1;
+        if let Some((_, decoder)) = self.decoder.as_mut() {
+            if let Some(samples) = decoder.conceal(self.frame_samples) {
+                return samples;
+            }
+        }
+        self.concealer.conceal(self.frame_samples)
+    }
+
+    fn decode(&mut self, packet: &AudioPacket) -> Option<Vec<i16>> {
+        if self.decoder.as_ref().map(|(pt, _)| *pt) != Some(packet.payload_type) {
+            match codec::new_decoder(packet.payload_type) {
+                Ok(decoder) => {
+                    log::info!("Decoding incoming audio with payload type {}", packet.payload_type);
+                    self.decoder = Some((packet.payload_type, decoder));
+                }
+                Err(e) => {
+                    log::warn!("❌ Cannot decode packet {}: {}", packet.seq, e);
+                    return None;
+                }
+            }
+        }
+
+        let (_, decoder) = self.decoder.as_mut()?;
+        match decoder.decode(&packet.payload) {
+            Ok(samples) => Some(samples),
+            Err(e) => {
+                log::warn!("❌ Failed to decode packet {}: {}", packet.seq, e);
+                None
+            }
+        }
+    }
+
+    /// RFC 3550 interarrival jitter, kept in 48 kHz samples, which then sets
+    /// the target delay.
+    fn update_jitter(&mut self, packet: &AudioPacket, arrival: Instant) {
+        let clock_rate = Codec::from_payload_type(packet.payload_type)
+            .map(Codec::clock_rate)
+            .unwrap_or(codec::SAMPLE_RATE) as f64;
+
+        if let Some((last_arrival, last_timestamp)) = self.last_arrival {
+            let arrival_delta = arrival.duration_since(last_arrival).as_secs_f64() * clock_rate;
+            let timestamp_delta = packet.timestamp.wrapping_sub(last_timestamp) as i32 as f64;
+            let d = (arrival_delta - timestamp_delta).abs() * codec::SAMPLE_RATE as f64 / clock_rate;
+            self.jitter += (d - self.jitter) / 16.0;
+
+            let wanted = self.frame_samples + (self.jitter * 4.0) as usize;
+            self.target_delay = self.clamp_delay(wanted);
+        }
+        self.last_arrival = Some((arrival, packet.timestamp));
+    }
+
+    fn clamp_delay(&self, delay: usize) -> usize {
+        let min = self.config.min_delay_ms as usize * SAMPLES_PER_MS;
+        let max = self.config.max_delay_ms as usize * SAMPLES_PER_MS;
+        delay.clamp(min, max.max(min))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PAYLOAD_TYPE_L16;
+    use std::time::Duration;
+
+    const FRAME: usize = DEFAULT_FRAME_SAMPLES;
+
+    /// A 20 ms L16 frame whose samples all hold `seq`, so playout order shows.
+    fn packet(seq: u16) -> AudioPacket {
+        AudioPacket {
+            marker: false,
+            payload_type: PAYLOAD_TYPE_L16,
+            seq,
+            timestamp: seq as u32 * FRAME as u32,
+            ssrc: 1,
+            payload: vec![seq as i16; FRAME].iter().flat_map(|s| s.to_be_bytes()).collect(),
+        }
+    }
+
+    /// A buffer holding at least 80 ms, so the few frames these tests queue
+    /// up front are not skipped to catch up on latency.
+    fn buffer(max_delay_ms: u32) -> JitterBuffer {
+        JitterBuffer::new(JitterConfig { min_delay_ms: 80, max_delay_ms, initial_delay_ms: 80 })
+    }
+
+    /// Pushes `seq` as if it arrived `offset_ms` away from its 20 ms slot.
+    fn push(jb: &mut JitterBuffer, start: Instant, seq: u16, offset_ms: i64) {
+        let at = seq as i64 * 20 + offset_ms;
+        jb.push_packet(packet(seq), seq as u64, start + Duration::from_millis(at as u64));
     }
 
-    #[allow(dead_code)]
-    pub fn len(&self) -> usize {
-        self.buffer.len()
+    /// The next frame played out, identified by its last sample, which a
+    /// fade-in after concealment leaves alone.
+    fn play(jb: &mut JitterBuffer) -> i16 {
+        let frame: Vec<i16> = (0..FRAME).map(|_| jb.pop_sample()).collect();
+        frame[FRAME - 1]
     }
 
-    #[allow(dead_code)]
-    pub fn is_empty(&self) -> bool {
-        self.buffer.is_empty()
+    #[test]
+    fn plays_reordered_packets_in_order() {
+        let mut jb = buffer(400);
+        let start = Instant::now();
+        for seq in [1, 3, 2, 4] {
+            push(&mut jb, start, seq, 0);
+        }
+        let played: Vec<i16> = (0..4).map(|_| play(&mut jb)).collect();
+        assert_eq!(played, [1, 2, 3, 4]);
+        assert_eq!(jb.stats().received, 4);
+    }
+
+    #[test]
+    fn drops_late_and_duplicate_packets() {
+        let mut jb = buffer(400);
+        let start = Instant::now();
+        for seq in 1..=4 {
+            push(&mut jb, start, seq, 0);
+        }
+        push(&mut jb, start, 4, 0);
+        assert_eq!(play(&mut jb), 1);
+        assert_eq!(play(&mut jb), 2);
+
+        // Its slot has played, so it is not queued again.
+        push(&mut jb, start, 1, 60);
+        assert_eq!(play(&mut jb), 3);
+        let stats = jb.stats();
+        assert_eq!((stats.late, stats.duplicate, stats.received), (1, 1, 4));
     }
 
-    pub fn set_min_delay_ms(&mut self, delay_ms: u32) {
-       
-        self.min_delay = delay_ms as usize * 48;
+    #[test]
+    fn conceals_a_lost_packet() {
+        let mut jb = buffer(400);
+        let start = Instant::now();
+        for seq in [1, 2, 4, 5] {
+            push(&mut jb, start, seq, 0);
+        }
+        assert_eq!(play(&mut jb), 1);
+        assert_eq!(play(&mut jb), 2);
+        play(&mut jb);
+        assert_eq!(play(&mut jb), 4);
+        let stats = jb.stats();
+        assert_eq!((stats.lost, stats.concealed), (1, 1));
+    }
+
+    #[test]
+    fn discards_the_oldest_packets_on_overflow() {
+        let mut jb = buffer(100);
+        let start = Instant::now();
+        for seq in 1..=20 {
+            push(&mut jb, start, seq, 0);
+        }
+        assert!(jb.buffered_samples() <= 100 * SAMPLES_PER_MS + FRAME, "{} buffered", jb.buffered_samples());
+        assert_eq!(jb.stats().discarded, 14);
+        // Playout picks up after what was dropped.
+        assert_eq!(play(&mut jb), 15);
+    }
+
+    #[test]
+    fn target_delay_follows_jitter() {
+        let mut jb = JitterBuffer::new(JitterConfig::default());
+        let start = Instant::now();
+        assert_eq!(jb.stats().target_delay_ms, 60);
+
+        // Packets arriving 15 ms early and late in turn.
+        for seq in 1..=100 {
+            push(&mut jb, start, seq, if seq % 2 == 0 { 15 } else { -15 } + 20);
+            play(&mut jb);
+        }
+        let grown = jb.stats();
+        assert!(grown.target_delay_ms > 100, "target {} ms, jitter {:.1} ms", grown.target_delay_ms, grown.jitter_ms);
+
+        // Steady arrivals bring it back down to the minimum.
+        for seq in 101..=300 {
+            push(&mut jb, start, seq, 20);
+            play(&mut jb);
+        }
+        let settled = jb.stats();
+        assert_eq!(settled.target_delay_ms, 20, "jitter {:.1} ms", settled.jitter_ms);
     }
 }
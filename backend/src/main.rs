@@ -1,15 +1,42 @@
+mod account;
+mod aec;
 mod audio_udp;
+mod auth;
 mod call_manager;
+mod call_state;
+mod cdr;
+mod codec;
+mod config;
+mod conference;
+mod events;
+mod fft;
+mod g711;
 mod io;
 mod jitter;
 mod packet;
+mod plc;
+mod processing;
+mod recorder;
+mod relay;
+mod resample;
+mod sdp;
+mod sequence;
 mod signaling;
+mod storage;
+mod stun;
+mod turn;
 mod user;
+mod ws;
 
-use actix_web::{web, App, HttpServer, middleware::Logger};
+#[cfg(test)]
+mod test_support;
+
+use actix_web::{web, App, HttpServer, middleware::{from_fn, Logger}};
 use actix_cors::Cors;
 use actix_files::Files;
+use account::AccountError;
 use audio_udp::UdpCommand;
+use auth::AuthUser;
 use call_manager::CallManager;
 use std::sync::Arc;
 use tokio::sync::{mpsc, Mutex};
@@ -33,17 +60,67 @@ async fn main() -> std::io::Result<()> {
         .with_no_client_auth()
         .with_single_cert(cert_chain, keys.remove(0)).expect("Failed to create TLS config");
 
-    let call_manager = Arc::new(Mutex::new(CallManager::new()));
+    let call_config = config::load_or_default("call", call_manager::CallConfig::from_env());
+    let mut cdr = cdr::CdrStore::open(call_config.cdr_path.clone()).expect("Failed to open call detail records");
+    if let Some(writer) = cdr.take_writer() {
+        tokio::spawn(writer.run());
+    }
+    let storage_config = config::load_or_default("storage", storage::StorageConfig::from_env());
+    let storage = storage_config.open().expect("Failed to open storage");
+    let (udp_tx, udp_rx) = mpsc::unbounded_channel::<UdpCommand>();
+    let mut call_manager = CallManager::new(storage, cdr);
+    if let Some(writer) = call_manager.take_storage_writer() {
+        tokio::spawn(writer.run());
+    }
+    call_manager.set_media_sender(udp_tx.clone());
+    let call_manager = Arc::new(Mutex::new(call_manager));
 
-   
-    let (udp_tx, udp_rx) = mpsc::channel::<UdpCommand>(32);
+    let codec_config = config::load_or_default("codec", codec::CodecConfig::from_env());
+
+    let jitter_config = config::load_or_default("jitter buffer", jitter::JitterConfig::from_env());
+
+    let recording_config = config::load_or_default("recording", recorder::RecordingConfig::from_env());
+
+    let echo_config = config::load_or_default("echo canceller", aec::EchoConfig::from_env());
+
+    let processing_config = config::load_or_default("capture processing", processing::ProcessingConfig::from_env());
+
+    let sdp_config = config::load_or_default("SDP", sdp::SdpConfig::from_env());
+
+    let stun_config = config::load_or_default("STUN", stun::StunConfig::from_env());
+    if stun_config.enabled {
+        let stun_config = stun_config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = stun::stun_server(stun_config).await {
+                log::error!("STUN server failed: {}", e);
+            }
+        });
+    }
+
+    let turn_config = config::load_or_default("TURN", turn::TurnConfig::from_env());
+    if turn_config.enabled {
+        let turn_config = turn_config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = turn::turn_server(turn_config).await {
+                log::error!("TURN server failed: {}", e);
+            }
+        });
+    }
+
+    let reset_delivery = config::load_or_default("password reset delivery", account::ResetDelivery::from_env());
+
+    // Hashed up front, or the first login with an unknown username would
+    // take longer than the rest.
+    account::dummy_hash();
+
+    tokio::spawn(call_manager::ring_timeout_task(Arc::clone(&call_manager), call_config));
 
    
     let call_manager_clone = Arc::clone(&call_manager);
     log::info!("Spawning UDP audio task...");
     tokio::spawn(async move {
         log::info!("UDP audio task started");
-        if let Err(e) = audio_udp::udp_audio_task(call_manager_clone, udp_rx).await {
+        if let Err(e) = audio_udp::udp_audio_task(call_manager_clone, udp_rx, codec_config, jitter_config, recording_config, echo_config, processing_config).await {
             log::error!("UDP audio task failed: {}", e);
         }
     });
@@ -59,7 +136,16 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(web::Data::new(call_manager))
             .app_data(web::Data::new(udp_tx_clone))
-            .wrap(Logger::default())
+            .app_data(web::Data::new(sdp_config.clone()))
+            .app_data(web::Data::new(stun_config.clone()))
+            .app_data(web::Data::new(turn_config.clone()))
+            .app_data(web::Data::new(reset_delivery.clone()))
+            // Not `Logger::default()`: its request line includes the query
+            // string, where WebSocket clients send their session token.
+            .wrap(
+                Logger::new(r#"%a "%{method}xi %U" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+                    .custom_request_replace("method", |req| req.method().to_string())
+            )
             .wrap(
                 Cors::default()
                     .allow_any_origin()
@@ -70,13 +156,23 @@ async fn main() -> std::io::Result<()> {
                 web::scope("/api")
                     .route("/health", web::get().to(health_check))
                     .route("/users/register", web::post().to(register_user))
-                    .route("/users/list", web::get().to(list_users))
-                    .route("/users/get", web::get().to(get_user))
-                    .route("/users/disconnect", web::post().to(disconnect_user))
-                    .route("/users/heartbeat", web::post().to(user_heartbeat))
+                    .route("/users/login", web::post().to(login_user))
+                    .route("/users/password", web::post().to(change_password))
+                    .route("/users/password/reset_request", web::post().to(request_password_reset))
+                    .route("/users/password/reset", web::post().to(reset_password))
                     .service(
                         web::scope("")
+                            .wrap(from_fn(auth::require_session))
                             .app_data(udp_tx_clone2)
+                            .route("/users/list", web::get().to(list_users))
+                            .route("/users/get", web::get().to(get_user))
+                            .route("/users/disconnect", web::post().to(disconnect_user))
+                            .route("/users/heartbeat", web::post().to(user_heartbeat))
+                            .route("/users/call_waiting", web::post().to(set_call_waiting))
+                            .route("/ice/servers", web::get().to(ice_servers))
+                            .route("/audio/devices", web::get().to(list_audio_devices))
+                            .route("/audio/devices", web::post().to(select_audio_devices))
+                            .route("/ws", web::get().to(ws::signal_socket))
                             .configure(signaling::config_with_udp_sender)
                     )
             )
@@ -87,26 +183,235 @@ async fn main() -> std::io::Result<()> {
     .await
 }
 
-async fn health_check() -> actix_web::HttpResponse {
-    actix_web::HttpResponse::Ok().json(serde_json::json!({"status": "ok"}))
+/// Also advertises the built-in STUN server, if enabled, as a `stun:` URI.
+async fn health_check(
+    req: actix_web::HttpRequest,
+    stun_config: web::Data<stun::StunConfig>,
+) -> actix_web::HttpResponse {
+    let stun = stun_config.uri(req.connection_info().host());
+    actix_web::HttpResponse::Ok().json(serde_json::json!({"status": "ok", "stun": stun}))
+}
+
+/// ICE servers for the session's user: the built-in STUN server and the
+/// TURN relay with credentials that expire after `ttl` seconds.
+async fn ice_servers(
+    req: actix_web::HttpRequest,
+    auth: AuthUser,
+    stun_config: web::Data<stun::StunConfig>,
+    turn_config: web::Data<turn::TurnConfig>,
+) -> actix_web::HttpResponse {
+    let host = req.connection_info().host().to_string();
+    let mut servers = Vec::new();
+    if let Some(uri) = stun_config.uri(&host) {
+        servers.push(serde_json::json!({"urls": [uri]}));
+    }
+    if let Some(uri) = turn_config.uri(&host) {
+        let credentials = turn_config.credentials(&auth.user_id);
+        servers.push(serde_json::json!({
+            "urls": [uri],
+            "username": credentials.username,
+            "credential": credentials.password
+        }));
+    }
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "iceServers": servers,
+        "ttl": turn_config.credential_ttl_secs
+    }))
+}
+
+/// Maps account errors to responses: bad input is 400, a taken username
+/// 409 and failed authentication 401.
+fn account_error_response(error: AccountError) -> actix_web::HttpResponse {
+    let body = serde_json::json!({
+        "status": "error",
+        "code": error.code(),
+        "message": error.to_string()
+    });
+    match error {
+        AccountError::InvalidUsername | AccountError::WeakPassword => {
+            actix_web::HttpResponse::BadRequest().json(body)
+        }
+        AccountError::UsernameTaken(_) => actix_web::HttpResponse::Conflict().json(body),
+        AccountError::InvalidCredentials | AccountError::InvalidResetToken => {
+            actix_web::HttpResponse::Unauthorized().json(body)
+        }
+        AccountError::Hash(_) => actix_web::HttpResponse::InternalServerError().json(body),
+    }
+}
+
+fn json_str<'a>(data: &'a serde_json::Value, key: &str) -> &'a str {
+    data.get(key).and_then(|v| v.as_str()).unwrap_or("")
+}
+
+/// Argon2 takes tens of milliseconds, so hashing runs on the blocking pool.
+async fn hash_password(password: &str) -> Result<String, AccountError> {
+    let password = password.to_string();
+    web::block(move || account::hash_password(&password))
+        .await
+        .map_err(|e| AccountError::Hash(e.to_string()))?
+}
+
+/// Checks `password` against `password_hash`, or against a dummy hash when
+/// there is none so that unknown usernames cost as much as wrong passwords.
+async fn verify_password(password_hash: Option<String>, password: &str) -> bool {
+    let password = password.to_string();
+    web::block(move || match &password_hash {
+        Some(password_hash) => account::verify_password(password_hash, &password),
+        None => {
+            account::verify_password(account::dummy_hash(), &password);
+            false
+        }
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Checks a username and password, returning the account on success.
+async fn authenticate(
+    call_manager: &Mutex<CallManager>,
+    username: &str,
+    password: &str,
+) -> Result<account::Account, AccountError> {
+    let account = call_manager.lock().await.account_by_name(username).cloned();
+    let password_hash = account.as_ref().map(|account| account.password_hash.clone());
+    let verified = verify_password(password_hash, password).await;
+    match account {
+        Some(account) if verified => Ok(account),
+        _ => Err(AccountError::InvalidCredentials),
+    }
 }
 
+/// Signs up a new account and logs it in.
 async fn register_user(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
     user_data: web::Json<serde_json::Value>,
 ) -> actix_web::HttpResponse {
-    let user_id = uuid::Uuid::new_v4().to_string();
-    let username = user_data
-        .get("username")
-        .and_then(|u| u.as_str())
-        .unwrap_or("Unknown");
-    
+    let username = json_str(&user_data, "username");
+    let password = json_str(&user_data, "password");
+    let call_waiting = user_data
+        .get("call_waiting")
+        .and_then(|c| c.as_bool())
+        .unwrap_or(false);
+
+    if let Err(e) = account::validate_username(username) {
+        return account_error_response(e);
+    }
+    if call_manager.lock().await.account_by_name(username).is_some() {
+        return account_error_response(AccountError::UsernameTaken(username.to_string()));
+    }
+    let password_hash = match hash_password(password).await {
+        Ok(hash) => hash,
+        Err(e) => return account_error_response(e),
+    };
+
     let mut manager = call_manager.lock().await;
-    manager.register_user(user_id.clone(), username.to_string());
-    
+    let account = match manager.create_account(username, password_hash) {
+        Ok(account) => account,
+        Err(e) => return account_error_response(e),
+    };
+    let token = manager.log_in(&account);
+    manager.set_call_waiting(&account.id, call_waiting);
+
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "user_id": account.id,
+        "username": account.username,
+        "token": token
+    }))
+}
+
+async fn login_user(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    user_data: web::Json<serde_json::Value>,
+) -> actix_web::HttpResponse {
+    let username = json_str(&user_data, "username");
+    let password = json_str(&user_data, "password");
+
+    let account = match authenticate(&call_manager, username, password).await {
+        Ok(account) => account,
+        Err(e) => return account_error_response(e),
+    };
+    let token = call_manager.lock().await.log_in(&account);
+    log::info!("🔓 {} logged in", account.username);
+
     actix_web::HttpResponse::Ok().json(serde_json::json!({
-        "user_id": user_id,
-        "username": username
+        "user_id": account.id,
+        "username": account.username,
+        "token": token
+    }))
+}
+
+async fn change_password(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    user_data: web::Json<serde_json::Value>,
+) -> actix_web::HttpResponse {
+    let username = json_str(&user_data, "username");
+    let old_password = json_str(&user_data, "old_password");
+    let new_password = json_str(&user_data, "new_password");
+
+    let account = match authenticate(&call_manager, username, old_password).await {
+        Ok(account) => account,
+        Err(e) => return account_error_response(e),
+    };
+    let password_hash = match hash_password(new_password).await {
+        Ok(hash) => hash,
+        Err(e) => return account_error_response(e),
+    };
+    call_manager.lock().await.set_password_hash(&account.id, password_hash);
+
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "message": "Password changed"
+    }))
+}
+
+/// Issues a password reset token and hands it to the configured
+/// `ResetDelivery` in the background. The response is the same whether or
+/// not the account exists.
+async fn request_password_reset(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    reset_delivery: web::Data<account::ResetDelivery>,
+    user_data: web::Json<serde_json::Value>,
+) -> actix_web::HttpResponse {
+    let username = json_str(&user_data, "username");
+
+    let mut manager = call_manager.lock().await;
+    if let Some(account) = manager.account_by_name(username).cloned() {
+        let token = manager.create_reset_token(&account.id);
+        let reset_delivery = reset_delivery.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = reset_delivery.deliver(&account.username, &token).await {
+                log::error!("Failed to deliver password reset token for {}: {}", account.username, e);
+            }
+        });
+    }
+
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "message": "If the account exists, a reset token has been issued"
+    }))
+}
+
+async fn reset_password(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    user_data: web::Json<serde_json::Value>,
+) -> actix_web::HttpResponse {
+    let token = json_str(&user_data, "token");
+    let new_password = json_str(&user_data, "new_password");
+
+    let password_hash = match hash_password(new_password).await {
+        Ok(hash) => hash,
+        Err(e) => return account_error_response(e),
+    };
+    let mut manager = call_manager.lock().await;
+    let Some(account_id) = manager.take_reset_token(token) else {
+        return account_error_response(AccountError::InvalidResetToken);
+    };
+    manager.set_password_hash(&account_id, password_hash);
+
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "message": "Password reset"
     }))
 }
 
@@ -121,39 +426,131 @@ async fn list_users(
     }))
 }
 
+/// Marks the user offline and ends the session the request was made with.
 async fn disconnect_user(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     user_data: web::Json<serde_json::Value>,
 ) -> actix_web::HttpResponse {
-    let user_id = user_data
-        .get("user_id")
-        .and_then(|u| u.as_str())
-        .unwrap_or("");
+    if let Err(e) = auth.check_claimed(user_data.get("user_id").and_then(|u| u.as_str())) {
+        return actix_web::HttpResponse::from_error(e);
+    }
     
     let mut manager = call_manager.lock().await;
-    let success = manager.disconnect_user(user_id);
+    let success = manager.disconnect_user(&auth.user_id);
+    manager.end_session(&auth.token);
     
     actix_web::HttpResponse::Ok().json(serde_json::json!({
         "success": success
     }))
 }
 
+async fn set_call_waiting(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
+    user_data: web::Json<serde_json::Value>,
+) -> actix_web::HttpResponse {
+    if let Err(e) = auth.check_claimed(user_data.get("user_id").and_then(|u| u.as_str())) {
+        return actix_web::HttpResponse::from_error(e);
+    }
+    let enabled = user_data
+        .get("enabled")
+        .and_then(|e| e.as_bool())
+        .unwrap_or(false);
+    
+    let mut manager = call_manager.lock().await;
+    let success = manager.set_call_waiting(&auth.user_id, enabled);
+    
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "success": success
+    }))
+}
+
+async fn audio_devices() -> Result<io::AudioDevices, actix_web::error::BlockingError> {
+    web::block(|| io::list_devices(&cpal::default_host())).await
+}
+
+/// The audio devices on this machine, with their supported configs, and
+/// the ones the user has chosen for native calls.
+async fn list_audio_devices(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
+) -> actix_web::HttpResponse {
+    let devices = match audio_devices().await {
+        Ok(devices) => devices,
+        Err(e) => return actix_web::HttpResponse::from_error(e),
+    };
+    let selected = call_manager.lock().await.audio_devices(&auth.user_id);
+
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "inputs": devices.inputs,
+        "outputs": devices.outputs,
+        "selected": selected
+    }))
+}
+
+/// Remembers the devices the user's calls start on: `input` and `output`
+/// are each a device name, an index from the device list, or null for the
+/// default.
+async fn select_audio_devices(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
+    user_data: web::Json<serde_json::Value>,
+) -> actix_web::HttpResponse {
+    if let Err(e) = auth.check_claimed(user_data.get("user_id").and_then(|u| u.as_str())) {
+        return actix_web::HttpResponse::from_error(e);
+    }
+    let selection: io::DeviceSelection = match serde_json::from_value(user_data.into_inner()) {
+        Ok(selection) => selection,
+        Err(e) => {
+            return actix_web::HttpResponse::BadRequest().json(serde_json::json!({
+                "status": "error",
+                "code": "invalid_request",
+                "message": format!("Invalid device selection: {}", e)
+            }))
+        }
+    };
+    let devices = match audio_devices().await {
+        Ok(devices) => devices,
+        Err(e) => return actix_web::HttpResponse::from_error(e),
+    };
+    if let Err(message) = devices.check(&selection) {
+        return actix_web::HttpResponse::NotFound().json(serde_json::json!({
+            "status": "error",
+            "code": "unknown_device",
+            "message": message
+        }));
+    }
+
+    let mut manager = call_manager.lock().await;
+    manager.set_audio_devices(&auth.user_id, selection.clone());
+    log::info!("🎧 {} selected audio devices {:?}", auth.user_id, selection);
+
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "selected": selection
+    }))
+}
+
 async fn user_heartbeat(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     user_data: web::Json<serde_json::Value>,
 ) -> actix_web::HttpResponse {
-    let user_id = user_data
-        .get("user_id")
-        .and_then(|u| u.as_str())
-        .unwrap_or("");
+    if let Err(e) = auth.check_claimed(user_data.get("user_id").and_then(|u| u.as_str())) {
+        return actix_web::HttpResponse::from_error(e);
+    }
     
     let mut manager = call_manager.lock().await;
     
    
-    let user_exists = manager.update_heartbeat(user_id);
+    let user_exists = manager.update_heartbeat(&auth.user_id);
     
    
-    let _ = manager.disconnect_inactive_users(10);
+    for user_id in manager.disconnect_inactive_users(10) {
+        log::info!("💤 Disconnected {} after missing heartbeats", user_id);
+    }
     
     actix_web::HttpResponse::Ok().json(serde_json::json!({
         "success": user_exists
@@ -1,31 +1,286 @@
+use std::fmt;
+
+pub const RTP_VERSION: u8 = 2;
+pub const RTP_HEADER_LEN: usize = 12;
+/// Largest datagram the media sockets read; the codec keeps packets within it.
+pub const MAX_PACKET_LEN: usize = 4096;
+
+/// Dynamic payload type used for 16-bit linear PCM, 48 kHz mono (L16/48000/1).
+pub const PAYLOAD_TYPE_L16: u8 = 96;
+
+/// RTP (RFC 3550) packet. The payload is codec-encoded audio; which codec is
+/// given by `payload_type` (see `codec::Codec`).
 #[derive(Debug, Clone)]
 pub struct AudioPacket {
+    pub marker: bool,
+    pub payload_type: u8,
     pub seq: u16,
-    pub samples: Vec<i16>,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Why a datagram is not a usable RTP packet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketError {
+    /// Shorter than its header, CSRC list or extension say it is.
+    Truncated(usize),
+    BadVersion(u8),
+    /// Padding count of zero or longer than the payload.
+    BadPadding(usize),
 }
 
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Truncated(len) => write!(f, "truncated {}-byte packet", len),
+            PacketError::BadVersion(version) => write!(f, "RTP version {}", version),
+            PacketError::BadPadding(padding) => write!(f, "invalid padding of {} bytes", padding),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
 impl AudioPacket {
     pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(2 + self.samples.len() * 2);
-        buf.extend_from_slice(&self.seq.to_le_bytes());
-        for s in &self.samples {
-            buf.extend_from_slice(&s.to_le_bytes());
+        let mut buf = Vec::with_capacity(RTP_HEADER_LEN + self.payload.len());
+        buf.push(RTP_VERSION << 6);
+        buf.push(((self.marker as u8) << 7) | (self.payload_type & 0x7f));
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.ssrc.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, PacketError> {
+        if data.len() < RTP_HEADER_LEN {
+            return Err(PacketError::Truncated(data.len()));
+        }
+
+        if data[0] >> 6 != RTP_VERSION {
+            return Err(PacketError::BadVersion(data[0] >> 6));
+        }
+        let has_padding = data[0] & 0x20 != 0;
+        let has_extension = data[0] & 0x10 != 0;
+        let csrc_count = (data[0] & 0x0f) as usize;
+
+        let marker = data[1] & 0x80 != 0;
+        let payload_type = data[1] & 0x7f;
+        let seq = u16::from_be_bytes([data[2], data[3]]);
+        let timestamp = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let ssrc = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+
+        let mut offset = RTP_HEADER_LEN + csrc_count * 4;
+        if has_extension {
+            if data.len() < offset + 4 {
+                return Err(PacketError::Truncated(data.len()));
+            }
+            let words = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+            offset += 4 + words * 4;
+        }
+        if data.len() < offset {
+            return Err(PacketError::Truncated(data.len()));
+        }
+
+        let mut end = data.len();
+        if has_padding {
+            let padding = data[end - 1] as usize;
+            if padding == 0 || offset + padding > end {
+                return Err(PacketError::BadPadding(padding));
+            }
+            end -= padding;
+        }
+
+        Ok(Self {
+            marker,
+            payload_type,
+            seq,
+            timestamp,
+            ssrc,
+            payload: data[offset..end].to_vec(),
+        })
+    }
+}
+
+/// Magic prefix of out-of-band control datagrams. Its first byte has
+/// version bits 01, so it can never be mistaken for an RTP v2 packet.
+const CONTROL_MAGIC: [u8; 4] = *b"VOIP";
+
+/// Control messages sent on the media socket alongside RTP.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlMessage {
+    /// Lets the receiver latch the sender's address for `call_id` before
+    /// media flows.
+    Ping { call_id: String },
+}
+
+impl ControlMessage {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = CONTROL_MAGIC.to_vec();
+        match self {
+            ControlMessage::Ping { call_id } => {
+                buf.push(1);
+                buf.extend_from_slice(call_id.as_bytes());
+            }
         }
         buf
     }
 
     pub fn deserialize(data: &[u8]) -> Option<Self> {
-        if data.len() < 2 {
+        if data.len() <= CONTROL_MAGIC.len() || data[..CONTROL_MAGIC.len()] != CONTROL_MAGIC {
             return None;
         }
+        let body = &data[CONTROL_MAGIC.len() + 1..];
+        match data[CONTROL_MAGIC.len()] {
+            1 => Some(ControlMessage::Ping {
+                call_id: String::from_utf8(body.to_vec()).ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Sender-side RTP state for one outgoing stream: random SSRC, sequence and
+/// timestamp origins, with the marker bit set on the first packet.
+pub struct RtpStream {
+    ssrc: u32,
+    seq: u16,
+    timestamp: u32,
+    first: bool,
+}
+
+impl RtpStream {
+    pub fn new() -> Self {
+        let random = uuid::Uuid::new_v4().as_u128();
+        Self {
+            ssrc: random as u32,
+            seq: (random >> 32) as u16,
+            timestamp: (random >> 64) as u32,
+            first: true,
+        }
+    }
+
+    /// Wraps one encoded frame; `frame_samples` is its duration in ticks of
+    /// the payload type's RTP clock.
+    pub fn next_packet(&mut self, payload_type: u8, payload: Vec<u8>, frame_samples: usize) -> AudioPacket {
+        let packet = AudioPacket {
+            marker: self.first,
+            payload_type,
+            seq: self.seq,
+            timestamp: self.timestamp,
+            ssrc: self.ssrc,
+            payload,
+        };
+        self.first = false;
+        self.seq = self.seq.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(frame_samples as u32);
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> AudioPacket {
+        AudioPacket {
+            marker: true,
+            payload_type: PAYLOAD_TYPE_L16,
+            seq: 65535,
+            timestamp: 0xdead_beef,
+            ssrc: 0x1234_5678,
+            payload: vec![1, 2, 3, 4, 5],
+        }
+    }
 
-        let seq = u16::from_le_bytes([data[0], data[1]]);
-        let mut samples = Vec::new();
+    #[test]
+    fn round_trips() {
+        let original = packet();
+        let parsed = AudioPacket::deserialize(&original.serialize()).unwrap();
+        assert_eq!(
+            (parsed.marker, parsed.payload_type, parsed.seq, parsed.timestamp, parsed.ssrc, parsed.payload),
+            (true, PAYLOAD_TYPE_L16, 65535, 0xdead_beef, 0x1234_5678, vec![1, 2, 3, 4, 5])
+        );
+    }
+
+    #[test]
+    fn skips_csrcs_extension_and_padding() {
+        let mut data = packet().serialize();
+        data[0] |= 0x20 | 0x10 | 0x01;
+        let payload = data.split_off(RTP_HEADER_LEN);
+        data.extend_from_slice(&[0xaa; 4]); // one CSRC
+        data.extend_from_slice(&[0xbe, 0xde, 0, 1, 0xbb, 0xbb, 0xbb, 0xbb]); // one-word extension
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(&[0, 0, 3]); // padding
+        assert_eq!(AudioPacket::deserialize(&data).unwrap().payload, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        let data = packet().serialize();
+        for len in 0..RTP_HEADER_LEN {
+            assert_eq!(AudioPacket::deserialize(&data[..len]).unwrap_err(), PacketError::Truncated(len));
+        }
+        // The CSRC list runs past the end.
+        let mut csrcs = data[..RTP_HEADER_LEN].to_vec();
+        csrcs[0] |= 0x0f;
+        assert_eq!(AudioPacket::deserialize(&csrcs).unwrap_err(), PacketError::Truncated(RTP_HEADER_LEN));
+        // The extension header is cut off.
+        let mut extension = data[..RTP_HEADER_LEN + 2].to_vec();
+        extension[0] |= 0x10;
+        assert_eq!(AudioPacket::deserialize(&extension).unwrap_err(), PacketError::Truncated(RTP_HEADER_LEN + 2));
+    }
 
-        for chunk in data[2..].chunks_exact(2) {
-            samples.push(i16::from_le_bytes([chunk[0], chunk[1]]));
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = packet().serialize();
+        for version in [0, 1, 3] {
+            data[0] = version << 6;
+            assert_eq!(AudioPacket::deserialize(&data).unwrap_err(), PacketError::BadVersion(version));
         }
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_end() {
+        // An extension claiming 0xffff words.
+        let mut data = packet().serialize();
+        data[0] |= 0x10;
+        data.splice(RTP_HEADER_LEN..RTP_HEADER_LEN, [0xbe, 0xde, 0xff, 0xff]);
+        assert_eq!(AudioPacket::deserialize(&data).unwrap_err(), PacketError::Truncated(data.len()));
 
-        Some(Self { seq, samples })
+        // Padding longer than the payload, or zero.
+        let mut data = packet().serialize();
+        data[0] |= 0x20;
+        *data.last_mut().unwrap() = 200;
+        assert_eq!(AudioPacket::deserialize(&data).unwrap_err(), PacketError::BadPadding(200));
+        *data.last_mut().unwrap() = 0;
+        assert_eq!(AudioPacket::deserialize(&data).unwrap_err(), PacketError::BadPadding(0));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn never_panics_on_any_prefix_with_any_flags() {
+        let mut data = packet().serialize();
+        data.extend_from_slice(&[0xff; 8]);
+        for first in 0..=255u8 {
+            data[0] = first;
+            for len in 0..=data.len() {
+                let _ = AudioPacket::deserialize(&data[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn control_messages_round_trip_and_are_not_rtp() {
+        let ping = ControlMessage::Ping { call_id: "0b5e5c36-4c4b-4a6e-9d2f-8f2f5b1e7a10".into() };
+        let data = ping.serialize();
+        assert_eq!(ControlMessage::deserialize(&data), Some(ping));
+        assert_eq!(AudioPacket::deserialize(&data).unwrap_err(), PacketError::BadVersion(1));
+        assert_eq!(ControlMessage::deserialize(&packet().serialize()), None);
+
+        assert_eq!(ControlMessage::deserialize(b"VOIP"), None);
+        assert_eq!(ControlMessage::deserialize(b"VOIP\x09call"), None);
+        assert_eq!(ControlMessage::deserialize(b"VOIP\x01\xff\xfe"), None);
+    }
+}
@@ -0,0 +1,161 @@
+/// Shortest and longest pitch period searched, in 48 kHz samples
+/// (400 Hz down to 66 Hz).
+const MIN_PITCH: usize = 120;
+const MAX_PITCH: usize = 720;
+
+/// Concealed audio fades to silence over this many samples (60 ms).
+const FADE_OUT: usize = 2880;
+/// The first good frame after a loss fades in over this many samples (2 ms).
+const FADE_IN: usize = 96;
+
+/// Waveform-repetition loss concealment for codecs without their own PLC.
+/// The last pitch period of good audio is repeated, fading out across
+/// consecutive losses.
+pub struct Concealer {
+    history: Vec<i16>,
+    pitch: usize,
+    offset: usize,
+    concealed: usize,
+}
+
+impl Concealer {
+    pub fn new() -> Self {
+        Self {
+            history: Vec::new(),
+            pitch: 0,
+            offset: 0,
+            concealed: 0,
+        }
+    }
+
+    /// Records a correctly received frame, fading it in if it follows
+    /// concealed audio.
+    pub fn good_frame(&mut self, frame: &mut [i16]) {
+        if self.concealed > 0 {
+            let fade = FADE_IN.min(frame.len());
+            for (i, sample) in frame.iter_mut().take(fade).enumerate() {
+                *sample = (*sample as f32 * i as f32 / fade as f32) as i16;
+            }
+            self.concealed = 0;
+        }
+
+        self.history.extend_from_slice(frame);
+        let excess = self.history.len().saturating_sub(MAX_PITCH * 2);
+        self.history.drain(..excess);
+        self.pitch = 0;
+    }
+
+    pub fn conceal(&mut self, len: usize) -> Vec<i16> {
+        if self.history.is_empty() || self.concealed >= FADE_OUT {
+            self.concealed += len;
+            return vec![0; len];
+        }
+
+        if self.concealed == 0 {
+            self.pitch = self.estimate_pitch();
+            self.offset = 0;
+        }
+
+        let period = &self.history[self.history.len() - self.pitch..];
+        let mut out = Vec::with_capacity(len);
+        for _ in 0..len {
+            let gain = 1.0 - (self.concealed as f32 / FADE_OUT as f32).min(1.0);
+            out.push((period[self.offset] as f32 * gain) as i16);
+            self.offset = (self.offset + 1) % self.pitch;
+            self.concealed += 1;
+        }
+        out
+    }
+
+    /// Picks the lag with the highest normalized autocorrelation over the
+    /// most recent history, falling back to the whole history when it is
+    /// too short to search.
+    fn estimate_pitch(&self) -> usize {
+        let len = self.history.len();
+        if len < MAX_PITCH * 2 {
+            return len;
+        }
+
+        let recent = &self.history[len - MAX_PITCH..];
+        let mut best = (0.0f64, MAX_PITCH);
+        for lag in MIN_PITCH..=MAX_PITCH {
+            let past = &self.history[len - MAX_PITCH - lag..len - lag];
+            let (mut dot, mut energy) = (0.0f64, 0.0f64);
+            for (&a, &b) in recent.iter().zip(past) {
+                dot += a as f64 * b as f64;
+                energy += b as f64 * b as f64;
+            }
+            if energy > 0.0 {
+                let score = dot / energy.sqrt();
+                if score > best.0 {
+                    best = (score, lag);
+                }
+            }
+        }
+        best.1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 200 Hz tone: a pitch period of 240 samples.
+    fn tone(range: std::ops::Range<usize>) -> Vec<i16> {
+        range.map(|i| (8000.0 * (2.0 * std::f32::consts::PI * i as f32 / 240.0).sin()) as i16).collect()
+    }
+
+    fn rms(samples: &[i16]) -> f32 {
+        (samples.iter().map(|&s| (s as f32).powi(2)).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    /// A concealer that has seen two 20 ms frames of the tone.
+    fn primed() -> Concealer {
+        let mut concealer = Concealer::new();
+        for frame in tone(0..1920).chunks_mut(960) {
+            concealer.good_frame(frame);
+        }
+        concealer
+    }
+
+    #[test]
+    fn continues_the_waveform_over_one_lost_frame() {
+        let out = primed().conceal(960);
+        let expected = tone(1920..2880);
+        for (i, (&got, &want)) in out.iter().zip(&expected).enumerate() {
+            let faded = want as f32 * (1.0 - i as f32 / FADE_OUT as f32);
+            assert!((got as f32 - faded).abs() <= 2.0, "sample {}: {} vs {}", i, got, faded);
+        }
+    }
+
+    #[test]
+    fn fades_to_silence_over_several_lost_frames() {
+        let mut concealer = primed();
+        let frames: Vec<Vec<i16>> = (0..4).map(|_| concealer.conceal(960)).collect();
+        let levels: Vec<f32> = frames.iter().map(|f| rms(f)).collect();
+        assert!(levels[0] > levels[1] && levels[1] > levels[2], "levels {:?}", levels);
+        assert!(levels[2] < 0.25 * levels[0], "levels {:?}", levels);
+        assert!(frames[3].iter().all(|&s| s == 0), "silent after {} samples", FADE_OUT);
+    }
+
+    #[test]
+    fn fades_the_next_good_frame_in() {
+        let mut concealer = primed();
+        concealer.conceal(960);
+        let mut frame = vec![1000; 960];
+        concealer.good_frame(&mut frame);
+        assert_eq!(frame[0], 0);
+        assert!(frame[..FADE_IN].windows(2).all(|w| w[0] <= w[1]));
+        assert!(frame[FADE_IN..].iter().all(|&s| s == 1000));
+
+        // Without a loss before it, a good frame is left alone.
+        let mut frame = vec![1000; 960];
+        concealer.good_frame(&mut frame);
+        assert!(frame.iter().all(|&s| s == 1000));
+    }
+
+    #[test]
+    fn conceals_with_silence_before_any_audio() {
+        assert_eq!(Concealer::new().conceal(960), vec![0; 960]);
+    }
+}
@@ -0,0 +1,444 @@
+use std::env;
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::env_bool;
+use crate::fft::{fft, spectrum, Complex};
+
+/// Q of the high-pass filter: a second-order Butterworth response.
+const HIGH_PASS_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+/// How fast the noise estimate may rise while speech keeps it from being
+/// re-measured, so it follows noise that gets louder.
+const NOISE_RISE_DB_PER_SEC: f32 = 5.0;
+/// Minimum tracking lands below the mean noise power; this makes up for it.
+const NOISE_BIAS: f32 = 2.0;
+/// Weight of the previous frame in the decision-directed SNR estimate.
+const PRIOR_WEIGHT: f32 = 0.98;
+/// Least gain noise suppression applies to a bin: at most 20 dB suppression.
+const SUPPRESSION_FLOOR: f32 = 0.1;
+/// AGC gain limits, so silence is not boosted into noise and a shout is
+/// not pushed into the ground.
+const MIN_GAIN_DB: f32 = -12.0;
+const MAX_GAIN_DB: f32 = 30.0;
+/// Fastest the AGC gain changes, in dB per second.
+const GAIN_SLEW_DB_PER_SEC: f32 = 10.0;
+/// Blocks count as speech for the AGC this far above the noise floor.
+const SPEECH_ABOVE_FLOOR_DB: f32 = 10.0;
+/// Blocks this far below the speech level are the tails of words, which
+/// would drag the level down; they are left out, as in ITU-T P.56.
+const ACTIVE_BELOW_LEVEL_DB: f32 = 16.0;
+/// Nothing quieter than this counts as speech, however quiet the floor.
+const SPEECH_GATE_DBFS: f32 = -60.0;
+/// Peak level the AGC output is limited to.
+const LIMIT: f32 = 0.9 * i16::MAX as f32;
+
+/// Which stages of the capture processing chain run.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ProcessingStages {
+    pub high_pass: bool,
+    pub noise_suppression: bool,
+    pub agc: bool,
+}
+
+/// A change to some of a call's processing stages; stages left out keep
+/// their setting.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StageToggles {
+    pub high_pass: Option<bool>,
+    pub noise_suppression: Option<bool>,
+    pub agc: Option<bool>,
+}
+
+impl ProcessingStages {
+    pub fn apply(&mut self, toggles: &StageToggles) {
+        self.high_pass = toggles.high_pass.unwrap_or(self.high_pass);
+        self.noise_suppression = toggles.noise_suppression.unwrap_or(self.noise_suppression);
+        self.agc = toggles.agc.unwrap_or(self.agc);
+    }
+}
+
+/// Capture processing: the stages calls start with, the high-pass cutoff
+/// and the speech level the AGC aims for.
+#[derive(Debug, Clone)]
+pub struct ProcessingConfig {
+    pub stages: ProcessingStages,
+    pub high_pass_hz: f32,
+    pub agc_target_dbfs: f32,
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        ProcessingConfig {
+            stages: ProcessingStages {
+                high_pass: true,
+                noise_suppression: true,
+                agc: true,
+            },
+            high_pass_hz: 80.0,
+            agc_target_dbfs: -18.0,
+        }
+    }
+}
+
+impl ProcessingConfig {
+    /// Reads `VOIP_HIGH_PASS`, `VOIP_NOISE_SUPPRESSION`, `VOIP_AGC`,
+    /// `VOIP_HIGH_PASS_HZ` and `VOIP_AGC_TARGET_DBFS`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = ProcessingConfig::default();
+        for (key, field) in [
+            ("VOIP_HIGH_PASS", &mut config.stages.high_pass),
+            ("VOIP_NOISE_SUPPRESSION", &mut config.stages.noise_suppression),
+            ("VOIP_AGC", &mut config.stages.agc),
+        ] {
+            if let Some(enabled) = env_bool(key)? {
+                *field = enabled;
+            }
+        }
+        if let Ok(value) = env::var("VOIP_HIGH_PASS_HZ") {
+            config.high_pass_hz = match value.parse() {
+                Ok(hz) if (20.0..=500.0).contains(&hz) => hz,
+                _ => return Err(format!("invalid value '{}' for VOIP_HIGH_PASS_HZ", value)),
+            };
+        }
+        if let Ok(value) = env::var("VOIP_AGC_TARGET_DBFS") {
+            config.agc_target_dbfs = match value.parse() {
+                Ok(dbfs) if (-40.0..=-3.0).contains(&dbfs) => dbfs,
+                _ => return Err(format!("invalid value '{}' for VOIP_AGC_TARGET_DBFS", value)),
+            };
+        }
+        Ok(config)
+    }
+}
+
+/// Second-order high-pass biquad, for rumble, hum and DC.
+struct HighPass {
+    b: [f32; 3],
+    a: [f32; 2],
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl HighPass {
+    fn new(sample_rate: u32, cutoff_hz: f32) -> Self {
+        let w0 = 2.0 * PI * cutoff_hz / sample_rate as f32;
+        let alpha = w0.sin() / (2.0 * HIGH_PASS_Q);
+        let a0 = 1.0 + alpha;
+        let b0 = (1.0 + w0.cos()) / 2.0 / a0;
+        HighPass {
+            b: [b0, -2.0 * b0, b0],
+            a: [-2.0 * w0.cos() / a0, (1.0 - alpha) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, block: &mut [f32]) {
+        for s in block {
+            let y = self.b[0] * *s + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+            self.x = [*s, self.x[0]];
+            self.y = [y, self.y[0]];
+            *s = y;
+        }
+    }
+}
+
+/// Spectral noise suppression: a Wiener gain per frequency bin, from a
+/// minimum-tracking noise estimate and a decision-directed SNR estimate.
+/// Frames overlap by half under a square-root Hann window, which adds
+/// back up to the input where no gain is applied.
+struct NoiseSuppressor {
+    hop: usize,
+    window: Vec<f32>,
+    frame: Vec<f32>,
+    overlap: Vec<f32>,
+    /// Smoothed power, noise estimate and last clean power estimate per bin.
+    power: Vec<f32>,
+    noise: Vec<f32>,
+    clean: Vec<f32>,
+    rise: f32,
+    started: bool,
+}
+
+impl NoiseSuppressor {
+    fn new(sample_rate: u32, hop: usize) -> Self {
+        let len = 2 * hop;
+        let bins = hop + 1;
+        NoiseSuppressor {
+            hop,
+            window: (0..len).map(|n| (PI * n as f32 / len as f32).sin()).collect(),
+            frame: vec![0.0; len],
+            overlap: vec![0.0; hop],
+            power: vec![0.0; bins],
+            noise: vec![0.0; bins],
+            clean: vec![0.0; bins],
+            rise: 10f32.powf(NOISE_RISE_DB_PER_SEC / 10.0 * hop as f32 / sample_rate as f32),
+            started: false,
+        }
+    }
+
+    /// Takes the next `hop` samples and returns `hop` samples, one hop
+    /// behind. The noise estimate is kept up to date while `suppress` is off.
+    fn process(&mut self, block: &mut [f32], suppress: bool) {
+        let hop = self.hop;
+        self.frame.copy_within(hop.., 0);
+        self.frame[hop..].copy_from_slice(block);
+        let mut bins = spectrum(self.frame.iter().zip(&self.window).map(|(s, w)| s * w), 2 * hop);
+
+        for k in 0..=hop {
+            let power = bins[k].norm_sqr();
+            self.power[k] = if self.started { 0.7 * self.power[k] + 0.3 * power } else { power };
+            self.noise[k] = if !self.started || self.power[k] < self.noise[k] {
+                self.power[k]
+            } else {
+                self.noise[k] * self.rise
+            };
+
+            let noise = NOISE_BIAS * self.noise[k] + f32::EPSILON;
+            let posterior = power / noise;
+            let prior = PRIOR_WEIGHT * self.clean[k] / noise + (1.0 - PRIOR_WEIGHT) * (posterior - 1.0).max(0.0);
+            let gain = (prior / (1.0 + prior)).max(SUPPRESSION_FLOOR);
+            self.clean[k] = gain * gain * power;
+
+            if suppress {
+                bins[k] = Complex { re: bins[k].re * gain, im: bins[k].im * gain };
+                if k != 0 && k != hop {
+                    let mirror = &mut bins[2 * hop - k];
+                    *mirror = Complex { re: mirror.re * gain, im: mirror.im * gain };
+                }
+            }
+        }
+        self.started = true;
+
+        fft(&mut bins, true);
+        for (i, s) in block.iter_mut().enumerate() {
+            *s = self.overlap[i] + bins[i].re * self.window[i];
+            self.overlap[i] = bins[hop + i].re * self.window[hop + i];
+        }
+    }
+}
+
+/// Automatic gain control: brings speech to the target RMS level. The
+/// level is measured on blocks well above the noise floor only, and the
+/// gain moves slowly, so pauses are not pumped up and words not flattened.
+struct Agc {
+    target_db: f32,
+    level: Option<f32>,
+    floor_db: f32,
+    gain_db: f32,
+    slew_db: f32,
+    floor_rise_db: f32,
+}
+
+impl Agc {
+    fn new(sample_rate: u32, block: usize, target_dbfs: f32) -> Self {
+        let block_secs = block as f32 / sample_rate as f32;
+        Agc {
+            target_db: target_dbfs,
+            level: None,
+            floor_db: 0.0,
+            gain_db: 0.0,
+            slew_db: GAIN_SLEW_DB_PER_SEC * block_secs,
+            floor_rise_db: NOISE_RISE_DB_PER_SEC * block_secs,
+        }
+    }
+
+    fn process(&mut self, block: &mut [f32]) {
+        let power = block.iter().map(|s| s * s).sum::<f32>() / block.len() as f32;
+        let rms_db = dbfs(power);
+
+        if rms_db < self.floor_db {
+            self.floor_db = rms_db;
+        } else {
+            self.floor_db += self.floor_rise_db;
+        }
+        let active = self.level.is_none_or(|level| rms_db > dbfs(level) - ACTIVE_BELOW_LEVEL_DB);
+        if active && rms_db > self.floor_db + SPEECH_ABOVE_FLOOR_DB && rms_db > SPEECH_GATE_DBFS {
+            // Mean speech power, smoothed over a second or so of speech.
+            self.level = Some(match self.level {
+                Some(level) => 0.97 * level + 0.03 * power,
+                None => power,
+            });
+        }
+
+        let previous = self.gain_db;
+        if let Some(level) = self.level {
+            let wanted = (self.target_db - dbfs(level)).clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+            self.gain_db += (wanted - self.gain_db).clamp(-self.slew_db, self.slew_db);
+        }
+
+        // Ramp across the block from the old gain to the new one.
+        let (from, to) = (db_to_gain(previous), db_to_gain(self.gain_db));
+        let step = (to - from) / block.len() as f32;
+        let mut peak = 0.0f32;
+        for (i, s) in block.iter_mut().enumerate() {
+            *s *= from + step * (i + 1) as f32;
+            peak = peak.max(s.abs());
+        }
+        if peak > LIMIT {
+            let scale = LIMIT / peak;
+            block.iter_mut().for_each(|s| *s *= scale);
+        }
+    }
+}
+
+fn dbfs(power: f32) -> f32 {
+    10.0 * (power / (i16::MAX as f32).powi(2) + 1e-12).log10()
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// The processing chain for captured audio: high-pass filter, noise
+/// suppression, then AGC. Stages can be switched per block; the output
+/// lags the input by one noise suppression hop whatever is switched on.
+pub struct CaptureProcessor {
+    hop: usize,
+    high_pass: HighPass,
+    suppressor: NoiseSuppressor,
+    agc: Agc,
+    pending: Vec<i16>,
+}
+
+impl CaptureProcessor {
+    pub fn new(sample_rate: u32, config: &ProcessingConfig) -> Self {
+        // The suppressor's frames are two hops long, so the hop is 10 ms
+        // rounded up to a power of two to keep them a valid FFT size.
+        let hop = (sample_rate as usize / 100).next_power_of_two();
+        CaptureProcessor {
+            hop,
+            high_pass: HighPass::new(sample_rate, config.high_pass_hz),
+            suppressor: NoiseSuppressor::new(sample_rate, hop),
+            agc: Agc::new(sample_rate, hop, config.agc_target_dbfs),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Runs `samples` through the `stages` switched on. Output comes in
+    /// whole hops.
+    pub fn process(&mut self, samples: &[i16], stages: ProcessingStages) -> Vec<i16> {
+        self.pending.extend_from_slice(samples);
+        let mut out = Vec::with_capacity(self.pending.len());
+        while self.pending.len() >= self.hop {
+            let mut block: Vec<f32> = self.pending.drain(..self.hop).map(f32::from).collect();
+            if stages.high_pass {
+                self.high_pass.process(&mut block);
+            }
+            self.suppressor.process(&mut block, stages.noise_suppression);
+            if stages.agc {
+                self.agc.process(&mut block);
+            }
+            out.extend(block.iter().map(|s| s.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{db, mix, power, read_fixture};
+
+    const ALL: ProcessingStages = ProcessingStages { high_pass: true, noise_suppression: true, agc: true };
+    const NONE: ProcessingStages = ProcessingStages { high_pass: false, noise_suppression: false, agc: false };
+
+    fn run(rate: u32, input: &[i16], stages: ProcessingStages) -> Vec<i16> {
+        let mut processor = CaptureProcessor::new(rate, &ProcessingConfig::default());
+        let chunk = rate as usize / 100;
+        let flush = vec![0; 2 * processor.hop];
+        let mut out: Vec<i16> = input.chunks(chunk).chain(Some(&flush[..])).flat_map(|c| processor.process(c, stages)).collect();
+        // Line the output up with the input again.
+        out.drain(..processor.hop);
+        out.truncate(input.len());
+        out
+    }
+
+    fn scaled(signal: &[i16], gain: f32) -> Vec<i16> {
+        signal.iter().map(|&s| (s as f32 * gain).round() as i16).collect()
+    }
+
+    /// 10 ms frames of the clean speech fixture from `from_secs` on, split
+    /// into those with speech and those in the pauses.
+    fn frames(rate: u32, speech: &[i16], from_secs: f32) -> (Vec<std::ops::Range<usize>>, Vec<std::ops::Range<usize>>) {
+        let len = rate as usize / 100;
+        let loudest = speech.chunks(len).map(power).fold(0.0, f64::max);
+        (0..speech.len() / len)
+            .map(|i| i * len..(i + 1) * len)
+            .filter(|r| r.start as f32 >= from_secs * rate as f32)
+            .partition(|r| power(&speech[r.clone()]) > loudest * 0.01)
+    }
+
+    fn mean_power(signal: &[i16], frames: &[std::ops::Range<usize>]) -> f64 {
+        frames.iter().map(|r| power(&signal[r.clone()])).sum::<f64>() / frames.len() as f64
+    }
+
+    /// Speech to noise: the power in speech frames above the power in the
+    /// pauses, over the power in the pauses.
+    fn snr(signal: &[i16], speech: &[std::ops::Range<usize>], pauses: &[std::ops::Range<usize>]) -> f64 {
+        let noise = mean_power(signal, pauses);
+        db((mean_power(signal, speech) - noise) / noise)
+    }
+
+    #[test]
+    fn suppresses_noise_and_keeps_speech() {
+        let (rate, speech) = read_fixture("processing/speech.wav");
+        let (_, noise) = read_fixture("processing/noise.wav");
+        let noisy = mix(&speech, &noise);
+        let stages = ProcessingStages { agc: false, ..ALL };
+        let out = run(rate, &noisy, stages);
+
+        // Past the first second, by which the noise estimate has settled.
+        let (talk, pauses) = frames(rate, &speech, 1.0);
+        let before = snr(&noisy, &talk, &pauses);
+        let after = snr(&out, &talk, &pauses);
+        assert!(after > before + 10.0, "SNR {:.1} dB, {:.1} dB before", after, before);
+
+        let loss = db(mean_power(&speech, &talk) / mean_power(&out, &talk));
+        assert!(loss < 3.0, "speech attenuated by {:.1} dB", loss);
+    }
+
+    #[test]
+    fn brings_quiet_and_loud_speech_to_target_level() {
+        let (rate, speech) = read_fixture("processing/speech.wav");
+        let (_, noise) = read_fixture("processing/noise.wav");
+        let (talk, _) = frames(rate, &speech, 3.0);
+        let target = ProcessingConfig::default().agc_target_dbfs as f64;
+
+        for gain in [0.1, 2.5] {
+            let input = mix(&scaled(&speech, gain), &scaled(&noise, gain));
+            let out = run(rate, &input, ALL);
+            let level = db(mean_power(&out, &talk) / (i16::MAX as f64).powi(2));
+            assert!((level - target).abs() < 3.0, "speech at {:.1} dBFS for input gain {}", level, gain);
+        }
+    }
+
+    #[test]
+    fn high_pass_removes_rumble() {
+        let rate = 16000;
+        let sine = |hz: f32| -> Vec<i16> {
+            (0..2 * rate).map(|i| (3000.0 * (2.0 * PI * hz * i as f32 / rate as f32).sin()) as i16).collect()
+        };
+        let stages = ProcessingStages { high_pass: true, ..NONE };
+        let settled = rate as usize / 2..rate as usize * 3 / 2;
+
+        let hum = sine(30.0);
+        let out = run(rate, &hum, stages);
+        let removed = db(power(&hum[settled.clone()]) / power(&out[settled.clone()]));
+        assert!(removed > 12.0, "30 Hz down by {:.1} dB", removed);
+
+        let voice = sine(1000.0);
+        let out = run(rate, &voice, stages);
+        let changed = db(power(&voice[settled.clone()]) / power(&out[settled]));
+        assert!(changed.abs() < 0.5, "1 kHz changed by {:.1} dB", changed);
+    }
+
+    #[test]
+    fn passes_audio_through_with_every_stage_off() {
+        let (rate, speech) = read_fixture("processing/speech.wav");
+        let out = run(rate, &speech, NONE);
+        for (x, y) in speech.iter().zip(&out) {
+            assert!((x - y).abs() <= 1, "{} became {}", x, y);
+        }
+    }
+}
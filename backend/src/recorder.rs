@@ -0,0 +1,398 @@
+use std::{
+    collections::VecDeque,
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+
+use crate::codec::SAMPLE_RATE;
+
+/// One channel may run this far ahead of the other (1 s at 48 kHz) before
+/// the lagging one is padded with silence, e.g. while the remote side sends
+/// nothing.
+const MAX_SKEW: usize = 48000;
+
+const OGG_FRAME: usize = 960;
+const OGG_BITRATE: i32 = 64000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordingFormat {
+    Wav,
+    Ogg,
+}
+
+impl RecordingFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            RecordingFormat::Wav => "wav",
+            RecordingFormat::Ogg => "ogg",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordingConfig {
+    pub dir: PathBuf,
+    pub format: RecordingFormat,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        RecordingConfig {
+            dir: PathBuf::from("recordings"),
+            format: RecordingFormat::Wav,
+        }
+    }
+}
+
+impl RecordingConfig {
+    /// Reads `VOIP_RECORDING_DIR` and `VOIP_RECORDING_FORMAT` (`wav` or `ogg`).
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = RecordingConfig::default();
+        if let Ok(dir) = env::var("VOIP_RECORDING_DIR") {
+            config.dir = PathBuf::from(dir);
+        }
+        if let Ok(format) = env::var("VOIP_RECORDING_FORMAT") {
+            config.format = match format.to_ascii_lowercase().as_str() {
+                "wav" => RecordingFormat::Wav,
+                "ogg" => RecordingFormat::Ogg,
+                _ => return Err(format!("unknown recording format '{}'", format)),
+            };
+        }
+        Ok(config)
+    }
+
+    /// `<dir>/<call_id>.<ext>`, numbered if the call was already recorded
+    /// so an earlier file is never overwritten.
+    fn path_for(&self, call_id: &str) -> PathBuf {
+        let ext = self.format.extension();
+        let mut path = self.dir.join(format!("{}.{}", call_id, ext));
+        let mut n = 2;
+        while path.exists() {
+            path = self.dir.join(format!("{}-{}.{}", call_id, n, ext));
+            n += 1;
+        }
+        path
+    }
+}
+
+/// Writes a stereo recording of a call, caller on the left channel and
+/// callee on the right whichever side this server runs media for. The two
+/// channels arrive independently and are interleaved as both have audio
+/// available.
+pub struct Recorder {
+    path: PathBuf,
+    sink: Box<dyn Sink>,
+    local_is_caller: bool,
+    left: VecDeque<i16>,
+    right: VecDeque<i16>,
+}
+
+impl Recorder {
+    /// `local_is_caller` says whether local capture is the caller's audio,
+    /// and so goes on the left, or the callee's.
+    pub fn create(config: &RecordingConfig, call_id: &str, local_is_caller: bool) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let path = config.path_for(call_id);
+        let sink: Box<dyn Sink> = match config.format {
+            RecordingFormat::Wav => Box::new(WavSink::create(&path)?),
+            RecordingFormat::Ogg => Box::new(OggSink::create(&path)?),
+        };
+        Ok(Recorder {
+            path,
+            sink,
+            local_is_caller,
+            left: VecDeque::new(),
+            right: VecDeque::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queues locally captured audio.
+    pub fn push_local(&mut self, samples: &[i16]) -> io::Result<()> {
+        if self.local_is_caller {
+            self.left.extend(samples);
+        } else {
+            self.right.extend(samples);
+        }
+        self.write_ready()
+    }
+
+    /// Queues audio received from the other party.
+    pub fn push_remote(&mut self, samples: &[i16]) -> io::Result<()> {
+        if self.local_is_caller {
+            self.right.extend(samples);
+        } else {
+            self.left.extend(samples);
+        }
+        self.write_ready()
+    }
+
+    /// Writes out whatever is still queued and closes the file.
+    pub fn finish(mut self) -> io::Result<PathBuf> {
+        let len = self.left.len().max(self.right.len());
+        self.left.resize(len, 0);
+        self.right.resize(len, 0);
+        self.write_ready()?;
+        self.sink.finish()?;
+        Ok(self.path)
+    }
+
+    fn write_ready(&mut self) -> io::Result<()> {
+        let (longer, shorter) = if self.left.len() > self.right.len() {
+            (self.left.len(), &mut self.right)
+        } else {
+            (self.right.len(), &mut self.left)
+        };
+        if longer - shorter.len() > MAX_SKEW {
+            shorter.resize(longer - MAX_SKEW, 0);
+        }
+
+        let ready = self.left.len().min(self.right.len());
+        if ready == 0 {
+            return Ok(());
+        }
+        let interleaved: Vec<i16> = self
+            .left
+            .drain(..ready)
+            .zip(self.right.drain(..ready))
+            .flat_map(|(l, r)| [l, r])
+            .collect();
+        self.sink.write(&interleaved)
+    }
+}
+
+trait Sink: Send {
+    /// Appends interleaved stereo samples.
+    fn write(&mut self, samples: &[i16]) -> io::Result<()>;
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// 16-bit stereo PCM WAV. The header is written up front with empty sizes
+/// and patched when the recording is finished.
+struct WavSink {
+    file: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavSink {
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut sink = WavSink {
+            file: BufWriter::new(File::create(path)?),
+            data_len: 0,
+        };
+        sink.write_header()?;
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let channels: u16 = 2;
+        let block_align = channels * 2;
+        let f = &mut self.file;
+        f.write_all(b"RIFF")?;
+        f.write_all(&(36 + self.data_len).to_le_bytes())?;
+        f.write_all(b"WAVEfmt ")?;
+        f.write_all(&16u32.to_le_bytes())?;
+        f.write_all(&1u16.to_le_bytes())?;
+        f.write_all(&channels.to_le_bytes())?;
+        f.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        f.write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+        f.write_all(&block_align.to_le_bytes())?;
+        f.write_all(&16u16.to_le_bytes())?;
+        f.write_all(b"data")?;
+        f.write_all(&self.data_len.to_le_bytes())
+    }
+}
+
+impl Sink for WavSink {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len = self.data_len.saturating_add(samples.len() as u32 * 2);
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+/// Stereo Opus in an Ogg container (RFC 7845), 20 ms per packet.
+struct OggSink {
+    writer: PacketWriter<'static, BufWriter<File>>,
+    encoder: opus::Encoder,
+    serial: u32,
+    pending: Vec<i16>,
+    pre_skip: u64,
+    frames: u64,
+    samples: u64,
+}
+
+impl OggSink {
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut encoder = opus::Encoder::new(SAMPLE_RATE, opus::Channels::Stereo, opus::Application::Audio)
+            .map_err(io::Error::other)?;
+        encoder
+            .set_bitrate(opus::Bitrate::Bits(OGG_BITRATE))
+            .map_err(io::Error::other)?;
+        let pre_skip = encoder.get_lookahead().map_err(io::Error::other)? as u16;
+
+        let mut writer = PacketWriter::new(BufWriter::new(File::create(path)?));
+        let serial = uuid::Uuid::new_v4().as_u128() as u32;
+
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1);
+        head.push(2);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(0);
+        writer.write_packet(head, serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        let vendor = b"voip-backend";
+        let mut tags = Vec::new();
+        tags.extend_from_slice(b"OpusTags");
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor);
+        tags.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_packet(tags, serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        Ok(OggSink {
+            writer,
+            encoder,
+            serial,
+            pending: Vec::new(),
+            pre_skip: pre_skip as u64,
+            frames: 0,
+            samples: 0,
+        })
+    }
+
+    fn write_frame(&mut self, end: PacketWriteEndInfo) -> io::Result<()> {
+        let frame: Vec<i16> = self.pending.drain(..OGG_FRAME * 2).collect();
+        let packet = self.encoder.encode_vec(&frame, 4000).map_err(io::Error::other)?;
+        self.frames += 1;
+        let mut granule = self.frames * OGG_FRAME as u64;
+        if end == PacketWriteEndInfo::EndStream {
+            // Trim the padding of the last frame.
+            granule = granule.min(self.pre_skip + self.samples);
+        }
+        self.writer.write_packet(packet, self.serial, end, granule)
+    }
+}
+
+impl Sink for OggSink {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        self.pending.extend_from_slice(samples);
+        self.samples += samples.len() as u64 / 2;
+        while self.pending.len() >= OGG_FRAME * 2 {
+            self.write_frame(PacketWriteEndInfo::NormalPacket)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.pending.resize(OGG_FRAME * 2, 0);
+        self.write_frame(PacketWriteEndInfo::EndStream)?;
+        self.writer.inner_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn wav_config() -> (RecordingConfig, TempDir) {
+        let dir = std::env::temp_dir().join(format!("voip-recordings-{}", uuid::Uuid::new_v4()));
+        let config = RecordingConfig { dir: dir.clone(), format: RecordingFormat::Wav };
+        (config, TempDir(dir))
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    /// The interleaved samples of a finished WAV recording.
+    fn wav_samples(bytes: &[u8]) -> Vec<i16> {
+        bytes[44..].chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+    }
+
+    #[test]
+    fn writes_a_stereo_wav_header() {
+        let (config, _dir) = wav_config();
+        let mut recorder = Recorder::create(&config, "call", false).unwrap();
+        recorder.push_local(&[1; 100]).unwrap();
+        recorder.push_remote(&[2; 100]).unwrap();
+        let path = recorder.finish().unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 400);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 400);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 16), 16);
+        assert_eq!(u16_at(&bytes, 20), 1, "PCM");
+        assert_eq!(u16_at(&bytes, 22), 2, "channels");
+        assert_eq!(u32_at(&bytes, 24), SAMPLE_RATE);
+        assert_eq!(u32_at(&bytes, 28), SAMPLE_RATE * 4, "byte rate");
+        assert_eq!(u16_at(&bytes, 32), 4, "block align");
+        assert_eq!(u16_at(&bytes, 34), 16, "bits per sample");
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 400);
+    }
+
+    #[test]
+    fn interleaves_caller_left_and_callee_right() {
+        let (config, _dir) = wav_config();
+
+        let mut callee_side = Recorder::create(&config, "call", false).unwrap();
+        callee_side.push_local(&[10, 11, 12]).unwrap();
+        callee_side.push_remote(&[20, 21]).unwrap();
+        let path = callee_side.finish().unwrap();
+        assert_eq!(wav_samples(&fs::read(&path).unwrap()), [20, 10, 21, 11, 0, 12]);
+
+        let mut caller_side = Recorder::create(&config, "call", true).unwrap();
+        caller_side.push_remote(&[20, 21]).unwrap();
+        caller_side.push_local(&[10, 11]).unwrap();
+        let second = caller_side.finish().unwrap();
+        assert_ne!(second, path, "an earlier recording is never overwritten");
+        assert_eq!(wav_samples(&fs::read(&second).unwrap()), [10, 20, 11, 21]);
+    }
+
+    #[test]
+    fn pads_a_silent_side_once_the_other_runs_ahead() {
+        let (config, _dir) = wav_config();
+        let mut recorder = Recorder::create(&config, "call", true).unwrap();
+        recorder.push_local(&vec![5; MAX_SKEW + 960]).unwrap();
+        assert_eq!(recorder.left.len(), MAX_SKEW);
+        assert!(recorder.right.is_empty());
+
+        recorder.push_remote(&[7; 960]).unwrap();
+        let samples = wav_samples(&fs::read(recorder.finish().unwrap()).unwrap());
+        assert_eq!(samples.len(), (MAX_SKEW + 960) * 2);
+        assert_eq!(&samples[..4], [5, 0, 5, 0]);
+        assert_eq!(samples[1920 + 1], 7, "remote audio resumes after the padding");
+    }
+}
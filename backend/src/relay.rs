@@ -0,0 +1,164 @@
+use std::{
+    error::Error,
+    net::{SocketAddr, UdpSocket as StdUdpSocket},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+use crate::packet::MAX_PACKET_LEN;
+
+/// Server ports each party should send its media to when a call is relayed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RelayPorts {
+    pub caller_port: u16,
+    pub callee_port: u16,
+}
+
+/// The two sockets of a relayed call, bound when the call is accepted and
+/// handed to the UDP audio task to run.
+#[derive(Debug, Clone)]
+pub struct RelayLegs {
+    caller: Arc<StdUdpSocket>,
+    callee: Arc<StdUdpSocket>,
+}
+
+impl RelayLegs {
+    pub fn bind() -> std::io::Result<Self> {
+        let caller = StdUdpSocket::bind("0.0.0.0:0")?;
+        let callee = StdUdpSocket::bind("0.0.0.0:0")?;
+        caller.set_nonblocking(true)?;
+        callee.set_nonblocking(true)?;
+        Ok(RelayLegs {
+            caller: Arc::new(caller),
+            callee: Arc::new(callee),
+        })
+    }
+
+    pub fn ports(&self) -> std::io::Result<RelayPorts> {
+        Ok(RelayPorts {
+            caller_port: self.caller.local_addr()?.port(),
+            callee_port: self.callee.local_addr()?.port(),
+        })
+    }
+}
+
+/// Forwards datagrams between the two legs of a call. Each leg latches onto
+/// the source of the first packet it receives and ignores other senders;
+/// media for a party is sent from that party's own leg so the path is
+/// symmetric through NATs.
+pub async fn relay_task(
+    call_id: String,
+    legs: RelayLegs,
+    cancel_token: CancellationToken,
+) -> Result<(), Box<dyn Error>> {
+    let caller = UdpSocket::from_std(legs.caller.try_clone()?)?;
+    let callee = UdpSocket::from_std(legs.callee.try_clone()?)?;
+    drop(legs);
+
+    let mut caller_addr: Option<SocketAddr> = None;
+    let mut callee_addr: Option<SocketAddr> = None;
+    let mut caller_buf = [0u8; MAX_PACKET_LEN];
+    let mut callee_buf = [0u8; MAX_PACKET_LEN];
+    let mut forwarded = (0u64, 0u64);
+
+    log::info!("🔁 Relay for call {} on ports {} / {}",
+        call_id,
+        caller.local_addr()?.port(),
+        callee.local_addr()?.port()
+    );
+
+    loop {
+        tokio::select! {
+            recv = caller.recv_from(&mut caller_buf) => {
+                let Ok((size, addr)) = recv else { continue };
+                if !latch(&call_id, "caller", &mut caller_addr, addr) {
+                    continue;
+                }
+                if let Some(dst) = callee_addr {
+                    let _ = callee.send_to(&caller_buf[..size], dst).await;
+                    forwarded.0 += 1;
+                }
+            }
+            recv = callee.recv_from(&mut callee_buf) => {
+                let Ok((size, addr)) = recv else { continue };
+                if !latch(&call_id, "callee", &mut callee_addr, addr) {
+                    continue;
+                }
+                if let Some(dst) = caller_addr {
+                    let _ = caller.send_to(&callee_buf[..size], dst).await;
+                    forwarded.1 += 1;
+                }
+            }
+            _ = cancel_token.cancelled() => break,
+        }
+    }
+
+    log::info!("🔁 Relay for call {} stopped | Caller -> callee: {} | Callee -> caller: {}",
+        call_id, forwarded.0, forwarded.1);
+    Ok(())
+}
+
+fn latch(call_id: &str, party: &str, latched: &mut Option<SocketAddr>, addr: SocketAddr) -> bool {
+    match latched {
+        Some(existing) => *existing == addr,
+        None => {
+            log::info!("🔁 Relay for call {} latched {} at {}", call_id, party, addr);
+            *latched = Some(addr);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn client() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    /// The next datagram `socket` receives and the port it came from.
+    async fn recv(socket: &UdpSocket) -> Option<(Vec<u8>, u16)> {
+        let mut buf = [0u8; 64];
+        let (size, from) = tokio::time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await.ok()?.unwrap();
+        Some((buf[..size].to_vec(), from.port()))
+    }
+
+    #[tokio::test]
+    async fn forwards_between_the_first_senders_on_each_leg() {
+        let legs = RelayLegs::bind().unwrap();
+        let ports = legs.ports().unwrap();
+        let token = CancellationToken::new();
+        let relay = tokio::spawn({
+            let token = token.clone();
+            async move { relay_task("c1".into(), legs, token).await.is_ok() }
+        });
+        let (caller, callee, intruder) = (client().await, client().await, client().await);
+        let caller_leg = ("127.0.0.1", ports.caller_port);
+        let callee_leg = ("127.0.0.1", ports.callee_port);
+
+        // Nothing to forward to until the callee has latched. The pause lets
+        // the relay latch the caller before the callee's packet arrives.
+        caller.send_to(b"early", caller_leg).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        callee.send_to(b"hi", callee_leg).await.unwrap();
+        assert_eq!(recv(&caller).await, Some((b"hi".to_vec(), ports.caller_port)));
+
+        caller.send_to(b"hello", caller_leg).await.unwrap();
+        assert_eq!(recv(&callee).await, Some((b"hello".to_vec(), ports.callee_port)));
+
+        // Other senders on a latched leg are ignored.
+        intruder.send_to(b"spoof", caller_leg).await.unwrap();
+        intruder.send_to(b"spoof", callee_leg).await.unwrap();
+        assert_eq!(recv(&callee).await, None);
+        assert_eq!(recv(&caller).await, None);
+        assert_eq!(recv(&intruder).await, None);
+
+        token.cancel();
+        assert!(relay.await.unwrap());
+    }
+}
@@ -0,0 +1,245 @@
+use std::f32::consts::PI;
+
+/// Windowed-sinc (Hamming) low-pass FIR with unity DC gain. `cutoff` is a
+/// fraction of the sample rate (0.0 - 0.5).
+fn lowpass(taps: usize, cutoff: f32) -> Vec<f32> {
+    let mid = (taps - 1) as f32 / 2.0;
+    let mut h: Vec<f32> = (0..taps)
+        .map(|i| {
+            let x = i as f32 - mid;
+            let sinc = if x == 0.0 {
+                2.0 * cutoff
+            } else {
+                (2.0 * PI * cutoff * x).sin() / (PI * x)
+            };
+            let window = 0.54 - 0.46 * (2.0 * PI * i as f32 / (taps - 1) as f32).cos();
+            sinc * window
+        })
+        .collect();
+    let sum: f32 = h.iter().sum();
+    for tap in &mut h {
+        *tap /= sum;
+    }
+    h
+}
+
+fn to_i16(value: f32) -> i16 {
+    value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// Integer-ratio decimator (e.g. 48 kHz -> 8 kHz with `factor` 6). Keeps
+/// filter history between calls so frames can be fed one at a time.
+pub struct Downsampler {
+    factor: usize,
+    taps: Vec<f32>,
+    history: Vec<f32>,
+    next: usize,
+}
+
+impl Downsampler {
+    pub fn new(factor: usize) -> Self {
+        let taps = lowpass(factor * 20 + 1, 0.45 / factor as f32);
+        let history = vec![0.0; taps.len() - 1];
+        let next = taps.len() - 1;
+        Self {
+            factor,
+            taps,
+            history,
+            next,
+        }
+    }
+
+    pub fn process(&mut self, input: &[i16]) -> Vec<i16> {
+        let n = self.taps.len();
+        self.history.extend(input.iter().map(|&s| s as f32));
+
+        let mut out = Vec::with_capacity(input.len() / self.factor + 1);
+        let mut pos = self.next;
+        while pos < self.history.len() {
+            let window = &self.history[pos + 1 - n..=pos];
+            let acc: f32 = self.taps.iter().zip(window.iter().rev()).map(|(h, x)| h * x).sum();
+            out.push(to_i16(acc));
+            pos += self.factor;
+        }
+
+        let consumed = self.history.len() - (n - 1);
+        self.history.drain(..consumed);
+        self.next = pos - consumed;
+        out
+    }
+}
+
+/// Integer-ratio interpolator (e.g. 8 kHz -> 48 kHz with `factor` 6), the
+/// polyphase counterpart of `Downsampler`.
+pub struct Upsampler {
+    factor: usize,
+    taps: Vec<f32>,
+    history: Vec<f32>,
+}
+
+impl Upsampler {
+    pub fn new(factor: usize) -> Self {
+        let taps = lowpass(factor * 20 + 1, 0.45 / factor as f32);
+        let history = vec![0.0; taps.len().div_ceil(factor)];
+        Self {
+            factor,
+            taps,
+            history,
+        }
+    }
+
+    pub fn process(&mut self, input: &[i16]) -> Vec<i16> {
+        let mut out = Vec::with_capacity(input.len() * self.factor);
+        for &sample in input {
+            self.history.rotate_right(1);
+            self.history[0] = sample as f32;
+
+            for phase in 0..self.factor {
+                let acc: f32 = self
+                    .taps
+                    .iter()
+                    .skip(phase)
+                    .step_by(self.factor)
+                    .zip(&self.history)
+                    .map(|(h, x)| h * x)
+                    .sum();
+                out.push(to_i16(acc * self.factor as f32));
+            }
+        }
+        out
+    }
+}
+
+/// Rational-ratio resampler between any two rates (e.g. 44.1 kHz -> 48 kHz
+/// is 160/147), as a polyphase FIR over the interpolated rate. Keeps
+/// filter history between calls like the integer-ratio filters above.
+pub struct Resampler {
+    up: usize,
+    down: usize,
+    taps: Vec<f32>,
+    taps_per_phase: usize,
+    history: Vec<f32>,
+    /// Position of the next output sample in interpolated-rate units,
+    /// counted from the start of `history`.
+    pos: usize,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Self {
+        let divisor = gcd(from_rate as usize, to_rate as usize);
+        let (up, down) = (to_rate as usize / divisor, from_rate as usize / divisor);
+        let taps_per_phase = if up == down { 1 } else { 24 };
+        let taps = if up == down {
+            vec![1.0]
+        } else {
+            lowpass(up * taps_per_phase, 0.45 / up.max(down) as f32)
+        };
+        Self {
+            up,
+            down,
+            taps,
+            taps_per_phase,
+            history: vec![0.0; taps_per_phase - 1],
+            pos: (taps_per_phase - 1) * up,
+        }
+    }
+
+    pub fn process(&mut self, input: &[i16]) -> Vec<i16> {
+        if self.up == self.down {
+            return input.to_vec();
+        }
+        self.history.extend(input.iter().map(|&s| s as f32));
+
+        let mut out = Vec::with_capacity(input.len() * self.up / self.down + 1);
+        while self.pos / self.up < self.history.len() {
+            let (index, phase) = (self.pos / self.up, self.pos % self.up);
+            let acc: f32 = (0..self.taps_per_phase)
+                .map(|k| self.taps[phase + k * self.up] * self.history[index - k])
+                .sum();
+            out.push(to_i16(acc * self.up as f32));
+            self.pos += self.down;
+        }
+
+        let consumed = self.history.len() - (self.taps_per_phase - 1);
+        self.history.drain(..consumed);
+        self.pos -= consumed * self.up;
+        out
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Averages interleaved frames of `channels` samples down to mono.
+pub fn downmix(interleaved: &[i16], channels: usize) -> Vec<i16> {
+    if channels <= 1 {
+        return interleaved.to_vec();
+    }
+    interleaved
+        .chunks_exact(channels)
+        .map(|frame| (frame.iter().map(|&s| s as i32).sum::<i32>() / channels as i32) as i16)
+        .collect()
+}
+
+/// Copies each mono sample to all `channels` of an interleaved frame.
+pub fn upmix(mono: &[i16], channels: usize) -> Vec<i16> {
+    mono.iter()
+        .flat_map(|&s| std::iter::repeat_n(s, channels.max(1)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, rate: u32, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|i| (10000.0 * (2.0 * PI * freq * i as f32 / rate as f32).sin()) as i16)
+            .collect()
+    }
+
+    /// Amplitude of `freq` in `signal`, by correlation with a sine and cosine.
+    fn amplitude(signal: &[i16], freq: f32, rate: u32) -> f32 {
+        let (mut re, mut im) = (0.0f32, 0.0f32);
+        for (i, &s) in signal.iter().enumerate() {
+            let phase = 2.0 * PI * freq * i as f32 / rate as f32;
+            re += s as f32 * phase.cos();
+            im += s as f32 * phase.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / signal.len() as f32
+    }
+
+    #[test]
+    fn converts_between_44100_and_48000() {
+        for (from, to) in [(44100, 48000), (48000, 44100), (16000, 48000), (48000, 22050)] {
+            let input = sine(1000.0, from, from as usize);
+            let mut resampler = Resampler::new(from, to);
+            // Fed in device-sized blocks, as the audio callbacks do.
+            let output: Vec<i16> = input.chunks(441).flat_map(|block| resampler.process(block)).collect();
+
+            let expected = to as usize;
+            assert!(output.len().abs_diff(expected) <= 1, "{} -> {}: {} samples", from, to, output.len());
+            let settled = &output[to as usize / 10..];
+            let tone = amplitude(settled, 1000.0, to);
+            assert!((tone - 10000.0).abs() < 300.0, "{} -> {}: 1 kHz at {}", from, to, tone);
+        }
+    }
+
+    #[test]
+    fn equal_rates_pass_through() {
+        let input = sine(440.0, 48000, 960);
+        assert_eq!(Resampler::new(48000, 48000).process(&input), input);
+    }
+
+    #[test]
+    fn maps_channels() {
+        assert_eq!(downmix(&[100, 300, -50, 50], 2), [200, 0]);
+        assert_eq!(upmix(&[1, 2], 2), [1, 1, 2, 2]);
+        assert_eq!(downmix(&[5, 6], 1), [5, 6]);
+    }
+}
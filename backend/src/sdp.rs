@@ -0,0 +1,764 @@
+use std::{env, fmt, str::FromStr};
+
+/// Payload types with an assigned encoding (RFC 3551), which may appear
+/// without an `a=rtpmap` line.
+const STATIC_PAYLOAD_TYPES: [(u8, &str, u32); 6] = [
+    (0, "PCMU", 8000),
+    (3, "GSM", 8000),
+    (8, "PCMA", 8000),
+    (9, "G722", 8000),
+    (13, "CN", 8000),
+    (18, "G729", 8000),
+];
+
+/// Which codecs the server lets through in audio sections.
+#[derive(Debug, Clone, Default)]
+pub struct SdpConfig {
+    /// Encoding names (case-insensitive). Empty allows any codec.
+    pub codecs: Vec<String>,
+}
+
+impl SdpConfig {
+    /// Reads `VOIP_SDP_CODECS`, a comma-separated list of encoding names
+    /// such as `opus,PCMU,telephone-event`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = SdpConfig::default();
+        if let Ok(value) = env::var("VOIP_SDP_CODECS") {
+            config.codecs = value
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect();
+            if config.codecs.is_empty() {
+                return Err(format!("invalid value '{}' for VOIP_SDP_CODECS", value));
+            }
+        }
+        Ok(config)
+    }
+
+    fn allows(&self, codec: &Codec) -> bool {
+        self.codecs.is_empty() || self.codecs.iter().any(|name| name.eq_ignore_ascii_case(&codec.name))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SdpError {
+    /// A line that does not parse, numbered from 1.
+    Syntax { line: usize, message: String },
+    /// Well-formed, but missing something a WebRTC session needs.
+    Invalid(String),
+    /// Nothing left in an audio section once the codec policy is applied.
+    NoAcceptableCodec(String),
+}
+
+impl fmt::Display for SdpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SdpError::Syntax { line, message } => write!(f, "SDP line {}: {}", line, message),
+            SdpError::Invalid(message) => write!(f, "Invalid SDP: {}", message),
+            SdpError::NoAcceptableCodec(offered) => {
+                write!(f, "No acceptable audio codec (offered: {})", offered)
+            }
+        }
+    }
+}
+
+impl SdpError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SdpError::Syntax { .. } | SdpError::Invalid(_) => "invalid_sdp",
+            SdpError::NoAcceptableCodec(_) => "unsupported_codec",
+        }
+    }
+}
+
+impl std::error::Error for SdpError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Direction {
+    fn from_attribute(name: &str) -> Option<Self> {
+        match name {
+            "sendrecv" => Some(Direction::SendRecv),
+            "sendonly" => Some(Direction::SendOnly),
+            "recvonly" => Some(Direction::RecvOnly),
+            "inactive" => Some(Direction::Inactive),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::SendRecv => "sendrecv",
+            Direction::SendOnly => "sendonly",
+            Direction::RecvOnly => "recvonly",
+            Direction::Inactive => "inactive",
+        }
+    }
+}
+
+/// `o=<username> <sess-id> <sess-version> <nettype> <addrtype> <address>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub username: String,
+    pub session_id: String,
+    pub session_version: u64,
+    pub net_type: String,
+    pub addr_type: String,
+    pub address: String,
+}
+
+/// `c=<nettype> <addrtype> <address>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connection {
+    pub net_type: String,
+    pub addr_type: String,
+    pub address: String,
+}
+
+/// `a=fingerprint:<hash-function> <hex bytes>` (RFC 8122)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    pub algorithm: String,
+    pub value: String,
+}
+
+/// A payload type with its `a=rtpmap` and `a=fmtp`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Codec {
+    pub payload_type: u8,
+    pub name: String,
+    pub clock_rate: u32,
+    pub channels: Option<u8>,
+    pub fmtp: Option<String>,
+}
+
+/// An attribute without a typed field, kept so it is written back out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// One `m=` section.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaDescription {
+    /// `audio`, `video` or `application`.
+    pub kind: String,
+    /// 0 for a rejected or disabled section.
+    pub port: u16,
+    pub port_count: Option<u16>,
+    pub protocol: String,
+    /// Formats from the `m=` line, in preference order.
+    pub formats: Vec<String>,
+    /// The formats that are RTP payload types, in the same order.
+    pub codecs: Vec<Codec>,
+    pub connection: Option<Connection>,
+    pub mid: Option<String>,
+    pub ice_ufrag: Option<String>,
+    pub ice_pwd: Option<String>,
+    pub fingerprint: Option<Fingerprint>,
+    pub direction: Option<Direction>,
+    pub attributes: Vec<Attribute>,
+    /// Other lines (`i=`, `b=`, `k=`) as type and value.
+    pub lines: Vec<(char, String)>,
+}
+
+/// A parsed offer or answer (RFC 4566, with the attributes WebRTC uses).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionDescription {
+    pub origin: Origin,
+    pub session_name: String,
+    pub connection: Option<Connection>,
+    pub ice_ufrag: Option<String>,
+    pub ice_pwd: Option<String>,
+    pub fingerprint: Option<Fingerprint>,
+    /// Session-level default for sections without their own direction.
+    pub direction: Option<Direction>,
+    pub attributes: Vec<Attribute>,
+    /// Other lines (`t=`, `b=`, `i=`, ...) as type and value.
+    pub lines: Vec<(char, String)>,
+    pub media: Vec<MediaDescription>,
+}
+
+impl MediaDescription {
+    /// Whether the section is keyed by DTLS, as SRTP media
+    /// (`UDP/TLS/RTP/SAVPF`) and data channels (`UDP/DTLS/SCTP`) are, and
+    /// so needs a fingerprint.
+    pub fn uses_dtls(&self) -> bool {
+        self.protocol.split('/').any(|part| part == "TLS" || part == "DTLS")
+    }
+}
+
+impl SessionDescription {
+    /// The direction that applies to `media`: its own, else the session's,
+    /// else `sendrecv`.
+    pub fn direction_of(&self, media: &MediaDescription) -> Direction {
+        media.direction.or(self.direction).unwrap_or(Direction::SendRecv)
+    }
+
+    /// Whether this offer puts the call on hold: every enabled audio section
+    /// is `sendonly` or `inactive` (RFC 3264 section 8.4).
+    pub fn is_hold(&self) -> bool {
+        let mut audio = self.media.iter().filter(|m| m.kind == "audio" && m.port != 0).peekable();
+        audio.peek().is_some()
+            && audio.all(|m| matches!(self.direction_of(m), Direction::SendOnly | Direction::Inactive))
+    }
+
+    /// Checks what a WebRTC peer needs beyond well-formed lines: a `t=`
+    /// line, media, ICE credentials for every enabled section, and a
+    /// fingerprint for every enabled one keyed by DTLS.
+    pub fn validate(&self) -> Result<(), SdpError> {
+        if !self.lines.iter().any(|(t, _)| *t == 't') {
+            return Err(SdpError::Invalid("missing t= line".to_string()));
+        }
+        if self.media.is_empty() {
+            return Err(SdpError::Invalid("no m= sections".to_string()));
+        }
+        for (index, media) in self.media.iter().enumerate().filter(|(_, m)| m.port != 0) {
+            let section = format!("m= section {} ({})", index + 1, media.kind);
+            if media.ice_ufrag.as_ref().or(self.ice_ufrag.as_ref()).is_none()
+                || media.ice_pwd.as_ref().or(self.ice_pwd.as_ref()).is_none()
+            {
+                return Err(SdpError::Invalid(format!("{} has no ICE ufrag/pwd", section)));
+            }
+            if media.uses_dtls() && media.fingerprint.as_ref().or(self.fingerprint.as_ref()).is_none() {
+                return Err(SdpError::Invalid(format!("{} has no DTLS fingerprint", section)));
+            }
+            if media.connection.as_ref().or(self.connection.as_ref()).is_none() {
+                return Err(SdpError::Invalid(format!("{} has no c= line", section)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops audio codecs `config` does not allow, along with their
+    /// `a=rtcp-fb` lines, and returns whether any were dropped. Fails if an
+    /// enabled audio section has no voice codec left.
+    pub fn apply_codec_policy(&mut self, config: &SdpConfig) -> Result<bool, SdpError> {
+        let mut changed = false;
+        for media in self.media.iter_mut().filter(|m| m.kind == "audio" && m.port != 0) {
+            let offered: Vec<String> = media.codecs.iter().map(|c| c.name.clone()).collect();
+            let removed: Vec<String> = media
+                .codecs
+                .iter()
+                .filter(|c| !config.allows(c))
+                .map(|c| c.payload_type.to_string())
+                .collect();
+            if removed.is_empty() {
+                continue;
+            }
+            changed = true;
+            media.codecs.retain(|c| config.allows(c));
+            // Comfort noise and DTMF are no use without a voice codec.
+            if !media.codecs.iter().any(|c| !matches!(c.name.to_ascii_lowercase().as_str(), "cn" | "telephone-event" | "red" | "rtx")) {
+                return Err(SdpError::NoAcceptableCodec(offered.join(", ")));
+            }
+            media.formats.retain(|f| !removed.contains(f));
+            media.attributes.retain(|a| {
+                a.name != "rtcp-fb"
+                    || !a.value.as_deref().and_then(|v| v.split(' ').next()).is_some_and(|pt| removed.iter().any(|r| r == pt))
+            });
+        }
+        Ok(changed)
+    }
+}
+
+impl FromStr for SessionDescription {
+    type Err = SdpError;
+
+    fn from_str(sdp: &str) -> Result<Self, SdpError> {
+        let mut origin = None;
+        let mut session_name = None;
+        let mut session = SessionDescription {
+            origin: Origin {
+                username: String::new(),
+                session_id: String::new(),
+                session_version: 0,
+                net_type: String::new(),
+                addr_type: String::new(),
+                address: String::new(),
+            },
+            session_name: String::new(),
+            connection: None,
+            ice_ufrag: None,
+            ice_pwd: None,
+            fingerprint: None,
+            direction: None,
+            attributes: Vec::new(),
+            lines: Vec::new(),
+            media: Vec::new(),
+        };
+        // Codecs of the current section, keyed by payload type until the
+        // section ends and they are put in m= line order.
+        let mut rtpmaps: Vec<Codec> = Vec::new();
+        let mut fmtps: Vec<(u8, String, usize)> = Vec::new();
+
+        if sdp.lines().next().map(|line| line.trim_end_matches('\r')) != Some("v=0") {
+            return Err(SdpError::Syntax { line: 1, message: "SDP must start with v=0".to_string() });
+        }
+        for (index, line) in sdp.lines().enumerate() {
+            let number = index + 1;
+            let syntax = |message: &str| SdpError::Syntax { line: number, message: message.to_string() };
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let Some((kind, value)) = line.split_once('=').filter(|(k, _)| k.len() == 1) else {
+                return Err(syntax("expected <type>=<value>"));
+            };
+            let kind = kind.chars().next().unwrap_or_default();
+
+            match kind {
+                'v' if number == 1 => {}
+                'o' if session.media.is_empty() => origin = Some(parse_origin(value).ok_or_else(|| syntax("malformed o= line"))?),
+                's' if session.media.is_empty() => session_name = Some(value.to_string()),
+                'c' => {
+                    let connection = parse_connection(value).ok_or_else(|| syntax("malformed c= line"))?;
+                    match session.media.last_mut() {
+                        Some(media) => media.connection = Some(connection),
+                        None => session.connection = Some(connection),
+                    }
+                }
+                'm' => {
+                    finish_media(&mut session, &mut rtpmaps, &mut fmtps)?;
+                    session.media.push(parse_media(value).ok_or_else(|| syntax("malformed m= line"))?);
+                }
+                'a' => {
+                    let (name, attr_value) = match value.split_once(':') {
+                        Some((name, v)) => (name, Some(v)),
+                        None => (value, None),
+                    };
+                    match (name, attr_value) {
+                        ("rtpmap", Some(v)) if !session.media.is_empty() => {
+                            rtpmaps.push(parse_rtpmap(v).ok_or_else(|| syntax("malformed a=rtpmap"))?);
+                        }
+                        ("fmtp", Some(v)) if !session.media.is_empty() => {
+                            let (pt, params) = v.split_once(' ').unwrap_or((v, ""));
+                            let pt = pt.parse().map_err(|_| syntax("malformed a=fmtp"))?;
+                            fmtps.push((pt, params.to_string(), number));
+                        }
+                        ("fingerprint", Some(v)) => {
+                            let fingerprint = parse_fingerprint(v).ok_or_else(|| syntax("malformed a=fingerprint"))?;
+                            match session.media.last_mut() {
+                                Some(media) => media.fingerprint = Some(fingerprint),
+                                None => session.fingerprint = Some(fingerprint),
+                            }
+                        }
+                        ("ice-ufrag", Some(v)) => match session.media.last_mut() {
+                            Some(media) => media.ice_ufrag = Some(v.to_string()),
+                            None => session.ice_ufrag = Some(v.to_string()),
+                        },
+                        ("ice-pwd", Some(v)) => match session.media.last_mut() {
+                            Some(media) => media.ice_pwd = Some(v.to_string()),
+                            None => session.ice_pwd = Some(v.to_string()),
+                        },
+                        ("mid", Some(v)) if !session.media.is_empty() => {
+                            if let Some(media) = session.media.last_mut() {
+                                media.mid = Some(v.to_string());
+                            }
+                        }
+                        (name, None) if Direction::from_attribute(name).is_some() => {
+                            let direction = Direction::from_attribute(name);
+                            match session.media.last_mut() {
+                                Some(media) => media.direction = direction,
+                                None => session.direction = direction,
+                            }
+                        }
+                        _ => {
+                            let attribute = Attribute {
+                                name: name.to_string(),
+                                value: attr_value.map(str::to_string),
+                            };
+                            match session.media.last_mut() {
+                                Some(media) => media.attributes.push(attribute),
+                                None => session.attributes.push(attribute),
+                            }
+                        }
+                    }
+                }
+                'i' | 'b' | 'k' => match session.media.last_mut() {
+                    Some(media) => media.lines.push((kind, value.to_string())),
+                    None => session.lines.push((kind, value.to_string())),
+                },
+                'u' | 'e' | 'p' | 't' | 'r' | 'z' if session.media.is_empty() => {
+                    session.lines.push((kind, value.to_string()));
+                }
+                _ => return Err(syntax(&format!("unexpected {}= line", kind))),
+            }
+        }
+        finish_media(&mut session, &mut rtpmaps, &mut fmtps)?;
+
+        session.origin = origin.ok_or_else(|| SdpError::Invalid("missing o= line".to_string()))?;
+        session.session_name = session_name.ok_or_else(|| SdpError::Invalid("missing s= line".to_string()))?;
+        Ok(session)
+    }
+}
+
+/// Attaches the collected `a=rtpmap`/`a=fmtp` lines to the last section's
+/// codecs, in `m=` line order.
+fn finish_media(
+    session: &mut SessionDescription,
+    rtpmaps: &mut Vec<Codec>,
+    fmtps: &mut Vec<(u8, String, usize)>,
+) -> Result<(), SdpError> {
+    let Some(media) = session.media.last_mut() else {
+        return Ok(());
+    };
+    if media.protocol.contains("RTP") {
+        for format in &media.formats {
+            let payload_type: u8 = format
+                .parse()
+                .map_err(|_| SdpError::Invalid(format!("payload type '{}' in {} section is not a number", format, media.kind)))?;
+            let codec = rtpmaps
+                .iter()
+                .find(|c| c.payload_type == payload_type)
+                .cloned()
+                .or_else(|| {
+                    STATIC_PAYLOAD_TYPES.iter().find(|(pt, _, _)| *pt == payload_type).map(|(pt, name, rate)| Codec {
+                        payload_type: *pt,
+                        name: name.to_string(),
+                        clock_rate: *rate,
+                        channels: None,
+                        fmtp: None,
+                    })
+                })
+                .ok_or_else(|| SdpError::Invalid(format!("payload type {} in {} section has no a=rtpmap", payload_type, media.kind)))?;
+            media.codecs.push(codec);
+        }
+        for (payload_type, params, line) in fmtps.iter() {
+            let codec = media.codecs.iter_mut().find(|c| c.payload_type == *payload_type).ok_or_else(|| SdpError::Syntax {
+                line: *line,
+                message: format!("a=fmtp for payload type {} not on the m= line", payload_type),
+            })?;
+            codec.fmtp = Some(params.clone());
+        }
+    }
+    rtpmaps.clear();
+    fmtps.clear();
+    Ok(())
+}
+
+fn parse_origin(value: &str) -> Option<Origin> {
+    let fields: Vec<&str> = value.split(' ').collect();
+    let [username, session_id, version, net_type, addr_type, address] = fields[..] else {
+        return None;
+    };
+    Some(Origin {
+        username: username.to_string(),
+        session_id: session_id.to_string(),
+        session_version: version.parse().ok()?,
+        net_type: net_type.to_string(),
+        addr_type: addr_type.to_string(),
+        address: address.to_string(),
+    })
+}
+
+fn parse_connection(value: &str) -> Option<Connection> {
+    let fields: Vec<&str> = value.split(' ').collect();
+    let [net_type, addr_type, address] = fields[..] else {
+        return None;
+    };
+    Some(Connection {
+        net_type: net_type.to_string(),
+        addr_type: addr_type.to_string(),
+        address: address.to_string(),
+    })
+}
+
+fn parse_media(value: &str) -> Option<MediaDescription> {
+    let mut fields = value.split(' ');
+    let kind = fields.next().filter(|k| !k.is_empty())?;
+    let port = fields.next()?;
+    let (port, port_count) = match port.split_once('/') {
+        Some((port, count)) => (port.parse().ok()?, Some(count.parse().ok()?)),
+        None => (port.parse().ok()?, None),
+    };
+    let protocol = fields.next().filter(|p| !p.is_empty())?;
+    let formats: Vec<String> = fields.map(str::to_string).collect();
+    if formats.is_empty() || formats.iter().any(String::is_empty) {
+        return None;
+    }
+    Some(MediaDescription {
+        kind: kind.to_string(),
+        port,
+        port_count,
+        protocol: protocol.to_string(),
+        formats,
+        codecs: Vec::new(),
+        connection: None,
+        mid: None,
+        ice_ufrag: None,
+        ice_pwd: None,
+        fingerprint: None,
+        direction: None,
+        attributes: Vec::new(),
+        lines: Vec::new(),
+    })
+}
+
+/// `<payload type> <encoding name>/<clock rate>[/<channels>]`
+fn parse_rtpmap(value: &str) -> Option<Codec> {
+    let (payload_type, encoding) = value.split_once(' ')?;
+    let mut parts = encoding.split('/');
+    let name = parts.next().filter(|n| !n.is_empty())?;
+    let clock_rate = parts.next()?.parse().ok()?;
+    let channels = match parts.next() {
+        Some(channels) => Some(channels.parse().ok()?),
+        None => None,
+    };
+    Some(Codec {
+        payload_type: payload_type.parse().ok()?,
+        name: name.to_string(),
+        clock_rate,
+        channels,
+        fmtp: None,
+    })
+}
+
+fn parse_fingerprint(value: &str) -> Option<Fingerprint> {
+    let (algorithm, fingerprint) = value.split_once(' ')?;
+    let valid = fingerprint
+        .split(':')
+        .all(|byte| byte.len() == 2 && byte.chars().all(|c| c.is_ascii_hexdigit()));
+    if algorithm.is_empty() || !valid {
+        return None;
+    }
+    Some(Fingerprint {
+        algorithm: algorithm.to_string(),
+        value: fingerprint.to_string(),
+    })
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "c={} {} {}\r\n", self.net_type, self.addr_type, self.address)
+    }
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "a={}:{}\r\n", self.name, value),
+            None => write!(f, "a={}\r\n", self.name),
+        }
+    }
+}
+
+/// Writes `lines` whose type is in `kinds`, keeping their order.
+fn write_lines(f: &mut fmt::Formatter<'_>, lines: &[(char, String)], kinds: &[char]) -> fmt::Result {
+    for (kind, value) in lines.iter().filter(|(k, _)| kinds.contains(k)) {
+        write!(f, "{}={}\r\n", kind, value)?;
+    }
+    Ok(())
+}
+
+fn write_credentials(
+    f: &mut fmt::Formatter<'_>,
+    ufrag: &Option<String>,
+    pwd: &Option<String>,
+    fingerprint: &Option<Fingerprint>,
+) -> fmt::Result {
+    if let Some(ufrag) = ufrag {
+        write!(f, "a=ice-ufrag:{}\r\n", ufrag)?;
+    }
+    if let Some(pwd) = pwd {
+        write!(f, "a=ice-pwd:{}\r\n", pwd)?;
+    }
+    if let Some(fingerprint) = fingerprint {
+        write!(f, "a=fingerprint:{} {}\r\n", fingerprint.algorithm, fingerprint.value)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for MediaDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m={} {}", self.kind, self.port)?;
+        if let Some(count) = self.port_count {
+            write!(f, "/{}", count)?;
+        }
+        write!(f, " {} {}\r\n", self.protocol, self.formats.join(" "))?;
+        write_lines(f, &self.lines, &['i'])?;
+        if let Some(connection) = &self.connection {
+            write!(f, "{}", connection)?;
+        }
+        write_lines(f, &self.lines, &['b', 'k'])?;
+        write_credentials(f, &self.ice_ufrag, &self.ice_pwd, &self.fingerprint)?;
+        if let Some(mid) = &self.mid {
+            write!(f, "a=mid:{}\r\n", mid)?;
+        }
+        if let Some(direction) = self.direction {
+            write!(f, "a={}\r\n", direction.as_str())?;
+        }
+        for attribute in &self.attributes {
+            write!(f, "{}", attribute)?;
+        }
+        for codec in &self.codecs {
+            write!(f, "a=rtpmap:{} {}/{}", codec.payload_type, codec.name, codec.clock_rate)?;
+            if let Some(channels) = codec.channels {
+                write!(f, "/{}", channels)?;
+            }
+            f.write_str("\r\n")?;
+            if let Some(fmtp) = &codec.fmtp {
+                write!(f, "a=fmtp:{} {}\r\n", codec.payload_type, fmtp)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Generates SDP text, with lines in the order RFC 4566 requires.
+impl fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let o = &self.origin;
+        f.write_str("v=0\r\n")?;
+        write!(
+            f,
+            "o={} {} {} {} {} {}\r\n",
+            o.username, o.session_id, o.session_version, o.net_type, o.addr_type, o.address
+        )?;
+        write!(f, "s={}\r\n", self.session_name)?;
+        write_lines(f, &self.lines, &['i', 'u', 'e', 'p'])?;
+        if let Some(connection) = &self.connection {
+            write!(f, "{}", connection)?;
+        }
+        write_lines(f, &self.lines, &['b'])?;
+        write_lines(f, &self.lines, &['t', 'r'])?;
+        write_lines(f, &self.lines, &['z', 'k'])?;
+        write_credentials(f, &self.ice_ufrag, &self.ice_pwd, &self.fingerprint)?;
+        if let Some(direction) = self.direction {
+            write!(f, "a={}\r\n", direction.as_str())?;
+        }
+        for attribute in &self.attributes {
+            write!(f, "{}", attribute)?;
+        }
+        for media in &self.media {
+            write!(f, "{}", media)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses and validates an offer or answer from a client.
+pub fn parse(sdp: &str) -> Result<SessionDescription, SdpError> {
+    let session: SessionDescription = sdp.parse()?;
+    session.validate()?;
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::BROWSER_OFFER as OFFER;
+
+    #[test]
+    fn parses_browser_offer() {
+        let session = parse(OFFER).unwrap();
+        assert_eq!(session.origin.session_version, 2);
+        assert_eq!(session.media.len(), 1);
+
+        let audio = &session.media[0];
+        assert_eq!((audio.kind.as_str(), audio.port), ("audio", 9));
+        assert_eq!(audio.ice_ufrag.as_deref(), Some("JtCq"));
+        assert_eq!(audio.ice_pwd.as_deref(), Some("qsMF9D+tt3PLnqvMt0G+SGG/"));
+        assert_eq!(audio.fingerprint.as_ref().unwrap().algorithm, "sha-256");
+        assert_eq!(audio.mid.as_deref(), Some("0"));
+        assert_eq!(session.direction_of(audio), Direction::SendRecv);
+
+        let names: Vec<&str> = audio.codecs.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["opus", "red", "G722", "PCMU", "PCMA", "CN", "telephone-event", "telephone-event"]);
+        let opus = &audio.codecs[0];
+        assert_eq!((opus.clock_rate, opus.channels), (48000, Some(2)));
+        assert_eq!(opus.fmtp.as_deref(), Some("minptime=10;useinbandfec=1"));
+        assert!(!session.is_hold());
+    }
+
+    #[test]
+    fn generated_sdp_parses_back_the_same() {
+        let session = parse(OFFER).unwrap();
+        let generated = session.to_string();
+        assert!(generated.starts_with("v=0\r\no=- 4611731400430051336 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n"));
+        assert_eq!(parse(&generated).unwrap(), session);
+    }
+
+    #[test]
+    fn static_payload_types_need_no_rtpmap() {
+        let sdp = OFFER.replace("a=rtpmap:0 PCMU/8000\r\n", "");
+        let session = parse(&sdp).unwrap();
+        assert!(session.media[0].codecs.iter().any(|c| c.payload_type == 0 && c.name == "PCMU"));
+    }
+
+    #[test]
+    fn rejects_malformed_sdp() {
+        let cases = [
+            ("", "must start with v=0"),
+            (&OFFER.replace("v=0", "v=1"), "line 1"),
+            (&OFFER.replace("o=- 4611731400430051336 2", "o=- 1 two"), "line 2: malformed o= line"),
+            (&OFFER.replace("a=rtpmap:110 telephone-event/48000\r\n", ""), "payload type 110"),
+            (&OFFER.replace("a=fmtp:63", "a=fmtp:64"), "payload type 64 not on the m= line"),
+            (&OFFER.replace("sha-256 7B:8B", "sha-256 7B8B"), "malformed a=fingerprint"),
+            (&OFFER.replace("a=ice-pwd:qsMF9D+tt3PLnqvMt0G+SGG/\r\n", ""), "no ICE ufrag/pwd"),
+            (&OFFER.replace("t=0 0\r\n", ""), "missing t= line"),
+            ("v=0\r\no=- 1 1 IN IP4 0.0.0.0\r\ns=-\r\nt=0 0\r\n", "no m= sections"),
+            (&OFFER.replace("a=rtcp-mux", "rtcp-mux"), "expected <type>=<value>"),
+        ];
+        for (sdp, expected) in cases {
+            let error = parse(sdp).unwrap_err().to_string();
+            assert!(error.contains(expected), "{:?} does not mention {:?}", error, expected);
+        }
+    }
+
+    #[test]
+    fn requires_a_fingerprint_for_every_dtls_section() {
+        let data = "m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+            c=IN IP4 0.0.0.0\r\n\
+            a=ice-ufrag:JtCq\r\n\
+            a=ice-pwd:qsMF9D+tt3PLnqvMt0G+SGG/\r\n\
+            a=mid:1\r\n\
+            a=sctp-port:5000\r\n";
+        let error = parse(&format!("{}{}", OFFER, data)).unwrap_err().to_string();
+        assert!(error.contains("m= section 2 (application) has no DTLS fingerprint"), "{}", error);
+
+        let fingerprint = OFFER.lines().find(|l| l.starts_with("a=fingerprint")).unwrap();
+        assert!(parse(&format!("{}{}{}\r\n", OFFER, data, fingerprint)).is_ok());
+        let plain = OFFER.replace("UDP/TLS/RTP/SAVPF", "RTP/AVP");
+        let plain = plain.lines().filter(|l| !l.starts_with("a=fingerprint")).map(|l| format!("{}\r\n", l)).collect::<String>();
+        assert!(parse(&plain).is_ok(), "plain RTP needs no fingerprint");
+    }
+
+    #[test]
+    fn detects_hold_from_direction() {
+        for (direction, hold) in [("sendonly", true), ("inactive", true), ("recvonly", false), ("sendrecv", false)] {
+            let session = parse(&OFFER.replace("a=sendrecv", &format!("a={}", direction))).unwrap();
+            assert_eq!(session.is_hold(), hold, "{}", direction);
+        }
+        // A session-level direction applies to sections without their own.
+        let session = parse(&OFFER.replace("a=sendrecv\r\n", "").replace("t=0 0\r\n", "t=0 0\r\na=inactive\r\n")).unwrap();
+        assert!(session.is_hold());
+    }
+
+    #[test]
+    fn codec_policy_strips_disallowed_codecs() {
+        let mut session = parse(OFFER).unwrap();
+        let config = SdpConfig { codecs: vec!["PCMU".into(), "PCMA".into(), "telephone-event".into()] };
+        assert!(session.apply_codec_policy(&config).unwrap());
+
+        let audio = &session.media[0];
+        assert_eq!(audio.formats, ["0", "8", "110", "126"]);
+        assert!(!audio.attributes.iter().any(|a| a.name == "rtcp-fb"));
+        let generated = session.to_string();
+        assert!(!generated.contains("opus"));
+        assert_eq!(parse(&generated).unwrap(), session);
+
+        let mut session = parse(OFFER).unwrap();
+        assert!(!session.apply_codec_policy(&SdpConfig::default()).unwrap());
+        let config = SdpConfig { codecs: vec!["G729".into(), "telephone-event".into()] };
+        assert_eq!(session.apply_codec_policy(&config).unwrap_err().code(), "unsupported_codec");
+    }
+}
@@ -0,0 +1,178 @@
+//! Receiver-side RTP sequence number validation (RFC 3550 Appendix A.1).
+
+const RTP_SEQ_MOD: u64 = 1 << 16;
+const MAX_DROPOUT: u16 = 3000;
+const MAX_MISORDER: u16 = 100;
+const MIN_SEQUENTIAL: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeqStatus {
+    /// In-order, reordered or duplicate packet, with its extended sequence.
+    Valid(u64),
+    /// The source restarted (e.g. the sender rejoined); the packet is the
+    /// first of the new sequence space.
+    Restarted(u64),
+    /// Still confirming a new source; the packet should not be played.
+    Probation,
+    /// A large jump that has not yet been confirmed as a restart.
+    Invalid,
+}
+
+pub struct SequenceTracker {
+    max_seq: u16,
+    cycles: u64,
+    bad_seq: u64,
+    probation: u32,
+}
+
+impl SequenceTracker {
+    /// Starts tracking a new source whose first packet carried `seq`.
+    pub fn new(seq: u16) -> Self {
+        Self {
+            max_seq: seq.wrapping_sub(1),
+            cycles: 0,
+            bad_seq: RTP_SEQ_MOD + 1,
+            probation: MIN_SEQUENTIAL,
+        }
+    }
+
+    pub fn update(&mut self, seq: u16) -> SeqStatus {
+        let udelta = seq.wrapping_sub(self.max_seq);
+
+        if self.probation > 0 {
+            if seq == self.max_seq.wrapping_add(1) {
+                self.probation -= 1;
+                self.max_seq = seq;
+                if self.probation == 0 {
+                    self.restart(seq);
+                    return SeqStatus::Valid(self.extend(seq));
+                }
+            } else {
+                self.probation = MIN_SEQUENTIAL - 1;
+                self.max_seq = seq;
+            }
+            return SeqStatus::Probation;
+        }
+
+        if udelta < MAX_DROPOUT {
+            if seq < self.max_seq {
+                self.cycles += RTP_SEQ_MOD;
+            }
+            self.max_seq = seq;
+        } else if udelta as u64 <= RTP_SEQ_MOD - MAX_MISORDER as u64 {
+            if seq as u64 == self.bad_seq {
+                // Two sequential packets after the jump: the sender restarted.
+                self.restart(seq);
+                return SeqStatus::Restarted(self.extend(seq));
+            }
+            self.bad_seq = (seq as u64 + 1) & (RTP_SEQ_MOD - 1);
+            return SeqStatus::Invalid;
+        }
+
+        SeqStatus::Valid(self.extend(seq))
+    }
+
+    /// Extended sequence number for `seq`, placing late packets from before
+    /// the most recent wrap in the previous cycle.
+    fn extend(&self, seq: u16) -> u64 {
+        let extended = RTP_SEQ_MOD + self.cycles + seq as u64;
+        if seq > self.max_seq && seq - self.max_seq > 0x8000 {
+            extended - RTP_SEQ_MOD
+        } else {
+            extended
+        }
+    }
+
+    fn restart(&mut self, seq: u16) {
+        self.max_seq = seq;
+        self.cycles = 0;
+        self.bad_seq = RTP_SEQ_MOD + 1;
+    }
+}
+
+/// Sequences a packet against a stream's current source, replacing the
+/// source when a new SSRC appears. The flag is set when the source changed,
+/// in which case anything buffered from the old source should be dropped.
+pub fn track_source(source: &mut Option<(u32, SequenceTracker)>, ssrc: u32, seq: u16) -> (SeqStatus, bool) {
+    match source {
+        Some((current, tracker)) if *current == ssrc => (tracker.update(seq), false),
+        _ => {
+            let mut tracker = SequenceTracker::new(seq);
+            let status = tracker.update(seq);
+            *source = Some((ssrc, tracker));
+            (status, true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tracker past probation, with `seq` the last packet accepted.
+    fn tracking(seq: u16) -> SequenceTracker {
+        let mut tracker = SequenceTracker::new(seq.wrapping_sub(1));
+        assert_eq!(tracker.update(seq.wrapping_sub(1)), SeqStatus::Probation);
+        assert!(matches!(tracker.update(seq), SeqStatus::Valid(_)));
+        tracker
+    }
+
+    #[test]
+    fn holds_new_sources_on_probation() {
+        let mut tracker = SequenceTracker::new(100);
+        assert_eq!(tracker.update(100), SeqStatus::Probation);
+        // Out of order during probation starts it over.
+        assert_eq!(tracker.update(105), SeqStatus::Probation);
+        assert_eq!(tracker.update(106), SeqStatus::Valid(RTP_SEQ_MOD + 106));
+        assert_eq!(tracker.update(107), SeqStatus::Valid(RTP_SEQ_MOD + 107));
+    }
+
+    #[test]
+    fn extends_across_the_16_bit_wrap() {
+        let mut tracker = tracking(65534);
+        assert_eq!(tracker.update(65535), SeqStatus::Valid(RTP_SEQ_MOD + 65535));
+        assert_eq!(tracker.update(0), SeqStatus::Valid(2 * RTP_SEQ_MOD));
+        assert_eq!(tracker.update(1), SeqStatus::Valid(2 * RTP_SEQ_MOD + 1));
+        // A loss across the wrap still counts the cycle.
+        let mut tracker = tracking(65530);
+        assert_eq!(tracker.update(20), SeqStatus::Valid(2 * RTP_SEQ_MOD + 20));
+    }
+
+    #[test]
+    fn places_late_and_duplicate_packets() {
+        let mut tracker = tracking(65535);
+        assert_eq!(tracker.update(1), SeqStatus::Valid(2 * RTP_SEQ_MOD + 1));
+        // Late from before the wrap: the previous cycle, below the highest seen.
+        assert_eq!(tracker.update(65535), SeqStatus::Valid(RTP_SEQ_MOD + 65535));
+        assert_eq!(tracker.update(0), SeqStatus::Valid(2 * RTP_SEQ_MOD));
+        // A duplicate extends to the same number as the original.
+        assert_eq!(tracker.update(1), SeqStatus::Valid(2 * RTP_SEQ_MOD + 1));
+        assert_eq!(tracker.update(2), SeqStatus::Valid(2 * RTP_SEQ_MOD + 2));
+    }
+
+    #[test]
+    fn confirms_a_sender_restart_after_two_sequential_packets() {
+        let mut tracker = tracking(1000);
+        assert_eq!(tracker.update(40000), SeqStatus::Invalid);
+        assert_eq!(tracker.update(40001), SeqStatus::Restarted(RTP_SEQ_MOD + 40001));
+        assert_eq!(tracker.update(40002), SeqStatus::Valid(RTP_SEQ_MOD + 40002));
+    }
+
+    #[test]
+    fn ignores_a_lone_jump() {
+        let mut tracker = tracking(1000);
+        assert_eq!(tracker.update(40000), SeqStatus::Invalid);
+        assert_eq!(tracker.update(1001), SeqStatus::Valid(RTP_SEQ_MOD + 1001));
+        assert_eq!(tracker.update(50000), SeqStatus::Invalid);
+        assert_eq!(tracker.update(1002), SeqStatus::Valid(RTP_SEQ_MOD + 1002));
+    }
+
+    #[test]
+    fn replaces_the_tracker_when_the_ssrc_changes() {
+        let mut source = None;
+        assert_eq!(track_source(&mut source, 7, 10), (SeqStatus::Probation, true));
+        assert_eq!(track_source(&mut source, 7, 11), (SeqStatus::Valid(RTP_SEQ_MOD + 11), false));
+        assert_eq!(track_source(&mut source, 8, 500), (SeqStatus::Probation, true));
+        assert_eq!(track_source(&mut source, 8, 501), (SeqStatus::Valid(RTP_SEQ_MOD + 501), false));
+    }
+}
@@ -1,13 +1,22 @@
 use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Serialize};
 use crate::audio_udp::UdpCommand;
+use crate::auth::AuthUser;
 use crate::call_manager::CallManager;
+use crate::call_state::{CallError, EndReason};
+use crate::cdr::HistoryQuery;
+use crate::processing::StageToggles;
+use crate::sdp::{self, SdpConfig, SdpError, SessionDescription};
+use crate::user::Unavailable;
 use std::sync::Arc;
-use tokio::sync::{mpsc::Sender, Mutex};
+use tokio::sync::{mpsc::UnboundedSender, oneshot, Mutex};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SignalingMessage {
     pub message_type: String,
+    /// Optional; the acting user comes from the session, and a mismatching
+    /// `user_id` is rejected.
+    #[serde(default)]
     pub user_id: String,
     pub call_id: Option<String>,
     pub target_user_id: Option<String>,
@@ -15,33 +24,111 @@ pub struct SignalingMessage {
     pub answer: Option<String>,
     pub candidate: Option<String>,
     pub ip_address: Option<String>,
+    pub relay: Option<bool>,
+    pub room_id: Option<String>,
+    pub processing: Option<StageToggles>,
 }
 
 pub fn config_with_udp_sender(cfg: &mut web::ServiceConfig) {
     cfg.route("/signal/initiate", web::post().to(initiate_call))
         .route("/signal/accept", web::post().to(accept_call))
         .route("/signal/reject", web::post().to(reject_call))
+        .route("/signal/busy", web::post().to(busy_call))
         .route("/signal/end", web::post().to(end_call))
         .route("/signal/hold", web::post().to(hold_call))
         .route("/signal/resume", web::post().to(resume_call))
         .route("/signal/incoming", web::get().to(check_incoming_calls))
         .route("/signal/status", web::get().to(get_call_status))
+        .route("/calls/history", web::get().to(get_call_history))
+        .route("/signal/missed", web::get().to(get_missed_calls))
+        .route("/signal/missed/clear", web::post().to(clear_missed_calls))
         .route("/signal/offer", web::post().to(send_offer))
         .route("/signal/answer", web::post().to(send_answer))
         .route("/signal/candidate", web::post().to(send_candidate))
         .route("/signal/get_offer", web::get().to(get_offer))
         .route("/signal/get_answer", web::get().to(get_answer))
-        .route("/signal/get_candidates", web::get().to(get_candidates));
+        .route("/signal/get_candidates", web::get().to(get_candidates))
+        .route("/signal/record/start", web::post().to(start_recording))
+        .route("/signal/record/stop", web::post().to(stop_recording))
+        .route("/signal/processing", web::post().to(set_processing))
+        .route("/signal/room/join", web::post().to(join_room))
+        .route("/signal/room/leave", web::post().to(leave_room))
+        .route("/signal/room/list", web::get().to(list_rooms));
+}
+
+/// Maps call errors to responses: unknown calls or users are 404, acting on
+/// someone else's call is 403, and anything the state machine refuses is 409.
+fn call_error_response(error: CallError) -> HttpResponse {
+    let body = serde_json::json!({
+        "status": "error",
+        "code": error.code(),
+        "message": error.to_string()
+    });
+    match error {
+        CallError::CallNotFound(_) | CallError::Unavailable(Unavailable::Unknown(_)) => {
+            HttpResponse::NotFound().json(body)
+        }
+        CallError::NotPermitted { .. } => HttpResponse::Forbidden().json(body),
+        CallError::Unavailable(_) | CallError::InvalidTransition { .. } | CallError::NoPendingOffer(_) => {
+            HttpResponse::Conflict().json(body)
+        }
+    }
+}
+
+/// Malformed SDP is 400; SDP with no codec the policy allows is 422.
+fn sdp_error_response(error: SdpError) -> HttpResponse {
+    let body = serde_json::json!({
+        "status": "error",
+        "code": error.code(),
+        "message": error.to_string()
+    });
+    match error {
+        SdpError::Syntax { .. } | SdpError::Invalid(_) => HttpResponse::BadRequest().json(body),
+        SdpError::NoAcceptableCodec(_) => HttpResponse::UnprocessableEntity().json(body),
+    }
+}
+
+/// Parses an offer or answer and applies the codec policy. The client's
+/// own text is passed on unless the policy removed codecs from it.
+fn prepare_sdp(text: &str, config: &SdpConfig) -> Result<(SessionDescription, String), SdpError> {
+    let mut session = sdp::parse(text)?;
+    let text = if session.apply_codec_policy(config)? {
+        session.to_string()
+    } else {
+        text.to_string()
+    };
+    Ok((session, text))
+}
+
+/// The user a signaling message acts for: the session's user, provided the
+/// message does not claim to be someone else.
+fn acting_user(auth: AuthUser, msg: &SignalingMessage) -> Result<String, actix_web::Error> {
+    auth.check_claimed(Some(&msg.user_id))?;
+    Ok(auth.user_id)
+}
+
+/// As `acting_user`, for requests that name the user in the query string.
+fn query_user(auth: AuthUser, query: &std::collections::HashMap<String, String>) -> Result<String, actix_web::Error> {
+    auth.check_claimed(query.get("user_id").map(String::as_str))?;
+    Ok(auth.user_id)
 }
 
 async fn initiate_call(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     msg: web::Json<SignalingMessage>,
 ) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let mut manager = call_manager.lock().await;
     
     if let Some(target_id) = &msg.target_user_id {
-        let call = manager.create_call(msg.user_id.clone(), target_id.clone());
+        let call = match manager.create_call(user_id.clone(), target_id.clone()) {
+            Ok(call) => call,
+            Err(e) => return call_error_response(e),
+        };
         
         HttpResponse::Ok().json(serde_json::json!({
             "status": "success",
@@ -58,24 +145,42 @@ async fn initiate_call(
 
 async fn accept_call(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
-    udp_sender: web::Data<Sender<UdpCommand>>,
+    udp_sender: web::Data<UnboundedSender<UdpCommand>>,
+    auth: AuthUser,
     msg: web::Json<SignalingMessage>,
 ) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let mut manager = call_manager.lock().await;
     
     if let Some(call_id) = &msg.call_id {
-        manager.accept_call(call_id);
+        let relay_legs = match manager.accept_call(call_id, &user_id, msg.relay.unwrap_or(false)) {
+            Ok(legs) => legs,
+            Err(e) => return call_error_response(e),
+        };
+
+        if let Some(legs) = relay_legs {
+            let udp_command = UdpCommand::StartRelay { call_id: call_id.clone(), legs };
+
+            if let Err(e) = udp_sender.send(udp_command) {
+                log::error!("Failed to send UDP relay command: {}", e);
+            }
+        }
         
        
         if let Some(ip_str) = &msg.ip_address {
             if let Ok(target_ip) = ip_str.parse::<std::net::IpAddr>() {
-                let udp_command = UdpCommand {
-                    user_id: msg.user_id.clone(),
-                    command: "start_call".to_string(),
-                    target_ip: Some(target_ip),
+                manager.update_user_ip(&user_id, target_ip.to_string());
+                let udp_command = UdpCommand::StartCall {
+                    call_id: call_id.clone(),
+                    target_ip,
+                    devices: manager.call_devices(call_id),
+                    local_is_caller: manager.get_call(call_id).is_some_and(|call| call.caller_id == user_id),
                 };
                 
-                if let Err(e) = udp_sender.send(udp_command).await {
+                if let Err(e) = udp_sender.send(udp_command) {
                     log::error!("Failed to send UDP start command: {}", e);
                 } else {
                     log::info!("Sent UDP start command to {}", target_ip);
@@ -94,7 +199,9 @@ async fn accept_call(
                     "call_id": call.call_id,
                     "caller_id": call.caller_id,
                     "callee_id": call.callee_id,
-                    "status": call.status
+                    "status": call.state.call_status(),
+                    "state": call.state,
+                    "relay": call.relay
                 }
             }))
         } else {
@@ -113,12 +220,19 @@ async fn accept_call(
 
 async fn reject_call(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     msg: web::Json<SignalingMessage>,
 ) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let mut manager = call_manager.lock().await;
     
     if let Some(call_id) = &msg.call_id {
-        manager.reject_call(call_id);
+        if let Err(e) = manager.reject_call(call_id, &user_id, EndReason::Rejected) {
+            return call_error_response(e);
+        }
         
         HttpResponse::Ok().json(serde_json::json!({
             "status": "success",
@@ -132,33 +246,48 @@ async fn reject_call(
     }
 }
 
-async fn end_call(
+async fn busy_call(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
-    udp_sender: web::Data<Sender<UdpCommand>>,
+    auth: AuthUser,
     msg: web::Json<SignalingMessage>,
 ) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let mut manager = call_manager.lock().await;
     
     if let Some(call_id) = &msg.call_id {
-        manager.end_call(call_id);
+        if let Err(e) = manager.reject_call(call_id, &user_id, EndReason::Busy) {
+            return call_error_response(e);
+        }
         
-       
-        if let Some(ip_str) = &msg.ip_address {
-            if let Ok(target_ip) = ip_str.parse::<std::net::IpAddr>() {
-                let udp_command = UdpCommand {
-                    user_id: msg.user_id.clone(),
-                    command: "end_call".to_string(),
-                    target_ip: Some(target_ip),
-                };
-                
-                if let Err(e) = udp_sender.send(udp_command).await {
-                    log::error!("Failed to send UDP end command: {}", e);
-                } else {
-                    log::info!("Sent UDP end command to {}", target_ip);
-                }
-            } else {
-                log::warn!("Invalid IP address in end_call: {}", ip_str);
-            }
+        HttpResponse::Ok().json(serde_json::json!({
+            "status": "success",
+            "message": "Call declined as busy"
+        }))
+    } else {
+        HttpResponse::BadRequest().json(serde_json::json!({
+            "status": "error",
+            "message": "Call ID required"
+        }))
+    }
+}
+
+async fn end_call(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
+    msg: web::Json<SignalingMessage>,
+) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+    let mut manager = call_manager.lock().await;
+    
+    if let Some(call_id) = &msg.call_id {
+        if let Err(e) = manager.end_call(call_id, &user_id) {
+            return call_error_response(e);
         }
         
         HttpResponse::Ok().json(serde_json::json!({
@@ -175,12 +304,19 @@ async fn end_call(
 
 async fn hold_call(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     msg: web::Json<SignalingMessage>,
 ) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let mut manager = call_manager.lock().await;
     
     if let Some(call_id) = &msg.call_id {
-        manager.hold_call(call_id);
+        if let Err(e) = manager.hold_call(call_id, &user_id) {
+            return call_error_response(e);
+        }
         
         HttpResponse::Ok().json(serde_json::json!({
             "status": "success",
@@ -196,12 +332,19 @@ async fn hold_call(
 
 async fn resume_call(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     msg: web::Json<SignalingMessage>,
 ) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let mut manager = call_manager.lock().await;
     
     if let Some(call_id) = &msg.call_id {
-        manager.accept_call(call_id);
+        if let Err(e) = manager.resume_call(call_id, &user_id) {
+            return call_error_response(e);
+        }
         
         HttpResponse::Ok().json(serde_json::json!({
             "status": "success",
@@ -217,19 +360,24 @@ async fn resume_call(
 
 async fn check_incoming_calls(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     query: web::Query<std::collections::HashMap<String, String>>,
 ) -> HttpResponse {
-    let user_id = query.get("user_id").map(|s| s.as_str()).unwrap_or("");
+    let user_id = match query_user(auth, &query) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     
     let manager = call_manager.lock().await;
-    let calls = manager.get_incoming_calls(user_id);
+    let calls = manager.get_incoming_calls(&user_id);
     
     if let Some(call) = calls.first() {
         HttpResponse::Ok().json(serde_json::json!({
             "call": {
                 "call_id": call.call_id,
                 "caller_id": call.caller_id,
-                "status": call.status
+                "status": call.state.call_status(),
+                "waiting": call.waiting
             }
         }))
     } else {
@@ -241,39 +389,181 @@ async fn check_incoming_calls(
 
 async fn get_call_status(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     query: web::Query<std::collections::HashMap<String, String>>,
 ) -> HttpResponse {
+    let user_id = match query_user(auth, &query) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let call_id = query.get("call_id").map(|s| s.as_str()).unwrap_or("");
     
     let manager = call_manager.lock().await;
     
-    if let Some(call) = manager.get_call(call_id) {
-        HttpResponse::Ok().json(serde_json::json!({
+    match manager.party_call(call_id, &user_id, "view") {
+        Ok(call) => HttpResponse::Ok().json(serde_json::json!({
             "status": "success",
             "call": {
                 "call_id": call.call_id,
                 "caller_id": call.caller_id,
                 "callee_id": call.callee_id,
-                "status": call.status
+                "status": call.state.call_status(),
+                "state": call.state,
+                "transitions": call.transitions,
+                "relay": call.relay,
+                "recording": call.recording
             }
-        }))
-    } else {
-        HttpResponse::NotFound().json(serde_json::json!({
-            "status": "error",
-            "message": "Call not found"
-        }))
+        })),
+        Err(e) => call_error_response(e),
     }
 }
 
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 200;
+
+/// Paginated call history for the session's user, newest first. Optional filters:
+/// `peer_id`, `direction` (`incoming` or `outgoing`), `reason` (an end
+/// reason such as `no_answer`), `since`/`until` (Unix seconds), plus
+/// `offset` and `limit`.
+async fn get_call_history(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> HttpResponse {
+    let user_id = match query_user(auth, &query) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+
+    let history_query = match parse_history_query(&user_id, &query) {
+        Ok(history_query) => history_query,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "status": "error",
+                "message": message
+            }));
+        }
+    };
+
+    let manager = call_manager.lock().await;
+    let (total, records) = manager.call_history(&history_query);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "total": total,
+        "offset": history_query.offset,
+        "limit": history_query.limit,
+        "calls": records
+    }))
+}
+
+fn parse_history_query(
+    user_id: &str,
+    query: &std::collections::HashMap<String, String>,
+) -> Result<HistoryQuery, String> {
+    fn number<T: std::str::FromStr>(
+        query: &std::collections::HashMap<String, String>,
+        key: &str,
+    ) -> Result<Option<T>, String> {
+        query
+            .get(key)
+            .map(|v| v.parse().map_err(|_| format!("Invalid {}: {}", key, v)))
+            .transpose()
+    }
+
+    let outgoing = match query.get("direction").map(|s| s.as_str()) {
+        None => None,
+        Some("outgoing") => Some(true),
+        Some("incoming") => Some(false),
+        Some(other) => return Err(format!("Invalid direction: {}", other)),
+    };
+    let end_reason = query
+        .get("reason")
+        .map(|r| {
+            serde_json::from_value::<EndReason>(serde_json::Value::String(r.clone()))
+                .map_err(|_| format!("Invalid reason: {}", r))
+        })
+        .transpose()?;
+
+    Ok(HistoryQuery {
+        user_id: user_id.to_string(),
+        peer_id: query.get("peer_id").cloned(),
+        outgoing,
+        end_reason,
+        since: number(query, "since")?,
+        until: number(query, "until")?,
+        offset: number(query, "offset")?.unwrap_or(0),
+        limit: number(query, "limit")?
+            .unwrap_or(DEFAULT_HISTORY_LIMIT)
+            .min(MAX_HISTORY_LIMIT),
+    })
+}
+
+async fn get_missed_calls(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> HttpResponse {
+    let user_id = match query_user(auth, &query) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+
+    let manager = call_manager.lock().await;
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "missed_calls": manager.get_missed_calls(&user_id)
+    }))
+}
+
+async fn clear_missed_calls(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
+    msg: web::Json<SignalingMessage>,
+) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+    let mut manager = call_manager.lock().await;
+    manager.clear_missed_calls(&user_id);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "message": "Missed calls cleared"
+    }))
+}
+
+/// Hold and resume can also be signalled by a re-offer whose audio is
+/// `sendonly`/`inactive` or receives again.
 async fn send_offer(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    sdp_config: web::Data<SdpConfig>,
+    auth: AuthUser,
     msg: web::Json<SignalingMessage>,
 ) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let mut manager = call_manager.lock().await;
     
     if let Some(call_id) = &msg.call_id {
         if let Some(offer) = &msg.offer {
-            manager.set_offer(call_id, offer.clone());
+            if let Err(e) = manager.party_call(call_id, &user_id, "signal") {
+                return call_error_response(e);
+            }
+            let (session, offer) = match prepare_sdp(offer, &sdp_config) {
+                Ok(prepared) => prepared,
+                Err(e) => return sdp_error_response(e),
+            };
+            if let Err(e) = manager.set_offer(call_id, &user_id, offer) {
+                return call_error_response(e);
+            }
+            if let Err(e) = manager.apply_offer_direction(call_id, &user_id, session.is_hold()) {
+                log::warn!("Could not follow hold state of offer for call {}: {}", call_id, e);
+            }
             HttpResponse::Ok().json(serde_json::json!({
                 "status": "success",
                 "message": "Offer sent"
@@ -294,13 +584,28 @@ async fn send_offer(
 
 async fn send_answer(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    sdp_config: web::Data<SdpConfig>,
+    auth: AuthUser,
     msg: web::Json<SignalingMessage>,
 ) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let mut manager = call_manager.lock().await;
     
     if let Some(call_id) = &msg.call_id {
         if let Some(answer) = &msg.answer {
-            manager.set_answer(call_id, answer.clone());
+            if let Err(e) = manager.party_call(call_id, &user_id, "signal") {
+                return call_error_response(e);
+            }
+            let answer = match prepare_sdp(answer, &sdp_config) {
+                Ok((_, answer)) => answer,
+                Err(e) => return sdp_error_response(e),
+            };
+            if let Err(e) = manager.set_answer(call_id, &user_id, answer) {
+                return call_error_response(e);
+            }
             HttpResponse::Ok().json(serde_json::json!({
                 "status": "success",
                 "message": "Answer sent"
@@ -321,13 +626,21 @@ async fn send_answer(
 
 async fn send_candidate(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     msg: web::Json<SignalingMessage>,
 ) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let mut manager = call_manager.lock().await;
     
     if let Some(call_id) = &msg.call_id {
         if let Some(candidate) = &msg.candidate {
-            let is_caller = msg.user_id == manager.get_call(call_id).unwrap().caller_id;
+            let is_caller = match manager.party_call(call_id, &user_id, "signal") {
+                Ok(call) => call.caller_id == user_id,
+                Err(e) => return call_error_response(e),
+            };
             manager.add_candidate(call_id, candidate.clone(), is_caller);
             HttpResponse::Ok().json(serde_json::json!({
                 "status": "success",
@@ -349,84 +662,415 @@ async fn send_candidate(
 
 async fn get_offer(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     query: web::Query<std::collections::HashMap<String, String>>,
 ) -> HttpResponse {
+    let user_id = match query_user(auth, &query) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let call_id = query.get("call_id").map(|s| s.as_str()).unwrap_or("");
     
     let manager = call_manager.lock().await;
     
-    if let Some(call) = manager.get_call(call_id) {
-        if let Some(offer) = &call.offer {
-            HttpResponse::Ok().json(serde_json::json!({
+    match manager.party_call(call_id, &user_id, "view") {
+        Ok(call) => match &call.offer {
+            Some(offer) => HttpResponse::Ok().json(serde_json::json!({
                 "status": "success",
                 "offer": offer
-            }))
-        } else {
-            HttpResponse::Ok().json(serde_json::json!({
+            })),
+            None => HttpResponse::Ok().json(serde_json::json!({
                 "status": "pending",
                 "message": "Offer not yet available"
-            }))
-        }
-    } else {
-        HttpResponse::NotFound().json(serde_json::json!({
-            "status": "error",
-            "message": "Call not found"
-        }))
+            })),
+        },
+        Err(e) => call_error_response(e),
     }
 }
 
 async fn get_answer(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     query: web::Query<std::collections::HashMap<String, String>>,
 ) -> HttpResponse {
+    let user_id = match query_user(auth, &query) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let call_id = query.get("call_id").map(|s| s.as_str()).unwrap_or("");
     
     let manager = call_manager.lock().await;
     
-    if let Some(call) = manager.get_call(call_id) {
-        if let Some(answer) = &call.answer {
-            HttpResponse::Ok().json(serde_json::json!({
+    match manager.party_call(call_id, &user_id, "view") {
+        Ok(call) => match &call.answer {
+            Some(answer) => HttpResponse::Ok().json(serde_json::json!({
                 "status": "success",
                 "answer": answer
-            }))
-        } else {
-            HttpResponse::Ok().json(serde_json::json!({
+            })),
+            None => HttpResponse::Ok().json(serde_json::json!({
                 "status": "pending",
                 "message": "Answer not yet available"
-            }))
-        }
-    } else {
-        HttpResponse::NotFound().json(serde_json::json!({
-            "status": "error",
-            "message": "Call not found"
-        }))
+            })),
+        },
+        Err(e) => call_error_response(e),
     }
 }
 
 async fn get_candidates(
     call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
     query: web::Query<std::collections::HashMap<String, String>>,
 ) -> HttpResponse {
+    let user_id = match query_user(auth, &query) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
     let call_id = query.get("call_id").map(|s| s.as_str()).unwrap_or("");
-    let user_id = query.get("user_id").map(|s| s.as_str()).unwrap_or("");
     
     let manager = call_manager.lock().await;
     
-    if let Some(call) = manager.get_call(call_id) {
-        let candidates = if user_id == call.caller_id {
-            &call.callee_candidates
+    match manager.party_call(call_id, &user_id, "view") {
+        Ok(call) => {
+            let candidates = if user_id == call.caller_id {
+                &call.callee_candidates
+            } else {
+                &call.caller_candidates
+            };
+            
+            HttpResponse::Ok().json(serde_json::json!({
+                "status": "success",
+                "candidates": candidates
+            }))
+        }
+        Err(e) => call_error_response(e),
+    }
+}
+
+async fn start_recording(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    udp_sender: web::Data<UnboundedSender<UdpCommand>>,
+    auth: AuthUser,
+    msg: web::Json<SignalingMessage>,
+) -> HttpResponse {
+    let command = |call_id, applied| UdpCommand::StartRecording { call_id, applied };
+    send_recording_command(call_manager, udp_sender, auth, msg, command, "Recording started").await
+}
+
+async fn stop_recording(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    udp_sender: web::Data<UnboundedSender<UdpCommand>>,
+    auth: AuthUser,
+    msg: web::Json<SignalingMessage>,
+) -> HttpResponse {
+    let command = |call_id, applied| UdpCommand::StopRecording { call_id, applied };
+    send_recording_command(call_manager, udp_sender, auth, msg, command, "Recording stopped").await
+}
+
+async fn send_recording_command(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    udp_sender: web::Data<UnboundedSender<UdpCommand>>,
+    auth: AuthUser,
+    msg: web::Json<SignalingMessage>,
+    command: fn(String, oneshot::Sender<bool>) -> UdpCommand,
+    message: &str,
+) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+    let manager = call_manager.lock().await;
+
+    let Some(call_id) = &msg.call_id else {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "status": "error",
+            "message": "Call ID required"
+        }));
+    };
+
+    if let Err(e) = manager.party_call(call_id, &user_id, "record") {
+        return call_error_response(e);
+    }
+    drop(manager);
+
+    let (applied_tx, applied_rx) = oneshot::channel();
+    let udp_command = command(call_id.clone(), applied_tx);
+    if let Err(e) = udp_sender.send(udp_command) {
+        log::error!("Failed to send UDP recording command: {}", e);
+    }
+
+    if !applied_rx.await.unwrap_or(false) {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "status": "error",
+            "code": "no_media",
+            "message": format!("Call {} has no media running", call_id)
+        }));
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "message": message
+    }))
+}
+
+/// Switches capture processing stages on or off for the rest of a call.
+async fn set_processing(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    udp_sender: web::Data<UnboundedSender<UdpCommand>>,
+    auth: AuthUser,
+    msg: web::Json<SignalingMessage>,
+) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+    let manager = call_manager.lock().await;
+
+    let (Some(call_id), Some(toggles)) = (&msg.call_id, &msg.processing) else {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "status": "error",
+            "message": "Call ID and processing settings required"
+        }));
+    };
+
+    if let Err(e) = manager.party_call(call_id, &user_id, "change processing for") {
+        return call_error_response(e);
+    }
+    drop(manager);
+
+    let (applied_tx, applied_rx) = oneshot::channel();
+    let udp_command = UdpCommand::SetProcessing {
+        call_id: call_id.clone(),
+        toggles: toggles.clone(),
+        applied: applied_tx,
+    };
+
+    if let Err(e) = udp_sender.send(udp_command) {
+        log::error!("Failed to send UDP set_processing command: {}", e);
+    }
+
+    if !applied_rx.await.unwrap_or(false) {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "status": "error",
+            "code": "no_media",
+            "message": format!("Call {} has no media running", call_id)
+        }));
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "message": "Processing updated"
+    }))
+}
+
+async fn join_room(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    udp_sender: web::Data<UnboundedSender<UdpCommand>>,
+    auth: AuthUser,
+    msg: web::Json<SignalingMessage>,
+) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+    let mut manager = call_manager.lock().await;
+
+    if manager.get_user(&user_id).is_none() {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "status": "error",
+            "message": "User not found"
+        }));
+    }
+
+    if let Some(call) = manager.call_of(&user_id) {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "status": "error",
+            "message": format!("User is in call {}", call.call_id)
+        }));
+    }
+
+    if let Some(current) = manager.room_of(&user_id) {
+        if msg.room_id.as_ref() != Some(&current.room_id) {
+            return HttpResponse::Conflict().json(serde_json::json!({
+                "status": "error",
+                "message": format!("User is already in room {}", current.room_id)
+            }));
+        }
+    }
+
+    let room = manager.join_room(msg.room_id.clone(), &user_id);
+
+    if let Some(ip_str) = &msg.ip_address {
+        if let Ok(target_ip) = ip_str.parse::<std::net::IpAddr>() {
+            manager.update_user_ip(&user_id, target_ip.to_string());
+            let udp_command = UdpCommand::JoinRoom {
+                room_id: room.room_id.clone(),
+                user_id: user_id.clone(),
+                target_ip,
+            };
+
+            if let Err(e) = udp_sender.send(udp_command) {
+                log::error!("Failed to send UDP join command: {}", e);
+            }
         } else {
-            &call.caller_candidates
-        };
-        
-        HttpResponse::Ok().json(serde_json::json!({
-            "status": "success",
-            "candidates": candidates
-        }))
-    } else {
-        HttpResponse::NotFound().json(serde_json::json!({
+            log::warn!("Invalid IP address in join_room: {}", ip_str);
+        }
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "message": "Joined room",
+        "room": room
+    }))
+}
+
+async fn leave_room(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
+    msg: web::Json<SignalingMessage>,
+) -> HttpResponse {
+    let user_id = match acting_user(auth, &msg) {
+        Ok(user_id) => user_id,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+    let mut manager = call_manager.lock().await;
+
+    let Some(room_id) = &msg.room_id else {
+        return HttpResponse::BadRequest().json(serde_json::json!({
             "status": "error",
-            "message": "Call not found"
-        }))
+            "message": "Room ID required"
+        }));
+    };
+
+    if !manager.leave_room(room_id, &user_id) {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "status": "error",
+            "message": "User is not in this room"
+        }));
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "message": "Left room"
+    }))
+}
+
+async fn list_rooms(
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    _auth: AuthUser,
+    query: web::Query<std::collections::HashMap<String, String>>,
+) -> HttpResponse {
+    let manager = call_manager.lock().await;
+
+    if let Some(room_id) = query.get("room_id") {
+        return match manager.get_room(room_id) {
+            Some(room) => HttpResponse::Ok().json(serde_json::json!({
+                "status": "success",
+                "room": room
+            })),
+            None => HttpResponse::NotFound().json(serde_json::json!({
+                "status": "error",
+                "message": "Room not found"
+            })),
+        };
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "success",
+        "rooms": manager.list_rooms()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, test::{self as actix_test, TestRequest}, App};
+    use crate::auth::require_session;
+    use crate::cdr::CdrStore;
+    use crate::storage::{MemoryStorage, Storage};
+    use std::collections::HashSet;
+    use tokio::sync::mpsc;
+
+    struct Harness {
+        manager: Arc<Mutex<CallManager>>,
+        udp: UnboundedSender<UdpCommand>,
+        /// Calls the stand-in UDP audio task has media running for.
+        media: Arc<std::sync::Mutex<HashSet<String>>>,
+        cdr_path: std::path::PathBuf,
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.cdr_path);
+        }
+    }
+
+    fn harness() -> Harness {
+        let cdr_path = std::env::temp_dir().join(format!("voip-signaling-{}.jsonl", uuid::Uuid::new_v4()));
+        let storage: Box<dyn Storage> = Box::new(MemoryStorage::new());
+        let manager = CallManager::new(storage, CdrStore::open(cdr_path.clone()).unwrap());
+        let media = Arc::new(std::sync::Mutex::new(HashSet::new()));
+        let (udp, mut commands) = mpsc::unbounded_channel();
+        {
+            let media = media.clone();
+            tokio::spawn(async move {
+                while let Some(command) = commands.recv().await {
+                    if let UdpCommand::SetProcessing { call_id, applied, .. } = command {
+                        let _ = applied.send(media.lock().unwrap().contains(&call_id));
+                    }
+                }
+            });
+        }
+        Harness { manager: Arc::new(Mutex::new(manager)), udp, media, cdr_path }
+    }
+
+    /// Logs in a new user, returning their ID and session token.
+    async fn user(harness: &Harness, name: &str) -> (String, String) {
+        let mut manager = harness.manager.lock().await;
+        let account = manager.create_account(name, "hash".into()).unwrap();
+        let token = manager.log_in(&account);
+        (account.id, token)
+    }
+
+    async fn post(harness: &Harness, token: &str, uri: &str, body: serde_json::Value) -> (u16, serde_json::Value) {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::clone(&harness.manager)))
+                .app_data(web::Data::new(harness.udp.clone()))
+                .service(web::scope("/api").wrap(from_fn(require_session)).configure(config_with_udp_sender)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(body)
+            .to_request();
+        let response = actix_test::call_service(&app, req).await;
+        let status = response.status().as_u16();
+        (status, actix_test::read_body_json(response).await)
+    }
+
+    #[actix_web::test]
+    async fn processing_changes_need_a_call_with_media() {
+        let h = harness();
+        let (alice, alice_token) = user(&h, "alice").await;
+        let (bob, _) = user(&h, "bob").await;
+        let (_, carol_token) = user(&h, "carol").await;
+        let call = h.manager.lock().await.create_call(alice, bob).unwrap();
+        let body = |call_id: &str| serde_json::json!({
+            "message_type": "processing",
+            "call_id": call_id,
+            "processing": {"noise_suppression": false}
+        });
+
+        let (status, json) = post(&h, &alice_token, "/api/signal/processing", body("missing")).await;
+        assert_eq!((status, json["code"].as_str()), (404, Some("call_not_found")));
+        let (status, _) = post(&h, &carol_token, "/api/signal/processing", body(&call.call_id)).await;
+        assert_eq!(status, 403);
+        let (status, json) = post(&h, &alice_token, "/api/signal/processing", body(&call.call_id)).await;
+        assert_eq!((status, json["code"].as_str()), (409, Some("no_media")));
+
+        h.media.lock().unwrap().insert(call.call_id.clone());
+        let (status, json) = post(&h, &alice_token, "/api/signal/processing", body(&call.call_id)).await;
+        assert_eq!((status, json["status"].as_str()), (200, Some("success")));
     }
 }
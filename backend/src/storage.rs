@@ -0,0 +1,498 @@
+use std::{collections::HashMap, env, fmt, path::PathBuf};
+
+use rusqlite::{params, Connection};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::account::Account;
+use crate::auth::Session;
+use crate::call_manager::Call;
+use crate::user::User;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageBackend {
+    Memory,
+    Sqlite,
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub db_path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::Sqlite,
+            db_path: PathBuf::from("voip.db"),
+        }
+    }
+}
+
+impl StorageConfig {
+    /// Reads `VOIP_STORAGE` (`memory` or `sqlite`) and `VOIP_DB_PATH`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = StorageConfig::default();
+        if let Ok(backend) = env::var("VOIP_STORAGE") {
+            config.backend = match backend.to_ascii_lowercase().as_str() {
+                "memory" => StorageBackend::Memory,
+                "sqlite" => StorageBackend::Sqlite,
+                _ => return Err(format!("unknown storage backend '{}'", backend)),
+            };
+        }
+        if let Ok(path) = env::var("VOIP_DB_PATH") {
+            config.db_path = PathBuf::from(path);
+        }
+        Ok(config)
+    }
+
+    pub fn open(&self) -> Result<Box<dyn Storage>, StorageError> {
+        Ok(match self.backend {
+            StorageBackend::Memory => Box::new(MemoryStorage::new()),
+            StorageBackend::Sqlite => Box::new(SqliteStorage::open(&self.db_path)?),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Sqlite(rusqlite::Error),
+    Serde(serde_json::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            StorageError::Serde(e) => write!(f, "serialization error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::Sqlite(e)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Serde(e)
+    }
+}
+
+/// Where `CallManager` persists accounts, users, sessions and live calls.
+/// The manager works on its own in-memory copy, loading everything at
+/// startup and queueing each change for a `StorageWriter`.
+pub trait Storage: Send {
+    fn load_accounts(&self) -> Result<Vec<Account>, StorageError>;
+    fn save_account(&mut self, account: &Account) -> Result<(), StorageError>;
+    fn load_users(&self) -> Result<Vec<User>, StorageError>;
+    fn save_user(&mut self, user: &User) -> Result<(), StorageError>;
+    fn load_calls(&self) -> Result<Vec<Call>, StorageError>;
+    fn save_call(&mut self, call: &Call) -> Result<(), StorageError>;
+    fn delete_call(&mut self, call_id: &str) -> Result<(), StorageError>;
+    fn load_sessions(&self) -> Result<Vec<Session>, StorageError>;
+    fn save_session(&mut self, session: &Session) -> Result<(), StorageError>;
+    fn delete_sessions(&mut self, keys: &[String]) -> Result<(), StorageError>;
+}
+
+impl Storage for Box<dyn Storage> {
+    fn load_accounts(&self) -> Result<Vec<Account>, StorageError> {
+        (**self).load_accounts()
+    }
+
+    fn save_account(&mut self, account: &Account) -> Result<(), StorageError> {
+        (**self).save_account(account)
+    }
+
+    fn load_users(&self) -> Result<Vec<User>, StorageError> {
+        (**self).load_users()
+    }
+
+    fn save_user(&mut self, user: &User) -> Result<(), StorageError> {
+        (**self).save_user(user)
+    }
+
+    fn load_calls(&self) -> Result<Vec<Call>, StorageError> {
+        (**self).load_calls()
+    }
+
+    fn save_call(&mut self, call: &Call) -> Result<(), StorageError> {
+        (**self).save_call(call)
+    }
+
+    fn delete_call(&mut self, call_id: &str) -> Result<(), StorageError> {
+        (**self).delete_call(call_id)
+    }
+
+    fn load_sessions(&self) -> Result<Vec<Session>, StorageError> {
+        (**self).load_sessions()
+    }
+
+    fn save_session(&mut self, session: &Session) -> Result<(), StorageError> {
+        (**self).save_session(session)
+    }
+
+    fn delete_sessions(&mut self, keys: &[String]) -> Result<(), StorageError> {
+        (**self).delete_sessions(keys)
+    }
+}
+
+/// A change queued by `CallManager` for its `StorageWriter`.
+pub enum StorageWrite {
+    SaveAccount(Account),
+    SaveUser(User),
+    SaveCall(Call),
+    DeleteCall(String),
+    SaveSession(Session),
+    DeleteSessions(Vec<String>),
+}
+
+/// Applies queued changes to the store in order, a batch at a time on the
+/// blocking thread pool, so writes never block the executor.
+pub struct StorageWriter<S> {
+    storage: S,
+    writes: UnboundedReceiver<StorageWrite>,
+}
+
+impl<S: Storage> StorageWriter<S> {
+    pub fn new(storage: S, writes: UnboundedReceiver<StorageWrite>) -> Self {
+        StorageWriter { storage, writes }
+    }
+}
+
+impl<S: Storage + 'static> StorageWriter<S> {
+    /// Runs until the queue's sender, i.e. the manager, is dropped.
+    pub async fn run(self) {
+        let StorageWriter { mut storage, mut writes } = self;
+        while let Some(write) = writes.recv().await {
+            let mut batch = vec![write];
+            while let Ok(write) = writes.try_recv() {
+                batch.push(write);
+            }
+            storage = match tokio::task::spawn_blocking(move || {
+                for write in batch {
+                    apply(&mut storage, write);
+                }
+                storage
+            })
+            .await
+            {
+                Ok(storage) => storage,
+                Err(e) => {
+                    log::error!("Storage writer stopped: {}", e);
+                    return;
+                }
+            };
+        }
+    }
+}
+
+fn apply<S: Storage>(storage: &mut S, write: StorageWrite) {
+    let (result, what) = match write {
+        StorageWrite::SaveAccount(account) => (storage.save_account(&account), format!("store account {}", account.username)),
+        StorageWrite::SaveUser(user) => (storage.save_user(&user), format!("store user {}", user.id)),
+        StorageWrite::SaveCall(call) => (storage.save_call(&call), format!("store call {}", call.call_id)),
+        StorageWrite::DeleteCall(call_id) => (storage.delete_call(&call_id), format!("delete stored call {}", call_id)),
+        StorageWrite::SaveSession(session) => {
+            (storage.save_session(&session), format!("store session for {}", session.user_id))
+        }
+        StorageWrite::DeleteSessions(keys) => (storage.delete_sessions(&keys), format!("delete {} sessions", keys.len())),
+    };
+    if let Err(e) = result {
+        log::error!("Failed to {}: {}", what, e);
+    }
+}
+
+/// Keeps nothing beyond the process lifetime.
+pub struct MemoryStorage {
+    accounts: HashMap<String, Account>,
+    users: HashMap<String, User>,
+    calls: HashMap<String, Call>,
+    sessions: HashMap<String, Session>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage {
+            accounts: HashMap::new(),
+            users: HashMap::new(),
+            calls: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load_accounts(&self) -> Result<Vec<Account>, StorageError> {
+        Ok(self.accounts.values().cloned().collect())
+    }
+
+    fn save_account(&mut self, account: &Account) -> Result<(), StorageError> {
+        self.accounts.insert(account.id.clone(), account.clone());
+        Ok(())
+    }
+
+    fn load_users(&self) -> Result<Vec<User>, StorageError> {
+        Ok(self.users.values().cloned().collect())
+    }
+
+    fn save_user(&mut self, user: &User) -> Result<(), StorageError> {
+        self.users.insert(user.id.clone(), user.clone());
+        Ok(())
+    }
+
+    fn load_calls(&self) -> Result<Vec<Call>, StorageError> {
+        Ok(self.calls.values().cloned().collect())
+    }
+
+    fn save_call(&mut self, call: &Call) -> Result<(), StorageError> {
+        self.calls.insert(call.call_id.clone(), call.clone());
+        Ok(())
+    }
+
+    fn delete_call(&mut self, call_id: &str) -> Result<(), StorageError> {
+        self.calls.remove(call_id);
+        Ok(())
+    }
+
+    fn load_sessions(&self) -> Result<Vec<Session>, StorageError> {
+        Ok(self.sessions.values().cloned().collect())
+    }
+
+    fn save_session(&mut self, session: &Session) -> Result<(), StorageError> {
+        self.sessions.insert(session.key.clone(), session.clone());
+        Ok(())
+    }
+
+    fn delete_sessions(&mut self, keys: &[String]) -> Result<(), StorageError> {
+        for key in keys {
+            self.sessions.remove(key);
+        }
+        Ok(())
+    }
+}
+
+/// Accounts, users, sessions and calls as JSON documents in an SQLite
+/// database, keyed by ID. Usernames are additionally kept unique, ignoring
+/// case.
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &std::path::Path) -> Result<Self, StorageError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS accounts (
+                 id TEXT PRIMARY KEY,
+                 username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                 data TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS users (id TEXT PRIMARY KEY, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS calls (call_id TEXT PRIMARY KEY, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS sessions (key TEXT PRIMARY KEY, data TEXT NOT NULL);",
+        )?;
+        log::info!("💾 Using SQLite storage at {}", path.display());
+        Ok(SqliteStorage { conn })
+    }
+
+    fn load<T: serde::de::DeserializeOwned>(&self, sql: &str) -> Result<Vec<T>, StorageError> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut items = Vec::new();
+        for data in rows {
+            items.push(serde_json::from_str(&data?)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_accounts(&self) -> Result<Vec<Account>, StorageError> {
+        self.load("SELECT data FROM accounts")
+    }
+
+    fn save_account(&mut self, account: &Account) -> Result<(), StorageError> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO accounts (id, username, data) VALUES (?1, ?2, ?3)",
+            params![account.id, account.username, serde_json::to_string(account)?],
+        )?;
+        Ok(())
+    }
+
+    fn load_users(&self) -> Result<Vec<User>, StorageError> {
+        self.load("SELECT data FROM users")
+    }
+
+    fn save_user(&mut self, user: &User) -> Result<(), StorageError> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO users (id, data) VALUES (?1, ?2)",
+            params![user.id, serde_json::to_string(user)?],
+        )?;
+        Ok(())
+    }
+
+    fn load_calls(&self) -> Result<Vec<Call>, StorageError> {
+        self.load("SELECT data FROM calls")
+    }
+
+    fn save_call(&mut self, call: &Call) -> Result<(), StorageError> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO calls (call_id, data) VALUES (?1, ?2)",
+            params![call.call_id, serde_json::to_string(call)?],
+        )?;
+        Ok(())
+    }
+
+    fn delete_call(&mut self, call_id: &str) -> Result<(), StorageError> {
+        self.conn.execute("DELETE FROM calls WHERE call_id = ?1", params![call_id])?;
+        Ok(())
+    }
+
+    fn load_sessions(&self) -> Result<Vec<Session>, StorageError> {
+        self.load("SELECT data FROM sessions")
+    }
+
+    fn save_session(&mut self, session: &Session) -> Result<(), StorageError> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO sessions (key, data) VALUES (?1, ?2)",
+            params![session.key, serde_json::to_string(session)?],
+        )?;
+        Ok(())
+    }
+
+    fn delete_sessions(&mut self, keys: &[String]) -> Result<(), StorageError> {
+        let tx = self.conn.transaction()?;
+        for key in keys {
+            tx.execute("DELETE FROM sessions WHERE key = ?1", params![key])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::call_manager::CallManager;
+    use crate::call_state::CallState;
+    use crate::cdr::CdrStore;
+    use crate::user::CallStatus;
+    use std::path::Path;
+    use tokio::task::JoinHandle;
+
+    fn temp_path(ext: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("voip-storage-{}.{}", uuid::Uuid::new_v4(), ext))
+    }
+
+    /// Signs up alice and bob and puts them on an answered call, returning
+    /// the call, alice's and bob's IDs.
+    fn start_call<S: Storage>(manager: &mut CallManager<S>) -> (String, String, String) {
+        let alice = manager.create_account("alice", "hash".into()).unwrap();
+        let bob = manager.create_account("bob", "hash".into()).unwrap();
+        manager.log_in(&alice);
+        manager.log_in(&bob);
+        let call = manager.create_call(alice.id.clone(), bob.id.clone()).unwrap();
+        manager.accept_call(&call.call_id, &bob.id, false).unwrap();
+        (call.call_id, alice.id, bob.id)
+    }
+
+    #[test]
+    fn memory_backend_keeps_todays_behaviour() {
+        let cdr_path = temp_path("jsonl");
+        let mut manager = CallManager::new(MemoryStorage::new(), CdrStore::open(cdr_path.clone()).unwrap());
+        let (call_id, alice, bob) = start_call(&mut manager);
+
+        assert_eq!(manager.get_call(&call_id).unwrap().state, CallState::Active);
+        assert_eq!(manager.get_user(&bob).unwrap().status, CallStatus::InCall);
+        manager.end_call(&call_id, &alice).unwrap();
+        assert!(manager.get_call(&call_id).is_none());
+
+        let _ = std::fs::remove_file(cdr_path);
+    }
+
+    /// Opens a manager on the database with its storage writer running.
+    fn open_manager(db_path: &Path, cdr_path: &Path) -> (CallManager<SqliteStorage>, JoinHandle<()>) {
+        let storage = SqliteStorage::open(db_path).unwrap();
+        let mut manager = CallManager::new(storage, CdrStore::open(cdr_path.to_path_buf()).unwrap());
+        let writer = tokio::spawn(manager.take_storage_writer().unwrap().run());
+        (manager, writer)
+    }
+
+    /// Drops the manager and waits for its queued changes to be written.
+    async fn close_manager(manager: CallManager<SqliteStorage>, writer: JoinHandle<()>) {
+        drop(manager);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sqlite_backend_survives_restart() {
+        let db_path = temp_path("db");
+        let cdr_path = temp_path("jsonl");
+
+        let (mut manager, writer) = open_manager(&db_path, &cdr_path);
+        let (call_id, _, bob) = start_call(&mut manager);
+        manager.set_call_waiting(&bob, true);
+        close_manager(manager, writer).await;
+
+        let (mut manager, writer) = open_manager(&db_path, &cdr_path);
+        let call = manager.get_call(&call_id).expect("call restored");
+        assert_eq!(call.state, CallState::Active);
+        assert_eq!(call.transitions.len(), 2);
+        let user = manager.get_user(&bob).expect("user restored");
+        assert_eq!(user.status, CallStatus::InCall);
+        assert!(user.call_waiting);
+        assert_eq!(manager.account_by_name("BOB").map(|a| a.id.as_str()), Some(bob.as_str()));
+        assert!(manager.create_account("Bob", "hash".into()).is_err());
+
+        manager.end_call(&call_id, &bob).unwrap();
+        close_manager(manager, writer).await;
+        let storage = SqliteStorage::open(&db_path).unwrap();
+        assert!(storage.load_calls().unwrap().is_empty());
+        assert_eq!(storage.load_users().unwrap().len(), 2);
+        assert_eq!(storage.load_accounts().unwrap().len(), 2);
+
+        for path in [db_path.clone(), db_path.with_extension("db-wal"), db_path.with_extension("db-shm"), cdr_path] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[tokio::test]
+    async fn sqlite_sessions_survive_restart() {
+        let db_path = temp_path("db");
+        let cdr_path = temp_path("jsonl");
+
+        SqliteStorage::open(&db_path)
+            .unwrap()
+            .save_session(&crate::auth::Session {
+                key: crate::auth::session_key("stale"),
+                user_id: "nobody".to_string(),
+                expires: chrono::Local::now().timestamp() - 1,
+            })
+            .unwrap();
+        let (mut manager, writer) = open_manager(&db_path, &cdr_path);
+        let alice = manager.create_account("alice", "hash".into()).unwrap();
+        let kept = manager.log_in(&alice);
+        let ended = manager.log_in(&alice);
+        manager.end_session(&ended);
+        close_manager(manager, writer).await;
+
+        let (manager, writer) = open_manager(&db_path, &cdr_path);
+        assert!(manager.session_user(&kept).is_some());
+        assert_eq!(manager.session_user(&ended), None);
+        assert_eq!(manager.session_user("stale"), None);
+        close_manager(manager, writer).await;
+        let sessions = SqliteStorage::open(&db_path).unwrap().load_sessions().unwrap();
+        assert_eq!(sessions.len(), 1, "ended and expired sessions are deleted");
+        assert_ne!(sessions[0].key, kept, "tokens are not stored in the clear");
+
+        for path in [db_path.clone(), db_path.with_extension("db-wal"), db_path.with_extension("db-shm"), cdr_path] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
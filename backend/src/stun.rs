@@ -0,0 +1,685 @@
+use std::{
+    env,
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use tokio::net::UdpSocket;
+
+use crate::config::env_bool;
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const HEADER_LEN: usize = 20;
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+const SOFTWARE: &str = "voip-backend";
+
+pub const BINDING: u16 = 0x001;
+
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_SUCCESS: u16 = 0x0101;
+pub const BINDING_ERROR: u16 = 0x0111;
+
+/// Message classes, as the class bits of a message type.
+pub const CLASS_REQUEST: u16 = 0x0000;
+pub const CLASS_INDICATION: u16 = 0x0010;
+pub const CLASS_SUCCESS: u16 = 0x0100;
+pub const CLASS_ERROR: u16 = 0x0110;
+
+/// Attribute types from RFC 5389 and RFC 5766 (TURN), plus the ICE
+/// attributes (RFC 8445) that connectivity checks carry.
+pub mod attr {
+    pub const MAPPED_ADDRESS: u16 = 0x0001;
+    pub const USERNAME: u16 = 0x0006;
+    pub const MESSAGE_INTEGRITY: u16 = 0x0008;
+    pub const ERROR_CODE: u16 = 0x0009;
+    pub const UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+    pub const CHANNEL_NUMBER: u16 = 0x000C;
+    pub const LIFETIME: u16 = 0x000D;
+    pub const XOR_PEER_ADDRESS: u16 = 0x0012;
+    pub const DATA: u16 = 0x0013;
+    pub const REALM: u16 = 0x0014;
+    pub const NONCE: u16 = 0x0015;
+    pub const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+    pub const REQUESTED_TRANSPORT: u16 = 0x0019;
+    pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+    pub const PRIORITY: u16 = 0x0024;
+    pub const USE_CANDIDATE: u16 = 0x0025;
+    pub const SOFTWARE: u16 = 0x8022;
+    pub const FINGERPRINT: u16 = 0x8028;
+}
+
+/// Where the STUN server listens, and the host clients are told to use.
+#[derive(Debug, Clone)]
+pub struct StunConfig {
+    pub enabled: bool,
+    pub port: u16,
+    /// Host advertised to clients. Without one, clients are given the host
+    /// they reached the HTTP API on.
+    pub host: Option<String>,
+}
+
+impl Default for StunConfig {
+    fn default() -> Self {
+        StunConfig {
+            enabled: true,
+            port: 3478,
+            host: None,
+        }
+    }
+}
+
+impl StunConfig {
+    /// Reads `VOIP_STUN_ENABLED`, `VOIP_STUN_PORT` and `VOIP_STUN_HOST`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = StunConfig::default();
+        if let Some(enabled) = env_bool("VOIP_STUN_ENABLED")? {
+            config.enabled = enabled;
+        }
+        if let Ok(value) = env::var("VOIP_STUN_PORT") {
+            config.port = match value.parse() {
+                Ok(port) if port > 0 => port,
+                _ => return Err(format!("invalid value '{}' for VOIP_STUN_PORT", value)),
+            };
+        }
+        if let Ok(host) = env::var("VOIP_STUN_HOST") {
+            config.host = Some(host);
+        }
+        Ok(config)
+    }
+
+    /// The `stun:` URI for clients that reached the API at `request_host`
+    /// (a `Host` header value, possibly with a port).
+    pub fn uri(&self, request_host: &str) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let host = self.host.as_deref().unwrap_or_else(|| strip_port(request_host));
+        Some(format!("stun:{}:{}", host, self.port))
+    }
+}
+
+/// `host` without a trailing `:port`, leaving bare IPv6 addresses alone.
+pub fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port))
+            if port.chars().all(|c| c.is_ascii_digit()) && (!name.contains(':') || name.ends_with(']')) =>
+        {
+            name
+        }
+        _ => host,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StunError {
+    TooShort,
+    NotStun,
+    BadLength,
+    BadAttribute(u16),
+    BadFingerprint,
+}
+
+impl fmt::Display for StunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StunError::TooShort => write!(f, "message too short"),
+            StunError::NotStun => write!(f, "not a STUN message"),
+            StunError::BadLength => write!(f, "length does not match the datagram"),
+            StunError::BadAttribute(t) => write!(f, "malformed attribute {:#06x}", t),
+            StunError::BadFingerprint => write!(f, "FINGERPRINT does not match"),
+        }
+    }
+}
+
+impl std::error::Error for StunError {}
+
+/// A STUN message with its attributes kept as raw values, in order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub msg_type: u16,
+    pub transaction_id: [u8; 12],
+    pub attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl Message {
+    pub fn new(msg_type: u16, transaction_id: [u8; 12]) -> Self {
+        Message {
+            msg_type,
+            transaction_id,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// The method, with the class bits interleaved in the type removed.
+    pub fn method(&self) -> u16 {
+        let t = self.msg_type;
+        (t & 0x000F) | ((t & 0x00E0) >> 1) | ((t & 0x3E00) >> 2)
+    }
+
+    pub fn class(&self) -> u16 {
+        self.msg_type & 0x0110
+    }
+
+    /// A response (`CLASS_SUCCESS` or `CLASS_ERROR`) to this request.
+    pub fn response(&self, class: u16) -> Message {
+        Message::new(message_type(self.method(), class), self.transaction_id)
+    }
+
+    /// Whether `buf` looks like a STUN message: top two bits clear and the
+    /// magic cookie in place. Used to tell STUN apart from other traffic on
+    /// a shared port.
+    pub fn is_stun(buf: &[u8]) -> bool {
+        buf.len() >= HEADER_LEN
+            && buf[0] & 0xC0 == 0
+            && u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) == MAGIC_COOKIE
+    }
+
+    /// Parses a message, checking its FINGERPRINT if it has one.
+    pub fn parse(buf: &[u8]) -> Result<Self, StunError> {
+        if buf.len() < HEADER_LEN {
+            return Err(StunError::TooShort);
+        }
+        if !Self::is_stun(buf) {
+            return Err(StunError::NotStun);
+        }
+        let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if !length.is_multiple_of(4) || HEADER_LEN + length != buf.len() {
+            return Err(StunError::BadLength);
+        }
+
+        let mut message = Message::new(
+            u16::from_be_bytes([buf[0], buf[1]]),
+            buf[8..HEADER_LEN].try_into().expect("header length checked"),
+        );
+        let mut offset = HEADER_LEN;
+        while offset < buf.len() {
+            if offset + 4 > buf.len() {
+                return Err(StunError::BadLength);
+            }
+            let attr_type = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+            let attr_len = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+            let value_start = offset + 4;
+            let value_end = value_start + attr_len;
+            if value_end > buf.len() {
+                return Err(StunError::BadAttribute(attr_type));
+            }
+
+            if attr_type == attr::FINGERPRINT {
+                if attr_len != 4 || value_end != buf.len() {
+                    return Err(StunError::BadAttribute(attr_type));
+                }
+                let expected = u32::from_be_bytes(buf[value_start..value_end].try_into().unwrap());
+                if crc32(&buf[..offset]) ^ FINGERPRINT_XOR != expected {
+                    return Err(StunError::BadFingerprint);
+                }
+            }
+
+            message.attributes.push((attr_type, buf[value_start..value_end].to_vec()));
+            offset = value_start + padded(attr_len);
+        }
+        Ok(message)
+    }
+
+    pub fn attribute(&self, attr_type: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(t, _)| *t == attr_type)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn add_attribute(&mut self, attr_type: u16, value: Vec<u8>) {
+        self.attributes.push((attr_type, value));
+    }
+
+    pub fn add_xor_mapped_address(&mut self, addr: SocketAddr) {
+        let value = self.xor_address(addr);
+        self.add_attribute(attr::XOR_MAPPED_ADDRESS, value);
+    }
+
+    /// The address a Binding response reports. Only clients read it, and
+    /// the server is not one, so this is for checking responses in tests.
+    #[cfg(test)]
+    pub fn xor_mapped_address(&self) -> Option<SocketAddr> {
+        self.decode_xor_address(self.attribute(attr::XOR_MAPPED_ADDRESS)?)
+    }
+
+    /// Encodes `addr` as an XOR-MAPPED-ADDRESS style value for this
+    /// message's transaction.
+    pub fn xor_address(&self, addr: SocketAddr) -> Vec<u8> {
+        let mask = self.xor_mask();
+        let mut value = vec![0];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                value.push(0x01);
+                value.extend_from_slice(&(addr.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+                value.extend(ip.octets().iter().zip(&mask).map(|(b, m)| b ^ m));
+            }
+            IpAddr::V6(ip) => {
+                value.push(0x02);
+                value.extend_from_slice(&(addr.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+                value.extend(ip.octets().iter().zip(&mask).map(|(b, m)| b ^ m));
+            }
+        }
+        value
+    }
+
+    pub fn decode_xor_address(&self, value: &[u8]) -> Option<SocketAddr> {
+        if value.len() < 4 {
+            return None;
+        }
+        let mask = self.xor_mask();
+        let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+        let ip = match (value[1], value.len()) {
+            (0x01, 8) => {
+                let octets: [u8; 4] = std::array::from_fn(|i| value[4 + i] ^ mask[i]);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            (0x02, 20) => {
+                let octets: [u8; 16] = std::array::from_fn(|i| value[4 + i] ^ mask[i]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// Magic cookie followed by the transaction ID, which addresses are
+    /// XORed with.
+    fn xor_mask(&self) -> [u8; 16] {
+        let mut mask = [0u8; 16];
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(&self.transaction_id);
+        mask
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + 64);
+        buf.extend_from_slice(&self.msg_type.to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id);
+        for (attr_type, value) in &self.attributes {
+            buf.extend_from_slice(&attr_type.to_be_bytes());
+            buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            buf.extend_from_slice(value);
+            buf.resize(buf.len() + padded(value.len()) - value.len(), 0);
+        }
+        set_length(&mut buf);
+        buf
+    }
+
+    /// Encodes the message with a FINGERPRINT attribute appended.
+    pub fn encode_with_fingerprint(&self) -> Vec<u8> {
+        let mut buf = self.encode();
+        append_fingerprint(&mut buf);
+        buf
+    }
+
+    /// Encodes the message with MESSAGE-INTEGRITY under `key`, followed by
+    /// FINGERPRINT.
+    pub fn encode_with_integrity(&self, key: &[u8]) -> Vec<u8> {
+        let mut buf = self.encode();
+        append_message_integrity(&mut buf, key);
+        append_fingerprint(&mut buf);
+        buf
+    }
+}
+
+/// Combines a method and class into a message type.
+pub fn message_type(method: u16, class: u16) -> u16 {
+    (method & 0x000F) | ((method & 0x0070) << 1) | ((method & 0x0F80) << 2) | class
+}
+
+/// Long-term credential key: MD5 of `username:realm:password`.
+pub fn long_term_key(username: &str, realm: &str, password: &str) -> Vec<u8> {
+    Md5::digest(format!("{}:{}:{}", username, realm, password)).to_vec()
+}
+
+/// Appends MESSAGE-INTEGRITY, an HMAC-SHA1 over the message so far with
+/// the length field already covering the attribute.
+pub fn append_message_integrity(buf: &mut Vec<u8>, key: &[u8]) {
+    let start = buf.len();
+    buf.extend_from_slice(&attr::MESSAGE_INTEGRITY.to_be_bytes());
+    buf.extend_from_slice(&20u16.to_be_bytes());
+    buf.extend_from_slice(&[0; 20]);
+    set_length(buf);
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&buf[..start]);
+    buf[start + 4..].copy_from_slice(&mac.finalize().into_bytes());
+}
+
+/// Checks the MESSAGE-INTEGRITY of an encoded message against `key`.
+/// Attributes after it (such as FINGERPRINT) are not covered.
+pub fn check_message_integrity(buf: &[u8], key: &[u8]) -> bool {
+    let mut offset = HEADER_LEN;
+    while offset + 4 <= buf.len() {
+        let attr_type = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+        let attr_len = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+        if attr_type == attr::MESSAGE_INTEGRITY {
+            if attr_len != 20 || offset + 24 > buf.len() {
+                return false;
+            }
+            let mut covered = buf[..offset].to_vec();
+            covered[2..4].copy_from_slice(&((offset + 24 - HEADER_LEN) as u16).to_be_bytes());
+            let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key length");
+            mac.update(&covered);
+            return mac.verify_slice(&buf[offset + 4..offset + 24]).is_ok();
+        }
+        offset += 4 + padded(attr_len);
+    }
+    false
+}
+
+/// Appends FINGERPRINT to an encoded message.
+pub fn append_fingerprint(buf: &mut Vec<u8>) {
+    let start = buf.len();
+    buf.extend_from_slice(&attr::FINGERPRINT.to_be_bytes());
+    buf.extend_from_slice(&4u16.to_be_bytes());
+    buf.extend_from_slice(&[0; 4]);
+    set_length(buf);
+    let crc = crc32(&buf[..start]) ^ FINGERPRINT_XOR;
+    buf[start + 4..].copy_from_slice(&crc.to_be_bytes());
+}
+
+fn set_length(buf: &mut [u8]) {
+    let length = (buf.len() - HEADER_LEN) as u16;
+    buf[2..4].copy_from_slice(&length.to_be_bytes());
+}
+
+fn padded(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+/// CRC-32 (ISO HDLC, as used by FINGERPRINT).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// ERROR-CODE value for `code` (e.g. 420) with its reason phrase.
+pub fn error_code(code: u16, reason: &str) -> Vec<u8> {
+    let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+    value.extend_from_slice(reason.as_bytes());
+    value
+}
+
+/// Builds the response to a Binding request from `src`, or `None` for
+/// anything that is not one. A request with comprehension-required
+/// attributes the server does not know gets a 420 error listing them.
+pub fn handle_binding(buf: &[u8], src: SocketAddr) -> Option<Vec<u8>> {
+    let request = Message::parse(buf).ok()?;
+    if request.msg_type != BINDING_REQUEST {
+        return None;
+    }
+
+    let unknown: Vec<u16> = request
+        .attributes
+        .iter()
+        .map(|(t, _)| *t)
+        .filter(|t| *t < 0x8000 && !matches!(*t,
+            attr::MAPPED_ADDRESS | attr::USERNAME | attr::MESSAGE_INTEGRITY | attr::ERROR_CODE
+            | attr::UNKNOWN_ATTRIBUTES | attr::REALM | attr::NONCE | attr::XOR_MAPPED_ADDRESS
+            | attr::PRIORITY | attr::USE_CANDIDATE))
+        .collect();
+
+    let mut response;
+    if unknown.is_empty() {
+        response = Message::new(BINDING_SUCCESS, request.transaction_id);
+        response.add_xor_mapped_address(src);
+    } else {
+        response = Message::new(BINDING_ERROR, request.transaction_id);
+        response.add_attribute(attr::ERROR_CODE, error_code(420, "Unknown Attribute"));
+        response.add_attribute(attr::UNKNOWN_ATTRIBUTES, unknown.iter().flat_map(|t| t.to_be_bytes()).collect());
+    }
+    response.add_attribute(attr::SOFTWARE, SOFTWARE.as_bytes().to_vec());
+    Some(response.encode_with_fingerprint())
+}
+
+/// Answers STUN Binding requests so clients can discover their public
+/// address without reaching an outside server.
+pub async fn stun_server(config: StunConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let socket = UdpSocket::bind(("0.0.0.0", config.port)).await?;
+    log::info!("🧭 STUN server listening on {}", socket.local_addr()?);
+
+    let mut buf = [0u8; 1500];
+    loop {
+        let (size, src) = match socket.recv_from(&mut buf).await {
+            Ok(recv) => recv,
+            Err(e) => {
+                log::warn!("STUN receive failed: {}", e);
+                continue;
+            }
+        };
+        if let Some(response) = handle_binding(&buf[..size], src) {
+            let _ = socket.send_to(&response, src).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 5769 section 2.1: request with SOFTWARE, PRIORITY, ICE-CONTROLLED,
+    /// USERNAME, MESSAGE-INTEGRITY and FINGERPRINT.
+    const SAMPLE_REQUEST: [u8; 108] = [
+        0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01,
+        0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10,
+        0x53, 0x54, 0x55, 0x4e, 0x20, 0x74, 0x65, 0x73, 0x74, 0x20, 0x63, 0x6c,
+        0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e, 0x00, 0x01, 0xff,
+        0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36,
+        0x00, 0x06, 0x00, 0x09, 0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76,
+        0x59, 0x20, 0x20, 0x20, 0x00, 0x08, 0x00, 0x14, 0x9a, 0xea, 0xa7, 0x0c,
+        0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e, 0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49,
+        0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5, 0x7a, 0x3b, 0xcf,
+    ];
+
+    /// RFC 5769 section 2.2: success response mapping 192.0.2.1:32853.
+    const SAMPLE_IPV4_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01,
+        0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b,
+        0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x20,
+        0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+        0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7,
+        0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    /// RFC 5769 section 2.3: success response mapping
+    /// [2001:db8:1234:5678:11:2233:4455:6677]:32853.
+    const SAMPLE_IPV6_RESPONSE: [u8; 92] = [
+        0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01,
+        0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b,
+        0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x20,
+        0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa,
+        0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        0x00, 0x08, 0x00, 0x14, 0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1,
+        0x17, 0x84, 0xc9, 0x7c, 0x82, 0x92, 0xc2, 0x75, 0xbf, 0xe3, 0xed, 0x41,
+        0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb, 0x0b, 0x4c,
+    ];
+
+    /// RFC 5769 section 2.4: request with long-term credentials and no
+    /// FINGERPRINT.
+    const SAMPLE_LONG_TERM_REQUEST: [u8; 116] = [
+        0x00, 0x01, 0x00, 0x60, 0x21, 0x12, 0xa4, 0x42, 0x78, 0xad, 0x34, 0x33,
+        0xc6, 0xad, 0x72, 0xc0, 0x29, 0xda, 0x41, 0x2e, 0x00, 0x06, 0x00, 0x12,
+        0xe3, 0x83, 0x9e, 0xe3, 0x83, 0x88, 0xe3, 0x83, 0xaa, 0xe3, 0x83, 0x83,
+        0xe3, 0x82, 0xaf, 0xe3, 0x82, 0xb9, 0x00, 0x00, 0x00, 0x15, 0x00, 0x1c,
+        0x66, 0x2f, 0x2f, 0x34, 0x39, 0x39, 0x6b, 0x39, 0x35, 0x34, 0x64, 0x36,
+        0x4f, 0x4c, 0x33, 0x34, 0x6f, 0x4c, 0x39, 0x46, 0x53, 0x54, 0x76, 0x79,
+        0x36, 0x34, 0x73, 0x41, 0x00, 0x14, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d,
+        0x70, 0x6c, 0x65, 0x2e, 0x6f, 0x72, 0x67, 0x00, 0x00, 0x08, 0x00, 0x14,
+        0xf6, 0x70, 0x24, 0x65, 0x6d, 0xd6, 0x4a, 0x3e, 0x02, 0xb8, 0xe0, 0x71,
+        0x2e, 0x85, 0xc9, 0xa2, 0x8c, 0xa8, 0x96, 0x66,
+    ];
+
+    const TRANSACTION_ID: [u8; 12] = [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+
+    fn ipv4_mapped() -> SocketAddr {
+        "192.0.2.1:32853".parse().unwrap()
+    }
+
+    fn ipv6_mapped() -> SocketAddr {
+        "[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap()
+    }
+
+    #[test]
+    fn parses_sample_request() {
+        let message = Message::parse(&SAMPLE_REQUEST).unwrap();
+        assert_eq!(message.msg_type, BINDING_REQUEST);
+        assert_eq!(message.transaction_id, TRANSACTION_ID);
+        assert_eq!(message.attribute(attr::SOFTWARE), Some(&b"STUN test client"[..]));
+        assert_eq!(message.attribute(attr::USERNAME), Some(&b"evtj:h6vY"[..]));
+        assert_eq!(message.attribute(attr::PRIORITY), Some(&[0x6e, 0x00, 0x01, 0xff][..]));
+        assert_eq!(message.attributes.last().unwrap().0, attr::FINGERPRINT);
+    }
+
+    #[test]
+    fn parses_sample_long_term_request() {
+        let message = Message::parse(&SAMPLE_LONG_TERM_REQUEST).unwrap();
+        assert_eq!(message.attribute(attr::USERNAME), Some("マトリックス".as_bytes()));
+        assert_eq!(message.attribute(attr::NONCE), Some(&b"f//499k954d6OL34oL9FSTvy64sA"[..]));
+        assert_eq!(message.attribute(attr::REALM), Some(&b"example.org"[..]));
+        assert!(message.attribute(attr::FINGERPRINT).is_none());
+    }
+
+    #[test]
+    fn decodes_sample_responses() {
+        let v4 = Message::parse(&SAMPLE_IPV4_RESPONSE).unwrap();
+        assert_eq!(v4.msg_type, BINDING_SUCCESS);
+        assert_eq!(v4.attribute(attr::SOFTWARE), Some(&b"test vector"[..]));
+        assert_eq!(v4.xor_mapped_address(), Some(ipv4_mapped()));
+
+        let v6 = Message::parse(&SAMPLE_IPV6_RESPONSE).unwrap();
+        assert_eq!(v6.xor_mapped_address(), Some(ipv6_mapped()));
+    }
+
+    #[test]
+    fn encodes_xor_mapped_address_like_samples() {
+        let message = Message::new(BINDING_SUCCESS, TRANSACTION_ID);
+        assert_eq!(message.xor_address(ipv4_mapped()), &SAMPLE_IPV4_RESPONSE[40..48]);
+        assert_eq!(message.xor_address(ipv6_mapped()), &SAMPLE_IPV6_RESPONSE[40..60]);
+    }
+
+    #[test]
+    fn computes_sample_fingerprints() {
+        for sample in [&SAMPLE_REQUEST[..], &SAMPLE_IPV4_RESPONSE, &SAMPLE_IPV6_RESPONSE] {
+            let body = &sample[..sample.len() - 8];
+            let mut buf = body.to_vec();
+            append_fingerprint(&mut buf);
+            assert_eq!(buf, sample);
+        }
+    }
+
+    #[test]
+    fn checks_sample_message_integrity() {
+        let short_term = b"VOkJxbRl1RmTxUk/WvJxBt";
+        for sample in [&SAMPLE_REQUEST[..], &SAMPLE_IPV4_RESPONSE, &SAMPLE_IPV6_RESPONSE] {
+            assert!(check_message_integrity(sample, short_term));
+            assert!(!check_message_integrity(sample, b"wrong password"));
+        }
+
+        let long_term = long_term_key("マトリックス", "example.org", "TheMatrIX");
+        assert!(check_message_integrity(&SAMPLE_LONG_TERM_REQUEST, &long_term));
+    }
+
+    #[test]
+    fn computes_sample_message_integrity() {
+        // Everything before MESSAGE-INTEGRITY, then MI and FINGERPRINT again.
+        let mut buf = SAMPLE_REQUEST[..SAMPLE_REQUEST.len() - 32].to_vec();
+        append_message_integrity(&mut buf, b"VOkJxbRl1RmTxUk/WvJxBt");
+        append_fingerprint(&mut buf);
+        assert_eq!(buf, SAMPLE_REQUEST);
+
+        let long_term = long_term_key("マトリックス", "example.org", "TheMatrIX");
+        let mut buf = SAMPLE_LONG_TERM_REQUEST[..SAMPLE_LONG_TERM_REQUEST.len() - 24].to_vec();
+        append_message_integrity(&mut buf, &long_term);
+        assert_eq!(buf, SAMPLE_LONG_TERM_REQUEST);
+    }
+
+    #[test]
+    fn splits_message_types() {
+        let message = Message::new(0x0113, TRANSACTION_ID);
+        assert_eq!((message.method(), message.class()), (0x003, CLASS_ERROR));
+        assert_eq!(message_type(0x009, CLASS_SUCCESS), 0x0109);
+        assert_eq!(message_type(0x006, CLASS_INDICATION), 0x0016);
+        let high = Message::new(message_type(0xABC, CLASS_INDICATION), TRANSACTION_ID);
+        assert_eq!((high.method(), high.class()), (0xABC, CLASS_INDICATION));
+        assert_eq!(Message::new(BINDING_REQUEST, TRANSACTION_ID).response(CLASS_SUCCESS).msg_type, BINDING_SUCCESS);
+    }
+
+    #[test]
+    fn rejects_corrupted_fingerprint() {
+        let mut request = SAMPLE_REQUEST;
+        request[30] ^= 0x01;
+        assert_eq!(Message::parse(&request), Err(StunError::BadFingerprint));
+
+        let mut request = SAMPLE_REQUEST;
+        request[107] ^= 0x01;
+        assert_eq!(Message::parse(&request), Err(StunError::BadFingerprint));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert_eq!(Message::parse(&SAMPLE_REQUEST[..12]), Err(StunError::TooShort));
+        assert_eq!(Message::parse(&SAMPLE_REQUEST[..104]), Err(StunError::BadLength));
+
+        let mut rtp = SAMPLE_REQUEST;
+        rtp[0] = 0x80;
+        assert_eq!(Message::parse(&rtp), Err(StunError::NotStun));
+    }
+
+    #[test]
+    fn answers_sample_request() {
+        let response = handle_binding(&SAMPLE_REQUEST, ipv4_mapped()).unwrap();
+        let message = Message::parse(&response).unwrap();
+        assert_eq!(message.msg_type, BINDING_SUCCESS);
+        assert_eq!(message.transaction_id, TRANSACTION_ID);
+        assert_eq!(message.xor_mapped_address(), Some(ipv4_mapped()));
+        assert_eq!(message.attributes.last().unwrap().0, attr::FINGERPRINT);
+
+        let response = handle_binding(&SAMPLE_REQUEST, ipv6_mapped()).unwrap();
+        assert_eq!(Message::parse(&response).unwrap().xor_mapped_address(), Some(ipv6_mapped()));
+    }
+
+    #[test]
+    fn rejects_unknown_comprehension_required_attributes() {
+        let mut request = Message::new(BINDING_REQUEST, TRANSACTION_ID);
+        request.add_attribute(0x0030, vec![1, 2, 3, 4]);
+        request.add_attribute(0x8030, vec![1, 2, 3, 4]);
+
+        let response = handle_binding(&request.encode(), ipv4_mapped()).unwrap();
+        let message = Message::parse(&response).unwrap();
+        assert_eq!(message.msg_type, BINDING_ERROR);
+        assert_eq!(message.attribute(attr::ERROR_CODE).unwrap()[2..4], [4, 20]);
+        assert_eq!(message.attribute(attr::UNKNOWN_ATTRIBUTES), Some(&[0x00, 0x30][..]));
+    }
+
+    #[test]
+    fn ignores_responses_and_indications() {
+        assert!(handle_binding(&SAMPLE_IPV4_RESPONSE, ipv4_mapped()).is_none());
+        let indication = Message::new(BINDING | CLASS_INDICATION, TRANSACTION_ID);
+        assert!(handle_binding(&indication.encode(), ipv4_mapped()).is_none());
+    }
+
+    #[test]
+    fn advertises_request_host() {
+        let config = StunConfig::default();
+        assert_eq!(config.uri("voip.example:5000").as_deref(), Some("stun:voip.example:3478"));
+        assert_eq!(config.uri("[::1]:5000").as_deref(), Some("stun:[::1]:3478"));
+        assert_eq!(config.uri("10.0.0.5").as_deref(), Some("stun:10.0.0.5:3478"));
+
+        let config = StunConfig { host: Some("stun.internal".into()), ..StunConfig::default() };
+        assert_eq!(config.uri("10.0.0.5:5000").as_deref(), Some("stun:stun.internal:3478"));
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::PathBuf;
+
+/// An audio offer as Chrome generates it, trimmed.
+pub const BROWSER_OFFER: &str = "v=0\r\n\
+    o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+    s=-\r\n\
+    t=0 0\r\n\
+    a=group:BUNDLE 0\r\n\
+    a=msid-semantic: WMS\r\n\
+    m=audio 9 UDP/TLS/RTP/SAVPF 111 63 9 0 8 13 110 126\r\n\
+    c=IN IP4 0.0.0.0\r\n\
+    a=rtcp:9 IN IP4 0.0.0.0\r\n\
+    a=ice-ufrag:JtCq\r\n\
+    a=ice-pwd:qsMF9D+tt3PLnqvMt0G+SGG/\r\n\
+    a=ice-options:trickle\r\n\
+    a=fingerprint:sha-256 7B:8B:F0:65:5F:78:E2:51:3B:AC:6F:F3:3F:46:1B:35:DC:B8:5F:64:1A:24:C2:43:F0:A1:58:D0:A1:2C:19:08\r\n\
+    a=setup:actpass\r\n\
+    a=mid:0\r\n\
+    a=sendrecv\r\n\
+    a=rtcp-mux\r\n\
+    a=rtpmap:111 opus/48000/2\r\n\
+    a=rtcp-fb:111 transport-cc\r\n\
+    a=fmtp:111 minptime=10;useinbandfec=1\r\n\
+    a=rtpmap:63 red/48000/2\r\n\
+    a=fmtp:63 111/111\r\n\
+    a=rtpmap:9 G722/8000\r\n\
+    a=rtpmap:0 PCMU/8000\r\n\
+    a=rtpmap:8 PCMA/8000\r\n\
+    a=rtpmap:13 CN/8000\r\n\
+    a=rtpmap:110 telephone-event/48000\r\n\
+    a=rtpmap:126 telephone-event/8000\r\n";
+
+/// Reads a mono 16-bit PCM WAV from `tests/fixtures`, returning its sample
+/// rate and samples.
+pub fn read_fixture(name: &str) -> (u32, Vec<i16>) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    let bytes = std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    assert!(bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WAVE", "{} is not a WAV file", name);
+
+    let mut rate = None;
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let id = &bytes[offset..offset + 4];
+        let len = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let body = &bytes[offset + 8..(offset + 8 + len).min(bytes.len())];
+        match id {
+            b"fmt " => {
+                let format = u16::from_le_bytes([body[0], body[1]]);
+                let channels = u16::from_le_bytes([body[2], body[3]]);
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                assert_eq!((format, channels, bits), (1, 1, 16), "{} must be mono 16-bit PCM", name);
+                rate = Some(u32::from_le_bytes(body[4..8].try_into().unwrap()));
+            }
+            b"data" => {
+                let samples = body.chunks_exact(2).map(|s| i16::from_le_bytes([s[0], s[1]])).collect();
+                return (rate.expect("fmt chunk before data"), samples);
+            }
+            _ => {}
+        }
+        offset += 8 + len + len % 2;
+    }
+    panic!("{} has no data chunk", name);
+}
+
+/// Sums two signals sample by sample, saturating.
+pub fn mix(a: &[i16], b: &[i16]) -> Vec<i16> {
+    a.iter().zip(b).map(|(x, y)| x.saturating_add(*y)).collect()
+}
+
+pub fn energy(signal: &[i16]) -> f64 {
+    signal.iter().map(|&s| (s as f64).powi(2)).sum()
+}
+
+/// Mean energy per sample.
+pub fn power(signal: &[i16]) -> f64 {
+    energy(signal) / signal.len().max(1) as f64
+}
+
+pub fn db(ratio: f64) -> f64 {
+    10.0 * ratio.log10()
+}
+
+/// The samples from `from` to `to` seconds in.
+pub fn seconds(rate: u32, from: f32, to: f32) -> std::ops::Range<usize> {
+    (from * rate as f32) as usize..(to * rate as f32) as usize
+}
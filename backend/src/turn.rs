@@ -0,0 +1,997 @@
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::env_bool;
+use crate::stun::{self, attr, Message, CLASS_ERROR, CLASS_INDICATION, CLASS_REQUEST, CLASS_SUCCESS};
+
+/// TURN methods (RFC 5766).
+const ALLOCATE: u16 = 0x003;
+const REFRESH: u16 = 0x004;
+const SEND: u16 = 0x006;
+const DATA: u16 = 0x007;
+const CREATE_PERMISSION: u16 = 0x008;
+const CHANNEL_BIND: u16 = 0x009;
+
+/// REQUESTED-TRANSPORT protocol number for UDP, the only one relayed.
+const TRANSPORT_UDP: u8 = 17;
+
+const DEFAULT_LIFETIME_SECS: u32 = 600;
+const MAX_LIFETIME_SECS: u32 = 3600;
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+const NONCE_LIFETIME_SECS: i64 = 3600;
+const CHANNELS: std::ops::RangeInclusive<u16> = 0x4000..=0x7FFE;
+
+/// The embedded TURN relay and the credentials handed out for it.
+#[derive(Debug, Clone)]
+pub struct TurnConfig {
+    pub enabled: bool,
+    pub port: u16,
+    /// Host advertised to clients, as for `StunConfig::host`.
+    pub host: Option<String>,
+    pub realm: String,
+    /// Shared secret credentials are derived from. Without one a random
+    /// secret is made at startup, so credentials do not survive a restart.
+    pub secret: String,
+    /// How long the credentials returned by `/api/ice/servers` are valid.
+    pub credential_ttl_secs: i64,
+    /// Address reported in XOR-RELAYED-ADDRESS. Without one, the local
+    /// address the server uses to reach each client.
+    pub relay_ip: Option<IpAddr>,
+    pub max_allocations: usize,
+    /// Allocations one user may hold at a time, across all their clients.
+    pub max_user_allocations: usize,
+    /// Peers allowed even though they are in a restricted range such as
+    /// loopback or a private network, which are refused otherwise.
+    pub allowed_peers: Vec<IpAddr>,
+}
+
+impl Default for TurnConfig {
+    fn default() -> Self {
+        TurnConfig {
+            enabled: true,
+            port: 3479,
+            host: None,
+            realm: "voip".to_string(),
+            secret: format!("{}", uuid::Uuid::new_v4().simple()),
+            credential_ttl_secs: 3600,
+            relay_ip: None,
+            max_allocations: 100,
+            max_user_allocations: 5,
+            allowed_peers: Vec::new(),
+        }
+    }
+}
+
+/// Time-limited TURN credentials for one user.
+#[derive(Debug, Clone)]
+pub struct TurnCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl TurnConfig {
+    /// Reads `VOIP_TURN_ENABLED`, `VOIP_TURN_PORT`, `VOIP_TURN_HOST`,
+    /// `VOIP_TURN_REALM`, `VOIP_TURN_SECRET`, `VOIP_TURN_TTL_SECS`,
+    /// `VOIP_TURN_RELAY_IP`, `VOIP_TURN_MAX_ALLOCATIONS`,
+    /// `VOIP_TURN_MAX_USER_ALLOCATIONS` and `VOIP_TURN_ALLOWED_PEERS` (a
+    /// comma-separated list of IP addresses).
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = TurnConfig::default();
+        if let Some(enabled) = env_bool("VOIP_TURN_ENABLED")? {
+            config.enabled = enabled;
+        }
+        if let Ok(value) = env::var("VOIP_TURN_PORT") {
+            config.port = match value.parse() {
+                Ok(port) if port > 0 => port,
+                _ => return Err(format!("invalid value '{}' for VOIP_TURN_PORT", value)),
+            };
+        }
+        if let Ok(host) = env::var("VOIP_TURN_HOST") {
+            config.host = Some(host);
+        }
+        if let Ok(realm) = env::var("VOIP_TURN_REALM") {
+            config.realm = realm;
+        }
+        if let Ok(secret) = env::var("VOIP_TURN_SECRET") {
+            if secret.is_empty() {
+                return Err("VOIP_TURN_SECRET must not be empty".to_string());
+            }
+            config.secret = secret;
+        }
+        if let Ok(value) = env::var("VOIP_TURN_TTL_SECS") {
+            config.credential_ttl_secs = match value.parse() {
+                Ok(ttl) if ttl > 0 => ttl,
+                _ => return Err(format!("invalid value '{}' for VOIP_TURN_TTL_SECS", value)),
+            };
+        }
+        if let Ok(value) = env::var("VOIP_TURN_RELAY_IP") {
+            config.relay_ip = match value.parse() {
+                Ok(ip) => Some(ip),
+                Err(_) => return Err(format!("invalid value '{}' for VOIP_TURN_RELAY_IP", value)),
+            };
+        }
+        if let Ok(value) = env::var("VOIP_TURN_MAX_ALLOCATIONS") {
+            config.max_allocations = match value.parse() {
+                Ok(max) if max > 0 => max,
+                _ => return Err(format!("invalid value '{}' for VOIP_TURN_MAX_ALLOCATIONS", value)),
+            };
+        }
+        if let Ok(value) = env::var("VOIP_TURN_MAX_USER_ALLOCATIONS") {
+            config.max_user_allocations = match value.parse() {
+                Ok(max) if max > 0 => max,
+                _ => return Err(format!("invalid value '{}' for VOIP_TURN_MAX_USER_ALLOCATIONS", value)),
+            };
+        }
+        if let Ok(value) = env::var("VOIP_TURN_ALLOWED_PEERS") {
+            config.allowed_peers = value
+                .split(',')
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty())
+                .map(|ip| ip.parse().map_err(|_| format!("invalid address '{}' in VOIP_TURN_ALLOWED_PEERS", ip)))
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(config)
+    }
+
+    /// The `turn:` URI for clients that reached the API at `request_host`.
+    pub fn uri(&self, request_host: &str) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let host = self.host.as_deref().unwrap_or_else(|| stun::strip_port(request_host));
+        Some(format!("turn:{}:{}?transport=udp", host, self.port))
+    }
+
+    /// Credentials for `user_id` that expire after `credential_ttl_secs`:
+    /// the username is `<expiry>:<user_id>` and the password an HMAC of it,
+    /// so the server can check them without keeping any state.
+    pub fn credentials(&self, user_id: &str) -> TurnCredentials {
+        let expires = chrono::Local::now().timestamp() + self.credential_ttl_secs;
+        let username = format!("{}:{}", expires, user_id);
+        let password = self.password(&username);
+        TurnCredentials { username, password }
+    }
+
+    fn password(&self, username: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.hmac(username.as_bytes()))
+    }
+
+    fn hmac(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(self.secret.as_bytes()).expect("HMAC accepts any key length");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// A nonce carrying its own expiry and signed with the secret.
+    fn nonce(&self) -> String {
+        self.nonce_expiring(chrono::Local::now().timestamp() + NONCE_LIFETIME_SECS)
+    }
+
+    fn nonce_expiring(&self, expires: i64) -> String {
+        let expires = format!("{:016x}", expires);
+        let mac: String = self.hmac(expires.as_bytes())[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        expires + &mac
+    }
+
+    fn nonce_valid(&self, nonce: &[u8]) -> bool {
+        let Ok(nonce) = std::str::from_utf8(nonce) else {
+            return false;
+        };
+        let Some(expires) = nonce.get(..16) else {
+            return false;
+        };
+        let mac: String = self.hmac(expires.as_bytes())[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        nonce[16..] == mac
+            && i64::from_str_radix(expires, 16).is_ok_and(|expires| expires > chrono::Local::now().timestamp())
+    }
+}
+
+/// Peers an allocation may exchange data with, shared with its relay task.
+#[derive(Default)]
+struct Peers {
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<u16, (SocketAddr, Instant)>,
+}
+
+impl Peers {
+    fn permitted(&self, ip: IpAddr, now: Instant) -> bool {
+        self.permissions.get(&ip).is_some_and(|expires| *expires > now)
+    }
+
+    fn channel_of(&self, peer: SocketAddr, now: Instant) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, (addr, expires))| *addr == peer && *expires > now)
+            .map(|(channel, _)| *channel)
+    }
+
+    fn peer_of(&self, channel: u16, now: Instant) -> Option<SocketAddr> {
+        self.channels
+            .get(&channel)
+            .filter(|(_, expires)| *expires > now)
+            .map(|(addr, _)| *addr)
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.permissions.retain(|_, expires| *expires > now);
+        self.channels.retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+struct Allocation {
+    username: String,
+    relay: Arc<UdpSocket>,
+    relayed: SocketAddr,
+    expires: Instant,
+    peers: Arc<Mutex<Peers>>,
+    cancel: CancellationToken,
+    /// The Allocate success response, re-sent if the request is retransmitted.
+    allocate_response: ([u8; 12], Vec<u8>),
+}
+
+type TurnResult = Result<Message, (u16, &'static str)>;
+
+struct TurnServer {
+    config: TurnConfig,
+    socket: Arc<UdpSocket>,
+    allocations: HashMap<SocketAddr, Allocation>,
+}
+
+impl TurnServer {
+    async fn handle(&mut self, buf: &[u8], client: SocketAddr) {
+        if buf.len() >= 4 && (0x40..0x80).contains(&buf[0]) {
+            self.forward_channel_data(buf, client).await;
+            return;
+        }
+        let Ok(request) = Message::parse(buf) else {
+            return;
+        };
+        let response = match (request.method(), request.class()) {
+            (stun::BINDING, CLASS_REQUEST) => stun::handle_binding(buf, client),
+            (SEND, CLASS_INDICATION) => {
+                self.forward_send(&request, client).await;
+                None
+            }
+            (ALLOCATE | REFRESH | CREATE_PERMISSION | CHANNEL_BIND, CLASS_REQUEST) => {
+                Some(self.handle_request(&request, buf, client))
+            }
+            _ => None,
+        };
+        if let Some(response) = response {
+            let _ = self.socket.send_to(&response, client).await;
+        }
+    }
+
+    fn handle_request(&mut self, request: &Message, raw: &[u8], client: SocketAddr) -> Vec<u8> {
+        let (username, key) = match self.authenticate(request, raw) {
+            Ok(credentials) => credentials,
+            Err(response) => return response,
+        };
+
+        if let Some(allocation) = self.allocations.get(&client) {
+            if allocation.username != username {
+                return error_response(request, 441, "Wrong Credentials").encode_with_integrity(&key);
+            }
+            if request.method() == ALLOCATE && allocation.allocate_response.0 == request.transaction_id {
+                return allocation.allocate_response.1.clone();
+            }
+        }
+
+        let unknown = unknown_attributes(request);
+        let result = if !unknown.is_empty() {
+            let mut response = error_response(request, 420, "Unknown Attribute");
+            response.add_attribute(attr::UNKNOWN_ATTRIBUTES, unknown.iter().flat_map(|t| t.to_be_bytes()).collect());
+            Ok(response)
+        } else {
+            match request.method() {
+                ALLOCATE => self.allocate(request, client, &username),
+                REFRESH => self.refresh(request, client),
+                CREATE_PERMISSION => self.create_permission(request, client),
+                _ => self.channel_bind(request, client),
+            }
+        };
+
+        let response = result
+            .unwrap_or_else(|(code, reason)| error_response(request, code, reason))
+            .encode_with_integrity(&key);
+        if request.method() == ALLOCATE {
+            if let Some(allocation) = self.allocations.get_mut(&client) {
+                if allocation.allocate_response.1.is_empty() {
+                    allocation.allocate_response = (request.transaction_id, response.clone());
+                }
+            }
+        }
+        response
+    }
+
+    /// Checks the long-term credentials of a request, returning the
+    /// username and key, or the error response to send.
+    fn authenticate(&self, request: &Message, raw: &[u8]) -> Result<(String, Vec<u8>), Vec<u8>> {
+        if request.attribute(attr::MESSAGE_INTEGRITY).is_none() {
+            return Err(self.challenge(request, 401, "Unauthorized"));
+        }
+        let (Some(username), Some(_), Some(nonce)) = (
+            request.attribute(attr::USERNAME),
+            request.attribute(attr::REALM),
+            request.attribute(attr::NONCE),
+        ) else {
+            return Err(error_response(request, 400, "Bad Request").encode_with_fingerprint());
+        };
+        if !self.config.nonce_valid(nonce) {
+            return Err(self.challenge(request, 438, "Stale Nonce"));
+        }
+        let Ok(username) = std::str::from_utf8(username) else {
+            return Err(self.challenge(request, 401, "Unauthorized"));
+        };
+        let now = chrono::Local::now().timestamp();
+        let unexpired = username
+            .split_once(':')
+            .and_then(|(expires, _)| expires.parse::<i64>().ok())
+            .is_some_and(|expires| expires > now);
+        let key = stun::long_term_key(username, &self.config.realm, &self.config.password(username));
+        if !unexpired || !stun::check_message_integrity(raw, &key) {
+            return Err(self.challenge(request, 401, "Unauthorized"));
+        }
+        Ok((username.to_string(), key))
+    }
+
+    /// An error response asking the client to (re)authenticate.
+    fn challenge(&self, request: &Message, code: u16, reason: &str) -> Vec<u8> {
+        let mut response = error_response(request, code, reason);
+        response.add_attribute(attr::REALM, self.config.realm.as_bytes().to_vec());
+        response.add_attribute(attr::NONCE, self.config.nonce().into_bytes());
+        response.encode_with_fingerprint()
+    }
+
+    fn allocate(&mut self, request: &Message, client: SocketAddr, username: &str) -> TurnResult {
+        if self.allocations.contains_key(&client) {
+            return Err((437, "Allocation Mismatch"));
+        }
+        match request.attribute(attr::REQUESTED_TRANSPORT) {
+            None => return Err((400, "Bad Request")),
+            Some(value) if value.first() != Some(&TRANSPORT_UDP) => {
+                return Err((442, "Unsupported Transport Protocol"))
+            }
+            _ => {}
+        }
+        if self.allocations.len() >= self.config.max_allocations {
+            return Err((486, "Allocation Quota Reached"));
+        }
+        let user = user_of(username);
+        if self.allocations.values().filter(|a| user_of(&a.username) == user).count() >= self.config.max_user_allocations {
+            return Err((486, "Allocation Quota Reached"));
+        }
+
+        let relay = std::net::UdpSocket::bind(("0.0.0.0", 0))
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+            .and_then(UdpSocket::from_std)
+            .map_err(|_| (508, "Insufficient Capacity"))?;
+        let relay_ip = self.config.relay_ip.or_else(|| local_ip_towards(client));
+        let relayed = match (relay_ip, relay.local_addr()) {
+            (Some(ip), Ok(local)) => SocketAddr::new(ip, local.port()),
+            _ => return Err((508, "Insufficient Capacity")),
+        };
+        let lifetime = lifetime(request);
+
+        let relay = Arc::new(relay);
+        let peers = Arc::new(Mutex::new(Peers::default()));
+        let cancel = CancellationToken::new();
+        tokio::spawn(relay_task(
+            client,
+            Arc::clone(&relay),
+            Arc::clone(&self.socket),
+            Arc::clone(&peers),
+            cancel.clone(),
+        ));
+        self.allocations.insert(
+            client,
+            Allocation {
+                username: username.to_string(),
+                relay,
+                relayed,
+                expires: Instant::now() + Duration::from_secs(lifetime as u64),
+                peers,
+                cancel,
+                allocate_response: ([0; 12], Vec::new()),
+            },
+        );
+        log::info!("🔀 TURN allocation {} for {} ({}s)", relayed, client, lifetime);
+
+        let mut response = request.response(CLASS_SUCCESS);
+        response.add_attribute(attr::XOR_RELAYED_ADDRESS, response.xor_address(relayed));
+        response.add_attribute(attr::LIFETIME, lifetime.to_be_bytes().to_vec());
+        response.add_xor_mapped_address(client);
+        Ok(response)
+    }
+
+    fn refresh(&mut self, request: &Message, client: SocketAddr) -> TurnResult {
+        let requested = request.attribute(attr::LIFETIME).and_then(read_u32);
+        if requested == Some(0) {
+            let allocation = self.allocations.remove(&client).ok_or((437, "Allocation Mismatch"))?;
+            allocation.cancel.cancel();
+            log::info!("🔀 TURN allocation {} for {} released", allocation.relayed, client);
+            let mut response = request.response(CLASS_SUCCESS);
+            response.add_attribute(attr::LIFETIME, 0u32.to_be_bytes().to_vec());
+            return Ok(response);
+        }
+        let allocation = self.allocations.get_mut(&client).ok_or((437, "Allocation Mismatch"))?;
+        let lifetime = lifetime(request);
+        allocation.expires = Instant::now() + Duration::from_secs(lifetime as u64);
+
+        let mut response = request.response(CLASS_SUCCESS);
+        response.add_attribute(attr::LIFETIME, lifetime.to_be_bytes().to_vec());
+        Ok(response)
+    }
+
+    fn create_permission(&mut self, request: &Message, client: SocketAddr) -> TurnResult {
+        let allocation = self.allocations.get(&client).ok_or((437, "Allocation Mismatch"))?;
+        let mut ips = Vec::new();
+        for (attr_type, value) in &request.attributes {
+            if *attr_type == attr::XOR_PEER_ADDRESS {
+                ips.push(peer_address(request, value)?.ip());
+            }
+        }
+        if ips.is_empty() {
+            return Err((400, "Bad Request"));
+        }
+        if ips.iter().any(|ip| !self.peer_allowed(allocation, *ip)) {
+            return Err((403, "Forbidden"));
+        }
+
+        let expires = Instant::now() + PERMISSION_LIFETIME;
+        let mut peers = allocation.peers.lock().unwrap();
+        for ip in ips {
+            peers.permissions.insert(ip, expires);
+        }
+        Ok(request.response(CLASS_SUCCESS))
+    }
+
+    fn channel_bind(&mut self, request: &Message, client: SocketAddr) -> TurnResult {
+        let allocation = self.allocations.get(&client).ok_or((437, "Allocation Mismatch"))?;
+        let (Some(channel), Some(peer)) = (
+            request.attribute(attr::CHANNEL_NUMBER).filter(|v| v.len() >= 2),
+            request.attribute(attr::XOR_PEER_ADDRESS),
+        ) else {
+            return Err((400, "Bad Request"));
+        };
+        let channel = u16::from_be_bytes([channel[0], channel[1]]);
+        let peer = peer_address(request, peer)?;
+        if !CHANNELS.contains(&channel) {
+            return Err((400, "Bad Request"));
+        }
+        if !self.peer_allowed(allocation, peer.ip()) {
+            return Err((403, "Forbidden"));
+        }
+
+        let now = Instant::now();
+        let mut peers = allocation.peers.lock().unwrap();
+        // A channel stays bound to one peer, and a peer to one channel.
+        let bound_peer = peers.channels.get(&channel).map(|(addr, _)| *addr);
+        let bound_channel = peers.channels.iter().find(|(_, (addr, _))| *addr == peer).map(|(c, _)| *c);
+        if bound_peer.is_some_and(|addr| addr != peer) || bound_channel.is_some_and(|c| c != channel) {
+            return Err((400, "Bad Request"));
+        }
+        peers.channels.insert(channel, (peer, now + CHANNEL_LIFETIME));
+        peers.permissions.insert(peer.ip(), now + PERMISSION_LIFETIME);
+        Ok(request.response(CLASS_SUCCESS))
+    }
+
+    /// Whether `allocation` may relay to `ip`. The relay must not reach the
+    /// server itself or networks behind it, so restricted ranges and the
+    /// server's own addresses are refused unless explicitly allowed.
+    fn peer_allowed(&self, allocation: &Allocation, ip: IpAddr) -> bool {
+        if self.config.allowed_peers.contains(&ip) {
+            return true;
+        }
+        !restricted_ip(ip) && ip != allocation.relayed.ip() && Some(ip) != self.config.relay_ip
+    }
+
+    /// Relays the DATA of a Send indication to its peer.
+    async fn forward_send(&self, indication: &Message, client: SocketAddr) {
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+        let (Some(peer), Some(data)) = (
+            indication.attribute(attr::XOR_PEER_ADDRESS).and_then(|v| indication.decode_xor_address(v)),
+            indication.attribute(attr::DATA),
+        ) else {
+            return;
+        };
+        if allocation.peers.lock().unwrap().permitted(peer.ip(), Instant::now()) {
+            let _ = allocation.relay.send_to(data, peer).await;
+        }
+    }
+
+    /// Relays a ChannelData message to the peer bound to its channel.
+    async fn forward_channel_data(&self, buf: &[u8], client: SocketAddr) {
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+        let channel = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        let Some(data) = buf.get(4..4 + len) else {
+            return;
+        };
+        let peer = {
+            let peers = allocation.peers.lock().unwrap();
+            let now = Instant::now();
+            peers.peer_of(channel, now).filter(|peer| peers.permitted(peer.ip(), now))
+        };
+        if let Some(peer) = peer {
+            let _ = allocation.relay.send_to(data, peer).await;
+        }
+    }
+
+    /// Drops expired allocations, permissions and channel bindings.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.allocations.retain(|client, allocation| {
+            if allocation.expires <= now {
+                allocation.cancel.cancel();
+                log::info!("🔀 TURN allocation {} for {} expired", allocation.relayed, client);
+                return false;
+            }
+            allocation.peers.lock().unwrap().prune(now);
+            true
+        });
+    }
+}
+
+fn error_response(request: &Message, code: u16, reason: &str) -> Message {
+    let mut response = request.response(CLASS_ERROR);
+    response.add_attribute(attr::ERROR_CODE, stun::error_code(code, reason));
+    response
+}
+
+fn unknown_attributes(request: &Message) -> Vec<u16> {
+    request
+        .attributes
+        .iter()
+        .map(|(t, _)| *t)
+        .filter(|t| *t < 0x8000 && !matches!(*t,
+            attr::USERNAME | attr::MESSAGE_INTEGRITY | attr::REALM | attr::NONCE | attr::LIFETIME
+            | attr::REQUESTED_TRANSPORT | attr::XOR_PEER_ADDRESS | attr::CHANNEL_NUMBER | attr::DATA))
+        .collect()
+}
+
+fn peer_address(request: &Message, value: &[u8]) -> Result<SocketAddr, (u16, &'static str)> {
+    match request.decode_xor_address(value) {
+        // Relay sockets are IPv4 only.
+        Some(peer) if peer.is_ipv6() => Err((443, "Peer Address Family Mismatch")),
+        Some(peer) => Ok(peer),
+        None => Err((400, "Bad Request")),
+    }
+}
+
+/// The user ID part of a `<expiry>:<user_id>` TURN username.
+fn user_of(username: &str) -> &str {
+    username.split_once(':').map_or(username, |(_, user)| user)
+}
+
+/// Loopback, private, link-local, shared (CGNAT), unspecified, broadcast
+/// and multicast addresses, which a relay must not be pointed at.
+fn restricted_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => restricted_ipv4(ip),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || first & 0xFE00 == 0xFC00
+                || first & 0xFFC0 == 0xFE80
+                || ip.to_ipv4_mapped().is_some_and(restricted_ipv4)
+        }
+    }
+}
+
+fn restricted_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || a == 0
+        || (a == 100 && b & 0xC0 == 64)
+}
+
+fn read_u32(value: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(value.get(..4)?.try_into().ok()?))
+}
+
+/// The lifetime granted for a request: what it asked for, within bounds.
+fn lifetime(request: &Message) -> u32 {
+    request
+        .attribute(attr::LIFETIME)
+        .and_then(read_u32)
+        .unwrap_or(DEFAULT_LIFETIME_SECS)
+        .clamp(DEFAULT_LIFETIME_SECS, MAX_LIFETIME_SECS)
+}
+
+/// The local address the OS would use to send to `client`.
+fn local_ip_towards(client: SocketAddr) -> Option<IpAddr> {
+    let socket = std::net::UdpSocket::bind(("0.0.0.0", 0)).ok()?;
+    socket.connect(client).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// Encodes a ChannelData message, padded to a multiple of four bytes.
+fn channel_data(channel: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + data.len() + 3);
+    buf.extend_from_slice(&channel.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(4 + data.len().div_ceil(4) * 4, 0);
+    buf
+}
+
+/// Passes datagrams from permitted peers back to the client, over a
+/// channel if one is bound and as Data indications otherwise.
+async fn relay_task(
+    client: SocketAddr,
+    relay: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    peers: Arc<Mutex<Peers>>,
+    cancel: CancellationToken,
+) {
+    let mut buf = [0u8; 65536];
+    loop {
+        let (size, peer) = tokio::select! {
+            _ = cancel.cancelled() => break,
+            recv = relay.recv_from(&mut buf) => match recv {
+                Ok(recv) => recv,
+                Err(_) => continue,
+            },
+        };
+        let now = Instant::now();
+        let channel = {
+            let peers = peers.lock().unwrap();
+            if !peers.permitted(peer.ip(), now) {
+                continue;
+            }
+            peers.channel_of(peer, now)
+        };
+        let packet = match channel {
+            Some(channel) => channel_data(channel, &buf[..size]),
+            None => {
+                let mut transaction_id = [0u8; 12];
+                transaction_id.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..12]);
+                let mut indication = Message::new(stun::message_type(DATA, CLASS_INDICATION), transaction_id);
+                indication.add_attribute(attr::XOR_PEER_ADDRESS, indication.xor_address(peer));
+                indication.add_attribute(attr::DATA, buf[..size].to_vec());
+                indication.encode_with_fingerprint()
+            }
+        };
+        let _ = socket.send_to(&packet, client).await;
+    }
+}
+
+/// Runs the TURN relay (RFC 5766) over UDP: Allocate, Refresh,
+/// CreatePermission and ChannelBind requests, Send/Data indications and
+/// ChannelData. Binding requests are answered as by the STUN server.
+pub async fn turn_server(config: TurnConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let socket = Arc::new(UdpSocket::bind(("0.0.0.0", config.port)).await?);
+    log::info!("🔀 TURN server listening on {} (realm {})", socket.local_addr()?, config.realm);
+
+    let mut server = TurnServer {
+        config,
+        socket: Arc::clone(&socket),
+        allocations: HashMap::new(),
+    };
+    let mut sweep = tokio::time::interval(Duration::from_secs(5));
+    let mut buf = [0u8; 65536];
+    loop {
+        tokio::select! {
+            recv = socket.recv_from(&mut buf) => match recv {
+                Ok((size, client)) => server.handle(&buf[..size], client).await,
+                Err(e) => log::warn!("TURN receive failed: {}", e),
+            },
+            _ = sweep.tick() => server.expire(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn test_config() -> TurnConfig {
+        TurnConfig {
+            secret: "secret".to_string(),
+            allowed_peers: vec![LOCALHOST],
+            ..TurnConfig::default()
+        }
+    }
+
+    /// A server on a localhost socket it replies from, with nothing
+    /// reading that socket: tests drive `handle` directly.
+    async fn server(config: TurnConfig) -> TurnServer {
+        TurnServer {
+            config,
+            socket: Arc::new(UdpSocket::bind((LOCALHOST, 0)).await.unwrap()),
+            allocations: HashMap::new(),
+        }
+    }
+
+    struct Client {
+        socket: UdpSocket,
+        credentials: TurnCredentials,
+        nonce: Vec<u8>,
+    }
+
+    impl Client {
+        async fn new(config: &TurnConfig, user_id: &str) -> Self {
+            Client {
+                socket: UdpSocket::bind((LOCALHOST, 0)).await.unwrap(),
+                credentials: config.credentials(user_id),
+                nonce: config.nonce().into_bytes(),
+            }
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.socket.local_addr().unwrap()
+        }
+
+        fn signed(&self, config: &TurnConfig, mut request: Message) -> Vec<u8> {
+            let key = stun::long_term_key(&self.credentials.username, &config.realm, &self.credentials.password);
+            request.add_attribute(attr::USERNAME, self.credentials.username.as_bytes().to_vec());
+            request.add_attribute(attr::REALM, config.realm.as_bytes().to_vec());
+            request.add_attribute(attr::NONCE, self.nonce.clone());
+            request.encode_with_integrity(&key)
+        }
+
+        /// Sends `buf` as this client and returns the server's reply.
+        async fn exchange(&self, server: &mut TurnServer, buf: &[u8]) -> Message {
+            server.handle(buf, self.addr()).await;
+            Message::parse(&recv(&self.socket).await.unwrap()).unwrap()
+        }
+
+        async fn request(&self, server: &mut TurnServer, request: Message) -> Message {
+            let buf = self.signed(&server.config.clone(), request);
+            self.exchange(server, &buf).await
+        }
+
+        async fn allocate(&self, server: &mut TurnServer) -> Message {
+            let mut request = Message::new(stun::message_type(ALLOCATE, CLASS_REQUEST), transaction_id());
+            request.add_attribute(attr::REQUESTED_TRANSPORT, vec![TRANSPORT_UDP, 0, 0, 0]);
+            self.request(server, request).await
+        }
+
+        async fn create_permission(&self, server: &mut TurnServer, peer: SocketAddr) -> Message {
+            let mut request = Message::new(stun::message_type(CREATE_PERMISSION, CLASS_REQUEST), transaction_id());
+            request.add_attribute(attr::XOR_PEER_ADDRESS, request.xor_address(peer));
+            self.request(server, request).await
+        }
+
+        async fn channel_bind(&self, server: &mut TurnServer, channel: u16, peer: SocketAddr) -> Message {
+            let mut request = Message::new(stun::message_type(CHANNEL_BIND, CLASS_REQUEST), transaction_id());
+            request.add_attribute(attr::CHANNEL_NUMBER, vec![(channel >> 8) as u8, channel as u8, 0, 0]);
+            request.add_attribute(attr::XOR_PEER_ADDRESS, request.xor_address(peer));
+            self.request(server, request).await
+        }
+
+        async fn refresh(&self, server: &mut TurnServer, lifetime: u32) -> Message {
+            let mut request = Message::new(stun::message_type(REFRESH, CLASS_REQUEST), transaction_id());
+            request.add_attribute(attr::LIFETIME, lifetime.to_be_bytes().to_vec());
+            self.request(server, request).await
+        }
+    }
+
+    fn transaction_id() -> [u8; 12] {
+        uuid::Uuid::new_v4().as_bytes()[..12].try_into().unwrap()
+    }
+
+    async fn recv(socket: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 2048];
+        let (size, _) = tokio::time::timeout(Duration::from_millis(500), socket.recv_from(&mut buf))
+            .await
+            .ok()?
+            .ok()?;
+        Some(buf[..size].to_vec())
+    }
+
+    fn error_of(response: &Message) -> Option<u16> {
+        let value = response.attribute(attr::ERROR_CODE)?;
+        Some(value[2] as u16 * 100 + value[3] as u16)
+    }
+
+    fn relayed_address(response: &Message) -> SocketAddr {
+        let relayed = response.decode_xor_address(response.attribute(attr::XOR_RELAYED_ADDRESS).unwrap()).unwrap();
+        // The relay socket listens on every interface.
+        SocketAddr::new(LOCALHOST, relayed.port())
+    }
+
+    #[tokio::test]
+    async fn allocates_after_challenge() {
+        let mut server = server(test_config()).await;
+        let mut client = Client::new(&server.config, "alice").await;
+
+        let mut request = Message::new(stun::message_type(ALLOCATE, CLASS_REQUEST), transaction_id());
+        request.add_attribute(attr::REQUESTED_TRANSPORT, vec![TRANSPORT_UDP, 0, 0, 0]);
+        let challenge = client.exchange(&mut server, &request.encode()).await;
+        assert_eq!(error_of(&challenge), Some(401));
+        assert_eq!(challenge.attribute(attr::REALM), Some(server.config.realm.as_bytes()));
+        client.nonce = challenge.attribute(attr::NONCE).unwrap().to_vec();
+
+        let response = client.allocate(&mut server).await;
+        assert_eq!(response.class(), CLASS_SUCCESS);
+        assert_eq!(response.xor_mapped_address(), Some(client.addr()));
+        assert_eq!(response.attribute(attr::LIFETIME).and_then(read_u32), Some(DEFAULT_LIFETIME_SECS));
+        assert_eq!(server.allocations[&client.addr()].relayed.port(), relayed_address(&response).port());
+
+        assert_eq!(error_of(&client.allocate(&mut server).await), Some(437));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_credentials_and_stale_nonces() {
+        let mut server = server(test_config()).await;
+        let mut client = Client::new(&server.config, "alice").await;
+        client.credentials.password = "wrong".to_string();
+        assert_eq!(error_of(&client.allocate(&mut server).await), Some(401));
+
+        let mut client = Client::new(&server.config, "alice").await;
+        client.nonce = server.config.nonce_expiring(chrono::Local::now().timestamp() - 1).into_bytes();
+        let response = client.allocate(&mut server).await;
+        assert_eq!(error_of(&response), Some(438));
+        assert!(server.config.nonce_valid(response.attribute(attr::NONCE).unwrap()));
+
+        let mut forged = server.config.nonce().into_bytes();
+        *forged.last_mut().unwrap() ^= 1;
+        client.nonce = forged;
+        assert_eq!(error_of(&client.allocate(&mut server).await), Some(438));
+
+        let expired = TurnConfig {
+            credential_ttl_secs: -1,
+            ..server.config.clone()
+        };
+        let client = Client::new(&expired, "alice").await;
+        assert_eq!(error_of(&client.allocate(&mut server).await), Some(401));
+        assert!(server.allocations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn limits_allocations_per_user() {
+        let mut server = server(TurnConfig {
+            max_user_allocations: 1,
+            ..test_config()
+        })
+        .await;
+        let first = Client::new(&server.config, "alice").await;
+        let second = Client::new(&server.config, "alice").await;
+        let other = Client::new(&server.config, "bob").await;
+
+        assert_eq!(first.allocate(&mut server).await.class(), CLASS_SUCCESS);
+        assert_eq!(error_of(&second.allocate(&mut server).await), Some(486));
+        assert_eq!(other.allocate(&mut server).await.class(), CLASS_SUCCESS);
+    }
+
+    #[tokio::test]
+    async fn refreshes_and_releases_allocations() {
+        let mut server = server(test_config()).await;
+        let client = Client::new(&server.config, "alice").await;
+        client.allocate(&mut server).await;
+
+        let response = client.refresh(&mut server, MAX_LIFETIME_SECS * 2).await;
+        assert_eq!(response.attribute(attr::LIFETIME).and_then(read_u32), Some(MAX_LIFETIME_SECS));
+
+        let response = client.refresh(&mut server, 0).await;
+        assert_eq!(response.class(), CLASS_SUCCESS);
+        assert!(server.allocations.is_empty());
+
+        assert_eq!(error_of(&client.refresh(&mut server, 0).await), Some(437));
+        assert_eq!(error_of(&client.refresh(&mut server, 600).await), Some(437));
+    }
+
+    #[tokio::test]
+    async fn refuses_restricted_peers() {
+        let mut server = server(TurnConfig {
+            allowed_peers: Vec::new(),
+            ..test_config()
+        })
+        .await;
+        let client = Client::new(&server.config, "alice").await;
+        let relayed = relayed_address(&client.allocate(&mut server).await);
+
+        for peer in ["127.0.0.1:5000", "10.1.2.3:5000", "172.16.0.1:5000", "192.168.1.1:5000", "169.254.169.254:80", "100.64.0.1:5000", "0.0.0.0:5000"] {
+            let peer: SocketAddr = peer.parse().unwrap();
+            assert_eq!(error_of(&client.create_permission(&mut server, peer).await), Some(403), "{}", peer);
+            assert_eq!(error_of(&client.channel_bind(&mut server, 0x4000, peer).await), Some(403), "{}", peer);
+        }
+        assert_eq!(error_of(&client.create_permission(&mut server, relayed).await), Some(403));
+
+        let public: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        assert_eq!(client.create_permission(&mut server, public).await.class(), CLASS_SUCCESS);
+        assert_eq!(client.channel_bind(&mut server, 0x4000, public).await.class(), CLASS_SUCCESS);
+        assert!(server.allocations[&client.addr()].peers.lock().unwrap().permitted(public.ip(), Instant::now()));
+
+        assert!(restricted_ip("::1".parse().unwrap()));
+        assert!(restricted_ip("fd00::1".parse().unwrap()));
+        assert!(restricted_ip("fe80::1".parse().unwrap()));
+        assert!(restricted_ip("::ffff:192.168.0.1".parse().unwrap()));
+        assert!(!restricted_ip("2001:db8::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn relays_send_indications_and_data() {
+        let mut server = server(test_config()).await;
+        let client = Client::new(&server.config, "alice").await;
+        let relayed = relayed_address(&client.allocate(&mut server).await);
+        let peer = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        let mut send = Message::new(stun::message_type(SEND, CLASS_INDICATION), transaction_id());
+        send.add_attribute(attr::XOR_PEER_ADDRESS, send.xor_address(peer_addr));
+        send.add_attribute(attr::DATA, b"before".to_vec());
+        server.handle(&send.encode(), client.addr()).await;
+        assert_eq!(recv(&peer).await, None, "relayed without a permission");
+
+        assert_eq!(client.create_permission(&mut server, peer_addr).await.class(), CLASS_SUCCESS);
+        send.attributes[1].1 = b"hello".to_vec();
+        server.handle(&send.encode(), client.addr()).await;
+        assert_eq!(recv(&peer).await.as_deref(), Some(&b"hello"[..]));
+
+        peer.send_to(b"world", relayed).await.unwrap();
+        let data = Message::parse(&recv(&client.socket).await.unwrap()).unwrap();
+        assert_eq!(data.msg_type, stun::message_type(DATA, CLASS_INDICATION));
+        assert_eq!(data.decode_xor_address(data.attribute(attr::XOR_PEER_ADDRESS).unwrap()), Some(peer_addr));
+        assert_eq!(data.attribute(attr::DATA), Some(&b"world"[..]));
+    }
+
+    #[tokio::test]
+    async fn relays_channel_data() {
+        let mut server = server(test_config()).await;
+        let client = Client::new(&server.config, "alice").await;
+        let relayed = relayed_address(&client.allocate(&mut server).await);
+        let peer = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        assert_eq!(client.channel_bind(&mut server, 0x7FFF, peer_addr).await.class(), CLASS_ERROR);
+        assert_eq!(client.channel_bind(&mut server, 0x4001, peer_addr).await.class(), CLASS_SUCCESS);
+        let other: SocketAddr = (LOCALHOST, peer_addr.port() + 1).into();
+        assert_eq!(error_of(&client.channel_bind(&mut server, 0x4001, other).await), Some(400));
+
+        server.handle(&channel_data(0x4001, b"hi"), client.addr()).await;
+        assert_eq!(recv(&peer).await.as_deref(), Some(&b"hi"[..]));
+
+        peer.send_to(b"there", relayed).await.unwrap();
+        assert_eq!(recv(&client.socket).await, Some(channel_data(0x4001, b"there")));
+    }
+
+    #[tokio::test]
+    async fn permissions_expire() {
+        let mut server = server(test_config()).await;
+        let client = Client::new(&server.config, "alice").await;
+        client.allocate(&mut server).await;
+        let peer: SocketAddr = (LOCALHOST, 5000).into();
+        client.create_permission(&mut server, peer).await;
+        client.channel_bind(&mut server, 0x4000, (LOCALHOST, 5001).into()).await;
+
+        let now = Instant::now();
+        let allocation = &server.allocations[&client.addr()];
+        let mut peers = allocation.peers.lock().unwrap();
+        assert!(peers.permitted(peer.ip(), now));
+        assert!(!peers.permitted(peer.ip(), now + PERMISSION_LIFETIME));
+        assert_eq!(peers.peer_of(0x4000, now + PERMISSION_LIFETIME), Some((LOCALHOST, 5001).into()));
+        assert_eq!(peers.peer_of(0x4000, now + CHANNEL_LIFETIME), None);
+
+        peers.prune(now + CHANNEL_LIFETIME);
+        assert!(peers.permissions.is_empty() && peers.channels.is_empty());
+    }
+}
@@ -1,5 +1,11 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
+use crate::io::DeviceSelection;
+
+/// An account's presence: online status and heartbeat, created on first
+/// login. `id` is the account ID, so it is the same on every login.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
@@ -7,6 +13,12 @@ pub struct User {
     pub ip_address: Option<String>,
     pub status: CallStatus,
     pub last_heartbeat: i64,
+    /// Lets a second call ring while the user is already on one.
+    #[serde(default)]
+    pub call_waiting: bool,
+    /// Audio devices the user's native calls run on.
+    #[serde(default)]
+    pub audio_devices: DeviceSelection,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -19,12 +31,41 @@ pub enum CallStatus {
     Offline,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for CallStatus {
     fn default() -> Self {
         CallStatus::Offline
     }
 }
 
+/// Why a user cannot take a new call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Unavailable {
+    Unknown(String),
+    Offline(String),
+    Busy(String),
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unavailable::Unknown(user_id) => write!(f, "User {} not found", user_id),
+            Unavailable::Offline(user_id) => write!(f, "User {} is offline", user_id),
+            Unavailable::Busy(user_id) => write!(f, "User {} is busy", user_id),
+        }
+    }
+}
+
+impl Unavailable {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Unavailable::Unknown(_) => "unknown_user",
+            Unavailable::Offline(_) => "offline",
+            Unavailable::Busy(_) => "busy",
+        }
+    }
+}
+
 impl User {
     pub fn new(id: String, username: String) -> Self {
         User {
@@ -33,6 +74,8 @@ impl User {
             ip_address: None,
             status: CallStatus::Idle,
             last_heartbeat: chrono::Local::now().timestamp(),
+            call_waiting: false,
+            audio_devices: DeviceSelection::default(),
         }
     }
 
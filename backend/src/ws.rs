@@ -0,0 +1,164 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use crate::auth::AuthUser;
+use crate::call_manager::CallManager;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Push channel for signaling events. The client connects with
+/// `/api/ws?access_token=...` and receives one JSON `SignalEvent` per text
+/// frame for the session's user.
+pub async fn signal_socket(
+    req: HttpRequest,
+    body: web::Payload,
+    call_manager: web::Data<Arc<Mutex<CallManager>>>,
+    auth: AuthUser,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user_id = auth.user_id;
+
+    let mut events = {
+        let mut manager = call_manager.lock().await;
+        if manager.get_user(&user_id).is_none() {
+            return Ok(HttpResponse::NotFound().json(serde_json::json!({
+                "status": "error",
+                "message": "User not found"
+            })));
+        }
+        manager.subscribe(&user_id)
+    };
+
+    let (response, mut session, mut msg_stream) = actix_ws::handle(&req, body)?;
+    log::info!("Signaling socket opened for {}", user_id);
+
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::select! {
+                event = events.recv() => {
+                    let Some(event) = event else { break };
+                    let text = match serde_json::to_string(&event) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            log::error!("Failed to encode signaling event: {}", e);
+                            continue;
+                        }
+                    };
+                    if session.text(text).await.is_err() {
+                        break;
+                    }
+                }
+                msg = msg_stream.recv() => {
+                    match msg {
+                        Some(Ok(Message::Ping(bytes))) => {
+                            if session.pong(&bytes).await.is_err() {
+                                break;
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => {}
+                    }
+                }
+            }
+        }
+
+        let _ = session.close(None).await;
+        log::info!("Signaling socket closed for {}", user_id);
+    });
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use actix_web::{middleware::from_fn, test::{self as actix_test, TestRequest}, App, HttpServer};
+    use crate::auth::require_session;
+    use crate::cdr::CdrStore;
+    use crate::storage::{MemoryStorage, Storage};
+
+    struct Server {
+        manager: Arc<Mutex<CallManager>>,
+        cdr_path: std::path::PathBuf,
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.cdr_path);
+        }
+    }
+
+    fn server() -> Server {
+        let cdr_path = std::env::temp_dir().join(format!("voip-ws-{}.jsonl", uuid::Uuid::new_v4()));
+        let storage: Box<dyn Storage> = Box::new(MemoryStorage::new());
+        let manager = CallManager::new(storage, CdrStore::open(cdr_path.clone()).unwrap());
+        Server { manager: Arc::new(Mutex::new(manager)), cdr_path }
+    }
+
+    macro_rules! app {
+        ($manager:expr) => {
+            App::new()
+                .app_data(web::Data::new($manager))
+                .service(web::scope("/api").wrap(from_fn(require_session)).route("/ws", web::get().to(signal_socket)))
+        };
+    }
+
+    #[actix_web::test]
+    async fn pushes_events_to_the_sessions_user() {
+        let server = server();
+        let (alice, bob, token) = {
+            let mut manager = server.manager.lock().await;
+            let alice = manager.create_account("alice", "hash".into()).unwrap();
+            let bob = manager.create_account("bob", "hash".into()).unwrap();
+            manager.log_in(&alice);
+            let token = manager.log_in(&bob);
+            (alice.id, bob.id, token)
+        };
+
+        let http = {
+            let manager = server.manager.clone();
+            HttpServer::new(move || app!(manager.clone())).workers(1).bind(("127.0.0.1", 0)).unwrap()
+        };
+        let addr = http.addrs()[0];
+        let http = http.run();
+        let handle = http.handle();
+        actix_web::rt::spawn(http);
+
+        let url = format!("ws://{}/api/ws?access_token={}", addr, token);
+        let (mut client, response) = tokio_tungstenite::connect_async(url).await.unwrap();
+        assert_eq!(response.status(), 101);
+
+        let call = server.manager.lock().await.create_call(alice.clone(), bob).unwrap();
+        let Some(Ok(Message::Text(text))) = client.next().await else { panic!("expected a text frame") };
+        let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(event["type"], "incoming_call");
+        assert_eq!(event["call_id"], call.call_id.as_str());
+        assert_eq!(event["caller_id"], alice.as_str());
+
+        client.send(Message::Ping(b"ping".to_vec())).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::Pong(b"ping".to_vec()));
+
+        client.close(None).await.unwrap();
+        handle.stop(true).await;
+    }
+
+    #[actix_web::test]
+    async fn rejects_requests_that_are_not_upgrades() {
+        let server = server();
+        let token = {
+            let mut manager = server.manager.lock().await;
+            let account = manager.create_account("alice", "hash".into()).unwrap();
+            manager.log_in(&account)
+        };
+        let app = actix_test::init_service(app!(server.manager.clone())).await;
+        let req = TestRequest::get()
+            .uri("/api/ws")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status().as_u16(), 400);
+
+        let unauthenticated = TestRequest::get().uri("/api/ws").to_request();
+        let error = actix_test::try_call_service(&app, unauthenticated).await.unwrap_err();
+        assert_eq!(error.as_response_error().status_code().as_u16(), 401);
+    }
+}
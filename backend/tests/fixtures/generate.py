@@ -0,0 +1,191 @@
+"""Regenerates the audio fixtures used by the echo canceller and capture
+processing tests.
+
+The signals are synthetic but shaped like the real thing: voiced speech
+(a glottal pulse train through moving formant resonators, with syllable
+and phrase pauses), a room echo path (direct sound, early reflections
+and an exponentially decaying diffuse tail) and background noise (fan
+hiss with a bit of low-frequency rumble). Everything is seeded, so the
+output is the same on every run.
+
+The echo canceller fixtures come as a recording would, at the 48 kHz the
+canceller runs at in calls: what was played (`far_end.wav`) and what the
+microphone picked up while it played (`echo.wav`), plus a near-end
+talker to mix in. Recordings made on real hardware can replace them
+under the same names, as long as `echo.wav` is sample-aligned with
+`far_end.wav`; the tests add device latency and clock drift themselves.
+
+    python3 tests/fixtures/generate.py
+"""
+
+import math
+import os
+import random
+import struct
+import wave
+
+RATE = 16000
+AEC_RATE = 48000
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+
+def write_wav(path, samples, rate=RATE):
+    with wave.open(path, "wb") as f:
+        f.setnchannels(1)
+        f.setsampwidth(2)
+        f.setframerate(rate)
+        f.writeframes(b"".join(struct.pack("<h", max(-32768, min(32767, int(round(s))))) for s in samples))
+
+
+def resonator(freq, bandwidth, rate=RATE):
+    r = math.exp(-math.pi * bandwidth / rate)
+    return 2 * r * math.cos(2 * math.pi * freq / rate), -r * r
+
+
+def speech(seconds, seed, pitch, level, active=None, rate=RATE):
+    """Voiced, speech-like signal; `active` limits it to (start, end) seconds."""
+    rng = random.Random(seed)
+    n = int(seconds * rate)
+    out = [0.0] * n
+    vowels = [(730, 1090), (270, 2290), (530, 1840), (660, 1720), (300, 870), (490, 1350)]
+
+    # Syllables of 120-280 ms with short gaps, and a longer pause every few.
+    segments, t = [], 0.0
+    while t < seconds:
+        for _ in range(rng.randint(3, 6)):
+            length = rng.uniform(0.12, 0.28)
+            segments.append((t, t + length, rng.choice(vowels), rng.uniform(0.9, 1.2)))
+            t += length + rng.uniform(0.03, 0.08)
+        t += rng.uniform(0.25, 0.5)
+
+    phase = 0.0
+    state = [[0.0, 0.0], [0.0, 0.0]]
+    for start, end, (f1, f2), inflection in segments:
+        if active and (end <= active[0] or start >= active[1]):
+            continue
+        coeffs = [resonator(f1, 90, rate), resonator(f2, 120, rate)]
+        for i in range(int(start * rate), min(int(end * rate), n)):
+            progress = (i / rate - start) / (end - start)
+            f0 = pitch * inflection * (1.0 + 0.1 * math.sin(math.pi * progress))
+            phase += f0 / rate
+            excitation = 1.0 if phase >= 1.0 else 0.0
+            phase -= math.floor(phase)
+            excitation += 0.02 * rng.gauss(0, 1)
+            x = excitation
+            for (a1, a2), s in zip(coeffs, state):
+                y = x + a1 * s[0] + a2 * s[1]
+                s[1], s[0] = s[0], y
+                x = y
+            envelope = max(0.0, math.sin(math.pi * progress)) ** 0.5
+            out[i] = x * envelope
+
+    peak = max(abs(s) for s in out) or 1.0
+    return [s / peak * level for s in out]
+
+
+def echo_path(seed, rate=RATE):
+    """Room impulse response, 100 ms long: 2.5 ms delay, ~170 ms RT60,
+    about -9 dB."""
+    rng = random.Random(seed)
+    ms = rate // 1000
+    taps = 100 * ms
+    h = [0.0] * taps
+    delay = 5 * ms // 2
+    h[delay] = 0.18
+    for _ in range(12):
+        k = rng.randint(delay + 4 * ms, delay + 44 * ms)
+        h[k] += rng.uniform(-0.07, 0.07) * math.exp(-(k - delay) / (37.5 * ms))
+    tau = 25 * ms
+    for k in range(delay + ms, taps):
+        h[k] += 0.018 * rng.gauss(0, 1) * math.exp(-(k - delay) / tau) * math.sqrt(16 / ms)
+    # Fade out the last 10 ms so the response ends within `taps`.
+    for k in range(taps - 10 * ms, taps):
+        h[k] *= (taps - k) / (10 * ms)
+    return h
+
+
+def fft(a, inverse=False):
+    """In-place radix-2 FFT of a list of complex numbers."""
+    n = len(a)
+    j = 0
+    for i in range(1, n):
+        bit = n >> 1
+        while j & bit:
+            j ^= bit
+            bit >>= 1
+        j |= bit
+        if i < j:
+            a[i], a[j] = a[j], a[i]
+    length = 2
+    sign = 1 if inverse else -1
+    while length <= n:
+        w = complex(math.cos(2 * math.pi / length), sign * math.sin(2 * math.pi / length))
+        half = length // 2
+        twiddles = [1.0]
+        for _ in range(half - 1):
+            twiddles.append(twiddles[-1] * w)
+        for start in range(0, n, length):
+            for k in range(half):
+                u = a[start + k]
+                v = a[start + k + half] * twiddles[k]
+                a[start + k] = u + v
+                a[start + k + half] = u - v
+        length <<= 1
+    if inverse:
+        for i in range(n):
+            a[i] /= n
+
+
+def convolve(x, h):
+    """The first len(x) samples of x filtered by h."""
+    n = 1
+    while n < len(x) + len(h):
+        n <<= 1
+    a = [complex(v) for v in x] + [0j] * (n - len(x))
+    b = [complex(v) for v in h] + [0j] * (n - len(h))
+    fft(a)
+    fft(b)
+    c = [p * q for p, q in zip(a, b)]
+    fft(c, inverse=True)
+    return [v.real for v in c[: len(x)]]
+
+
+def noise(seconds, seed, level, rate=RATE):
+    """Stationary noise: pinkish hiss plus rumble below 100 Hz, at `level` RMS."""
+    rng = random.Random(seed)
+    n = int(seconds * rate)
+    hiss, rumble = 0.0, 0.0
+    out = []
+    for i in range(n):
+        white = rng.gauss(0, 1)
+        hiss = 0.9 * hiss + white
+        rumble = 0.995 * rumble + 0.05 * rng.gauss(0, 1)
+        out.append(hiss + 0.3 * white + 4.0 * rumble + 2.0 * math.sin(2 * math.pi * 50 * i / rate))
+    rms = math.sqrt(sum(s * s for s in out) / n)
+    return [s / rms * level for s in out]
+
+
+def main():
+    aec = os.path.join(HERE, "aec")
+    os.makedirs(aec, exist_ok=True)
+    far = speech(6.0, seed=1, pitch=115, level=16000, rate=AEC_RATE)
+    # The microphone hears the far end through the room, over a quiet
+    # noise floor.
+    mic_noise = noise(6.0, seed=6, level=8, rate=AEC_RATE)
+    echo = [e + n for e, n in zip(convolve(far, echo_path(seed=3, rate=AEC_RATE)), mic_noise)]
+    write_wav(os.path.join(aec, "far_end.wav"), far, AEC_RATE)
+    write_wav(os.path.join(aec, "echo.wav"), echo, AEC_RATE)
+    write_wav(
+        os.path.join(aec, "near_end.wav"),
+        speech(6.0, seed=2, pitch=210, level=16000, active=(3.5, 5.0), rate=AEC_RATE),
+        AEC_RATE,
+    )
+
+    processing = os.path.join(HERE, "processing")
+    os.makedirs(processing, exist_ok=True)
+    write_wav(os.path.join(processing, "speech.wav"), speech(6.0, seed=4, pitch=160, level=12000))
+    write_wav(os.path.join(processing, "noise.wav"), noise(6.0, seed=5, level=500))
+
+
+if __name__ == "__main__":
+    main()
@@ -3,6 +3,7 @@ const API_BASE = '/api';
 let appState = {
     userId: null,
     username: null,
+    token: null,
     currentCallId: null,
     currentCallPartner: null,
     isMuted: false,
@@ -15,14 +16,169 @@ let appState = {
     peerConnection: null,
     localStream: null,
     remoteStream: null,
+    socket: null,
+    socketConnected: false,
+    pendingOffer: null,
+    pendingCandidates: [],
 };
 
-const ICE_SERVERS = {
-    iceServers: [
-        { urls: 'stun:stun.l.google.com:19302' },
-        { urls: 'stun:stun1.l.google.com:19302' },
-    ]
-};
+// Fetched from the backend before each call, since the TURN credentials in
+// it expire.
+async function loadIceServers() {
+    try {
+        const response = await apiFetch(`${API_BASE}/ice/servers`);
+        const data = await response.json();
+        if (data.status === 'success') {
+            return { iceServers: data.iceServers };
+        }
+    } catch (error) {
+        console.error('Failed to load ICE servers:', error);
+    }
+    return { iceServers: [] };
+}
+
+// Sends an API request with the session token from login.
+function apiFetch(url, options = {}) {
+    const headers = { ...(options.headers || {}) };
+    if (appState.token) {
+        headers['Authorization'] = `Bearer ${appState.token}`;
+    }
+    return fetch(url, { ...options, headers });
+}
+
+// Opens the push channel for call events. While it is connected the polling
+// checks below stand down; when it drops they take over until it reconnects.
+function connectSignalSocket() {
+    if (!appState.token) return;
+
+    const scheme = location.protocol === 'https:' ? 'wss' : 'ws';
+    const socket = new WebSocket(`${scheme}://${location.host}${API_BASE}/ws?access_token=${encodeURIComponent(appState.token)}`);
+    appState.socket = socket;
+
+    socket.onopen = () => {
+        appState.socketConnected = true;
+        console.log('Signaling socket connected');
+    };
+    socket.onmessage = (message) => {
+        try {
+            handleSignalEvent(JSON.parse(message.data));
+        } catch (error) {
+            console.error('Bad signaling event:', error);
+        }
+    };
+    socket.onclose = () => {
+        if (appState.socket !== socket) return;
+        appState.socketConnected = false;
+        appState.socket = null;
+        console.log('Signaling socket closed, polling until it reconnects');
+        setTimeout(connectSignalSocket, 3000);
+    };
+}
+
+async function handleSignalEvent(event) {
+    const newCall = event.type === 'incoming_call' || event.type === 'missed_call';
+    if (event.call_id && !newCall && event.call_id !== appState.currentCallId) {
+        return;
+    }
+
+    switch (event.type) {
+        case 'incoming_call':
+            if (!appState.currentCallId) {
+                appState.currentCallId = event.call_id;
+                appState.currentCallPartner = event.caller_id;
+                simulateIncomingCall(await getUserName(event.caller_id));
+            }
+            break;
+        case 'accepted':
+            markCallAccepted();
+            break;
+        case 'rejected':
+        case 'ended':
+            console.log(`🔴 Call ${event.type} (${event.reason})`);
+            endCallCleanup();
+            await loadUsers();
+            break;
+        case 'held':
+            applyRemoteHold(true);
+            break;
+        case 'resumed':
+            applyRemoteHold(false);
+            break;
+        case 'missed_call':
+            console.log('Missed call from', await getUserName(event.caller_id));
+            break;
+        case 'offer':
+            await receiveOffer(event.offer);
+            break;
+        case 'answer':
+            await receiveAnswer(event.answer);
+            break;
+        case 'candidate':
+            await receiveCandidate(event.candidate);
+            break;
+        case 'room_joined':
+        case 'room_left':
+            console.log(`${event.user_id} ${event.type === 'room_joined' ? 'joined' : 'left'} room ${event.room_id}`);
+            break;
+    }
+}
+
+// An offer before the call is accepted is kept for acceptCall; one during the
+// call renegotiates it.
+async function receiveOffer(sdp) {
+    const pc = appState.peerConnection;
+    if (!pc || !pc.currentRemoteDescription) {
+        appState.pendingOffer = sdp;
+        return;
+    }
+
+    await pc.setRemoteDescription({ type: 'offer', sdp });
+    const answer = await pc.createAnswer();
+    await pc.setLocalDescription(answer);
+    await sendAnswer(answer.sdp);
+}
+
+async function receiveAnswer(sdp) {
+    const pc = appState.peerConnection;
+    if (!pc || pc.signalingState !== 'have-local-offer') return;
+
+    await pc.setRemoteDescription({ type: 'answer', sdp });
+    await flushCandidates();
+    updateStatus('connecting');
+    console.log('✅ Received answer, establishing WebRTC connection...');
+}
+
+// Candidates can arrive before the offer or answer they belong to has been
+// applied, so they wait until then.
+async function receiveCandidate(candidate) {
+    const pc = appState.peerConnection;
+    if (!pc || !pc.remoteDescription) {
+        appState.pendingCandidates.push(candidate);
+        return;
+    }
+    await pc.addIceCandidate({ candidate, sdpMLineIndex: 0, sdpMid: '0' });
+}
+
+async function flushCandidates() {
+    const candidates = appState.pendingCandidates;
+    appState.pendingCandidates = [];
+    for (const candidate of candidates) {
+        await receiveCandidate(candidate);
+    }
+}
+
+async function sendAnswer(sdp) {
+    await apiFetch(`${API_BASE}/signal/answer`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({
+            message_type: 'answer',
+            user_id: appState.userId,
+            call_id: appState.currentCallId,
+            answer: sdp,
+        })
+    });
+}
 
 async function getLocalIP() {
     try {
@@ -87,7 +243,7 @@ window.addEventListener('beforeunload', async () => {
         try {
            
             if (appState.currentCallId) {
-                await fetch(`${API_BASE}/signal/end`, {
+                await apiFetch(`${API_BASE}/signal/end`, {
                     method: 'POST',
                     headers: { 'Content-Type': 'application/json' },
                     body: JSON.stringify({
@@ -99,7 +255,7 @@ window.addEventListener('beforeunload', async () => {
             }
             
            
-            await fetch(`${API_BASE}/users/disconnect`, {
+            await apiFetch(`${API_BASE}/users/disconnect`, {
                 method: 'POST',
                 headers: { 'Content-Type': 'application/json' },
                 body: JSON.stringify({ user_id: appState.userId })
@@ -110,10 +266,10 @@ window.addEventListener('beforeunload', async () => {
 });
 
 async function checkIncomingCalls() {
-    if (!appState.userId) return;
+    if (!appState.userId || appState.socketConnected) return;
     
     try {
-        const response = await fetch(`${API_BASE}/signal/incoming?user_id=${appState.userId}`);
+        const response = await apiFetch(`${API_BASE}/signal/incoming?user_id=${appState.userId}`);
         const data = await response.json();
         
         if (data.incoming_call && !appState.currentCallId) {
@@ -128,27 +284,47 @@ async function checkIncomingCalls() {
 }
 
 async function initializeUser() {
-    const username = prompt('Enter your name:', 'User_' + Math.floor(Math.random() * 1000));
+    const username = prompt('Username:', 'User_' + Math.floor(Math.random() * 1000));
     
     if (!username) {
         console.error('Username required!');
         return;
     }
     
+    const password = prompt('Password (at least 8 characters):');
+    
+    if (!password) {
+        console.error('Password required!');
+        return;
+    }
+    
     try {
-        const response = await fetch(`${API_BASE}/users/register`, {
+        let response = await apiFetch(`${API_BASE}/users/login`, {
             method: 'POST',
             headers: { 'Content-Type': 'application/json' },
-            body: JSON.stringify({ username })
+            body: JSON.stringify({ username, password })
         });
         
+        if (response.status === 401 && confirm(`Log in failed. Create a new account for ${username}?`)) {
+            response = await apiFetch(`${API_BASE}/users/register`, {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ username, password })
+            });
+        }
+        
         const data = await response.json();
+        if (!response.ok) {
+            alert(data.message);
+            return;
+        }
         appState.userId = data.user_id;
         appState.username = data.username;
+        appState.token = data.token;
         
         document.getElementById('user-info').textContent = `Connected as: ${appState.username}`;
         
-       
+        connectSignalSocket();
         setInterval(checkIncomingCalls, 1000);
     } catch (error) {
         console.error(`Failed to connect to server! API URL: ${API_BASE}, Error: ${error.message}`);
@@ -157,7 +333,7 @@ async function initializeUser() {
 
 async function loadUsers() {
     try {
-        const response = await fetch(`${API_BASE}/users/list`);
+        const response = await apiFetch(`${API_BASE}/users/list`);
         const data = await response.json();
         
         const users = data.users.filter(u => u.id !== appState.userId);
@@ -246,7 +422,7 @@ async function initiateCall(targetId, isIpCall = false) {
         appState.localStream = await navigator.mediaDevices.getUserMedia({ audio: true });
         
        
-        appState.peerConnection = new RTCPeerConnection(ICE_SERVERS);
+        appState.peerConnection = new RTCPeerConnection(await loadIceServers());
         
        
         appState.localStream.getTracks().forEach(track => {
@@ -257,7 +433,7 @@ async function initiateCall(targetId, isIpCall = false) {
         setupPeerConnectionHandlers();
         
        
-        const response = await fetch(`${API_BASE}/signal/initiate`, {
+        const response = await apiFetch(`${API_BASE}/signal/initiate`, {
             method: 'POST',
             headers: { 'Content-Type': 'application/json' },
             body: JSON.stringify({
@@ -278,7 +454,7 @@ async function initiateCall(targetId, isIpCall = false) {
             await appState.peerConnection.setLocalDescription(offer);
             
            
-            await fetch(`${API_BASE}/signal/offer`, {
+            await apiFetch(`${API_BASE}/signal/offer`, {
                 method: 'POST',
                 headers: { 'Content-Type': 'application/json' },
                 body: JSON.stringify({
@@ -307,7 +483,7 @@ function setupPeerConnectionHandlers() {
     appState.peerConnection.onicecandidate = async (event) => {
         if (event.candidate) {
            
-            await fetch(`${API_BASE}/signal/candidate`, {
+            await apiFetch(`${API_BASE}/signal/candidate`, {
                 method: 'POST',
                 headers: { 'Content-Type': 'application/json' },
                 body: JSON.stringify({
@@ -345,7 +521,7 @@ async function acceptCall() {
     
     try {
        
-        const response = await fetch(`${API_BASE}/signal/accept`, {
+        const response = await apiFetch(`${API_BASE}/signal/accept`, {
             method: 'POST',
             headers: { 'Content-Type': 'application/json' },
             body: JSON.stringify({
@@ -362,7 +538,7 @@ async function acceptCall() {
             appState.localStream = await navigator.mediaDevices.getUserMedia({ audio: true });
             
            
-            appState.peerConnection = new RTCPeerConnection(ICE_SERVERS);
+            appState.peerConnection = new RTCPeerConnection(await loadIceServers());
             
            
             appState.localStream.getTracks().forEach(track => {
@@ -373,31 +549,30 @@ async function acceptCall() {
             setupPeerConnectionHandlers();
             
            
-            const offerResponse = await fetch(`${API_BASE}/signal/get_offer?call_id=${appState.currentCallId}`);
-            const offerData = await offerResponse.json();
+            let offer = appState.pendingOffer;
+            appState.pendingOffer = null;
+            if (!offer) {
+                const offerResponse = await apiFetch(`${API_BASE}/signal/get_offer?call_id=${appState.currentCallId}`);
+                const offerData = await offerResponse.json();
+                if (offerData.status === 'success') {
+                    offer = offerData.offer;
+                }
+            }
             
-            if (offerData.status === 'success') {
+            if (offer) {
                
                 await appState.peerConnection.setRemoteDescription({
                     type: 'offer',
-                    sdp: offerData.offer
+                    sdp: offer
                 });
+                await flushCandidates();
                 
                
                 const answer = await appState.peerConnection.createAnswer();
                 await appState.peerConnection.setLocalDescription(answer);
                 
                
-                await fetch(`${API_BASE}/signal/answer`, {
-                    method: 'POST',
-                    headers: { 'Content-Type': 'application/json' },
-                    body: JSON.stringify({
-                        message_type: 'answer',
-                        user_id: appState.userId,
-                        call_id: appState.currentCallId,
-                        answer: answer.sdp,
-                    })
-                });
+                await sendAnswer(answer.sdp);
                 
                
                 pollForCandidates();
@@ -422,9 +597,10 @@ async function pollForCandidates() {
             clearInterval(pollInterval);
             return;
         }
+        if (appState.socketConnected) return;
         
         try {
-            const response = await fetch(`${API_BASE}/signal/get_candidates?call_id=${appState.currentCallId}&user_id=${appState.userId}`);
+            const response = await apiFetch(`${API_BASE}/signal/get_candidates?call_id=${appState.currentCallId}&user_id=${appState.userId}`);
             const data = await response.json();
             
             if (data.status === 'success' && data.candidates) {
@@ -451,25 +627,21 @@ async function pollForAnswer() {
             clearInterval(pollInterval);
             return;
         }
+        if (appState.peerConnection && appState.peerConnection.signalingState !== 'have-local-offer') {
+           
+            pollForCandidates();
+            clearInterval(pollInterval);
+            return;
+        }
+        if (appState.socketConnected) return;
         
         try {
-            const response = await fetch(`${API_BASE}/signal/get_answer?call_id=${appState.currentCallId}`);
+            const response = await apiFetch(`${API_BASE}/signal/get_answer?call_id=${appState.currentCallId}`);
             const data = await response.json();
             
             if (data.status === 'success') {
-               
-                await appState.peerConnection.setRemoteDescription({
-                    type: 'answer',
-                    sdp: data.answer
-                });
-                
-               
+                await receiveAnswer(data.answer);
                 pollForCandidates();
-                
-               
-                updateStatus('connecting');
-                console.log('✅ Received answer, establishing WebRTC connection...');
-                
                 clearInterval(pollInterval);
             }
         } catch (error) {
@@ -485,7 +657,7 @@ async function rejectCall() {
     if (!appState.currentCallId) return;
     
     try {
-        const response = await fetch(`${API_BASE}/signal/reject`, {
+        const response = await apiFetch(`${API_BASE}/signal/reject`, {
             method: 'POST',
             headers: { 'Content-Type': 'application/json' },
             body: JSON.stringify({
@@ -507,7 +679,7 @@ async function endCall() {
     if (!appState.currentCallId) return;
     
     try {
-        const response = await fetch(`${API_BASE}/signal/end`, {
+        const response = await apiFetch(`${API_BASE}/signal/end`, {
             method: 'POST',
             headers: { 'Content-Type': 'application/json' },
             body: JSON.stringify({
@@ -584,7 +756,7 @@ async function holdCall() {
     
     try {
         const endpoint = appState.isOnHold ? '/signal/hold' : '/signal/resume';
-        const response = await fetch(`${API_BASE}${endpoint}`, {
+        const response = await apiFetch(`${API_BASE}${endpoint}`, {
             method: 'POST',
             headers: { 'Content-Type': 'application/json' },
             body: JSON.stringify({
@@ -633,6 +805,12 @@ function endCallCleanup() {
     appState.callDuration = 0;
     appState.pausedDuration = 0;
     appState.holdStartTime = null;
+    appState.pendingOffer = null;
+    appState.pendingCandidates = [];
+    const holdBtn = document.getElementById('hold-btn');
+    holdBtn.disabled = false;
+    holdBtn.classList.remove('active');
+    holdBtn.textContent = 'Hold';
     
    
     document.getElementById('call-timer').textContent = '00:00';
@@ -667,12 +845,12 @@ function simulateIncomingCall(callerId) {
 }
 
 async function checkForIncomingCalls() {
-    if (!appState.userId || appState.currentCallId) {
+    if (!appState.userId || appState.currentCallId || appState.socketConnected) {
         return;
     }
     
     try {
-        const response = await fetch(`${API_BASE}/signal/incoming?user_id=${appState.userId}`);
+        const response = await apiFetch(`${API_BASE}/signal/incoming?user_id=${appState.userId}`);
         const data = await response.json();
         
         if (data.call) {
@@ -690,14 +868,14 @@ async function checkForIncomingCalls() {
 async function checkCallAcceptance() {
    
    
-    const shouldCheck = appState.currentCallId && !appState.callStartTime;
+    const shouldCheck = appState.currentCallId && !appState.callStartTime && !appState.socketConnected;
     
     if (!shouldCheck) {
         return;
     }
     
     try {
-        const response = await fetch(`${API_BASE}/signal/status?call_id=${appState.currentCallId}`);
+        const response = await apiFetch(`${API_BASE}/signal/status?call_id=${appState.currentCallId}`);
         
         if (!response.ok) {
             console.log('Call status check failed, status:', response.status);
@@ -715,17 +893,7 @@ async function checkCallAcceptance() {
            
             if (callStatus === 'incall') {
                 console.log('🎯 Detected call accepted! Starting timer...');
-                
-               
-                if (!appState.callStartTime) {
-                    appState.callStartTime = Date.now();
-                    updateStatus('in-call');
-                    showCallControls();
-                    document.getElementById('current-call-info').classList.remove('hidden');
-                    document.getElementById('call-modal').classList.add('hidden');
-                    
-                    console.log('✅ Call acceptance detected - timer started, UI updated');
-                }
+                markCallAccepted();
             }
         } else {
             if (data.status !== 'success') {
@@ -739,12 +907,12 @@ async function checkCallAcceptance() {
 
 async function checkIfCallEnded() {
    
-    if (!appState.currentCallId) {
+    if (!appState.currentCallId || appState.socketConnected) {
         return;
     }
     
     try {
-        const response = await fetch(`${API_BASE}/signal/status?call_id=${appState.currentCallId}`);
+        const response = await apiFetch(`${API_BASE}/signal/status?call_id=${appState.currentCallId}`);
         
         if (!response.ok) {
            
@@ -766,38 +934,10 @@ async function checkIfCallEnded() {
         } else if (data.call) {
            
             const serverStatus = String(data.call.status).toLowerCase().trim();
-            if (serverStatus === 'onhold' && !appState.isOnHold) {
-                console.log('📴 Other user put call on hold');
-               
-                appState.isOnHold = true;
-                if (appState.callStartTime) {
-                    appState.pausedDuration = Math.floor((Date.now() - appState.callStartTime) / 1000);
-                    appState.holdStartTime = Date.now();
-                }
-                const remoteAudio = document.getElementById('remote-audio');
-                if (remoteAudio) remoteAudio.muted = true;
-                updateStatus('on-hold');
-                const holdBtn = document.getElementById('hold-btn');
-                if (holdBtn) {
-                    holdBtn.classList.add('active');
-                    holdBtn.textContent = 'Resume';
-                }
-            } else if (serverStatus === 'incall' && appState.isOnHold) {
-                console.log('📞 Other user resumed call');
-               
-                appState.isOnHold = false;
-                if (appState.holdStartTime && appState.callStartTime) {
-                    appState.callStartTime = Date.now() - (appState.pausedDuration * 1000);
-                    appState.holdStartTime = null;
-                }
-                const remoteAudio = document.getElementById('remote-audio');
-                if (remoteAudio) remoteAudio.muted = false;
-                updateStatus('in-call');
-                const holdBtn = document.getElementById('hold-btn');
-                if (holdBtn) {
-                    holdBtn.classList.remove('active');
-                    holdBtn.textContent = 'Hold';
-                }
+            if (serverStatus === 'onhold') {
+                applyRemoteHold(true);
+            } else if (serverStatus === 'incall') {
+                applyRemoteHold(false);
             }
         }
     } catch (error) {
@@ -805,13 +945,49 @@ async function checkIfCallEnded() {
     }
 }
 
+function markCallAccepted() {
+    if (appState.callStartTime) return;
+
+    appState.callStartTime = Date.now();
+    updateStatus('in-call');
+    showCallControls();
+    document.getElementById('current-call-info').classList.remove('hidden');
+    document.getElementById('call-modal').classList.add('hidden');
+    console.log('✅ Call acceptance detected - timer started, UI updated');
+}
+
+// Follows a hold or resume by the other party. Our own hold has already
+// been applied by holdCall, so its event changes nothing.
+function applyRemoteHold(held) {
+    if (held === appState.isOnHold) return;
+
+    console.log(held ? '📴 Other user put call on hold' : '📞 Other user resumed call');
+    appState.isOnHold = held;
+    if (held && appState.callStartTime) {
+        appState.pausedDuration = Math.floor((Date.now() - appState.callStartTime) / 1000);
+        appState.holdStartTime = Date.now();
+    } else if (!held && appState.holdStartTime && appState.callStartTime) {
+        appState.callStartTime = Date.now() - (appState.pausedDuration * 1000);
+        appState.holdStartTime = null;
+    }
+    const remoteAudio = document.getElementById('remote-audio');
+    if (remoteAudio) remoteAudio.muted = held;
+    updateStatus(held ? 'on-hold' : 'in-call');
+    // Only the party who held the call can resume it.
+    const holdBtn = document.getElementById('hold-btn');
+    if (holdBtn) {
+        holdBtn.disabled = held;
+        holdBtn.textContent = held ? 'Held by other party' : 'Hold';
+    }
+}
+
 async function sendHeartbeat() {
     if (!appState.userId) {
         return;
     }
     
     try {
-        await fetch(`${API_BASE}/users/heartbeat`, {
+        await apiFetch(`${API_BASE}/users/heartbeat`, {
             method: 'POST',
             headers: { 'Content-Type': 'application/json' },
             body: JSON.stringify({ user_id: appState.userId })
@@ -823,7 +999,7 @@ async function sendHeartbeat() {
 
 async function getUserName(userId) {
     try {
-        const response = await fetch(`${API_BASE}/users/get?user_id=${userId}`);
+        const response = await apiFetch(`${API_BASE}/users/get?user_id=${userId}`);
         const data = await response.json();
         return data.username || userId;
     } catch (error) {